@@ -0,0 +1,38 @@
+use crate::{config, models::HistoryEntry, persist, AppState};
+use anyhow::Result;
+use std::path::PathBuf;
+
+/// The download archive of `use_download_archive`: yt-dlp's `--download-archive` file, with
+/// one `<extractor> <id>` line per video downloaded. There is one for the whole server, so a
+/// video downloaded under any API key or session is skipped for all of them.
+pub fn path() -> Result<PathBuf> {
+    Ok(config::data_dir()?.join("download-archive.txt"))
+}
+
+/// Creates the archive if needed and gives it to `run_as_uid`, for yt-dlp to read and append
+/// to. Returns its path.
+pub fn prepare(state: &AppState) -> Result<PathBuf> {
+    let path = path()?;
+    std::fs::OpenOptions::new().create(true).append(true).open(&path)?;
+    let (uid, gid) = {
+        let config = state.config.read().unwrap();
+        (config.run_as_uid, config.run_as_gid)
+    };
+    crate::privileges::hand_over(&path, uid, gid)?;
+    Ok(path)
+}
+
+/// The archive line of an item, as yt-dlp writes it: the extractor in lowercase and the ID.
+fn line(entry: &HistoryEntry) -> Option<String> {
+    Some(format!("{} {}\n", entry.extractor.as_deref()?.to_lowercase(), entry.video_id.as_deref()?))
+}
+
+/// Adds the items a job group downloaded. Its children run without the archive, or the first
+/// to finish a video would make the others skip it, so the group records them once at the end.
+pub async fn record(entries: &[HistoryEntry]) -> Result<()> {
+    let lines: String = entries.iter().filter_map(line).collect();
+    if lines.is_empty() {
+        return Ok(());
+    }
+    persist::append(&path()?, lines.as_bytes()).await
+}
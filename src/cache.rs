@@ -0,0 +1,144 @@
+use crate::{config::CacheSettings, AppState};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A small shared in-memory cache whose entries expire after a fixed time-to-live.
+/// Holds at most `capacity` entries; the oldest is evicted to make room.
+#[derive(Clone)]
+pub struct TtlCache<V> {
+    inner: Arc<Mutex<Inner<V>>>,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+}
+
+struct Inner<V> {
+    ttl: Duration,
+    capacity: usize,
+    entries: HashMap<String, (Instant, V)>,
+}
+
+/// A snapshot of a cache's size and counters, for `GET /caches` and `/metrics`.
+#[derive(Serialize, Debug)]
+pub struct CacheStats {
+    pub name: &'static str,
+    pub entries: usize,
+    pub capacity: usize,
+    pub ttl_secs: u64,
+    pub hits: u64,
+    pub misses: u64,
+    /// Approximate memory held by the entries (keys plus JSON-encoded values).
+    pub approx_bytes: usize,
+    /// Bytes on disk; all caches are in memory for now.
+    pub disk_bytes: u64,
+}
+
+impl<V: Clone> TtlCache<V> {
+    pub fn new(settings: CacheSettings) -> Self {
+        TtlCache {
+            inner: Arc::new(Mutex::new(Inner {
+                ttl: Duration::from_secs(settings.ttl_secs),
+                capacity: settings.capacity,
+                entries: HashMap::new(),
+            })),
+            hits: Arc::new(AtomicU64::new(0)),
+            misses: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Returns a copy of the value for `key` if it is present and not expired.
+    pub fn get(&self, key: &str) -> Option<V> {
+        let mut inner = self.inner.lock().unwrap();
+        let ttl = inner.ttl;
+        let value = match inner.entries.get(key) {
+            Some((inserted, value)) if inserted.elapsed() < ttl => Some(value.clone()),
+            Some(_) => {
+                inner.entries.remove(key);
+                None
+            }
+            None => None,
+        };
+        let counter = if value.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    pub fn insert(&self, key: String, value: V) {
+        let mut inner = self.inner.lock().unwrap();
+        // Drop expired entries on write so the map can't grow without bound.
+        let ttl = inner.ttl;
+        inner.entries.retain(|_, (inserted, _)| inserted.elapsed() < ttl);
+        while inner.entries.len() >= inner.capacity.max(1) && !inner.entries.contains_key(&key) {
+            let Some(oldest) = inner.entries.iter().min_by_key(|(_, (inserted, _))| *inserted).map(|(k, _)| k.clone()) else {
+                break;
+            };
+            inner.entries.remove(&oldest);
+        }
+        inner.entries.insert(key, (Instant::now(), value));
+    }
+
+    /// Applies new TTL and capacity settings; surplus entries are evicted on the next insert.
+    pub fn configure(&self, settings: CacheSettings) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.ttl = Duration::from_secs(settings.ttl_secs);
+        inner.capacity = settings.capacity;
+    }
+}
+
+/// The operations `GET /caches` and `DELETE /caches` need, independent of the value type.
+pub trait CacheControl: Send + Sync {
+    fn stats(&self, name: &'static str) -> CacheStats;
+    /// Removes every entry and returns how many there were. Counters are kept.
+    fn clear(&self) -> usize;
+}
+
+impl<V: Clone + Serialize + Send> CacheControl for TtlCache<V> {
+    fn stats(&self, name: &'static str) -> CacheStats {
+        let inner = self.inner.lock().unwrap();
+        let approx_bytes = inner
+            .entries
+            .iter()
+            .map(|(key, (_, value))| key.len() + serde_json::to_vec(value).map_or(0, |v| v.len()))
+            .sum();
+        CacheStats {
+            name,
+            entries: inner.entries.len(),
+            capacity: inner.capacity,
+            ttl_secs: inner.ttl.as_secs(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            approx_bytes,
+            disk_bytes: 0,
+        }
+    }
+
+    fn clear(&self) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let count = inner.entries.len();
+        inner.entries.clear();
+        count
+    }
+}
+
+/// Every cache the server keeps, by the name used in `/caches/:name`.
+pub fn registry(state: &AppState) -> Vec<(&'static str, &dyn CacheControl)> {
+    vec![
+        ("formats", &state.formats_cache as &dyn CacheControl),
+        ("storage_probes", &state.storage_probes as &dyn CacheControl),
+        ("library_scans", &state.library_scans as &dyn CacheControl),
+        ("file_probes", &state.file_probes as &dyn CacheControl),
+        ("playlist_counts", &state.playlist_counts as &dyn CacheControl),
+    ]
+}
+
+/// Applies the `caches` section of a (new) config to the running caches.
+pub fn apply_settings(state: &AppState) {
+    let caches = state.config.read().unwrap().caches;
+    state.formats_cache.configure(caches.formats);
+    state.storage_probes.configure(caches.storage_probes);
+    state.library_scans.configure(caches.library_scans);
+    state.file_probes.configure(caches.file_probes);
+    state.playlist_counts.configure(caches.playlist_counts);
+}
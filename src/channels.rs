@@ -0,0 +1,118 @@
+use crate::{config, error::AppError, handlers::add_http_args, models::HistoryEntry, persist, AppState};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::Stdio;
+
+/// One item of a channel (or playlist) as listed by `--flat-playlist`.
+#[derive(Clone, Serialize, Debug)]
+pub struct ChannelItem {
+    pub id: String,
+    pub url: String,
+    pub title: Option<String>,
+}
+
+/// What is remembered about a synced channel. Persisted across restarts.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ChannelRecord {
+    pub last_sync: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct FlatPlaylist {
+    #[serde(default)]
+    entries: Vec<FlatEntry>,
+}
+
+#[derive(Deserialize)]
+struct FlatEntry {
+    id: Option<String>,
+    url: Option<String>,
+    webpage_url: Option<String>,
+    title: Option<String>,
+    #[serde(rename = "_type")]
+    kind: Option<String>,
+}
+
+/// Returns the path of the per-channel sync records, keyed by channel URL.
+fn channels_path() -> Result<PathBuf> {
+    Ok(config::data_dir()?.join("channels.json"))
+}
+
+/// Lists the channel's current items with `yt-dlp --flat-playlist`, without resolving each one.
+/// Nested playlists (e.g. the tabs of a channel's main page) are skipped; use the tab's URL.
+pub async fn list_items(state: &AppState, url: &str) -> Result<Vec<ChannelItem>, AppError> {
+    if url.is_empty() {
+        return Err(AppError::BadRequest("URL parameter cannot be empty".to_string()));
+    }
+    let mut cmd = state.runner.command("yt-dlp");
+    cmd.arg("--flat-playlist").arg("--dump-single-json");
+    add_http_args(&mut cmd, state, url, &HashMap::new(), None);
+    let output = cmd.arg(url).stdin(Stdio::null()).output().await?;
+    if !output.status.success() {
+        let error_message = String::from_utf8_lossy(&output.stderr).to_string();
+        tracing::error!("yt-dlp failed to list {}: {}", url, error_message);
+        return Err(AppError::YtDlp(error_message));
+    }
+    let playlist: FlatPlaylist = serde_json::from_slice(&output.stdout)
+        .map_err(|e| anyhow::anyhow!("Failed to parse the flat playlist of {}: {}", url, e))?;
+    Ok(playlist
+        .entries
+        .into_iter()
+        .filter(|entry| entry.kind.as_deref() != Some("playlist"))
+        .filter_map(|entry| {
+            let id = entry.id?;
+            let url = entry.webpage_url.or(entry.url)?;
+            Some(ChannelItem { id, url, title: entry.title })
+        })
+        .collect())
+}
+
+/// Keeps the items that aren't in the history and aren't already queued or downloading.
+pub fn new_items(state: &AppState, items: Vec<ChannelItem>, history: &[HistoryEntry]) -> Vec<ChannelItem> {
+    let downloads = state.downloads.lock();
+    items
+        .into_iter()
+        .filter(|item| {
+            !history.iter().filter(|entry| entry.outcome.is_none()).any(|entry| entry.video_id.as_deref() == Some(item.id.as_str()) || entry.url == item.url)
+        })
+        .filter(|item| downloads.get(&item.url).is_none_or(|status| status.status == "failed"))
+        .collect()
+}
+
+/// Loads every channel's sync record. A missing file means nothing was synced yet.
+pub async fn load() -> Result<HashMap<String, ChannelRecord>> {
+    Ok(persist::read_json(&channels_path()?).await?.unwrap_or_default())
+}
+
+/// Records that `url` was synced now and returns the new record. The file stays locked from
+/// reading it to saving it, so syncs of other channels at the same time aren't lost.
+pub async fn record_sync(url: &str) -> Result<ChannelRecord> {
+    let record = ChannelRecord { last_sync: Utc::now() };
+    persist::update_json(&channels_path()?, |records: &mut HashMap<String, ChannelRecord>| {
+        records.insert(url.to_string(), record.clone());
+    })
+    .await?;
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{lock_files, state, FakeRunner};
+
+    #[tokio::test]
+    async fn keeps_every_sync_recorded_at_the_same_time() {
+        let _files = lock_files().await;
+        let _state = state(FakeRunner::new());
+        let urls: Vec<String> = (0..16).map(|i| format!("https://example.com/@channel-{}", i)).collect();
+        let syncs: Vec<_> = urls.iter().cloned().map(|url| tokio::spawn(async move { record_sync(&url).await })).collect();
+        for sync in syncs {
+            sync.await.unwrap().unwrap();
+        }
+        let records = load().await.unwrap();
+        assert!(urls.iter().all(|url| records.contains_key(url)), "{:?}", records.keys());
+    }
+}
@@ -0,0 +1,76 @@
+use anyhow::Result;
+use serde_json::Value;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+/// The suffix yt-dlp gives a live chat replay, e.g. `Stream [id].live_chat.json`.
+pub const LIVE_CHAT_SUFFIX: &str = ".live_chat.json";
+
+/// Converts a YouTube live chat replay, one JSON action per line, into a text file next to it
+/// with one `[offset] author: message` line per chat message. Other actions, e.g. polls and
+/// membership banners, are skipped. Returns the path of the text file.
+pub async fn to_text(path: &Path) -> Result<PathBuf> {
+    let content = tokio::fs::read_to_string(path).await?;
+    let mut text = String::new();
+    for line in content.lines() {
+        let Ok(action) = serde_json::from_str::<Value>(line) else { continue };
+        let Some(replay) = action.get("replayChatItemAction") else { continue };
+        let actions = replay["actions"].as_array().map(Vec::as_slice).unwrap_or_default();
+        for item in actions {
+            let renderer = &item["addChatItemAction"]["item"];
+            let Some(message) = renderer.get("liveChatTextMessageRenderer").or_else(|| renderer.get("liveChatPaidMessageRenderer")) else {
+                continue;
+            };
+            let offset = message["timestampText"]["simpleText"].as_str().unwrap_or("?");
+            let author = message["authorName"]["simpleText"].as_str().unwrap_or("unknown");
+            let _ = writeln!(text, "[{}] {}: {}", offset, author, message_text(&message["message"]));
+        }
+    }
+
+    let name = path.to_string_lossy();
+    let stem = name.strip_suffix(LIVE_CHAT_SUFFIX).unwrap_or(&name);
+    let text_path = PathBuf::from(format!("{}.live_chat.txt", stem));
+    tokio::fs::write(&text_path, text).await?;
+    Ok(text_path)
+}
+
+/// Joins a message's runs: plain text, and emojis as their first shortcut (e.g. `:smile:`).
+fn message_text(message: &Value) -> String {
+    let runs = message["runs"].as_array().map(Vec::as_slice).unwrap_or_default();
+    runs.iter()
+        .map(|run| match run.get("text").and_then(Value::as_str) {
+            Some(text) => text.to_string(),
+            None => run["emoji"]["shortcuts"][0].as_str().or_else(|| run["emoji"]["emojiId"].as_str()).unwrap_or_default().to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn writes_the_chat_messages_as_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(format!("Stream [abc]{}", LIVE_CHAT_SUFFIX));
+        let message = |renderer: &str, offset: &str, author: &str, runs: Value| {
+            serde_json::json!({ "replayChatItemAction": { "actions": [{ "addChatItemAction": { "item": { renderer: {
+                "timestampText": { "simpleText": offset },
+                "authorName": { "simpleText": author },
+                "message": { "runs": runs },
+            } } } }] } })
+        };
+        let lines = [
+            message("liveChatTextMessageRenderer", "0:05", "Ann", serde_json::json!([{ "text": "hello " }, { "emoji": { "shortcuts": [":wave:"] } }])),
+            serde_json::json!({ "replayChatItemAction": { "actions": [{ "addChatItemAction": { "item": { "liveChatPollRenderer": {} } } }] } }),
+            message("liveChatPaidMessageRenderer", "1:02:03", "Bo", serde_json::json!([{ "text": "thanks" }])),
+        ];
+        let mut content: Vec<String> = lines.iter().map(Value::to_string).collect();
+        content.insert(1, "not json".to_string());
+        tokio::fs::write(&path, content.join("\n")).await.unwrap();
+
+        let text_path = to_text(&path).await.unwrap();
+        assert_eq!(text_path, dir.path().join("Stream [abc].live_chat.txt"));
+        assert_eq!(std::fs::read_to_string(text_path).unwrap(), "[0:05] Ann: hello :wave:\n[1:02:03] Bo: thanks\n");
+    }
+}
@@ -0,0 +1,36 @@
+use crate::models::{ChecksumAlgorithm, FileChecksum};
+use anyhow::Result;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::path::Path;
+
+/// Hashes a file on a blocking thread, reading it in small chunks so a large video is never
+/// held in memory. Returns the lowercase hex digest.
+pub async fn file_digest(path: &Path, algorithm: ChecksumAlgorithm) -> Result<String> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || match algorithm {
+        ChecksumAlgorithm::Sha256 => digest_file::<Sha256>(&path),
+        ChecksumAlgorithm::Md5 => digest_file::<Md5>(&path),
+    })
+    .await?
+}
+
+fn digest_file<D: Digest + Write>(path: &Path) -> Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = D::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Hashes each of a finished download's files. A file that can't be read is left out and logged.
+pub async fn checksum_files(files: &[String], algorithm: ChecksumAlgorithm) -> Vec<FileChecksum> {
+    let mut checksums = Vec::new();
+    for path in files {
+        match file_digest(Path::new(path), algorithm).await {
+            Ok(digest) => checksums.push(FileChecksum { path: path.clone(), algorithm, digest }),
+            Err(e) => tracing::warn!("Failed to compute the checksum of {}: {:?}", path, e),
+        }
+    }
+    checksums
+}
@@ -0,0 +1,196 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Why a host doesn't take downloads right now.
+#[derive(Clone, Serialize, Debug)]
+pub struct OpenCircuit {
+    pub host: String,
+    pub consecutive_failures: u32,
+    /// When a trial download may go to the host again.
+    pub retry_at: DateTime<Utc>,
+}
+
+#[derive(Default)]
+struct HostCircuit {
+    consecutive_failures: u32,
+    /// Set once the circuit opened. Downloads fail until then; after it the circuit is
+    /// half-open and lets one trial download through, which pushes it out by a cooldown
+    /// again so a trial that never reports back doesn't hold the host forever.
+    opened_until: Option<DateTime<Utc>>,
+}
+
+/// How a download that was let through to a host ended, as far as its circuit is concerned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// The download completed, at least in part.
+    Succeeded,
+    /// It failed in a way that points at the host: a network error, a 5xx or a 429 (see
+    /// `ErrorCode::is_host_failure`).
+    HostFailed,
+}
+
+/// A download let through to a host by `CircuitBreakers::admit`, to be settled with `record`.
+/// Dropping it unsettled, e.g. on a cancel or a failure that says nothing about the host, leaves
+/// the circuit as it was; a half-open circuit's trial is handed back for the next download.
+pub struct Attempt {
+    breakers: CircuitBreakers,
+    host: String,
+    threshold: u32,
+    cooldown: Duration,
+    trial: bool,
+    settled: bool,
+}
+
+/// Per-host circuit breakers for `circuit_breaker_threshold`. After that many downloads from
+/// a host fail in a row, new downloads from it fail right away for
+/// `circuit_breaker_cooldown_secs`. Then one trial download is let through: if it succeeds
+/// the circuit closes, if it fails the cooldown starts over.
+#[derive(Clone, Default)]
+pub struct CircuitBreakers {
+    hosts: Arc<Mutex<HashMap<String, HostCircuit>>>,
+}
+
+impl CircuitBreakers {
+    /// Fails if downloads from `host` are held back, without claiming the trial of a
+    /// half-open circuit. Used when a download is requested.
+    pub fn check(&self, host: &str) -> Result<(), OpenCircuit> {
+        let hosts = self.hosts.lock().unwrap();
+        let Some(circuit) = hosts.get(host) else { return Ok(()) };
+        match circuit.opened_until {
+            Some(until) if until > Utc::now() => Err(open_circuit(host, circuit, until)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Like `check`, but claims the trial of a half-open circuit. Used right before a
+    /// download runs; the returned attempt records how it went.
+    pub fn admit(&self, host: &str, threshold: u32, cooldown: Duration) -> Result<Attempt, OpenCircuit> {
+        let mut hosts = self.hosts.lock().unwrap();
+        let mut trial = false;
+        if let Some(circuit) = hosts.get_mut(host) {
+            match circuit.opened_until {
+                Some(until) if until > Utc::now() => return Err(open_circuit(host, circuit, until)),
+                Some(_) => {
+                    circuit.opened_until = Some(Utc::now() + cooldown);
+                    trial = true;
+                    tracing::info!("Circuit for {} is half-open; letting a trial download through", host);
+                }
+                None => {}
+            }
+        }
+        Ok(Attempt { breakers: self.clone(), host: host.to_string(), threshold, cooldown, trial, settled: false })
+    }
+
+    /// Forgets every host, e.g. when `circuit_breaker_threshold` is unset.
+    pub fn clear(&self) {
+        self.hosts.lock().unwrap().clear();
+    }
+
+    /// The hosts whose circuit is open or half-open, for `GET /health`.
+    pub fn open(&self) -> Vec<OpenCircuit> {
+        let hosts = self.hosts.lock().unwrap();
+        let mut open: Vec<OpenCircuit> = hosts
+            .iter()
+            .filter_map(|(host, circuit)| circuit.opened_until.map(|until| open_circuit(host, circuit, until)))
+            .collect();
+        open.sort_by(|a, b| a.host.cmp(&b.host));
+        open
+    }
+}
+
+impl Attempt {
+    /// Records how the download ended. Returns the circuit if this failure opened it.
+    pub fn record(mut self, outcome: Outcome) -> Option<OpenCircuit> {
+        self.settled = true;
+        let mut hosts = self.breakers.hosts.lock().unwrap();
+        if outcome == Outcome::Succeeded {
+            if hosts.remove(&self.host).is_some_and(|circuit| circuit.opened_until.is_some()) {
+                tracing::info!("Circuit for {} closed after a successful download", self.host);
+            }
+            return None;
+        }
+        let circuit = hosts.entry(self.host.clone()).or_default();
+        circuit.consecutive_failures += 1;
+        // Any failure of a circuit that has opened before is its trial failing.
+        if circuit.opened_until.is_some() || circuit.consecutive_failures >= self.threshold {
+            let until = Utc::now() + self.cooldown;
+            circuit.opened_until = Some(until);
+            return Some(open_circuit(&self.host, circuit, until));
+        }
+        None
+    }
+}
+
+impl Drop for Attempt {
+    fn drop(&mut self) {
+        if self.settled || !self.trial {
+            return;
+        }
+        if let Some(circuit) = self.breakers.hosts.lock().unwrap().get_mut(&self.host) {
+            if circuit.opened_until.is_some() {
+                circuit.opened_until = Some(Utc::now());
+            }
+        }
+    }
+}
+
+fn open_circuit(host: &str, circuit: &HostCircuit, until: DateTime<Utc>) -> OpenCircuit {
+    OpenCircuit { host: host.to_string(), consecutive_failures: circuit.consecutive_failures, retry_at: until }
+}
+
+/// The host a download's circuit is keyed by: the URL's host, lowercased.
+pub fn host_of(url: &str) -> Option<String> {
+    Some(reqwest::Url::parse(url).ok()?.host_str()?.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: &str = "example.com";
+
+    fn fail(breakers: &CircuitBreakers, cooldown: Duration) -> Option<OpenCircuit> {
+        breakers.admit(HOST, 2, cooldown).unwrap().record(Outcome::HostFailed)
+    }
+
+    #[test]
+    fn trips_after_the_threshold_and_resets_on_success() {
+        let breakers = CircuitBreakers::default();
+        let cooldown = Duration::from_secs(60);
+        assert!(fail(&breakers, cooldown).is_none());
+        assert!(breakers.admit(HOST, 2, cooldown).unwrap().record(Outcome::Succeeded).is_none());
+        assert!(fail(&breakers, cooldown).is_none(), "a success starts the count over");
+
+        let opened = fail(&breakers, cooldown).unwrap();
+        assert_eq!(opened.consecutive_failures, 2);
+        assert!(breakers.check(HOST).is_err());
+        assert!(breakers.admit(HOST, 2, cooldown).is_err());
+        assert!(breakers.check("other.example.com").is_ok());
+        assert_eq!(breakers.open().len(), 1);
+    }
+
+    #[test]
+    fn a_half_open_circuit_lets_one_trial_through() {
+        let breakers = CircuitBreakers::default();
+        // Without a cooldown the circuit is half-open as soon as it opens.
+        fail(&breakers, Duration::ZERO);
+        fail(&breakers, Duration::ZERO).unwrap();
+        assert!(breakers.check(HOST).is_ok());
+
+        let trial = breakers.admit(HOST, 2, Duration::from_secs(60)).unwrap();
+        assert!(breakers.admit(HOST, 2, Duration::from_secs(60)).is_err(), "only one trial at a time");
+        // A trial that ends without an outcome, e.g. cancelled, hands the trial back.
+        drop(trial);
+        let trial = breakers.admit(HOST, 2, Duration::ZERO).unwrap();
+        // A failed trial opens the circuit again right away, below the threshold.
+        assert_eq!(trial.record(Outcome::HostFailed).unwrap().consecutive_failures, 3);
+
+        let trial = breakers.admit(HOST, 2, Duration::from_secs(60)).unwrap();
+        assert!(trial.record(Outcome::Succeeded).is_none());
+        assert!(breakers.open().is_empty());
+        assert!(fail(&breakers, Duration::from_secs(60)).is_none(), "the closed circuit counts from zero");
+    }
+}
@@ -1,13 +1,776 @@
+use crate::models::{ChecksumAlgorithm, ForceIp};
+use crate::persist;
 use anyhow::{anyhow, Result};
+use axum::http::HeaderName;
 use directories::{ProjectDirs, UserDirs};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
 use std::path::PathBuf;
 use tokio::fs;
 
 /// The structure of our configuration file (config.toml)
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
+    /// The layout version of the file; older files are migrated on load (see `MIGRATIONS`).
+    /// A file without it is version 0 (see `parse_config`); `POST /config` may leave it out.
+    #[serde(default = "default_config_version")]
+    pub config_version: u32,
     pub download_directory: String,
+    /// Extra directories a download may target via `download_root`, in addition to the primary one.
+    #[serde(default)]
+    pub allowed_download_roots: Vec<String>,
+    /// Put downloads without an explicit `output_template` into a `YYYY-MM-DD` folder,
+    /// named after the local date the download was requested, under the download root.
+    #[serde(default)]
+    pub date_folders: bool,
+    /// How many `yt-dlp` format probes the batch formats endpoint runs in parallel.
+    #[serde(default = "default_max_concurrent_probes")]
+    pub max_concurrent_probes: usize,
+    /// How many downloads may run at once; the rest wait in the "queued" state. Unlimited when
+    /// unset. Changes through `POST /config` apply without a restart.
+    #[serde(default)]
+    pub max_concurrent_downloads: Option<usize>,
+    /// How many downloads may be running or queued at once; further requests are refused with
+    /// 503 instead of queuing. Unlimited when unset.
+    #[serde(default)]
+    pub max_queue_length: Option<usize>,
+    /// How many downloads with CPU-heavy post-processing (`extract_audio`, `remux_video`,
+    /// `sponsorblock_remove`) may run at once. Unlimited when unset.
+    #[serde(default)]
+    pub max_concurrent_postprocess: Option<usize>,
+    /// After this many downloads from one host fail in a row, new downloads from it are refused
+    /// for `circuit_breaker_cooldown_secs`. Off when unset.
+    #[serde(default)]
+    pub circuit_breaker_threshold: Option<u32>,
+    /// How long a host's downloads are refused once `circuit_breaker_threshold` is reached,
+    /// before one trial download is let through.
+    #[serde(default = "default_circuit_breaker_cooldown_secs")]
+    pub circuit_breaker_cooldown_secs: u64,
+    /// Kill a download that reports no progress for this many seconds once it is downloading,
+    /// e.g. a transfer that hangs with the process still alive. Post-processing pauses the
+    /// timer. It fails as stalled. Off when unset.
+    #[serde(default)]
+    pub stall_timeout_secs: Option<u64>,
+    /// How many downloads that bypass the queue may run at once.
+    #[serde(default = "default_max_express_downloads")]
+    pub max_express_downloads: usize,
+    /// How many `ffprobe` processes the media index runs at once. Read at startup.
+    #[serde(default = "default_max_concurrent_ffprobes")]
+    pub max_concurrent_ffprobes: usize,
+    /// Also write a `<file>.source.json` next to each completed file with its provenance.
+    #[serde(default)]
+    pub write_provenance_sidecar: bool,
+    /// The size the history file may grow to. Beyond it the oldest entries are dropped until
+    /// it is down to three quarters of this.
+    #[serde(default = "default_max_history_bytes")]
+    pub max_history_bytes: u64,
+    /// Permissions set on each file of a completed download, e.g. `0o640` in TOML.
+    /// Unix only; ignored with a log note elsewhere.
+    #[serde(default)]
+    pub output_file_mode: Option<u32>,
+    /// The user yt-dlp runs as, e.g. an unprivileged account when the server starts as root.
+    /// Unix only. The download roots and the data directory must be writable by it.
+    #[serde(default)]
+    pub run_as_uid: Option<u32>,
+    /// The group yt-dlp runs as. Defaults to the primary group of `run_as_uid`.
+    #[serde(default)]
+    pub run_as_gid: Option<u32>,
+    /// When set (e.g. "X-Accel-Redirect" for nginx, "X-Sendfile" for Apache), `GET /files/*path`
+    /// returns this header and lets the fronting web server send the file instead of streaming it.
+    #[serde(default)]
+    pub sendfile_header: Option<String>,
+    /// The internal URI prefix nginx maps to the download directory, used with "X-Accel-Redirect".
+    #[serde(default)]
+    pub sendfile_prefix: Option<String>,
+    /// The URL clients reach this server at, e.g. "https://example.com/yt" behind a reverse
+    /// proxy. File links in responses start with it; without it they are relative to the host.
+    #[serde(default)]
+    pub public_base_url: Option<String>,
+    /// Caps how fast `GET /files/*path` streams a file to one client, e.g. "5M" bytes per
+    /// second (suffixes as in yt-dlp's `--limit-rate`). Unlimited when unset.
+    #[serde(default)]
+    pub file_serve_rate_limit: Option<String>,
+    /// Caps how fast all `GET /files/*path` transfers together may go, in the same format.
+    /// Unlimited when unset.
+    #[serde(default)]
+    pub file_serve_global_rate_limit: Option<String>,
+    /// Compress API responses with gzip or brotli for clients that accept it. Served files
+    /// never are. Turn off to read raw responses while debugging.
+    #[serde(default = "default_true")]
+    pub compress_responses: bool,
+    /// Include the error chain of internal server errors in their response, as `debug`, for
+    /// requests with an admin key. They are always logged in full either way.
+    #[serde(default)]
+    pub debug_errors: bool,
+    /// Append "+bestaudio" to video-only formats (and extract audio-only ones) instead of rejecting them.
+    #[serde(default = "default_true")]
+    pub auto_merge_audio: bool,
+    /// What to do when `remux_video` targets a container the selected codecs can't go into.
+    #[serde(default)]
+    pub remux_check: RemuxCheck,
+    /// The tallest video, in pixels, clients may download, e.g. 1080 to forbid 4K.
+    #[serde(default)]
+    pub max_height: Option<u32>,
+    /// Downloads estimated above this many bytes need `confirm_large: true` in the request.
+    /// Off when unset.
+    #[serde(default)]
+    pub large_download_threshold: Option<u64>,
+    /// Playlists and channels with more items than this need `override_playlist_limit: true`
+    /// or a `playlist_items` range in the request. 0 turns the check off.
+    #[serde(default = "default_max_playlist_items")]
+    pub max_playlist_items: u64,
+    /// Hide formats that can't be downloaded as media (storyboards, no streams, zero bitrate)
+    /// from `/formats` responses unless `include_all` is requested.
+    #[serde(default = "default_true")]
+    pub filter_formats: bool,
+    /// How many entries of a playlist `GET /formats` probes before truncating.
+    #[serde(default = "default_playlist_probe_entries")]
+    pub playlist_probe_entries: usize,
+    /// Show a desktop notification when a download finishes or fails, while `server run`
+    /// runs in a terminal. Ignored by a server launched with `server start` or without a terminal.
+    #[serde(default)]
+    pub desktop_notifications: bool,
+    /// In `server run`, let active downloads finish on the first Ctrl-C instead of killing them.
+    #[serde(default = "default_true")]
+    pub drain_on_ctrl_c: bool,
+    /// Refuse to start downloads when the target filesystem has less free space than this.
+    #[serde(default = "default_min_free_space_mb")]
+    pub min_free_space_mb: u64,
+    /// The `source_address` used when a download request doesn't set one.
+    #[serde(default)]
+    pub default_source_address: Option<String>,
+    /// The `force_ip` used when a download request doesn't set one.
+    #[serde(default)]
+    pub default_force_ip: Option<ForceIp>,
+    /// The `compute_checksum` used when a download request doesn't set one.
+    #[serde(default)]
+    pub default_checksum: Option<ChecksumAlgorithm>,
+    /// On Unix, check that `source_address` is assigned to a local interface before starting.
+    #[serde(default = "default_true")]
+    pub verify_source_address: bool,
+    /// Stop accepting downloads once this many bytes have been downloaded in total,
+    /// until `POST /admin/quota/reset`. Unlimited when unset.
+    #[serde(default)]
+    pub total_bytes_quota: Option<u64>,
+    /// List the files a download will produce (`expected_files` on the status) before it starts.
+    /// Turn off for very large playlists.
+    #[serde(default = "default_true")]
+    pub preview_expected_files: bool,
+    /// The longest the expected-files preview may delay the start of a download.
+    #[serde(default = "default_expected_files_budget_ms")]
+    pub expected_files_budget_ms: u64,
+    /// The longest a rendered file or directory name may be, in UTF-8 bytes. Checked against
+    /// the expected-files preview, so only enforced while `preview_expected_files` is on.
+    #[serde(default = "default_max_filename_bytes")]
+    pub max_filename_bytes: usize,
+    /// What happens to a download whose rendered names are longer than `max_filename_bytes`.
+    #[serde(default)]
+    pub long_filename_policy: LongFilenamePolicy,
+    /// Let `GET /files` list, and `GET /files/*path` serve, files reached through symlinks.
+    /// Targets outside the download root are refused either way.
+    #[serde(default)]
+    pub follow_symlinks: bool,
+    /// Records the client's IP address in the `origin` of each download it starts. Turn off
+    /// to keep addresses out of statuses and history.
+    #[serde(default = "default_true")]
+    pub record_client_ip: bool,
+    /// Serves `GET /debug/bundle`, which includes the status map and recent logs, to requests
+    /// with an admin key. Off by default.
+    #[serde(default)]
+    pub allow_debug_bundle: bool,
+    /// Keeps the files of each download root in memory and walks them again every this many
+    /// seconds, instead of on every `GET /files` or `GET /stats`. Off when unset.
+    #[serde(default)]
+    pub library_index_interval_secs: Option<u64>,
+    /// Time-to-live and capacity of the in-memory caches (see `GET /caches`).
+    #[serde(default)]
+    pub caches: CachesConfig,
+    /// Headers sent with every extraction (`--add-headers`), e.g. for trackers that require them.
+    /// Values of secret-looking headers such as `Authorization` are redacted from `GET /config`.
+    #[serde(default)]
+    pub http_headers: HashMap<String, String>,
+    /// The user agent yt-dlp uses unless a request sets its own.
+    #[serde(default)]
+    pub user_agent: Option<String>,
+    /// A Referer sent to content hosts that refuse requests without a matching one, by host,
+    /// e.g. `"cdn.example.com" = "https://example.com/"`. A host also covers its subdomains.
+    /// A `Referer` in `http_headers` or in the request takes precedence.
+    #[serde(default)]
+    pub host_referers: HashMap<String, String>,
+    /// A Netscape-format cookies file, passed as `--cookies` when retrying an age-restricted download.
+    #[serde(default)]
+    pub cookies_file: Option<String>,
+    /// A browser to load cookies from, e.g. "firefox", passed as `--cookies-from-browser` when
+    /// retrying an age-restricted download. Used if `cookies_file` isn't set.
+    #[serde(default)]
+    pub cookies_from_browser: Option<String>,
+    /// How long a session (see `POST /session`) lives after it was last used.
+    #[serde(default = "default_session_ttl_secs")]
+    pub session_ttl_secs: u64,
+    /// Accepts resumable uploads (`POST /uploads`) of cookie files and URL lists.
+    #[serde(default)]
+    pub allow_uploads: bool,
+    /// The largest file `POST /uploads` accepts; larger ones are refused with 413.
+    #[serde(default = "default_max_upload_bytes")]
+    pub max_upload_bytes: u64,
+    /// How many uploads may be in progress at once; further ones are refused with 429.
+    #[serde(default = "default_max_uploads")]
+    pub max_uploads: usize,
+    /// How long an unfinished upload is kept after its last chunk.
+    #[serde(default = "default_upload_stale_after_secs")]
+    pub upload_stale_after_secs: u64,
+    /// Refuse age-restricted videos in `/formats` and downloads, e.g. for family deployments.
+    #[serde(default)]
+    pub block_age_restricted: bool,
+    /// Keep one download archive for every key and session, and skip the videos in it.
+    #[serde(default)]
+    pub use_download_archive: bool,
+    /// What to do at startup when `config.toml` can't be parsed.
+    #[serde(default)]
+    pub on_parse_error: OnParseError,
+    /// Labels the download metrics with the extractor. Turn off when downloads come from
+    /// thousands of different sites, to keep the number of series down.
+    #[serde(default = "default_true")]
+    pub metrics_extractor_label: bool,
+    /// A URL to POST anonymous usage counters to, e.g. a fleet dashboard. Off when unset.
+    #[serde(default)]
+    pub report_to: Option<String>,
+    /// When set, reports are signed with an HMAC-SHA256 of the body using this shared secret.
+    #[serde(default)]
+    pub report_secret: Option<String>,
+    #[serde(default = "default_report_interval_minutes")]
+    pub report_interval_minutes: u64,
+    /// API keys by name, e.g. `{ alice = "<secret>" }` or `{ ops = { secret = "<secret>", role =
+    /// "admin" } }`. Clients send the secret in `X-Api-Key`. Once any are set, routes that start
+    /// downloads refuse requests without a known key, and configuration and admin routes refuse
+    /// keys without the admin role.
+    #[serde(default)]
+    pub api_keys: BTreeMap<String, ApiKey>,
+    /// How many downloads each API key may start per day (UTC). Unlimited when unset.
+    #[serde(default)]
+    pub max_downloads_per_day: Option<u64>,
+    /// How many bytes each API key's downloads may fetch per day (UTC). A download running when
+    /// the limit is reached finishes; later ones are refused. Unlimited when unset.
+    #[serde(default)]
+    pub max_bytes_per_day: Option<u64>,
+    /// A URL to POST to when a download starts and when it finishes. Off when unset.
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+    /// When set, webhooks are signed with an HMAC-SHA256 of the body using this shared secret.
+    #[serde(default)]
+    pub webhook_secret: Option<String>,
+    /// The JSON body of webhooks, with `{{event}}`, `{{title}}`, `{{status}}` and `{{files}}`
+    /// placeholders inside its strings. Without one, the download's status is sent.
+    #[serde(default)]
+    pub webhook_template: Option<String>,
+    /// How many redirects the server's own outbound requests, e.g. usage reports, may follow.
+    #[serde(default = "default_max_redirects")]
+    pub max_redirects: usize,
+    /// Identifies this instance in usage reports.
+    #[serde(default)]
+    pub instance_name: Option<String>,
+    /// Which yt-dlp runs: the one on PATH, or a release the server downloads and verifies itself.
+    #[serde(default)]
+    pub ytdlp_channel: YtDlpChannel,
+    /// The release `managed` mode installs, e.g. "2024.08.06", or "latest". `POST /ytdlp/install`
+    /// updates it.
+    #[serde(default = "default_ytdlp_version")]
+    pub ytdlp_version: String,
+    /// Where `managed` mode downloads releases from: a GitHub releases page, or a mirror laid out
+    /// the same way (`<url>/download/<version>/<asset>` and `<url>/latest`).
+    #[serde(default = "default_ytdlp_release_url")]
+    pub ytdlp_release_url: String,
+    /// yt-dlp options in its config file syntax, written to a file in the data directory and
+    /// passed to every invocation with `--config-locations`. The options the API derives come
+    /// after it and take precedence.
+    #[serde(default)]
+    pub ytdlp_config_inline: Option<String>,
+    /// Settings this version doesn't know, e.g. from a newer release. Kept and written back
+    /// unchanged so a downgrade doesn't lose them.
+    #[serde(flatten)]
+    pub extra: toml::Table,
+}
+
+/// The `config_version` written by this release.
+pub const CONFIG_VERSION: u32 = 1;
+
+/// The longest `report_interval_minutes`: a week.
+const MAX_REPORT_INTERVAL_MINUTES: u64 = 7 * 24 * 60;
+
+/// Upgrades a config file one version at a time: entry `n` turns version `n` into `n + 1`,
+/// typically by renaming keys in the raw table before it is deserialized.
+const MIGRATIONS: &[fn(&mut toml::Table)] = &[
+    // 0 -> 1: files written before `config_version` existed. Nothing was renamed.
+    |_| {},
+];
+
+fn default_config_version() -> u32 {
+    CONFIG_VERSION
+}
+
+fn default_report_interval_minutes() -> u64 {
+    15
+}
+
+fn default_max_express_downloads() -> usize {
+    1
+}
+
+fn default_circuit_breaker_cooldown_secs() -> u64 {
+    300
+}
+
+fn default_ytdlp_version() -> String {
+    "latest".to_string()
+}
+
+fn default_ytdlp_release_url() -> String {
+    "https://github.com/yt-dlp/yt-dlp/releases".to_string()
+}
+
+fn default_max_redirects() -> usize {
+    5
+}
+
+/// What `load_config` does with a config file that can't be parsed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnParseError {
+    /// Refuse to start.
+    #[default]
+    Fail,
+    /// Rename the file to `config.toml.corrupt-<timestamp>`, log a warning and start with the
+    /// defaults.
+    Backup,
+}
+
+/// What a download does when a rendered file name is longer than `max_filename_bytes`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LongFilenamePolicy {
+    /// Shorten the name, keeping the extension and a trailing `[id]`.
+    #[default]
+    Truncate,
+    /// Fail the download before it starts.
+    Error,
+}
+
+/// Settings for each cache the server keeps, by the name used in `/caches/:name`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CachesConfig {
+    /// `yt-dlp` format probes, keyed by URL.
+    #[serde(default = "default_formats_cache")]
+    pub formats: CacheSettings,
+    /// Download-directory writability and free-space probes.
+    #[serde(default = "default_storage_probes_cache")]
+    pub storage_probes: CacheSettings,
+    /// File counts and sizes of download directories, for `GET /stats`.
+    #[serde(default = "default_library_scans_cache")]
+    pub library_scans: CacheSettings,
+    /// ffprobe details of single files, for `GET /files/:path/probe`. An entry is also
+    /// bypassed as soon as the file's size or modification time changes.
+    #[serde(default = "default_file_probes_cache")]
+    pub file_probes: CacheSettings,
+    /// Playlist sizes probed for `max_playlist_items`, keyed by URL.
+    #[serde(default = "default_playlist_counts_cache")]
+    pub playlist_counts: CacheSettings,
+}
+
+impl Default for CachesConfig {
+    fn default() -> Self {
+        CachesConfig {
+            formats: default_formats_cache(),
+            storage_probes: default_storage_probes_cache(),
+            library_scans: default_library_scans_cache(),
+            file_probes: default_file_probes_cache(),
+            playlist_counts: default_playlist_counts_cache(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheSettings {
+    /// How long an entry stays valid.
+    pub ttl_secs: u64,
+    /// The most entries kept; the oldest is evicted beyond this.
+    pub capacity: usize,
+}
+
+fn default_formats_cache() -> CacheSettings {
+    CacheSettings { ttl_secs: 30 * 60, capacity: 500 }
+}
+
+fn default_storage_probes_cache() -> CacheSettings {
+    // Short, so a freed-up or remounted disk is noticed quickly.
+    CacheSettings { ttl_secs: 10, capacity: 64 }
+}
+
+fn default_library_scans_cache() -> CacheSettings {
+    CacheSettings { ttl_secs: 60, capacity: 16 }
+}
+
+fn default_file_probes_cache() -> CacheSettings {
+    CacheSettings { ttl_secs: 60 * 60, capacity: 256 }
+}
+
+fn default_playlist_counts_cache() -> CacheSettings {
+    CacheSettings { ttl_secs: 30 * 60, capacity: 500 }
+}
+
+impl Config {
+    /// Checks the settings that can't be expressed in the types, returning one message per problem.
+    /// Filesystem checks that need I/O are done by `handlers::config::validate_config`.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.download_directory.trim().is_empty() {
+            problems.push("download_directory must not be empty".to_string());
+        }
+        if self.allowed_download_roots.iter().any(|root| root.trim().is_empty()) {
+            problems.push("allowed_download_roots must not contain empty paths".to_string());
+        }
+        if self.max_concurrent_probes == 0 {
+            problems.push("max_concurrent_probes must be greater than 0".to_string());
+        }
+        if !(1..=255).contains(&self.max_filename_bytes) {
+            problems.push("max_filename_bytes must be between 1 and 255".to_string());
+        }
+        problems.extend(crate::privileges::problems(self.run_as_uid, self.run_as_gid));
+        if self.output_file_mode.is_some_and(|mode| mode > 0o7777) {
+            problems.push("output_file_mode must be a permission mode between 0o0 and 0o7777".to_string());
+        }
+        if self.max_concurrent_ffprobes == 0 {
+            problems.push("max_concurrent_ffprobes must be greater than 0".to_string());
+        }
+        if self.max_concurrent_downloads == Some(0) {
+            problems.push("max_concurrent_downloads must be greater than 0".to_string());
+        }
+        if self.max_queue_length == Some(0) {
+            problems.push("max_queue_length must be greater than 0".to_string());
+        }
+        if self.max_concurrent_postprocess == Some(0) {
+            problems.push("max_concurrent_postprocess must be greater than 0".to_string());
+        }
+        if self.circuit_breaker_threshold == Some(0) {
+            problems.push("circuit_breaker_threshold must be greater than 0".to_string());
+        }
+        if self.stall_timeout_secs == Some(0) {
+            problems.push("stall_timeout_secs must be greater than 0".to_string());
+        }
+        if self.circuit_breaker_cooldown_secs == 0 {
+            problems.push("circuit_breaker_cooldown_secs must be greater than 0".to_string());
+        }
+        if self.max_express_downloads == 0 {
+            problems.push("max_express_downloads must be greater than 0".to_string());
+        }
+        if self.library_index_interval_secs == Some(0) {
+            problems.push("library_index_interval_secs must be greater than 0".to_string());
+        }
+        if self.large_download_threshold == Some(0) {
+            problems.push("large_download_threshold must be greater than 0".to_string());
+        }
+        if self.max_height == Some(0) {
+            problems.push("max_height must be greater than 0".to_string());
+        }
+        if self.playlist_probe_entries == 0 {
+            problems.push("playlist_probe_entries must be greater than 0".to_string());
+        }
+        if let Some(base) = &self.public_base_url {
+            let usable = reqwest::Url::parse(base)
+                .is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.query().is_none() && url.fragment().is_none());
+            if !usable {
+                problems.push(format!("public_base_url '{}' must be an http(s) URL without a query or fragment", base));
+            }
+        }
+        if let Some(header) = &self.sendfile_header {
+            if HeaderName::from_bytes(header.as_bytes()).is_err() {
+                problems.push(format!("sendfile_header '{}' is not a valid header name", header));
+            }
+        }
+        let rate_limits = [
+            ("file_serve_rate_limit", &self.file_serve_rate_limit),
+            ("file_serve_global_rate_limit", &self.file_serve_global_rate_limit),
+        ];
+        for (name, limit) in rate_limits {
+            if let Some(limit) = limit.as_deref().filter(|limit| crate::throttle::parse_rate(limit).is_none()) {
+                problems.push(format!("{} '{}' is not a rate, e.g. \"500K\" or \"4.2M\"", name, limit));
+            }
+        }
+        if let Some(address) = &self.default_source_address {
+            match address.parse::<IpAddr>() {
+                Err(_) => problems.push(format!("default_source_address '{}' is not a valid IP address", address)),
+                Ok(ip) => match self.default_force_ip {
+                    Some(ForceIp::Ipv4) if !ip.is_ipv4() => {
+                        problems.push(format!("default_source_address '{}' is not an IPv4 address but default_force_ip is ipv4", address));
+                    }
+                    Some(ForceIp::Ipv6) if !ip.is_ipv6() => {
+                        problems.push(format!("default_source_address '{}' is not an IPv6 address but default_force_ip is ipv6", address));
+                    }
+                    _ => {}
+                },
+            }
+        }
+        let caches = [
+            ("formats", self.caches.formats),
+            ("storage_probes", self.caches.storage_probes),
+            ("library_scans", self.caches.library_scans),
+            ("file_probes", self.caches.file_probes),
+            ("playlist_counts", self.caches.playlist_counts),
+        ];
+        for (name, settings) in caches {
+            if settings.ttl_secs == 0 {
+                problems.push(format!("caches.{}.ttl_secs must be greater than 0", name));
+            }
+            if settings.capacity == 0 {
+                problems.push(format!("caches.{}.capacity must be greater than 0", name));
+            }
+        }
+        if let Some(target) = &self.report_to {
+            if !reqwest::Url::parse(target).is_ok_and(|url| matches!(url.scheme(), "http" | "https")) {
+                problems.push(format!("report_to '{}' is not an http(s) URL", target));
+            }
+        }
+        if let Some(target) = &self.webhook_url {
+            if !reqwest::Url::parse(target).is_ok_and(|url| matches!(url.scheme(), "http" | "https")) {
+                problems.push(format!("webhook_url '{}' is not an http(s) URL", target));
+            }
+        }
+        let mut secrets: Vec<&str> = self.api_keys.values().map(|key| key.secret.trim()).collect();
+        secrets.sort_unstable();
+        if secrets.first().is_some_and(|secret| secret.is_empty()) {
+            problems.push("api_keys must not have empty secrets".to_string());
+        }
+        if secrets.windows(2).any(|pair| pair[0] == pair[1]) {
+            problems.push("api_keys must have a different secret for each key".to_string());
+        }
+        if !self.api_keys.is_empty() && !self.api_keys.values().any(ApiKey::is_admin) {
+            problems.push("api_keys must include a key with role = \"admin\", or the configuration can't be changed again".to_string());
+        }
+        if self.max_downloads_per_day == Some(0) {
+            problems.push("max_downloads_per_day must be greater than 0".to_string());
+        }
+        if self.max_bytes_per_day == Some(0) {
+            problems.push("max_bytes_per_day must be greater than 0".to_string());
+        }
+        if let Some(inline) = &self.ytdlp_config_inline {
+            problems.extend(crate::ytdlp::inline_config_problems(inline));
+        }
+        if let Some(template) = &self.webhook_template {
+            problems.extend(crate::webhook::template_problems(template));
+        }
+        if !crate::ytdlp::is_valid_version(&self.ytdlp_version) {
+            problems.push(format!("ytdlp_version '{}' is not a release name, e.g. \"2024.08.06\" or \"latest\"", self.ytdlp_version));
+        }
+        if !reqwest::Url::parse(&self.ytdlp_release_url).is_ok_and(|url| matches!(url.scheme(), "http" | "https")) {
+            problems.push(format!("ytdlp_release_url '{}' is not an http(s) URL", self.ytdlp_release_url));
+        }
+        if self.session_ttl_secs == 0 {
+            problems.push("session_ttl_secs must be greater than 0".to_string());
+        }
+        if self.max_history_bytes == 0 {
+            problems.push("max_history_bytes must be greater than 0".to_string());
+        }
+        if self.max_upload_bytes == 0 {
+            problems.push("max_upload_bytes must be greater than 0".to_string());
+        }
+        if self.max_uploads == 0 {
+            problems.push("max_uploads must be greater than 0".to_string());
+        }
+        if self.upload_stale_after_secs == 0 {
+            problems.push("upload_stale_after_secs must be greater than 0".to_string());
+        }
+        if !(1..=MAX_REPORT_INTERVAL_MINUTES).contains(&self.report_interval_minutes) {
+            problems.push(format!("report_interval_minutes must be between 1 and {} (a week)", MAX_REPORT_INTERVAL_MINUTES));
+        }
+        if self.cookies_file.as_deref().is_some_and(|path| path.trim().is_empty()) {
+            problems.push("cookies_file must not be empty".to_string());
+        }
+        if self.cookies_from_browser.as_deref().is_some_and(|browser| browser.trim().is_empty() || browser.starts_with('-')) {
+            problems.push("cookies_from_browser must name a browser, e.g. \"firefox\"".to_string());
+        }
+        problems.extend(http_header_problems(&self.http_headers, self.user_agent.as_deref()));
+        problems.extend(host_referer_problems(&self.host_referers));
+        problems
+    }
+}
+
+/// Checks `host_referers`: keys must be bare host names, and values http(s) URLs without
+/// control characters, as they become `--referer` arguments.
+fn host_referer_problems(referers: &HashMap<String, String>) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut hosts: Vec<&String> = referers.keys().collect();
+    hosts.sort();
+    for host in hosts {
+        let is_host_name = !host.is_empty() && host.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-');
+        if !is_host_name {
+            problems.push(format!("host_referers key '{}' must be a host name such as \"cdn.example.com\"", host.escape_debug()));
+            continue;
+        }
+        let referer = &referers[host];
+        if !is_safe_referer(referer) {
+            problems.push(format!("The host_referers value for '{}' must be an http(s) URL without control characters", host));
+        }
+    }
+    problems
+}
+
+/// Whether a Referer value is an http(s) URL that can't smuggle in another header.
+pub fn is_safe_referer(referer: &str) -> bool {
+    !referer.chars().any(char::is_control)
+        && reqwest::Url::parse(referer).is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+}
+
+/// Checks headers and a user agent before they become `--add-headers NAME:VALUE` and
+/// `--user-agent` arguments: names must be plain tokens, and nothing may contain CR or LF.
+pub fn http_header_problems(headers: &HashMap<String, String>, user_agent: Option<&str>) -> Vec<String> {
+    let has_line_break = |s: &str| s.contains(['\r', '\n']);
+    let mut problems = Vec::new();
+    let mut names: Vec<&String> = headers.keys().collect();
+    names.sort();
+    for name in names {
+        if HeaderName::from_bytes(name.as_bytes()).is_err() {
+            problems.push(format!("'{}' is not a valid HTTP header name", name.escape_debug()));
+        } else if has_line_break(&headers[name]) {
+            problems.push(format!("The value of HTTP header '{}' must not contain line breaks", name));
+        }
+    }
+    if user_agent.is_some_and(has_line_break) {
+        problems.push("user_agent must not contain line breaks".to_string());
+    }
+    problems
+}
+
+fn default_session_ttl_secs() -> u64 {
+    3600
+}
+
+fn default_max_history_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_max_upload_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_max_uploads() -> usize {
+    8
+}
+
+fn default_upload_stale_after_secs() -> u64 {
+    24 * 3600
+}
+
+/// The name length limit of ext4, and of most other filesystems.
+fn default_max_filename_bytes() -> usize {
+    255
+}
+
+fn default_expected_files_budget_ms() -> u64 {
+    3000
+}
+
+fn default_min_free_space_mb() -> u64 {
+    100
+}
+
+fn default_max_playlist_items() -> u64 {
+    100
+}
+
+fn default_playlist_probe_entries() -> usize {
+    10
+}
+
+/// An entry of `api_keys`. Written as just the secret for a user key, or as a table to give the
+/// key a role or permissions.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(from = "ApiKeyEntry")]
+pub struct ApiKey {
+    pub secret: String,
+    pub role: KeyRole,
+    /// Whether the key may start downloads with `bypass_queue`, e.g. for a subtitle fetch while
+    /// the queue is full of long videos.
+    pub allow_express: bool,
+}
+
+impl ApiKey {
+    pub fn user(secret: &str) -> Self {
+        ApiKey { secret: secret.to_string(), ..ApiKey::default() }
+    }
+
+    pub fn admin(secret: &str) -> Self {
+        ApiKey { secret: secret.to_string(), role: KeyRole::Admin, ..ApiKey::default() }
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.role == KeyRole::Admin
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ApiKeyEntry {
+    Secret(String),
+    Full {
+        secret: String,
+        #[serde(default)]
+        role: KeyRole,
+        #[serde(default)]
+        allow_express: bool,
+    },
+}
+
+impl From<ApiKeyEntry> for ApiKey {
+    fn from(entry: ApiKeyEntry) -> Self {
+        match entry {
+            ApiKeyEntry::Secret(secret) => ApiKey { secret, ..ApiKey::default() },
+            ApiKeyEntry::Full { secret, role, allow_express } => ApiKey { secret, role, allow_express },
+        }
+    }
+}
+
+/// What an API key may do: users start and manage downloads, admins may also change the
+/// configuration and use the admin routes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyRole {
+    #[default]
+    User,
+    Admin,
+}
+
+/// Where the yt-dlp binary comes from.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum YtDlpChannel {
+    /// The `yt-dlp` found on PATH.
+    #[default]
+    System,
+    /// A release downloaded into the data directory; see `ytdlp_version`.
+    Managed,
+}
+
+/// How strictly `remux_video` is checked against the selected format's codecs.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RemuxCheck {
+    /// Start the download but attach a warning to the response and status.
+    #[default]
+    Warn,
+    /// Reject the request with 400 Bad Request.
+    Reject,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_max_concurrent_probes() -> usize {
+    4
+}
+
+fn default_max_concurrent_ffprobes() -> usize {
+    2
 }
 
 impl Default for Config {
@@ -19,12 +782,98 @@ impl Default for Config {
             .unwrap_or_else(|| "downloads".to_string()); // Fallback
 
         Config {
+            config_version: CONFIG_VERSION,
             download_directory: default_dir,
+            allowed_download_roots: Vec::new(),
+            date_folders: false,
+            max_concurrent_probes: default_max_concurrent_probes(),
+            max_concurrent_downloads: None,
+            max_queue_length: None,
+            max_concurrent_postprocess: None,
+            circuit_breaker_threshold: None,
+            stall_timeout_secs: None,
+            circuit_breaker_cooldown_secs: default_circuit_breaker_cooldown_secs(),
+            max_express_downloads: default_max_express_downloads(),
+            max_concurrent_ffprobes: default_max_concurrent_ffprobes(),
+            write_provenance_sidecar: false,
+            max_history_bytes: default_max_history_bytes(),
+            output_file_mode: None,
+            run_as_uid: None,
+            run_as_gid: None,
+            sendfile_header: None,
+            sendfile_prefix: None,
+            public_base_url: None,
+            file_serve_rate_limit: None,
+            file_serve_global_rate_limit: None,
+            compress_responses: true,
+            debug_errors: false,
+            auto_merge_audio: true,
+            remux_check: RemuxCheck::default(),
+            max_height: None,
+            large_download_threshold: None,
+            max_playlist_items: default_max_playlist_items(),
+            filter_formats: true,
+            playlist_probe_entries: default_playlist_probe_entries(),
+            desktop_notifications: false,
+            drain_on_ctrl_c: true,
+            min_free_space_mb: default_min_free_space_mb(),
+            default_source_address: None,
+            default_force_ip: None,
+            default_checksum: None,
+            verify_source_address: true,
+            total_bytes_quota: None,
+            preview_expected_files: true,
+            expected_files_budget_ms: default_expected_files_budget_ms(),
+            max_filename_bytes: default_max_filename_bytes(),
+            long_filename_policy: LongFilenamePolicy::default(),
+            follow_symlinks: false,
+            library_index_interval_secs: None,
+            record_client_ip: true,
+            allow_debug_bundle: false,
+            caches: CachesConfig::default(),
+            http_headers: HashMap::new(),
+            host_referers: HashMap::new(),
+            user_agent: None,
+            cookies_file: None,
+            cookies_from_browser: None,
+            session_ttl_secs: default_session_ttl_secs(),
+            allow_uploads: false,
+            max_upload_bytes: default_max_upload_bytes(),
+            max_uploads: default_max_uploads(),
+            upload_stale_after_secs: default_upload_stale_after_secs(),
+            block_age_restricted: false,
+            use_download_archive: false,
+            on_parse_error: OnParseError::default(),
+            metrics_extractor_label: true,
+            report_to: None,
+            report_secret: None,
+            report_interval_minutes: default_report_interval_minutes(),
+            api_keys: BTreeMap::new(),
+            max_downloads_per_day: None,
+            max_bytes_per_day: None,
+            webhook_url: None,
+            webhook_secret: None,
+            webhook_template: None,
+            max_redirects: default_max_redirects(),
+            instance_name: None,
+            ytdlp_channel: YtDlpChannel::default(),
+            ytdlp_version: default_ytdlp_version(),
+            ytdlp_release_url: default_ytdlp_release_url(),
+            ytdlp_config_inline: None,
+            extra: toml::Table::new(),
         }
     }
 }
 
-// --- THIS IS THE CORRECTED FUNCTION ---
+/// Returns the directory for runtime data (PID file, history, ...), creating it if needed.
+pub fn data_dir() -> Result<PathBuf> {
+    let project_dirs = ProjectDirs::from("com", "YourOrg", "YT-DLP-API")
+        .ok_or_else(|| anyhow!("Could not find a valid project directory"))?;
+    let data_dir = project_dirs.data_local_dir();
+    std::fs::create_dir_all(data_dir)?;
+    Ok(data_dir.to_path_buf())
+}
+
 /// Returns the cross-platform path to the configuration file, creating the directory if needed.
 async fn get_config_path() -> Result<PathBuf> {
     // This part is synchronous and can fail, so we handle it first.
@@ -33,15 +882,33 @@ async fn get_config_path() -> Result<PathBuf> {
 
     let config_dir = project_dirs.config_dir();
 
-    // This part is asynchronous and is now correctly awaited.
     fs::create_dir_all(config_dir).await?;
 
     Ok(config_dir.join("config.toml"))
 }
 
+/// Whether a config file exists. The server runs unconfigured until one is written,
+/// by `POST /setup` or `POST /config`.
+pub async fn config_file_exists() -> Result<bool> {
+    Ok(fs::try_exists(get_config_path().await?).await?)
+}
+
+/// Writes the first config file. Returns `false`, writing nothing, if one already exists.
+pub async fn create_config(config: &Config) -> Result<bool> {
+    let config_path = get_config_path().await?;
+    let toml_string = toml::to_string_pretty(config)?;
+    let file = fs::OpenOptions::new().write(true).create_new(true).open(&config_path).await;
+    let mut file = match file {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    tokio::io::AsyncWriteExt::write_all(&mut file, toml_string.as_bytes()).await?;
+    Ok(true)
+}
+
 /// Loads the configuration from the file, or creates a default one if it doesn't exist.
 pub async fn load_config() -> Result<Config> {
-    // The call to the async function is now correctly awaited.
     let config_path = get_config_path().await?;
 
     if !config_path.exists() {
@@ -55,17 +922,255 @@ pub async fn load_config() -> Result<Config> {
     }
 
     let config_content = fs::read_to_string(&config_path).await?;
-    let config: Config = toml::from_str(&config_content)
-        .map_err(|e| anyhow!("Failed to parse config file at {}: {}", config_path.display(), e))?;
+    let error = match parse_config(&config_content) {
+        Ok((config, migrated_from)) => {
+            if !config.extra.is_empty() {
+                let keys: Vec<&str> = config.extra.keys().map(String::as_str).collect();
+                tracing::warn!("Ignoring unknown config settings (kept in the file): {}", keys.join(", "));
+            }
+            if let Some(version) = migrated_from {
+                tracing::info!("Migrated config file from version {} to {}", version, CONFIG_VERSION);
+                save_config(&config).await?;
+            }
+            return Ok(config);
+        }
+        Err(e) => e,
+    };
+    if requested_parse_error_behavior(&config_content) == OnParseError::Fail {
+        return Err(anyhow!("Failed to parse config file at {}: {}", config_path.display(), error));
+    }
+
+    // Not `config.toml.bak`, which the next save replaces with the previous good generation.
+    let mut backup_path = config_path.clone().into_os_string();
+    backup_path.push(format!(".corrupt-{}", chrono::Utc::now().format("%Y%m%dT%H%M%S")));
+    let backup_path = PathBuf::from(backup_path);
+    fs::rename(&config_path, &backup_path).await?;
+    tracing::warn!(
+        "Failed to parse config file at {}: {}. Moved it to {} and starting with the defaults.",
+        config_path.display(),
+        error,
+        backup_path.display()
+    );
+    let default_config = Config { on_parse_error: OnParseError::Backup, ..Config::default() };
+    save_config(&default_config).await?;
+    Ok(default_config)
+}
 
+/// Re-reads the config file for a reload. Unlike `load_config`, a missing or unparsable
+/// file is an error: the running config stays in place instead of falling back to defaults.
+pub async fn reload_config() -> Result<Config> {
+    let config = read_config_file().await?;
+    if !config.extra.is_empty() {
+        let keys: Vec<&str> = config.extra.keys().map(String::as_str).collect();
+        tracing::warn!("Ignoring unknown config settings (kept in the file): {}", keys.join(", "));
+    }
+    Ok(config)
+}
+
+/// Reads and parses the config file as it is on disk, without saving or logging anything.
+pub async fn read_config_file() -> Result<Config> {
+    let config_path = get_config_path().await?;
+    let config_content = fs::read_to_string(&config_path)
+        .await
+        .map_err(|e| anyhow!("Failed to read config file at {}: {}", config_path.display(), e))?;
+    let (config, _) = parse_config(&config_content)
+        .map_err(|e| anyhow!("Failed to parse config file at {}: {}", config_path.display(), e))?;
     Ok(config)
 }
 
-/// Saves the provided configuration object to the file.
+/// Parses a config file, migrating it first if it's older than `CONFIG_VERSION`.
+/// Also returns the version it was migrated from, if any.
+fn parse_config(content: &str) -> Result<(Config, Option<u32>)> {
+    let mut table: toml::Table = toml::from_str(content)?;
+    let version = match table.get("config_version") {
+        Some(value) => value
+            .as_integer()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| anyhow!("config_version must be a non-negative integer"))?,
+        None => 0,
+    };
+    if version > CONFIG_VERSION {
+        tracing::warn!(
+            "The config file has version {}, newer than this release's {}; settings it doesn't know are kept but ignored.",
+            version,
+            CONFIG_VERSION
+        );
+    }
+    let migrated_from = migrate(&mut table, version, MIGRATIONS);
+    Ok((table.try_into()?, migrated_from))
+}
+
+/// Runs the `migrations` that a table of version `version` hasn't had yet, in order, and sets
+/// its `config_version` to the version they lead to. Returns `version` if any ran.
+fn migrate(table: &mut toml::Table, version: u32, migrations: &[fn(&mut toml::Table)]) -> Option<u32> {
+    let pending = migrations.get(version as usize..).unwrap_or_default();
+    if pending.is_empty() {
+        return None;
+    }
+    for migration in pending {
+        migration(table);
+    }
+    table.insert("config_version".to_string(), toml::Value::Integer(migrations.len() as i64));
+    Some(version)
+}
+
+/// Reads `on_parse_error` from a config file that doesn't parse as a `Config`. If the file
+/// isn't even valid TOML, the setting is looked for on a line of its own.
+fn requested_parse_error_behavior(content: &str) -> OnParseError {
+    let value = match toml::from_str::<toml::Value>(content) {
+        Ok(table) => table.get("on_parse_error").and_then(|v| v.as_str()).map(str::to_string),
+        Err(_) => content.lines().find_map(|line| {
+            let (key, value) = line.split_once('=')?;
+            (key.trim() == "on_parse_error").then(|| value.trim().trim_matches('"').to_string())
+        }),
+    };
+    match value.as_deref() {
+        Some("backup") => OnParseError::Backup,
+        _ => OnParseError::Fail,
+    }
+}
+
+/// Saves the provided configuration object to the file, keeping the previous version as
+/// `config.toml.bak`.
 pub async fn save_config(config: &Config) -> Result<()> {
-    // The call to the async function is now correctly awaited.
     let config_path = get_config_path().await?;
     let toml_string = toml::to_string_pretty(config)?;
-    fs::write(config_path, toml_string).await?;
-    Ok(())
+    persist::write(&config_path, toml_string.as_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{config_file, lock_files, remove_config_file};
+
+    #[tokio::test]
+    async fn saving_keeps_the_previous_version() {
+        let _files = lock_files().await;
+        remove_config_file();
+        let first = Config { max_concurrent_probes: 3, ..Config::default() };
+        save_config(&first).await.unwrap();
+        save_config(&Config { max_concurrent_probes: 4, ..Config::default() }).await.unwrap();
+
+        assert_eq!(read_config_file().await.unwrap().max_concurrent_probes, 4);
+        let previous = std::fs::read_to_string(config_file().with_extension("toml.bak")).unwrap();
+        assert_eq!(parse_config(&previous).unwrap().0.max_concurrent_probes, 3);
+        remove_config_file();
+    }
+
+    #[tokio::test]
+    async fn a_malformed_file_survives_later_saves() {
+        let _files = lock_files().await;
+        let dir = config_file().parent().unwrap().to_path_buf();
+        std::fs::create_dir_all(&dir).unwrap();
+        let corrupt_copies = || -> Vec<PathBuf> {
+            let mut copies: Vec<PathBuf> = std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().path()).collect();
+            copies.retain(|path| path.file_name().unwrap().to_string_lossy().starts_with("config.toml.corrupt-"));
+            copies
+        };
+        corrupt_copies().into_iter().for_each(|path| std::fs::remove_file(path).unwrap());
+        let broken = "on_parse_error = \"backup\"\nmax_concurrent_probes = [\n";
+        std::fs::write(config_file(), broken).unwrap();
+
+        assert_eq!(load_config().await.unwrap().max_concurrent_probes, Config::default().max_concurrent_probes);
+        save_config(&Config { max_concurrent_probes: 3, ..Config::default() }).await.unwrap();
+        save_config(&Config { max_concurrent_probes: 4, ..Config::default() }).await.unwrap();
+
+        let copies = corrupt_copies();
+        assert_eq!(copies.len(), 1, "{:?}", copies);
+        assert_eq!(std::fs::read_to_string(&copies[0]).unwrap(), broken);
+        std::fs::remove_file(&copies[0]).unwrap();
+        remove_config_file();
+    }
+
+    #[tokio::test]
+    async fn unknown_settings_survive_a_load_and_save() {
+        let _files = lock_files().await;
+        let content = format!("config_version = {}\ndownload_directory = \"/tmp\"\nfrom_a_newer_release = \"kept\"\n", CONFIG_VERSION);
+        std::fs::create_dir_all(config_file().parent().unwrap()).unwrap();
+        std::fs::write(config_file(), content).unwrap();
+
+        let config = read_config_file().await.unwrap();
+        assert_eq!(config.extra["from_a_newer_release"].as_str(), Some("kept"));
+        save_config(&config).await.unwrap();
+        let saved: toml::Table = toml::from_str(&std::fs::read_to_string(config_file()).unwrap()).unwrap();
+        assert_eq!(saved["from_a_newer_release"].as_str(), Some("kept"));
+        assert_eq!(parse_config(&toml::to_string(&saved).unwrap()).unwrap().0.extra, config.extra);
+        remove_config_file();
+    }
+
+    #[test]
+    fn current_files_are_not_rewritten() {
+        let content = format!("config_version = {}\ndownload_directory = \"/tmp\"\nmax_concurrent_postprocess = 3\n", CONFIG_VERSION);
+        let (config, migrated_from) = parse_config(&content).unwrap();
+        assert_eq!(migrated_from, None);
+        assert_eq!(config.max_concurrent_postprocess, Some(3));
+        assert!(config.extra.is_empty());
+        assert!(toml::to_string_pretty(&config).unwrap().contains("max_concurrent_postprocess = 3"));
+    }
+
+    #[test]
+    fn files_without_a_version_are_migrated_too() {
+        let (config, migrated_from) = parse_config("download_directory = \"/tmp\"\nmax_concurrent_postprocess = 1\n").unwrap();
+        assert_eq!((migrated_from, config.config_version), (Some(0), CONFIG_VERSION));
+        assert_eq!(config.max_concurrent_postprocess, Some(1));
+    }
+
+    #[test]
+    fn migrations_rename_keys_and_bump_the_version() {
+        let migrations: &[fn(&mut toml::Table)] = &[
+            |_| {},
+            |table| {
+                if let Some(value) = table.remove("max_probes") {
+                    table.insert("max_concurrent_probes".to_string(), value);
+                }
+            },
+        ];
+        let mut table: toml::Table = toml::from_str("config_version = 1\ndownload_directory = \"/tmp\"\nmax_probes = 3\nkept = true\n").unwrap();
+        assert_eq!(migrate(&mut table, 1, migrations), Some(1));
+        let expected: toml::Table = toml::from_str("config_version = 2\ndownload_directory = \"/tmp\"\nmax_concurrent_probes = 3\nkept = true\n").unwrap();
+        assert_eq!(table, expected);
+        let config: Config = table.clone().try_into().unwrap();
+        assert_eq!(config.max_concurrent_probes, 3);
+        assert!(config.extra.contains_key("kept") && !config.extra.contains_key("max_probes"));
+
+        // Up to date: nothing runs.
+        assert_eq!(migrate(&mut table, 2, migrations), None);
+        assert_eq!(table, expected);
+        // A file from before versions runs every migration.
+        let mut table: toml::Table = toml::from_str("max_probes = 5\n").unwrap();
+        assert_eq!(migrate(&mut table, 0, migrations), Some(0));
+        assert_eq!(table.get("max_concurrent_probes"), Some(&toml::Value::Integer(5)));
+        assert_eq!(table.get("config_version"), Some(&toml::Value::Integer(2)));
+    }
+
+    #[test]
+    fn api_keys_are_either_a_secret_or_a_table() {
+        let config: Config = toml::from_str(
+            r#"
+            download_directory = "/srv/videos"
+
+            [api_keys]
+            alice = "alice-secret"
+            ops = { secret = "ops-secret", role = "admin" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.api_keys["alice"], ApiKey::user("alice-secret"));
+        assert_eq!(config.api_keys["ops"], ApiKey::admin("ops-secret"));
+
+        let saved: Config = toml::from_str(&toml::to_string(&config).unwrap()).unwrap();
+        assert_eq!(saved.api_keys, config.api_keys);
+
+        // Without an admin key, nobody could change the configuration again.
+        let mut users_only = config.clone();
+        users_only.api_keys.remove("ops");
+        assert!(users_only.validate().iter().any(|problem| problem.contains("role = \"admin\"")), "{:?}", users_only.validate());
+    }
+
+    #[test]
+    fn the_report_interval_is_at_most_a_week() {
+        let valid = |minutes| Config { report_interval_minutes: minutes, ..Config::default() }.validate().iter().all(|problem| !problem.contains("report_interval_minutes"));
+        assert!(valid(1) && valid(MAX_REPORT_INTERVAL_MINUTES));
+        assert!(!valid(0) && !valid(MAX_REPORT_INTERVAL_MINUTES + 1) && !valid(u64::MAX));
+    }
 }
@@ -0,0 +1,93 @@
+use crate::{config::Config, models::DownloadStatus, redact};
+use anyhow::Result;
+use flate2::{write::GzEncoder, Compression};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::process::Command;
+use tokio::sync::OnceCell;
+
+/// The directory name every file in the bundle is placed under.
+const BUNDLE_DIR: &str = "yt-agent-debug";
+
+/// The suggested filename when the bundle is served over HTTP.
+pub const BUNDLE_FILENAME: &str = "yt-agent-debug.tar.gz";
+
+/// Set once ffmpeg was found on PATH. A missing ffmpeg isn't remembered, so installing it
+/// takes effect without a restart.
+static FFMPEG_FOUND: OnceCell<()> = OnceCell::const_new();
+
+/// Builds the debug bundle: a gzip tarball with the redacted config (including the options
+/// in `ytdlp_config_inline`), the status map,
+/// tool versions and the recent log lines. Everything is assembled in memory.
+pub async fn build(
+    config: &Config,
+    downloads: &HashMap<String, DownloadStatus>,
+    log_lines: &[String],
+) -> Result<Vec<u8>> {
+    let mut config_json = serde_json::to_value(config)?;
+    redact::redact_json(&mut config_json);
+    if let Some(inline) = &config.ytdlp_config_inline {
+        config_json["ytdlp_config_inline"] = json!(redact::redact_ytdlp_options(inline));
+    }
+
+    let versions = collect_versions().await;
+    let logs_jsonl = log_lines
+        .iter()
+        .map(|line| serde_json::to_string(&json!({ "line": line })))
+        .collect::<Result<Vec<_>, _>>()?
+        .join("\n");
+
+    let mut archive = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    append_file(&mut archive, "config.json", &serde_json::to_vec_pretty(&config_json)?)?;
+    append_file(&mut archive, "status.json", &serde_json::to_vec_pretty(downloads)?)?;
+    append_file(&mut archive, "versions.json", &serde_json::to_vec_pretty(&versions)?)?;
+    append_file(&mut archive, "logs.jsonl", logs_jsonl.as_bytes())?;
+
+    Ok(archive.into_inner()?.finish()?)
+}
+
+/// Adds a single in-memory file to the archive.
+fn append_file(archive: &mut tar::Builder<GzEncoder<Vec<u8>>>, name: &str, contents: &[u8]) -> Result<()> {
+    let mtime = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let mut header = tar::Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    header.set_cksum();
+    archive.append_data(&mut header, format!("{}/{}", BUNDLE_DIR, name), contents)?;
+    Ok(())
+}
+
+/// Reports the versions of this binary, the platform, and the external tools it depends on.
+async fn collect_versions() -> Value {
+    json!({
+        "yt-agent": env!("CARGO_PKG_VERSION"),
+        "os": std::env::consts::OS,
+        "arch": std::env::consts::ARCH,
+        "yt-dlp": tool_version("yt-dlp", "--version").await,
+        "ffmpeg": tool_version("ffmpeg", "-version").await,
+    })
+}
+
+/// Whether ffmpeg is on PATH, for options that need it. Checked by running it until it is found.
+pub(crate) async fn ffmpeg_available() -> bool {
+    if FFMPEG_FOUND.initialized() {
+        return true;
+    }
+    if tool_version("ffmpeg", "-version").await.is_none() {
+        return false;
+    }
+    let _ = FFMPEG_FOUND.set(());
+    true
+}
+
+/// Runs `program flag` and returns the first line of its output, or `None` if it isn't available.
+pub(crate) async fn tool_version(program: &str, flag: &str) -> Option<String> {
+    let program = if program == "yt-dlp" { crate::ytdlp::program() } else { program.into() };
+    let output = Command::new(program).arg(flag).output().await.ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8_lossy(&output.stdout).lines().next().map(|l| l.trim().to_string())
+}
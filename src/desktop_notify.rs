@@ -0,0 +1,84 @@
+use crate::{models::DownloadStatus, AppState};
+use std::io::IsTerminal;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set by `server start` on the `server run` it launches in the background.
+pub const BACKGROUND_ENV: &str = "YT_AGENT_BACKGROUND";
+
+/// The longest error text shown in a notification.
+const MAX_ERROR_CHARS: usize = 200;
+
+static FOREGROUND: AtomicBool = AtomicBool::new(false);
+
+/// Decides once, at startup, whether the server runs in the foreground: started with
+/// `server run` from a terminal rather than by `server start` or a service manager.
+pub fn detect_foreground() {
+    let foreground = std::env::var_os(BACKGROUND_ENV).is_none() && std::io::stderr().is_terminal();
+    FOREGROUND.store(foreground, Ordering::SeqCst);
+}
+
+/// Shows a desktop notification that a download finished or failed, when
+/// `desktop_notifications` is on and the server runs in the foreground. A desktop without a
+/// notification service only gets a log line.
+pub fn download_finished(state: &AppState, download_key: &str) {
+    if !FOREGROUND.load(Ordering::SeqCst) || !state.config.read().unwrap().desktop_notifications {
+        return;
+    }
+    let Some(status) = state.downloads.lock().get(download_key).cloned() else { return };
+    let Some((summary, body)) = notification(download_key, &status) else { return };
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = notify_rust::Notification::new().appname("yt-agent").summary(&summary).body(&body).show() {
+            tracing::debug!("Failed to show a desktop notification: {}", e);
+        }
+    });
+}
+
+/// The summary and body of the notification for a download's final status, named after its
+/// first file. A playlist that stopped partway, with some items downloaded, ends
+/// `completed_partially`. Statuses that aren't final get none.
+fn notification(download_key: &str, status: &DownloadStatus) -> Option<(String, String)> {
+    let name = status
+        .files
+        .first()
+        .and_then(|file| Path::new(file).file_name())
+        .map_or_else(|| download_key.to_string(), |name| name.to_string_lossy().to_string());
+    match status.status.as_str() {
+        "completed" => Some(("Download finished".to_string(), name)),
+        "completed_partially" => Some(("Download partly finished".to_string(), name)),
+        "completed_no_output" => Some(("Download produced no files".to_string(), name)),
+        "failed" => {
+            let error = status.error.as_deref().and_then(|e| e.lines().rev().find(|line| !line.trim().is_empty())).unwrap_or_default();
+            Some(("Download failed".to_string(), format!("{}\n{}", name, error.chars().take(MAX_ERROR_CHARS).collect::<String>())))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(status: &str, files: &[&str], error: Option<&str>) -> DownloadStatus {
+        DownloadStatus {
+            status: status.to_string(),
+            files: files.iter().map(|file| file.to_string()).collect(),
+            error: error.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn tells_how_a_download_ended() {
+        let key = "https://example.com/list";
+        let notification = |status: &DownloadStatus| notification(key, status);
+        assert_eq!(notification(&status("completed", &["/srv/a.mp4"], None)), Some(("Download finished".to_string(), "a.mp4".to_string())));
+        // What a playlist with failed items ends as; see `run_download_task`.
+        let partly = status("completed_partially", &["/srv/v1.mp4", "/srv/v3.mp4"], Some("ERROR: v2: Video unavailable"));
+        assert_eq!(notification(&partly), Some(("Download partly finished".to_string(), "v1.mp4".to_string())));
+        assert_eq!(notification(&status("completed_no_output", &[], None)), Some(("Download produced no files".to_string(), key.to_string())));
+        let failed = status("failed", &[], Some("WARNING: slow\nERROR: gone\n\n"));
+        assert_eq!(notification(&failed), Some(("Download failed".to_string(), format!("{}\nERROR: gone", key))));
+        assert_eq!(notification(&status("downloading", &[], None)), None);
+    }
+}
@@ -1,34 +1,216 @@
-use axum::{http::StatusCode, response::{IntoResponse, Response}, Json};
-use serde_json::json;
+use crate::{circuits::OpenCircuit, models::ErrorCode, quota::KeyQuota};
+use axum::{
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::{json, Value};
+use std::fmt;
+
+/// The request and response header carrying a request's correlation ID.
+pub const CORRELATION_HEADER: &str = "x-request-id";
+
+/// What an error response needs to know about the request it answers. Set for each request by
+/// `handlers::correlate`.
+#[derive(Clone)]
+pub struct RequestContext {
+    pub correlation_id: String,
+    /// Whether internal errors may include their error chain, i.e. `debug_errors` is on and the
+    /// request carries an admin key.
+    pub debug_errors: bool,
+}
+
+tokio::task_local! {
+    pub static REQUEST: RequestContext;
+}
 
 // Define our custom error type
 pub enum AppError {
     Internal(anyhow::Error),
     YtDlp(String),
     BadRequest(String),
+    /// The request needs an API key and didn't carry a known one.
+    Unauthorized(String),
+    Forbidden(String),
     NotFound(String),
+    Conflict(String),
+    /// The request body, or the upload it announces, is above a configured limit.
+    PayloadTooLarge(String),
+    Unprocessable(String),
+    ServiceUnavailable(String),
+    InsufficientStorage(String),
+    QuotaExceeded(String),
+    /// yt-dlp failed for a recognized reason; the response carries its code and hint.
+    Classified(ErrorCode, String),
+    /// The download is estimated above `large_download_threshold` and wasn't confirmed.
+    ConfirmationRequired { estimated_bytes: u64, threshold_bytes: u64 },
+    /// The URL is a playlist above `max_playlist_items` and the limit wasn't overridden.
+    PlaylistTooLong { items: u64, limit: u64 },
+    /// Downloads from the host failed `circuit_breaker_threshold` times in a row.
+    CircuitOpen(OpenCircuit),
+    /// The request's API key used up its daily quota.
+    KeyQuotaExceeded(KeyQuota),
 }
 
 // This implementation allows us to convert our AppError into a valid HTTP response.
+// Every body carries the request's correlation ID, which is also in the logs and the
+// `X-Request-Id` header, so a reported error can be found in the logs.
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        let (status, error_message) = match self {
+        let context = REQUEST.try_with(RequestContext::clone).ok();
+        let correlation_id = context.as_ref().map(|c| c.correlation_id.as_str());
+        let headers = match &self {
+            AppError::KeyQuotaExceeded(quota) => quota_headers(quota),
+            _ => HeaderMap::new(),
+        };
+        let (status, mut body) = match self {
             AppError::Internal(e) => {
                 // Log the full error for debugging
-                tracing::error!("Internal server error: {:?}", e);
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "An internal server error occurred".to_string(),
-                )
+                tracing::error!(correlation_id = correlation_id.unwrap_or("-"), "Internal server error: {:?}", e);
+                let mut body = json!({ "error": "An internal server error occurred" });
+                if context.as_ref().is_some_and(|c| c.debug_errors) {
+                    body["debug"] = json!(e.chain().map(|cause| cause.to_string()).collect::<Vec<_>>());
+                }
+                (StatusCode::INTERNAL_SERVER_ERROR, body)
             }
-            AppError::YtDlp(e) => (StatusCode::BAD_REQUEST, format!("yt-dlp error: {}", e)),
-            AppError::BadRequest(e) => (StatusCode::BAD_REQUEST, e),
-            AppError::NotFound(e) => (StatusCode::NOT_FOUND, e),
+            AppError::YtDlp(e) => (StatusCode::BAD_REQUEST, json!({ "error": format!("yt-dlp error: {}", e) })),
+            AppError::BadRequest(e) => (StatusCode::BAD_REQUEST, json!({ "error": e })),
+            AppError::Unauthorized(e) => (StatusCode::UNAUTHORIZED, json!({ "error": e })),
+            AppError::Forbidden(e) => (StatusCode::FORBIDDEN, json!({ "error": e })),
+            AppError::NotFound(e) => (StatusCode::NOT_FOUND, json!({ "error": e })),
+            AppError::Conflict(e) => (StatusCode::CONFLICT, json!({ "error": e })),
+            AppError::PayloadTooLarge(e) => (StatusCode::PAYLOAD_TOO_LARGE, json!({ "error": e })),
+            AppError::Unprocessable(e) => (StatusCode::UNPROCESSABLE_ENTITY, json!({ "error": e })),
+            AppError::ServiceUnavailable(e) => (StatusCode::SERVICE_UNAVAILABLE, json!({ "error": e })),
+            AppError::InsufficientStorage(e) => (StatusCode::INSUFFICIENT_STORAGE, json!({ "error": e })),
+            AppError::QuotaExceeded(e) => (StatusCode::TOO_MANY_REQUESTS, json!({ "error": e })),
+            AppError::Classified(code, e) => (
+                match code {
+                    ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+                    ErrorCode::ServerError | ErrorCode::NetworkError => StatusCode::BAD_GATEWAY,
+                    ErrorCode::AgeRestricted | ErrorCode::Stalled => StatusCode::FORBIDDEN,
+                },
+                json!({ "error": format!("yt-dlp error: {}", e), "error_code": code, "hint": code.hint() }),
+            ),
+            AppError::ConfirmationRequired { estimated_bytes, threshold_bytes } => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                json!({
+                    "error": confirmation_message(estimated_bytes, threshold_bytes),
+                    "error_code": "confirmation_required",
+                    "estimated_bytes": estimated_bytes,
+                    "threshold_bytes": threshold_bytes,
+                }),
+            ),
+            AppError::PlaylistTooLong { items, limit } => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                json!({
+                    "error": playlist_message(items, limit),
+                    "error_code": "playlist_too_long",
+                    "playlist_count": items,
+                    "max_playlist_items": limit,
+                }),
+            ),
+            AppError::CircuitOpen(circuit) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                json!({
+                    "error": circuit_message(&circuit),
+                    "error_code": "circuit_open",
+                    "host": circuit.host,
+                    "retry_at": circuit.retry_at,
+                }),
+            ),
+            AppError::KeyQuotaExceeded(quota) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                json!({
+                    "error": key_quota_message(&quota),
+                    "error_code": "quota_exceeded",
+                    "quota": quota,
+                }),
+            ),
         };
 
-        let body = Json(json!({ "error": error_message }));
-        (status, body).into_response()
+        if let Some(correlation_id) = correlation_id {
+            body["correlation_id"] = Value::from(correlation_id);
+        }
+        (status, headers, Json(body)).into_response()
+    }
+}
+
+// A client-safe description of the error, matching the message in the HTTP body.
+// Used where errors are reported inline rather than as a whole response.
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Internal(_) => write!(f, "An internal server error occurred"),
+            AppError::YtDlp(e) | AppError::Classified(_, e) => write!(f, "yt-dlp error: {}", e),
+            AppError::BadRequest(e)
+            | AppError::Unauthorized(e)
+            | AppError::Forbidden(e)
+            | AppError::NotFound(e)
+            | AppError::Conflict(e)
+            | AppError::PayloadTooLarge(e)
+            | AppError::Unprocessable(e)
+            | AppError::ServiceUnavailable(e)
+            | AppError::InsufficientStorage(e)
+            | AppError::QuotaExceeded(e) => write!(f, "{}", e),
+            AppError::ConfirmationRequired { estimated_bytes, threshold_bytes } => {
+                write!(f, "{}", confirmation_message(*estimated_bytes, *threshold_bytes))
+            }
+            AppError::PlaylistTooLong { items, limit } => write!(f, "{}", playlist_message(*items, *limit)),
+            AppError::CircuitOpen(circuit) => write!(f, "{}", circuit_message(circuit)),
+            AppError::KeyQuotaExceeded(quota) => write!(f, "{}", key_quota_message(quota)),
+        }
+    }
+}
+
+fn confirmation_message(estimated_bytes: u64, threshold_bytes: u64) -> String {
+    format!(
+        "This download is estimated at {} bytes, above the server's large_download_threshold of {} bytes. Repeat the request with \"confirm_large\": true to start it.",
+        estimated_bytes, threshold_bytes
+    )
+}
+
+fn playlist_message(items: u64, limit: u64) -> String {
+    format!(
+        "This URL is a playlist of {} items, above the server's max_playlist_items of {}. Pick a range with \"playlist_items\", e.g. \"1-{}\", or repeat the request with \"override_playlist_limit\": true to download all of it.",
+        items, limit, limit
+    )
+}
+
+pub(crate) fn circuit_message(circuit: &OpenCircuit) -> String {
+    format!(
+        "Circuit open for {}: its last {} downloads failed, so new ones are refused until {}.",
+        circuit.host,
+        circuit.consecutive_failures,
+        circuit.retry_at.to_rfc3339()
+    )
+}
+
+fn key_quota_message(quota: &KeyQuota) -> String {
+    format!(
+        "API key '{}' has used its daily quota ({} downloads, {} bytes today); it resets at {}.",
+        quota.key,
+        quota.downloads,
+        quota.bytes,
+        quota.resets_at.to_rfc3339()
+    )
+}
+
+/// `Retry-After` and the remaining quota, for clients that read headers rather than bodies.
+fn quota_headers(quota: &KeyQuota) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    let retry_after = (quota.resets_at - chrono::Utc::now()).num_seconds().max(0);
+    headers.insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+    if let Some(remaining) = quota.remaining_downloads {
+        headers.insert("x-quota-remaining-downloads", HeaderValue::from(remaining));
+    }
+    if let Some(remaining) = quota.remaining_bytes {
+        headers.insert("x-quota-remaining-bytes", HeaderValue::from(remaining));
+    }
+    if let Ok(reset) = HeaderValue::from_str(&quota.resets_at.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true)) {
+        headers.insert("x-quota-reset", reset);
     }
+    headers
 }
 
 // This allows us to use the `?` operator to automatically convert
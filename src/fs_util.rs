@@ -0,0 +1,160 @@
+use crate::error::AppError;
+use percent_encoding::percent_decode_str;
+use std::path::{Component, Path, PathBuf};
+
+/// Resolves a path a client sent, relative to `base`, to a canonical path inside `base`.
+/// Every endpoint that touches a file by a client-supplied path goes through here.
+///
+/// The path is percent-decoded, then refused if it holds a NUL byte, a `..` component or an
+/// absolute part, before anything touches the file system. The target doesn't have to exist,
+/// so a path about to be written can be checked too: its deepest existing ancestor is
+/// canonicalized and the missing components are appended. A path that resolves outside of
+/// `base` through a symlink, or ends in a dangling symlink, is refused.
+pub fn safe_path(base: &Path, user_path: &str) -> Result<PathBuf, AppError> {
+    confine(base, &decode(user_path)?)
+}
+
+/// Percent-decodes a client-supplied path into its normal components, keeping bytes that
+/// aren't valid UTF-8 as they are. `.` components are dropped.
+pub fn decode(user_path: &str) -> Result<PathBuf, AppError> {
+    let bytes: Vec<u8> = percent_decode_str(user_path).collect();
+    if bytes.contains(&0) {
+        return Err(AppError::BadRequest("File paths cannot contain NUL bytes".to_string()));
+    }
+    let mut relative = PathBuf::new();
+    for component in path_from_bytes(&bytes).components() {
+        match component {
+            Component::Normal(name) => relative.push(name),
+            Component::CurDir => {}
+            Component::ParentDir => return Err(AppError::BadRequest("File paths cannot contain '..'".to_string())),
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(AppError::BadRequest("File paths must be relative to the download directory".to_string()))
+            }
+        }
+    }
+    Ok(relative)
+}
+
+/// Resolves an already decoded `relative` path inside `base`. See [`safe_path`].
+pub fn confine(base: &Path, relative: &Path) -> Result<PathBuf, AppError> {
+    let canonical_base = std::fs::canonicalize(base)?;
+    let not_found = || AppError::NotFound(format!("File '{}' not found.", relative.display()));
+
+    // `symlink_metadata` doesn't follow links, so a dangling link counts as existing and then
+    // fails to canonicalize: writing through it would land wherever it points.
+    let mut existing = base.join(relative);
+    let mut missing = Vec::new();
+    while matches!(std::fs::symlink_metadata(&existing), Err(e) if e.kind() == std::io::ErrorKind::NotFound) {
+        let (Some(parent), Some(name)) = (existing.parent(), existing.file_name()) else { break };
+        missing.push(name.to_os_string());
+        existing = parent.to_path_buf();
+    }
+    // Loops, over-long names and the like are reported as a missing file.
+    let mut resolved = std::fs::canonicalize(&existing).map_err(|_| not_found())?;
+    resolved.extend(missing.iter().rev());
+
+    if !resolved.starts_with(&canonical_base) {
+        return Err(AppError::NotFound("File not found (Path Traversal Attempt)".to_string()));
+    }
+    Ok(resolved)
+}
+
+/// Turns decoded bytes back into a path, keeping bytes that aren't valid UTF-8 as they are.
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+    }
+    #[cfg(not(unix))]
+    {
+        PathBuf::from(String::from_utf8_lossy(bytes).to_string())
+    }
+}
+
+// Symlinks and non-UTF-8 names are built with Unix APIs.
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::symlink;
+
+    /// A download directory next to a directory that must stay out of reach.
+    fn dirs() -> (tempfile::TempDir, PathBuf, PathBuf) {
+        let root = tempfile::tempdir().unwrap();
+        let (base, outside) = (root.path().join("downloads"), root.path().join("outside"));
+        std::fs::create_dir_all(base.join("sub")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(outside.join("secret"), "secret").unwrap();
+        (root, base, outside)
+    }
+
+    #[test]
+    fn refuses_percent_encoded_traversal() {
+        let (_root, base, _) = dirs();
+        for path in [
+            "..%2Foutside%2Fsecret",
+            "%2e%2e/outside/secret",
+            "%2E%2E%2Foutside%2Fsecret",
+            "sub/%2e%2e/%2e%2e/outside/secret",
+            "sub%2f..%2f..%2foutside%2fsecret",
+            "%2Fetc%2Fpasswd",
+            "/etc/passwd",
+            "sub/%00/secret",
+        ] {
+            assert!(safe_path(&base, path).is_err(), "{}", path);
+        }
+        // Decoded once only: a doubly encoded `..` is a plain name inside the directory.
+        let canonical = std::fs::canonicalize(&base).unwrap();
+        assert_eq!(safe_path(&base, "%252e%252e/secret").ok(), Some(canonical.join("%2e%2e/secret")));
+        assert_eq!(safe_path(&base, "./sub/./new.mp4").ok(), Some(canonical.join("sub/new.mp4")));
+    }
+
+    #[test]
+    fn follows_symlink_chains_only_while_they_stay_inside() {
+        let (_root, base, outside) = dirs();
+        let canonical = std::fs::canonicalize(&base).unwrap();
+        std::fs::write(base.join("sub/video.mp4"), "video").unwrap();
+        symlink(base.join("sub/video.mp4"), base.join("inner")).unwrap();
+        symlink(base.join("inner"), base.join("inner2")).unwrap();
+        assert_eq!(safe_path(&base, "inner2").ok(), Some(canonical.join("sub/video.mp4")));
+
+        // Each link on its own points inside; the last one leaves.
+        symlink(&outside, base.join("sub/escape")).unwrap();
+        symlink(base.join("sub/escape"), base.join("hop1")).unwrap();
+        symlink(base.join("hop1"), base.join("hop2")).unwrap();
+        for path in ["hop2/secret", "hop1/secret", "sub/escape/secret", "hop2/new-file"] {
+            assert!(safe_path(&base, path).is_err(), "{}", path);
+        }
+
+        // Dangling links and loops are refused rather than written through.
+        symlink(outside.join("missing"), base.join("dangling")).unwrap();
+        symlink(base.join("loop-b"), base.join("loop-a")).unwrap();
+        symlink(base.join("loop-a"), base.join("loop-b")).unwrap();
+        for path in ["dangling", "loop-a", "loop-a/file"] {
+            assert!(safe_path(&base, path).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn refuses_overlong_components() {
+        let (_root, base, _) = dirs();
+        let long = "x".repeat(300);
+        assert!(safe_path(&base, &long).is_err());
+        assert!(safe_path(&base, &format!("sub/{}/file", long)).is_err());
+        // A long path of short components is fine as long as each name is.
+        let deep = vec!["d"; 200].join("/");
+        assert!(safe_path(&base, &deep).is_ok());
+    }
+
+    #[test]
+    fn keeps_names_that_are_not_utf8() {
+        let (_root, base, _) = dirs();
+        let name = std::ffi::OsStr::from_bytes(b"caf\xe9.mp4");
+        std::fs::write(base.join("sub").join(name), "video").unwrap();
+        let resolved = safe_path(&base, "sub/caf%E9.mp4").unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(resolved.file_name(), Some(name));
+        assert!(resolved.exists());
+        assert!(safe_path(&base, "sub/%FF%FE/..%2F..%2F..%2Foutside").is_err());
+    }
+}
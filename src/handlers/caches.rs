@@ -0,0 +1,85 @@
+use crate::{cache, error::AppError, AppState};
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    routing::{delete, get},
+    Json, Router,
+};
+use serde_json::json;
+
+use super::AdminKey;
+
+/// Routes for inspecting and clearing the in-memory caches.
+pub fn build_router(state: AppState) -> Router {
+    Router::new()
+        .route("/caches", get(list_caches).delete(clear_all_caches))
+        .route("/caches/:name", delete(clear_cache))
+        .with_state(state)
+}
+
+// ===================================================================
+//                          CACHE HANDLERS
+// ===================================================================
+
+/// # GET /caches - Reports each cache's size, settings and hit/miss counters.
+pub async fn list_caches(AdminKey(_admin): AdminKey, State(state): State<AppState>) -> impl IntoResponse {
+    let stats: Vec<_> = cache::registry(&state).into_iter().map(|(name, c)| c.stats(name)).collect();
+    Json(stats)
+}
+
+/// # DELETE /caches - Empties every cache.
+pub async fn clear_all_caches(AdminKey(_admin): AdminKey, State(state): State<AppState>) -> impl IntoResponse {
+    let mut cleared = serde_json::Map::new();
+    for (name, c) in cache::registry(&state) {
+        let count = c.clear();
+        tracing::info!("Cleared {} entries from the {} cache", count, name);
+        cleared.insert(name.to_string(), json!(count));
+    }
+    Json(json!({ "cleared": cleared }))
+}
+
+/// # DELETE /caches/:name - Empties one cache, e.g. `formats` after a yt-dlp upgrade.
+pub async fn clear_cache(
+    AdminKey(_admin): AdminKey,
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let registry = cache::registry(&state);
+    let Some((name, c)) = registry.into_iter().find(|(n, _)| *n == name) else {
+        return Err(AppError::NotFound(format!("No cache named '{}'", name)));
+    };
+    let count = c.clear();
+    tracing::info!("Cleared {} entries from the {} cache", count, name);
+    Ok(Json(json!({ "cleared": { name: count } })))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::{app, send, state, FakeRunner};
+    use axum::http::{Method, StatusCode};
+
+    #[tokio::test]
+    async fn lists_and_clears_the_caches() {
+        let state = state(FakeRunner::new());
+        state.playlist_counts.insert("https://example.com/list".to_string(), 3);
+        let app = app(&state);
+
+        let listed = send(&app, Method::GET, "/caches", None).await;
+        assert_eq!(listed.status, StatusCode::OK);
+        assert!(listed.body.as_array().unwrap().iter().any(|c| c["name"] == "playlist_counts"));
+
+        let cleared = send(&app, Method::DELETE, "/caches/playlist_counts", None).await;
+        assert_eq!(cleared.status, StatusCode::OK);
+        assert_eq!(cleared.body["cleared"]["playlist_counts"], 1);
+
+        let all = send(&app, Method::DELETE, "/caches", None).await;
+        assert_eq!(all.status, StatusCode::OK);
+        assert_eq!(all.body["cleared"]["playlist_counts"], 0);
+    }
+
+    #[tokio::test]
+    async fn an_unknown_cache_is_not_found() {
+        let app = app(&state(FakeRunner::new()));
+        assert_eq!(send(&app, Method::DELETE, "/caches/nope", None).await.status, StatusCode::NOT_FOUND);
+    }
+}
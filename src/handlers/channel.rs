@@ -0,0 +1,130 @@
+use crate::{
+    channels,
+    error::AppError,
+    history,
+    models::{ChannelQuery, DownloadRequest},
+    AppState,
+};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use serde_json::json;
+
+use super::{
+    download::{enqueue_client_download, DEFAULT_FORMAT},
+    ClientOrigin,
+};
+
+/// Routes for following channels: listing and downloading their new items.
+pub fn build_router(state: AppState) -> Router {
+    Router::new()
+        .route("/channel/new", get(list_new_items))
+        .route("/channel/sync", post(sync_channel))
+        .with_state(state)
+}
+
+// ===================================================================
+//                          CHANNEL HANDLERS
+// ===================================================================
+
+/// # GET /channel/new - Lists the channel's items that haven't been downloaded yet.
+pub async fn list_new_items(
+    State(state): State<AppState>,
+    Query(query): Query<ChannelQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let items = channels::list_items(&state, &query.url).await?;
+    let total_items = items.len();
+    let new_items = channels::new_items(&state, items, &history::load().await?);
+    let last_sync = channels::load().await?.remove(&query.url).map(|record| record.last_sync);
+    Ok(Json(json!({
+        "url": query.url,
+        "last_sync": last_sync,
+        "total_items": total_items,
+        "new_items": new_items,
+    })))
+}
+
+/// # POST /channel/sync - Starts a download for each of the channel's new items.
+/// Items that fail to start are reported; they are picked up again by the next sync.
+pub async fn sync_channel(
+    State(state): State<AppState>,
+    ClientOrigin(origin): ClientOrigin,
+    Query(query): Query<ChannelQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let items = channels::list_items(&state, &query.url).await?;
+    let new_items = channels::new_items(&state, items, &history::load().await?);
+    let format_id = query.format_id.as_deref().unwrap_or(DEFAULT_FORMAT);
+
+    let mut started = Vec::new();
+    let mut failed = Vec::new();
+    for item in new_items {
+        let request = DownloadRequest {
+            url: item.url.clone(),
+            format_id: format_id.to_string(),
+            // Each item is a single video; the channel was listed already.
+            override_playlist_limit: true,
+            origin: Some(origin.clone()),
+            ..Default::default()
+        };
+        match enqueue_client_download(&state, request).await {
+            Ok(response) => started.push(response.download_key),
+            Err(e) => failed.push(json!({ "url": item.url, "error": e.to_string() })),
+        }
+    }
+    let record = channels::record_sync(&query.url).await?;
+    tracing::info!("Synced channel {}: {} downloads started, {} failed", query.url, started.len(), failed.len());
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!({
+            "url": query.url,
+            "last_sync": record.last_sync,
+            "started": started,
+            "failed": failed,
+        })),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::{app, lock_files, send, state, FakeRunner};
+    use axum::http::{Method, StatusCode};
+
+    const LISTING: &str = r#"case "$*" in
+*--dump-single-json*) echo '{"entries": [
+    {"id": "a", "url": "https://example.com/a", "title": "A"},
+    {"id": "b", "webpage_url": "https://example.com/b"},
+    {"id": "tab", "url": "https://example.com/tab", "_type": "playlist"}]}' ;;
+esac"#;
+
+    #[tokio::test]
+    async fn lists_and_syncs_new_items() {
+        let _files = lock_files().await;
+        let app = app(&state(FakeRunner::new().script("yt-dlp", LISTING)));
+        let uri = "/channel/new?url=https%3A%2F%2Fexample.com%2Fchannel-list";
+        let listed = send(&app, Method::GET, uri, None).await;
+        assert_eq!(listed.status, StatusCode::OK);
+        assert_eq!(listed.body["total_items"], 2);
+        assert_eq!(listed.body["new_items"][1]["url"], "https://example.com/b");
+
+        let synced = send(&app, Method::POST, "/channel/sync?url=https%3A%2F%2Fexample.com%2Fchannel-list", None).await;
+        assert_eq!(synced.status, StatusCode::ACCEPTED);
+        assert_eq!(synced.body["started"].as_array().unwrap().len(), 2);
+        assert!(synced.body["last_sync"].is_string());
+
+        // Both items are queued or downloading now.
+        assert_eq!(send(&app, Method::GET, uri, None).await.body["new_items"], serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn a_failed_listing_is_a_yt_dlp_error() {
+        let app = app(&state(FakeRunner::new().script("yt-dlp", "echo 'ERROR: no such channel' >&2; exit 1")));
+        let listed = send(&app, Method::GET, "/channel/new?url=https%3A%2F%2Fexample.com%2Fgone", None).await;
+        assert!(listed.body["error"].as_str().unwrap().contains("no such channel"));
+        assert_eq!(send(&app, Method::GET, "/channel/new?url=", None).await.status, StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,363 @@
+use crate::{
+    cache,
+    config::{self, Config},
+    error::AppError,
+    redact, storage, ytdlp, AppState,
+};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use axum::{extract::State, http::StatusCode, response::IntoResponse, routing::{get, post}, Json, Router};
+
+use super::AdminKey;
+
+/// Routes for reading and updating the configuration.
+pub fn build_router(state: AppState) -> Router {
+    Router::new()
+        .route("/config", get(get_config).post(update_config))
+        .route("/config/validate", post(validate_config_handler))
+        .route("/config/reload", post(reload_config_handler))
+        .route("/config/effective", get(effective_config))
+        .with_state(state)
+}
+
+// ===================================================================
+//                          CONFIG HANDLERS
+// ===================================================================
+
+/// # GET /config - Returns the current application configuration.
+pub async fn get_config(AdminKey(_admin): AdminKey, State(state): State<AppState>) -> Result<impl IntoResponse, AppError> {
+    let config = state.config.read().unwrap().clone();
+    Ok((StatusCode::OK, Json(redact_secrets(config))))
+}
+
+/// # POST /config - Updates the configuration and saves it to disk.
+/// Secrets still redacted from `GET /config` keep their current value. Unknown settings are
+/// refused, except those already kept from the config file, which are written back.
+/// `config_version` is read-only: it may be left out, but not changed.
+pub async fn update_config(
+    AdminKey(_admin): AdminKey,
+    State(state): State<AppState>,
+    Json(mut payload): Json<Config>,
+) -> Result<impl IntoResponse, AppError> {
+    {
+        let current = state.config.read().unwrap();
+        for (name, value) in payload.http_headers.iter_mut() {
+            if value == redact::REDACTED {
+                if let Some(existing) = current.http_headers.get(name) {
+                    *value = existing.clone();
+                }
+            }
+        }
+        if payload.report_secret.as_deref() == Some(redact::REDACTED) {
+            payload.report_secret = current.report_secret.clone();
+        }
+        if payload.webhook_secret.as_deref() == Some(redact::REDACTED) {
+            payload.webhook_secret = current.webhook_secret.clone();
+        }
+        for (name, key) in payload.api_keys.iter_mut() {
+            if key.secret == redact::REDACTED {
+                if let Some(existing) = current.api_keys.get(name) {
+                    key.secret = existing.secret.clone();
+                }
+            }
+        }
+        if payload.config_version != current.config_version && payload.config_version != config::CONFIG_VERSION {
+            return Err(AppError::BadRequest("config_version is read-only".to_string()));
+        }
+        payload.config_version = current.config_version;
+        if let Some(problem) = unknown_settings(&payload, &current) {
+            return Err(AppError::BadRequest(problem));
+        }
+        let mut extra = current.extra.clone();
+        extra.extend(std::mem::take(&mut payload.extra));
+        payload.extra = extra;
+    }
+    let unsafe_options = payload.ytdlp_config_inline.as_deref().map(ytdlp::inline_config_problems).unwrap_or_default();
+    if !unsafe_options.is_empty() {
+        return Err(AppError::Unprocessable(format!("Refused ytdlp_config_inline: {}", unsafe_options.join("; "))));
+    }
+    let problems = validate_config(&state, &payload).await;
+    if !problems.is_empty() {
+        return Err(AppError::BadRequest(format!("Invalid configuration: {}", problems.join("; "))));
+    }
+    apply_config(&state, payload.clone());
+    config::save_config(&payload).await?;
+    tracing::info!("Configuration updated and saved.");
+    Ok((StatusCode::OK, Json(redact_secrets(payload))))
+}
+
+/// # POST /config/reload - Re-reads the config file, as SIGHUP does on Unix.
+/// An unreadable or invalid file leaves the running config unchanged.
+pub async fn reload_config_handler(AdminKey(_admin): AdminKey, State(state): State<AppState>) -> Result<impl IntoResponse, AppError> {
+    let config = reload(&state).await?;
+    Ok((StatusCode::OK, Json(redact_secrets(config))))
+}
+
+/// # GET /config/effective - Compares the running config with the config file, e.g. to spot a
+/// hand edit that hasn't been reloaded yet. Each difference names a setting by its dotted path.
+pub async fn effective_config(AdminKey(_admin): AdminKey, State(state): State<AppState>) -> Result<impl IntoResponse, AppError> {
+    let running = state.config.read().unwrap().clone();
+    let on_disk = match config::read_config_file().await {
+        Ok(on_disk) => on_disk,
+        Err(e) => {
+            return Ok(Json(json!({
+                "running": redact_secrets(running),
+                "on_disk": null,
+                "on_disk_error": e.to_string(),
+                "in_sync": false,
+                "differences": [],
+            })));
+        }
+    };
+
+    let (actual, shown) = (flatten_config(&running), flatten_config(&redact_secrets(running.clone())));
+    let (stored, stored_shown) = (flatten_config(&on_disk), flatten_config(&redact_secrets(on_disk.clone())));
+    let keys: BTreeSet<&String> = actual.keys().chain(stored.keys()).collect();
+    let differences: Vec<Value> = keys
+        .into_iter()
+        .filter(|key| actual.get(*key) != stored.get(*key))
+        .map(|key| json!({ "setting": key, "running": shown.get(key), "on_disk": stored_shown.get(key) }))
+        .collect();
+
+    Ok(Json(json!({
+        "running": redact_secrets(running),
+        "on_disk": redact_secrets(on_disk),
+        "on_disk_error": null,
+        "in_sync": differences.is_empty(),
+        "differences": differences,
+    })))
+}
+
+/// The config's settings keyed by dotted path, e.g. `caches.formats.ttl_secs`.
+/// Lists are compared as a whole.
+fn flatten_config(config: &Config) -> BTreeMap<String, Value> {
+    fn walk(prefix: &str, value: Value, out: &mut BTreeMap<String, Value>) {
+        match value {
+            Value::Object(fields) => {
+                for (name, value) in fields {
+                    let path = if prefix.is_empty() { name } else { format!("{}.{}", prefix, name) };
+                    walk(&path, value, out);
+                }
+            }
+            value => {
+                out.insert(prefix.to_string(), value);
+            }
+        }
+    }
+    let mut out = BTreeMap::new();
+    walk("", serde_json::to_value(config).unwrap_or_default(), &mut out);
+    out
+}
+
+/// Loads and validates the config file and swaps it in. Shared by `POST /config/reload` and SIGHUP.
+pub async fn reload(state: &AppState) -> Result<Config, AppError> {
+    let config = config::reload_config().await.map_err(|e| AppError::BadRequest(e.to_string()))?;
+    let problems = validate_config(state, &config).await;
+    if !problems.is_empty() {
+        return Err(AppError::BadRequest(format!("Invalid configuration: {}", problems.join("; "))));
+    }
+    apply_config(state, config.clone());
+    tracing::info!("Configuration reloaded from file.");
+    Ok(config)
+}
+
+/// Makes `config` the running config and applies the settings that take effect without a restart.
+pub(crate) fn apply_config(state: &AppState, config: Config) {
+    *state.config.write().unwrap() = config;
+    cache::apply_settings(state);
+    state.slots.reconfigure();
+    state.library_index.clear();
+    if state.config.read().unwrap().circuit_breaker_threshold.is_none() {
+        state.circuits.clear();
+    }
+    ytdlp::sync(state);
+}
+
+/// Hides `report_secret`, `webhook_secret`, the `api_keys` and the values of secret-looking `http_headers`, such as
+/// `Authorization`.
+pub(crate) fn redact_secrets(mut config: Config) -> Config {
+    for (name, value) in config.http_headers.iter_mut() {
+        if redact::is_secret_key(name) {
+            *value = redact::REDACTED.to_string();
+        }
+    }
+    if config.report_secret.is_some() {
+        config.report_secret = Some(redact::REDACTED.to_string());
+    }
+    if config.webhook_secret.is_some() {
+        config.webhook_secret = Some(redact::REDACTED.to_string());
+    }
+    for key in config.api_keys.values_mut() {
+        key.secret = redact::REDACTED.to_string();
+    }
+    config
+}
+
+/// # POST /config/validate - Checks a configuration like `POST /config` would, without saving it.
+/// Always answers 200 with `{"valid": bool, "problems": [...]}` so forms can show inline errors.
+pub async fn validate_config_handler(AdminKey(_admin): AdminKey, State(state): State<AppState>, Json(payload): Json<Config>) -> impl IntoResponse {
+    let unknown = unknown_settings(&payload, &state.config.read().unwrap());
+    let mut problems = validate_config(&state, &payload).await;
+    problems.extend(unknown);
+    Json(json!({ "valid": problems.is_empty(), "problems": problems }))
+}
+
+/// Names the settings in `payload` that this version doesn't know, e.g. a misspelling, leaving
+/// out those kept from the config file (`current.extra`) so a `GET /config` can be sent back.
+fn unknown_settings(payload: &Config, current: &Config) -> Option<String> {
+    let unknown: Vec<&str> = payload.extra.keys().filter(|key| !current.extra.contains_key(*key)).map(String::as_str).collect();
+    (!unknown.is_empty()).then(|| format!("Unknown config settings: {}", unknown.join(", ")))
+}
+
+/// Everything wrong with `config`: the static checks, whether each download root can be
+/// written, and whether yt-dlp accepts `ytdlp_config_inline`.
+pub(crate) async fn validate_config(state: &AppState, config: &Config) -> Vec<String> {
+    let mut problems = config.validate();
+    let roots = std::iter::once(&config.download_directory).chain(config.allowed_download_roots.iter());
+    for root in roots.filter(|root| !root.trim().is_empty()) {
+        if let Err(problem) = storage::check_writable_without_creating(Path::new(root)).await {
+            problems.push(format!("Download root '{}' is unusable: {}", root, problem));
+        }
+    }
+    // Options it may not set are among the static problems already; yt-dlp doesn't get to see
+    // those.
+    let inline = config.ytdlp_config_inline.as_deref().filter(|inline| !inline.trim().is_empty());
+    if let Some(inline) = inline.filter(|inline| ytdlp::inline_config_problems(inline).is_empty()) {
+        if let Err(problem) = ytdlp::check_inline_config(state, inline).await {
+            problems.push(format!("yt-dlp rejected ytdlp_config_inline: {}", problem));
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::{app, config_file, lock_files, remove_config_file, send, state, FakeRunner};
+    use axum::http::{Method, StatusCode};
+
+    #[tokio::test]
+    async fn saves_reloads_and_compares_the_config() {
+        let _files = lock_files().await;
+        remove_config_file();
+        let state = state(FakeRunner::new());
+        let app = app(&state);
+
+        let mut config = send(&app, Method::GET, "/config", None).await.body;
+        config["max_concurrent_probes"] = 7.into();
+        let saved = send(&app, Method::POST, "/config", Some(config)).await;
+        assert_eq!(saved.status, StatusCode::OK);
+        assert!(std::fs::read_to_string(config_file()).unwrap().contains("max_concurrent_probes = 7"));
+
+        let effective = send(&app, Method::GET, "/config/effective", None).await;
+        assert_eq!(effective.body["in_sync"], true);
+
+        let edited = std::fs::read_to_string(config_file()).unwrap().replace("max_concurrent_probes = 7", "max_concurrent_probes = 9");
+        std::fs::write(config_file(), edited).unwrap();
+        let effective = send(&app, Method::GET, "/config/effective", None).await;
+        assert_eq!(effective.body["in_sync"], false);
+        assert_eq!(effective.body["differences"][0]["setting"], "max_concurrent_probes");
+
+        let reloaded = send(&app, Method::POST, "/config/reload", None).await;
+        assert_eq!(reloaded.status, StatusCode::OK);
+        assert_eq!(state.config.read().unwrap().max_concurrent_probes, 9);
+        remove_config_file();
+    }
+
+    #[tokio::test]
+    async fn refuses_inline_options_that_run_programs_or_leave_the_download_directory() {
+        let _files = lock_files().await;
+        remove_config_file();
+        let runner = FakeRunner::new().script("yt-dlp", "echo 2024.01.01");
+        let calls = runner.calls();
+        let state = state(runner);
+        let app = app(&state);
+        let config = send(&app, Method::GET, "/config", None).await.body;
+        for inline in [
+            "--exec 'touch /tmp/owned'",
+            "--no-mtime\n  --exec=\"rm -rf ~\"",
+            "--exe 'touch /tmp/owned'",
+            "--exec-before-download id",
+            "--netrc-cmd 'cat /etc/shadow'",
+            "-o /etc/cron.d/job",
+            "--output 'thumbnail:../../job'",
+            "--paths=home:/root",
+            "-P/",
+            "-a urls.txt",
+            "--batch-file=urls.txt",
+            "--config-locations /tmp/more.conf",
+        ] {
+            let mut payload = config.clone();
+            payload["ytdlp_config_inline"] = inline.into();
+            let refused = send(&app, Method::POST, "/config", Some(payload.clone())).await;
+            assert_eq!(refused.status, StatusCode::UNPROCESSABLE_ENTITY, "{}: {}", inline, refused.body);
+            let checked = send(&app, Method::POST, "/config/validate", Some(payload)).await;
+            assert_eq!(checked.body["valid"], false, "{}", inline);
+        }
+        assert!(calls.lock().unwrap().is_empty(), "yt-dlp was given a refused config");
+        assert!(!config_file().exists());
+        assert!(state.config.read().unwrap().ytdlp_config_inline.is_none());
+
+        // Anything else is for yt-dlp to judge, run like every other yt-dlp.
+        let mut payload = config.clone();
+        payload["ytdlp_config_inline"] = "--no-mtime\n-o '%(title)s.%(ext)s'\n--print after_move:filepath".into();
+        let checked = send(&app, Method::POST, "/config/validate", Some(payload)).await;
+        assert_eq!(checked.body["valid"], true, "{}", checked.body);
+        assert_eq!(*calls.lock().unwrap(), ["yt-dlp"]);
+    }
+
+    #[tokio::test]
+    async fn reload_without_a_file_keeps_the_running_config() {
+        let _files = lock_files().await;
+        remove_config_file();
+        let app = app(&state(FakeRunner::new()));
+        assert_eq!(send(&app, Method::POST, "/config/reload", None).await.status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn validates_without_saving() {
+        let app = app(&state(FakeRunner::new()));
+        let mut config = send(&app, Method::GET, "/config", None).await.body;
+        config["download_directory"] = "/proc/not-writable".into();
+        let response = send(&app, Method::POST, "/config/validate", Some(config.clone())).await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.body["valid"], false);
+        assert_eq!(send(&app, Method::POST, "/config", Some(config)).await.status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn refuses_unknown_settings_but_keeps_those_from_the_file() {
+        let _files = lock_files().await;
+        remove_config_file();
+        let state = state(FakeRunner::new());
+        state.config.write().unwrap().extra.insert("from_a_newer_release".to_string(), toml::Value::Boolean(true));
+        let app = app(&state);
+        let config = send(&app, Method::GET, "/config", None).await.body;
+        assert_eq!(config["from_a_newer_release"], true);
+
+        let mut misspelled = config.clone();
+        misspelled["max_concurent_probes"] = 3.into();
+        let refused = send(&app, Method::POST, "/config", Some(misspelled.clone())).await;
+        assert_eq!(refused.status, StatusCode::BAD_REQUEST);
+        assert!(refused.body["error"].as_str().unwrap().contains("max_concurent_probes"), "{}", refused.body);
+        let checked = send(&app, Method::POST, "/config/validate", Some(misspelled)).await;
+        assert_eq!(checked.body["valid"], false);
+        assert_eq!(checked.body["problems"][0], "Unknown config settings: max_concurent_probes");
+
+        let mut without_extra = config.clone();
+        without_extra.as_object_mut().unwrap().remove("from_a_newer_release");
+        assert_eq!(send(&app, Method::POST, "/config", Some(without_extra)).await.status, StatusCode::OK);
+        assert!(std::fs::read_to_string(config_file()).unwrap().contains("from_a_newer_release = true"));
+
+        let mut other_version = config.clone();
+        other_version["config_version"] = 7.into();
+        let refused = send(&app, Method::POST, "/config", Some(other_version)).await;
+        assert_eq!(refused.status, StatusCode::BAD_REQUEST);
+        assert!(refused.body["error"].as_str().unwrap().contains("config_version"), "{}", refused.body);
+        let mut without_version = config;
+        without_version.as_object_mut().unwrap().remove("config_version");
+        assert_eq!(send(&app, Method::POST, "/config", Some(without_version)).await.status, StatusCode::OK);
+        assert_eq!(state.config.read().unwrap().config_version, crate::config::CONFIG_VERSION);
+        remove_config_file();
+    }
+}
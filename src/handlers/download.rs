@@ -0,0 +1,3787 @@
+use crate::{
+    archive, chat, checksum, circuits,
+    config::{self, LongFilenamePolicy, RemuxCheck},
+    debug_bundle, desktop_notify,
+    error::{self, AppError},
+    history,
+    live_logs::LogSender,
+    media_index,
+    models::{
+        DownloadRequest, DownloadResponse, DownloadStatus, ErrorCode, ExpectedFile, FailedItem, FetchRequest, ForceIp, FormatSpec, HistoryEntry, ImportRequest, OutputSpec, OutputStatus,
+        RetryQuery, RipRequest,
+        ScheduleQuery, SizeConfirmation,
+    },
+    progress::{self, ProgressMode, ProgressUpdate},
+    quota::{self, QuotaTracker},
+    redact,
+    scheduler::ScheduledDownload,
+    slots::{QueueEstimate, SlotGuard},
+    storage,
+    AppState, DownloadState,
+};
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, Query, State,
+    },
+    http::StatusCode,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use futures::{stream, Stream, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::convert::Infallible;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::path::{Component, Path as FsPath, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader};
+use tokio::process::{ChildStderr, Command};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{Notify, OwnedRwLockReadGuard};
+use tokio_util::sync::CancellationToken;
+
+use super::{
+    add_cookie_args, add_extractor_args, add_http_args, add_session_cookies, check_session, classify_error, format_part_sizes, has_cookies,
+    is_blocked_age_restricted, ApiCaller, ClientOrigin, resolve_download_root, validate_extractor_args, validate_http_headers, ADULT_AGE_LIMIT,
+    files::locate_file,
+    uploads,
+};
+
+/// The format downloads use when the caller doesn't pick one: yt-dlp's own default.
+pub(crate) const DEFAULT_FORMAT: &str = "bestvideo*+bestaudio/best";
+
+/// The side files `POST /fetch` can write.
+const FETCH_KINDS: &[&str] = &["subtitles", "thumbnail", "info_json", "description"];
+
+/// The audio formats accepted by `POST /rip`.
+const RIP_FORMATS: &[&str] = &["mp3", "opus", "flac"];
+
+/// The output template used by `POST /rip`, relative to the download directory.
+const RIP_TEMPLATE: &str = "%(artist,uploader)s - %(title)s.%(ext)s";
+
+/// How often a job group's parent status is refreshed from its children.
+const GROUP_PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The longest yt-dlp output line we keep in memory; longer lines are skipped.
+/// Progress lines are well under a kilobyte, so this only trips on malformed output.
+const MAX_OUTPUT_LINE_BYTES: usize = 64 * 1024;
+
+/// The `queue_reason` of a download waiting for one of `max_concurrent_downloads` slots.
+const WAITING_FOR_SLOT: &str = "waiting for a download slot";
+
+/// The `queue_reason` of a download waiting for one of `max_concurrent_postprocess` slots.
+const WAITING_FOR_POSTPROCESS_SLOT: &str = "waiting for a post-processing slot";
+
+/// How often a running download's file is checked on disk for `bytes_on_disk`.
+const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(3);
+
+/// How far `bytes_on_disk` and `downloaded_bytes` may drift apart, as a share of the larger
+/// one, before `progress_mismatch` is set. Writes lag the progress lines a little.
+const PROGRESS_MISMATCH_RATIO: f64 = 0.1;
+
+/// Differences below this many bytes never count as a mismatch, e.g. at the start of a file.
+const PROGRESS_MISMATCH_MIN_BYTES: u64 = 4 * 1024 * 1024;
+
+/// yt-dlp's line naming the file a download writes into.
+const DESTINATION_PREFIX: &str = "[download] Destination: ";
+
+/// yt-dlp's error when no format matches the selector, lowercased.
+const FORMAT_UNAVAILABLE_ERROR: &str = "requested format is not available";
+
+/// The `queue_reason` of a download added while a managed yt-dlp switch waits for the queue to drain.
+const WAITING_FOR_YTDLP_SWITCH: &str = "waiting for the yt-dlp version switch";
+
+/// Codec prefixes (as reported by yt-dlp) each remux target container can hold: (container, video, audio).
+/// Containers not listed here (e.g. mkv) accept practically anything and are not checked.
+const REMUX_COMPATIBILITY: &[(&str, &[&str], &[&str])] = &[
+    (
+        "mp4",
+        &["avc", "h264", "hev", "hvc", "h265", "av01", "vp09", "vp9", "mp4v"],
+        &["mp4a", "aac", "mp3", "ac-3", "ec-3", "opus", "flac", "alac"],
+    ),
+    (
+        "mov",
+        &["avc", "h264", "hev", "hvc", "h265", "mp4v", "prores"],
+        &["mp4a", "aac", "mp3", "ac-3", "alac"],
+    ),
+    ("webm", &["vp8", "vp09", "vp9", "av01"], &["opus", "vorbis"]),
+];
+
+/// Subtitle formats accepted by `sub_format` (yt-dlp's `--sub-format`).
+const SUB_FORMATS: &[&str] = &["best", "srt", "vtt", "ass", "ttml", "srv1", "srv2", "srv3", "json3"];
+
+/// Subtitle formats accepted by `convert_subs` (yt-dlp's `--convert-subs`).
+const CONVERT_SUB_FORMATS: &[&str] = &["ass", "lrc", "srt", "vtt"];
+
+/// The fields yt-dlp offers in a `--sponsorblock-chapter-title` template.
+const SPONSORBLOCK_CHAPTER_FIELDS: &[&str] = &["start_time", "end_time", "category", "categories", "name", "category_names"];
+
+/// Output template fields that render lists, dicts or free text of unbounded length, which make
+/// file names far longer than any filesystem allows.
+const UNBOUNDED_TEMPLATE_FIELDS: &[&str] = &[
+    "urls", "formats", "requested_formats", "requested_downloads", "requested_subtitles", "subtitles",
+    "automatic_captions", "thumbnails", "entries", "chapters", "heatmap", "comments", "fragments",
+    "http_headers", "description",
+];
+
+/// How many bytes a field of the file name is assumed to take when the preview didn't render
+/// it, for capping the title without knowing the real names.
+const UNPREVIEWED_FIELD_BYTES: usize = 32;
+
+/// Matches `%%` escapes and `%(...)` fields of an output template.
+static TEMPLATE_FIELD_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"%(?:%|\(([^)]*)\))").unwrap());
+
+/// A language code such as "en", "deu" or "pt-BR".
+static LANGUAGE_CODE_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"^[A-Za-z]{2,3}(-[A-Za-z0-9]{2,8})*$").unwrap());
+
+/// The audio selectors `audio_lang` narrows down.
+static BEST_AUDIO_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"\b(bestaudio|ba)\b").unwrap());
+
+/// Matches the lines where yt-dlp announces a file it is writing.
+static OUTPUT_FILE_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"^\[(?:download|ExtractAudio|info)\] (?:Destination: |Writing video subtitles to: |Writing video thumbnail \d+ to: |Writing video metadata as JSON to: |Writing video description to: )(?P<path>.+)$|^\[download\] (?P<existing>.+) has already been downloaded$|^\[(?:Merger|VideoRemuxer)\] (?:Merging formats into|Remuxing video from \w+ to \w+; Destination:) "?(?P<merged>[^"]+)"?$"#).unwrap()
+});
+
+/// yt-dlp's note that `--extract-audio` kept a downloaded file as it was.
+static UNCONVERTED_AUDIO_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"^\[ExtractAudio\] Not converting audio (?P<path>.+?); (?:the )?file is already in "#).unwrap());
+
+/// The prefix of the line naming the file `--extract-audio` writes.
+const EXTRACT_AUDIO_PREFIX: &str = "[ExtractAudio] Destination: ";
+
+/// Explains a `completed_no_output` status.
+const NO_OUTPUT_NOTE: &str = "yt-dlp finished without errors but produced no files. Check match_filter, \
+playlist_items, max_filesize and skip_download, which can exclude every item, and whether the download archive \
+(use_download_archive) already has every item.";
+
+/// Routes for starting, ripping and scheduling downloads.
+pub fn build_router(state: AppState) -> Router {
+    Router::new()
+        .route("/download", post(start_download))
+        .route("/rip", post(start_rip))
+        .route("/fetch", post(start_fetch))
+        .route("/schedule", get(list_scheduled).delete(cancel_scheduled))
+        .route("/download/import", post(import_downloads))
+        .route("/download/:key/retry", post(retry_download))
+        .route("/download/:key/cancel", post(cancel_download))
+        .route("/download/:key/log/ws", get(tail_download_log))
+        .route("/download/:key/log/stream", get(stream_download_log))
+        .with_state(state)
+}
+
+// ===================================================================
+//                          DOWNLOAD HANDLERS
+// ===================================================================
+
+/// # POST /download - Spawns a background download process.
+pub async fn start_download(
+    State(state): State<AppState>,
+    ClientOrigin(origin): ClientOrigin,
+    Json(mut payload): Json<DownloadRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    payload.origin = Some(origin);
+    let response = enqueue_client_download(&state, payload).await?;
+    Ok((StatusCode::ACCEPTED, Json(response)))
+}
+
+/// # POST /download/import - Starts a download for every URL of a list, sent inline or as a
+/// finished upload. A URL that can't start is reported in `failed` without stopping the rest.
+pub async fn import_downloads(
+    State(state): State<AppState>,
+    ClientOrigin(origin): ClientOrigin,
+    Json(payload): Json<ImportRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let list = uploads::uploaded_text(&state, payload.urls, payload.upload_id.as_deref(), "urls").await?;
+    let mut urls: Vec<&str> = Vec::new();
+    for url in list.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+        if !urls.contains(&url) {
+            urls.push(url);
+        }
+    }
+    if urls.is_empty() {
+        return Err(AppError::BadRequest("The list has no URLs".to_string()));
+    }
+    let request_for = |url: &str| {
+        let mut fields = payload.request.clone();
+        fields.insert("url".to_string(), Value::String(url.to_string()));
+        serde_json::from_value::<DownloadRequest>(Value::Object(fields))
+            .map_err(|e| AppError::BadRequest(format!("Invalid download options in request: {}", e)))
+    };
+    // Options that don't parse would fail every URL alike.
+    request_for(urls[0])?;
+
+    let (mut started, mut failed) = (Vec::new(), Vec::new());
+    for url in urls {
+        let mut request = request_for(url)?;
+        request.origin = Some(origin.clone());
+        match enqueue_client_download(&state, request).await {
+            Ok(response) => started.push(response.download_key),
+            Err(e) => failed.push(json!({ "url": url, "error": e.to_string() })),
+        }
+    }
+    tracing::info!("Imported a list: {} downloads started, {} failed", started.len(), failed.len());
+    Ok((StatusCode::ACCEPTED, Json(json!({ "started": started, "failed": failed }))))
+}
+
+/// # POST /rip - Extracts the best audio of a URL to mp3/opus/flac with sensible defaults.
+/// A deliberately minimal contract for simple clients; it maps onto a full `DownloadRequest`.
+pub async fn start_rip(
+    State(state): State<AppState>,
+    ClientOrigin(origin): ClientOrigin,
+    Json(payload): Json<RipRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    if !RIP_FORMATS.contains(&payload.format.as_str()) {
+        return Err(AppError::BadRequest(format!(
+            "Unsupported rip format '{}'. Allowed formats: {}",
+            payload.format,
+            RIP_FORMATS.join(", ")
+        )));
+    }
+
+    let output_template = default_output_dir(&state, resolve_download_root(&state, None)?)
+        .join(RIP_TEMPLATE)
+        .to_string_lossy()
+        .to_string();
+    let request = DownloadRequest {
+        url: payload.url,
+        format_id: "bestaudio".to_string(),
+        output_template: Some(output_template),
+        extract_audio: true,
+        audio_format: Some(payload.format),
+        audio_quality: Some("0".to_string()),
+        embed_thumbnail: Some(true),
+        embed_metadata: true,
+        origin: Some(origin),
+        ..Default::default()
+    };
+
+    let response = enqueue_client_download(&state, request).await?;
+    Ok((StatusCode::ACCEPTED, Json(response)))
+}
+
+/// # POST /fetch - Writes a video's subtitles, thumbnail, info JSON or description without
+/// downloading the video itself, e.g. next to a file that is already there.
+pub async fn start_fetch(
+    State(state): State<AppState>,
+    ClientOrigin(origin): ClientOrigin,
+    Json(payload): Json<FetchRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    if payload.kinds.is_empty() {
+        return Err(AppError::BadRequest("At least one kind is required".to_string()));
+    }
+    if let Some(kind) = payload.kinds.iter().find(|k| !FETCH_KINDS.contains(&k.as_str())) {
+        return Err(AppError::BadRequest(format!(
+            "Unsupported kind '{}'. Allowed kinds: {}",
+            kind,
+            FETCH_KINDS.join(", ")
+        )));
+    }
+    let target_dir = payload.target_dir.as_deref().unwrap_or("");
+    if FsPath::new(target_dir).is_absolute() {
+        return Err(AppError::BadRequest("target_dir must be relative to the download directory".to_string()));
+    }
+    let root = resolve_download_root(&state, None)?;
+    let template = FsPath::new(target_dir).join("%(title)s [%(id)s].%(ext)s");
+    let output_template = confine_template(&root, &template.to_string_lossy())?;
+
+    let wants = |kind: &str| payload.kinds.iter().any(|k| k == kind);
+    let request = DownloadRequest {
+        url: payload.url,
+        format_id: DEFAULT_FORMAT.to_string(),
+        output_template: Some(output_template),
+        write_subs: wants("subtitles"),
+        write_thumbnail: wants("thumbnail"),
+        write_info_json: wants("info_json"),
+        write_description: wants("description"),
+        skip_download: true,
+        origin: Some(origin),
+        ..Default::default()
+    };
+
+    let response = enqueue_client_download(&state, request).await?;
+    Ok((StatusCode::ACCEPTED, Json(response)))
+}
+
+/// Enqueues a download a client asked for, counting it against the daily quota of the API key
+/// it came with. A download that doesn't start isn't counted.
+pub(crate) async fn enqueue_client_download(state: &AppState, payload: DownloadRequest) -> Result<DownloadResponse, AppError> {
+    let api_key = payload.origin.as_ref().and_then(|origin| origin.api_key.clone());
+    let Some(api_key) = api_key else { return enqueue_download(state, payload).await };
+    state.key_quotas.reserve(state, &api_key)?;
+    let result = enqueue_download(state, payload).await;
+    if result.is_err() {
+        state.key_quotas.release(&api_key);
+    }
+    if let Err(e) = state.key_quotas.persist().await {
+        tracing::error!("Failed to persist the API key quotas: {:?}", e);
+    }
+    result
+}
+
+/// Validates a download request, registers its initial status and spawns the download task.
+/// Shared by every endpoint that starts a download.
+pub(crate) async fn enqueue_download(state: &AppState, mut payload: DownloadRequest) -> Result<DownloadResponse, AppError> {
+    let download_key = payload.url.clone();
+    if state.draining.load(Ordering::SeqCst) {
+        return Err(AppError::ServiceUnavailable("The server is shutting down and not accepting new downloads.".to_string()));
+    }
+    let quota = quota::report(state);
+    if quota.exceeded {
+        return Err(AppError::QuotaExceeded(format!(
+            "The download quota of {} bytes has been reached ({} bytes used since {}). Reset it with POST /admin/quota/reset.",
+            quota.limit_bytes.unwrap_or_default(),
+            quota.used_bytes,
+            quota.since.to_rfc3339()
+        )));
+    }
+    validate_download_request(&payload)?;
+    check_session(state, payload.session_id.as_deref())?;
+    let api_key = payload.origin.as_ref().and_then(|origin| origin.api_key.as_deref());
+    if payload.bypass_queue && !api_key.and_then(|name| state.config.read().unwrap().api_keys.get(name).map(|key| key.allow_express)).unwrap_or(false) {
+        return Err(AppError::Forbidden("bypass_queue is not allowed; it needs an API key with allow_express set.".to_string()));
+    }
+    if let Some(info) = state.formats_cache.get(&payload.url).filter(|info| is_blocked_age_restricted(state, info)) {
+        return Err(AppError::Forbidden(format!("'{}' is age-restricted and block_age_restricted is set", info.title)));
+    }
+    resolve_network_selection(state, &mut payload)?;
+    if payload.compute_checksum.is_none() {
+        payload.compute_checksum = state.config.read().unwrap().default_checksum;
+    }
+    if payload.embed_source_url && !debug_bundle::ffmpeg_available().await {
+        return Err(AppError::Unprocessable("embed_source_url requires ffmpeg, which was not found on PATH.".to_string()));
+    }
+    let scheduled_at = parse_scheduled_at(payload.scheduled_at.as_deref())?;
+    // Scheduled downloads are checked when they come due.
+    if scheduled_at.is_none() && state.config.read().unwrap().circuit_breaker_threshold.is_some() {
+        if let Some(host) = circuits::host_of(&payload.url) {
+            state.circuits.check(&host).map_err(AppError::CircuitOpen)?;
+        }
+    }
+    check_playlist_size(state, &payload).await?;
+
+    // Resolve the effective root: the primary directory, or an allowed alternate.
+    let download_root = resolve_download_root(state, payload.download_root.as_deref())?;
+
+    // Determine the final output template. Use the request's template, kept inside the
+    // effective download root, if it exists; otherwise, build one from that root.
+    let output_template = match &payload.output_template {
+        Some(template) => confine_template(&download_root, template)?,
+        None => default_output_dir(state, download_root.clone()).join("%(title)s [%(id)s].%(ext)s").to_string_lossy().to_string(),
+    };
+
+    // A request with `formats` becomes a job group: this job is the parent and each
+    // format runs as a child job with its own status.
+    let mut format_decision = None;
+    let mut warnings = Vec::new();
+    let mut children = Vec::new();
+    let (specs, output_sources) =
+        if payload.outputs.is_empty() { (payload.formats.clone(), Vec::new()) } else { plan_outputs(&payload.outputs) };
+    if specs.is_empty() {
+        format_decision = check_format_streams(state, &mut payload)?;
+        warnings = check_remux_compatibility(state, &payload)?;
+        enforce_max_height(state, &mut payload)?;
+    } else {
+        for (index, spec) in specs.iter().enumerate() {
+            let mut request = group_child_request(&payload, spec);
+            let decision = check_format_streams(state, &mut request)?;
+            enforce_max_height(state, &mut request)?;
+            warnings.extend(check_remux_compatibility(state, &request)?);
+            children.push(GroupChild {
+                key: format!("{}#{}", download_key, index + 1),
+                template: with_template_suffix(&output_template, spec.template_suffix.as_deref()),
+                request,
+                format_decision: decision,
+            });
+        }
+    }
+    let (size_unknown, size_confirmation) = check_download_size(state, &payload, &children)?;
+    if size_unknown {
+        warnings.push("The download's size couldn't be estimated, so large_download_threshold wasn't checked. Fetch /formats first and use format IDs to have it checked.".to_string());
+    }
+    if let Some(confirmation) = &size_confirmation {
+        tracing::info!(
+            "Download {} confirmed as large: estimated at {} bytes, threshold {} bytes",
+            download_key,
+            confirmation.estimated_bytes,
+            confirmation.threshold_bytes
+        );
+    }
+    let child_keys: Vec<String> = children.iter().map(|c| c.key.clone()).collect();
+    let initial_status = if scheduled_at.is_some() { "scheduled" } else { "starting" };
+    let outputs: Vec<OutputStatus> = payload
+        .outputs
+        .iter()
+        .zip(output_sources)
+        .map(|(output, source)| OutputStatus {
+            format_id: output.format_id.clone(),
+            extract_audio: output.extract_audio,
+            download_key: child_keys[source].clone(),
+            status: initial_status.to_string(),
+            files: Vec::new(),
+        })
+        .collect();
+
+    // Ensure the download root exists. Downloads starting now also need it writable with
+    // enough free space; scheduled ones are checked when they come due.
+    if scheduled_at.is_none() {
+        storage::check(state, &download_root).await?;
+    } else {
+        tokio::fs::create_dir_all(&download_root).await?;
+    }
+
+    // Check for existing downloads and set initial status.
+    {
+        let mut map = state.downloads.lock();
+        let busy = |key: &String| matches!(map.get(key), Some(s) if matches!(s.status.as_str(), "queued" | "starting" | "downloading"));
+        if busy(&download_key) || child_keys.iter().any(busy) {
+            return Err(AppError::BadRequest("A download for this URL is already in progress.".to_string()));
+        }
+        if state.scheduler.contains(&download_key) {
+            return Err(AppError::BadRequest("A download for this URL is already scheduled.".to_string()));
+        }
+        // Checked under the lock so concurrent requests can't overshoot the limit together.
+        let max_queue_length = state.config.read().unwrap().max_queue_length;
+        if let Some(limit) = max_queue_length.filter(|_| scheduled_at.is_none()) {
+            let length = crate::queue_length(&map);
+            if length >= limit {
+                return Err(AppError::ServiceUnavailable(format!(
+                    "The download queue is full ({} of max_queue_length {} running or queued). Try again once some have finished.",
+                    length, limit
+                )));
+            }
+        }
+        let initial = DownloadStatus {
+            status: initial_status.to_string(),
+            download_root: Some(download_root.to_string_lossy().to_string()),
+            scheduled_at: scheduled_at.map(|t| t.to_rfc3339()),
+            source_address: payload.source_address.clone(),
+            force_ip: payload.force_ip,
+            express: payload.bypass_queue,
+            size_unknown,
+            origin: payload.origin.clone(),
+            created_at: Some(Utc::now().to_rfc3339()),
+            ..Default::default()
+        };
+        for child in &children {
+            map.insert(child.key.clone(), DownloadStatus {
+                parent: Some(download_key.clone()),
+                format_decision: child.format_decision.clone(),
+                ..initial.clone()
+            });
+        }
+        map.insert(download_key.clone(), DownloadStatus {
+            format_decision,
+            warnings: warnings.clone(),
+            children: child_keys,
+            outputs,
+            size_confirmation,
+            request: Some(DownloadRequest { scheduled_at: None, ..payload.clone() }),
+            ..initial
+        });
+    }
+
+    // Future downloads wait in the scheduler, which calls back in here when they are due.
+    if let Some(scheduled_at) = scheduled_at {
+        payload.scheduled_at = None;
+        // Checked against max_playlist_items above; don't probe again when it comes due.
+        payload.override_playlist_limit = true;
+        state
+            .scheduler
+            .add(ScheduledDownload { download_key: download_key.clone(), scheduled_at, request: payload })
+            .await?;
+        return Ok(DownloadResponse {
+            message: format!("Download scheduled for {}", scheduled_at.to_rfc3339()),
+            download_key,
+            warnings,
+            queue_position: None,
+            estimated_start_at: None,
+        });
+    }
+
+    // Taken before the task joins the queue, so it counts the downloads ahead of it.
+    let estimate = if payload.bypass_queue { None } else { state.slots.preview(&state.config) };
+    if payload.bypass_queue {
+        let reason = payload.bypass_reason.as_deref().unwrap_or("no reason given");
+        let api_key = payload.origin.as_ref().and_then(|origin| origin.api_key.as_deref()).unwrap_or("-");
+        tracing::info!("Download {} bypasses the queue with API key '{}': {}", download_key, api_key, reason);
+    }
+
+    // Spawn the actual download logic in a separate, non-blocking task.
+    let (task_state, task_key) = (state.clone(), download_key.clone());
+    tokio::spawn(async move {
+        if children.is_empty() {
+            run_download_task(task_state.clone(), task_key.clone(), payload, output_template, None).await;
+        } else {
+            run_group_task(task_state.clone(), task_key.clone(), payload, children).await;
+        }
+        desktop_notify::download_finished(&task_state, &task_key);
+        crate::webhook::download_finished(&task_state, &task_key);
+    });
+
+    Ok(DownloadResponse {
+        message: "Download started successfully".to_string(),
+        download_key,
+        warnings,
+        queue_position: estimate.map(|estimate| estimate.position),
+        estimated_start_at: estimate.and_then(|estimate| estimate.estimated_start_at).map(|at| at.to_rfc3339()),
+    })
+}
+
+/// The core long-running task for a single download.
+/// This function is spawned by `enqueue_download` and runs in the background.
+/// Job group children pass `group_history` to hand their history entries to the parent.
+async fn run_download_task(
+    state: AppState,
+    download_key: String,
+    mut payload: DownloadRequest,
+    output_template: String,
+    group_history: Option<Arc<Mutex<Vec<HistoryEntry>>>>,
+) {
+    let downloads_state = &state.downloads;
+    let cancel = CancelGuard::new(&state, &download_key);
+    let waiting = async { (hold_binary(&state, &download_key).await, wait_for_slots(&state, &download_key, &payload).await) };
+    let (_binary, _slots) = tokio::select! {
+        held = waiting => held,
+        _ = cancel.token.cancelled() => {
+            mark_cancelled(downloads_state, &download_key);
+            return;
+        }
+    };
+    // Downloads queued before the host's circuit opened fail here instead of trying it.
+    let circuit_breaker = {
+        let config = state.config.read().unwrap();
+        config.circuit_breaker_threshold.map(|threshold| (threshold, Duration::from_secs(config.circuit_breaker_cooldown_secs)))
+    };
+    // Settled once the download ends; an early return below drops it without counting.
+    let circuit_attempt = match (circuits::host_of(&payload.url), circuit_breaker) {
+        (Some(host), Some((threshold, cooldown))) => match state.circuits.admit(&host, threshold, cooldown) {
+            Ok(attempt) => Some(attempt),
+            Err(circuit) => {
+                update_status_to_failed(downloads_state, &download_key, error::circuit_message(&circuit));
+                return;
+            }
+        },
+        _ => None,
+    };
+    if payload.auto_sub_langs {
+        select_sub_langs(&state, &download_key, &mut payload).await;
+    }
+    let mut expected_files = preview_expected_files(&state, &download_key, &payload, &output_template).await;
+    let output_template = match fit_filename_lengths(&state, &mut expected_files, output_template) {
+        Ok(template) => template,
+        Err(message) => {
+            update_status_to_failed(downloads_state, &download_key, message);
+            return;
+        }
+    };
+    if let Some(status) = downloads_state.lock().get_mut(&download_key) {
+        status.expected_files = expected_files;
+    }
+    // A job group sends one event for all its outputs, from `run_group_task`.
+    if group_history.is_none() {
+        crate::webhook::download_started(&state, &download_key);
+    }
+
+    // Capture provenance for history regardless of `write_info_json`.
+    let provenance_path = match history::provenance_capture_path() {
+        Ok(path) => Some(path),
+        Err(e) => {
+            tracing::warn!("Provenance capture disabled for {}: {}", download_key, e);
+            None
+        }
+    };
+    let progress_mode = progress::detect_mode().await;
+    let stall_timeout = state.config.read().unwrap().stall_timeout_secs.map(Duration::from_secs);
+
+    let live_log = state.live_logs.open(&download_key);
+    let started = Instant::now();
+    let started_at = Utc::now().to_rfc3339();
+    let mut transferred_bytes = 0u64;
+    // An age-restricted failure is retried once with the configured cookies.
+    let mut with_cookies = false;
+    // The playlist item being downloaded: its position among the selected items, and their count.
+    let mut current_item: Option<(u64, u64)>;
+    // The positions of items yt-dlp passed over on purpose, e.g. because the download archive
+    // has them already.
+    let mut passed_over: Vec<u64>;
+    // Set when yt-dlp reported no progress for `stall_timeout_secs` and was killed.
+    let mut stalled = false;
+    let disk_watcher = tokio::spawn(watch_bytes_on_disk(downloads_state.clone(), download_key.clone(), DISK_CHECK_INTERVAL));
+    let (exit_status, stderr) = loop {
+        current_item = None;
+        passed_over = Vec::new();
+        let mut cmd = match download_command(&state, &payload, &output_template, progress_mode, provenance_path.as_deref(), with_cookies) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                update_status_to_failed(downloads_state, &download_key, e.to_string());
+                return;
+            }
+        };
+        let mut child = match cmd.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                update_status_to_failed(downloads_state, &download_key, format!("Failed to start yt-dlp process: {}", e));
+                return;
+            }
+        };
+        if let Some(pid) = child.id() {
+            state.processes.lock().unwrap().insert(download_key.clone(), pid);
+        }
+        let command_line = render_command(&cmd);
+        tracing::info!("Started {}: {}", download_key, command_line);
+        if let Some(status) = downloads_state.lock().get_mut(&download_key) {
+            status.command = Some(command_line);
+        }
+
+        // Progress lines reset the stall deadline whichever pipe they arrive on.
+        let stderr_progress = Arc::new(Notify::new());
+        let stderr_reader =
+            child.stderr.take().map(|stderr| tokio::spawn(collect_stderr(stderr, live_log.sender(), stderr_progress.clone())));
+        if let Some(stdout) = child.stdout.take() {
+            let mut reader = BufReader::new(stdout);
+            // Armed by each progress update, so the timer only runs while downloading. Other
+            // output doesn't reset it, and post-processors such as ffmpeg, which report no
+            // progress while they work, disarm it when they announce themselves.
+            let mut stall_deadline: Option<tokio::time::Instant> = None;
+            let arm = || stall_timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+            loop {
+                let next_line = read_capped_line(&mut reader, MAX_OUTPUT_LINE_BYTES);
+                tokio::pin!(next_line);
+                let read = loop {
+                    tokio::select! {
+                        read = &mut next_line => break Some(read),
+                        _ = stderr_progress.notified() => stall_deadline = arm(),
+                        _ = cancel.token.cancelled() => break None,
+                        _ = tokio::time::sleep_until(stall_deadline.unwrap_or_else(tokio::time::Instant::now)), if stall_deadline.is_some() => {
+                            stalled = true;
+                            break None;
+                        }
+                    }
+                };
+                let Some(read) = read else {
+                    if stalled {
+                        let timeout = stall_timeout.unwrap_or_default().as_secs();
+                        tracing::warn!("{} reported no progress for {}s; killing it as stalled", download_key, timeout);
+                    } else {
+                        tracing::info!("{} was cancelled; killing yt-dlp", download_key);
+                    }
+                    kill_process_group(&mut child).await;
+                    break;
+                };
+                let Ok(Some((line, truncated))) = read else { break };
+                if truncated {
+                    tracing::warn!("Skipping overlong yt-dlp output line (over {} bytes) for {}", MAX_OUTPUT_LINE_BYTES, download_key);
+                    continue;
+                }
+                live_log.publish(&line);
+                if progress::is_postprocessing(&line) {
+                    stall_deadline = None;
+                    if let Some(status) = downloads_state.lock().get_mut(&download_key) {
+                        status.estimated_completion_at = None;
+                        status.speed_bytes_per_sec = None;
+                    }
+                }
+                if let Some((position, total)) = progress::parse_item(&line) {
+                    current_item = Some((position, total));
+                    if let Some(status) = downloads_state.lock().get_mut(&download_key) {
+                        status.items_total = Some(total);
+                        status.items_completed = Some(position - 1);
+                    }
+                } else if let Some(update) = progress::parse_line(&line) {
+                    transferred_bytes += apply_progress(downloads_state, &state.quota, &download_key, update);
+                    stall_deadline = arm();
+                    if let Err(e) = state.quota.persist_if_due().await {
+                        tracing::error!("Failed to persist quota usage: {:?}", e);
+                    }
+                } else if is_passed_over(&line) {
+                    passed_over.extend(current_item.map(|(position, _)| position));
+                } else if let Some(caps) = OUTPUT_FILE_REGEX.captures(&line) {
+                    let path = ["path", "merged", "existing"].iter().find_map(|name| caps.name(name)).unwrap().as_str().to_string();
+                    let mut map = downloads_state.lock();
+                    if let Some(status) = map.get_mut(&download_key) {
+                        if line.starts_with(DESTINATION_PREFIX) {
+                            status.current_file = Some(path.clone());
+                        }
+                        if line.starts_with(EXTRACT_AUDIO_PREFIX) {
+                            status.converted_audio.push(path.clone());
+                        }
+                        mark_expected_file(status, &path);
+                        if !status.files.contains(&path) {
+                            status.files.push(path);
+                        }
+                    }
+                } else if let Some(caps) = UNCONVERTED_AUDIO_REGEX.captures(&line) {
+                    if let Some(status) = downloads_state.lock().get_mut(&download_key) {
+                        status.unconverted_audio.push(caps["path"].to_string());
+                    }
+                }
+            }
+        }
+
+        let exit_status = child.wait().await;
+        let stderr = match stderr_reader {
+            Some(reader) => reader.await.unwrap_or_default(),
+            None => String::new(),
+        };
+        state.processes.lock().unwrap().remove(&download_key);
+        let failed = !stalled && !cancel.token.is_cancelled() && exit_status.as_ref().is_ok_and(|status| !status.success());
+        let retry_with_cookies = !with_cookies && payload.session_id.is_none() && has_cookies(&state);
+        if failed && retry_with_cookies && classify_error(&stderr) == Some(ErrorCode::AgeRestricted) {
+            tracing::info!("{} is age-restricted; retrying with the configured cookies", download_key);
+            with_cookies = true;
+            continue;
+        }
+        if failed && is_format_unavailable(&stderr) {
+            if let Some(fallback) = payload.fallback_format.take() {
+                tracing::info!("The requested format of {} isn't available; retrying with the fallback '{}'", download_key, fallback);
+                if let Some(status) = downloads_state.lock().get_mut(&download_key) {
+                    status.fallback_format = Some(fallback.clone());
+                }
+                payload.format_id = fallback;
+                continue;
+            }
+        }
+        break (exit_status, stderr);
+    };
+    disk_watcher.abort();
+    drop(live_log);
+    if let Err(e) = state.quota.persist().await {
+        tracing::error!("Failed to persist quota usage: {:?}", e);
+    }
+    if let Some(api_key) = payload.origin.as_ref().and_then(|origin| origin.api_key.as_deref()) {
+        state.key_quotas.add_bytes(api_key, transferred_bytes);
+        if let Err(e) = state.key_quotas.persist().await {
+            tracing::error!("Failed to persist the API key quotas: {:?}", e);
+        }
+    }
+    let exit_status = match exit_status {
+        Ok(exit_status) => exit_status,
+        Err(e) => {
+            update_status_to_failed(downloads_state, &download_key, format!("Download process failed to execute: {}", e));
+            return;
+        }
+    };
+
+    // Intermediate files (pre-merge formats, original subtitles) are gone by now; keep what exists.
+    let announced_files = downloads_state.lock().get(&download_key).map(|s| s.files.clone()).unwrap_or_default();
+    let mut final_files = resolve_output_files(announced_files, payload.convert_subs.as_deref()).await;
+    if payload.convert_live_chat {
+        convert_live_chats(&download_key, &mut final_files).await;
+    }
+
+    let checksums = match payload.compute_checksum {
+        Some(algorithm) => checksum::checksum_files(&final_files, algorithm).await,
+        None => Vec::new(),
+    };
+
+    // yt-dlp also exits 0 when nothing was downloaded, e.g. when `match_filter` excluded everything.
+    let no_output = exit_status.success() && final_files.is_empty();
+
+    let mut extractor = None;
+    // The playlist indices of the items that were downloaded, when the capture tells.
+    let mut finished_indices = None;
+    if let Some(path) = &provenance_path {
+        let mut entries = history::collect_provenance(path, &download_key, &payload.url, &payload.format_id).await;
+        extractor = entries.iter().find_map(|entry| entry.extractor.clone());
+        finished_indices = Some(entries.iter().filter_map(|entry| entry.playlist_index).collect::<Vec<_>>());
+        if no_output && entries.is_empty() {
+            entries.push(HistoryEntry {
+                download_key: download_key.clone(),
+                url: payload.url.clone(),
+                downloaded_at: Utc::now().to_rfc3339(),
+                outcome: Some("completed_no_output".to_string()),
+                ..Default::default()
+            });
+        }
+        for entry in &mut entries {
+            entry.origin = payload.origin.clone();
+            entry.started_at = Some(started_at.clone());
+            if let Some(filepath) = &entry.filepath {
+                entry.filesize = tokio::fs::metadata(filepath).await.ok().map(|meta| meta.len());
+                // yt-dlp reports the absolute path, which the announced files may not be.
+                let file = std::fs::canonicalize(filepath).ok();
+                entry.checksums =
+                    checksums.iter().filter(|c| file.is_some() && std::fs::canonicalize(&c.path).ok() == file).cloned().collect();
+            }
+        }
+        match &group_history {
+            Some(group) => group.lock().unwrap().extend(entries),
+            None => record_history(&state, &download_key, &entries, &entries).await,
+        }
+    }
+
+    // yt-dlp carries on past a playlist item that fails, so the items left to retry need not be
+    // adjacent. A playlist with some items downloaded keeps them.
+    let unfinished = match current_item {
+        Some((position, total)) if !exit_status.success() => {
+            Some(unfinished_items(payload.playlist_items.as_deref(), position, total, finished_indices.as_deref(), &passed_over))
+        }
+        _ => None,
+    };
+    let (final_status_str, final_error) = if cancel.token.is_cancelled() {
+        ("cancelled", None)
+    } else if stalled {
+        let timeout = stall_timeout.unwrap_or_default().as_secs();
+        tracing::error!("Download stalled for {}: no progress from yt-dlp for {}s", download_key, timeout);
+        let message = format!("Download stalled: yt-dlp reported no progress for {} seconds and was stopped.", timeout);
+        ("failed", Some(if stderr.trim().is_empty() { message } else { format!("{}\n{}", stderr.trim_end(), message) }))
+    } else if no_output {
+        tracing::warn!("Download {} finished without producing any files", download_key);
+        ("completed_no_output", None)
+    } else if exit_status.success() {
+        ("completed", None)
+    } else if unfinished.as_ref().is_some_and(|unfinished| unfinished.count < unfinished.total) {
+        tracing::error!("Download stopped partway for {}: {}", download_key, &stderr);
+        ("completed_partially", Some(stderr))
+    } else {
+        tracing::error!("Download failed for {}: {}", download_key, &stderr);
+        ("failed", Some(stderr))
+    };
+    // Only failures that point at the host count; e.g. a video that's gone says nothing about it.
+    let circuit_outcome = match final_status_str {
+        "cancelled" => None,
+        "failed" => final_error.as_deref().and_then(classify_error).filter(|code| code.is_host_failure()).map(|_| circuits::Outcome::HostFailed),
+        _ => Some(circuits::Outcome::Succeeded),
+    };
+    if let (Some(attempt), Some(outcome)) = (circuit_attempt, circuit_outcome) {
+        if let Some(circuit) = attempt.record(outcome) {
+            tracing::warn!("{}", error::circuit_message(&circuit));
+        }
+    }
+    let elapsed = started.elapsed();
+    state.metrics.record(extractor.as_deref(), final_status_str, elapsed, transferred_bytes);
+    tracing::info!(
+        "Download {} finished: {} after {:.1}s, {} bytes, extractor {}",
+        download_key,
+        final_status_str,
+        elapsed.as_secs_f64(),
+        transferred_bytes,
+        extractor.as_deref().unwrap_or("unknown")
+    );
+    if !matches!(final_status_str, "failed" | "cancelled") {
+        let output_file_mode = state.config.read().unwrap().output_file_mode;
+        if let Some(mode) = output_file_mode {
+            set_output_file_mode(&final_files, mode).await;
+        }
+        media_index::index_in_background(&state, final_files.iter().map(PathBuf::from).collect());
+        state.library_index.add_files(&final_files);
+    }
+
+    let file_refs: Vec<_> = final_files.iter().filter_map(|file| locate_file(&state, FsPath::new(file))).collect();
+    let mut map = downloads_state.lock();
+    if let Some(status) = map.get_mut(&download_key) {
+        status.status = final_status_str.to_string();
+        status.estimated_completion_at = None;
+        status.speed_bytes_per_sec = None;
+        status.current_file = None;
+        if no_output {
+            status.note = Some(NO_OUTPUT_NOTE.to_string());
+        }
+        let error_code = if stalled { Some(ErrorCode::Stalled) } else { final_error.as_deref().and_then(classify_error) };
+        if let Some(code) = error_code {
+            status.error_code = Some(code);
+            status.error_hint = Some(code.hint().to_string());
+        }
+        if let Some((_, total)) = current_item {
+            status.items_total = Some(total);
+            match &unfinished {
+                None => {
+                    status.items_completed = Some(total);
+                    status.items_failed = Some(0);
+                }
+                Some(unfinished) => {
+                    status.items_completed = Some(total - unfinished.count);
+                    status.items_failed = Some(unfinished.count);
+                    status.failed_item = Some(FailedItem {
+                        playlist_index: unfinished.indices.as_ref().and_then(|indices| indices.first().copied()),
+                        error: first_error_line(final_error.as_deref().unwrap_or_default()),
+                    });
+                    status.retry_playlist_items = unfinished.indices.as_ref().map(|indices| format_playlist_items(indices));
+                }
+            }
+        }
+        status.error = final_error;
+        for path in &final_files {
+            mark_expected_file(status, path);
+        }
+        status.files = final_files;
+        status.checksums = checksums;
+        status.file_refs = file_refs;
+        if status.status == "completed" {
+            status.progress = 100.0;
+            status.source_url_embedded = payload.embed_source_url;
+            let elapsed = elapsed.as_secs_f64();
+            if transferred_bytes > 0 && elapsed > 0.0 {
+                status.average_speed_bytes_per_sec = Some(transferred_bytes as f64 / elapsed);
+            }
+        }
+    }
+}
+
+/// Builds the yt-dlp command for one attempt of a download. `with_cookies` adds the configured
+/// `cookies_file` or `cookies_from_browser`, used when retrying an age-restricted video.
+/// Fails if the request's session has expired.
+fn download_command(
+    state: &AppState,
+    payload: &DownloadRequest,
+    output_template: &str,
+    progress_mode: ProgressMode,
+    provenance_path: Option<&FsPath>,
+    with_cookies: bool,
+) -> Result<Command, AppError> {
+    let mut cmd = state.runner.command("yt-dlp");
+
+    cmd.arg("-f").arg(format_selector(payload))
+       .arg("-o").arg(output_template);
+    progress::configure(&mut cmd, progress_mode);
+
+    // Conditionally add arguments based on the request payload
+    if payload.write_info_json { cmd.arg("--write-info-json"); }
+    if payload.write_thumbnail { cmd.arg("--write-thumbnail"); }
+    if payload.write_description { cmd.arg("--write-description"); }
+    if payload.skip_download { cmd.arg("--skip-download"); }
+    if payload.restrict_filenames { cmd.arg("--restrict-filenames"); }
+    if let Some(items) = &payload.playlist_items { cmd.arg("--playlist-items").arg(items); }
+    if let Some(filter) = match_filter(payload) { cmd.arg("--match-filters").arg(filter); }
+    if let Some(size) = &payload.max_filesize { cmd.arg("--max-filesize").arg(size); }
+    add_network_args(&mut cmd, payload);
+    add_extractor_args(&mut cmd, &payload.extractor_args);
+    add_http_args(&mut cmd, state, &payload.url, &payload.http_headers, payload.user_agent.as_deref());
+    if payload.extract_audio {
+        cmd.arg("--extract-audio");
+        if payload.keep_video { cmd.arg("--keep-video"); }
+        if let Some(format) = &payload.audio_format { cmd.arg("--audio-format").arg(format); }
+        if let Some(quality) = &payload.audio_quality { cmd.arg("--audio-quality").arg(quality); }
+    } else if let Some(format) = &payload.remux_video {
+        cmd.arg("--remux-video").arg(format);
+    }
+    if payload.embed_thumbnail.unwrap_or(false) { cmd.arg("--embed-thumbnail"); }
+    if payload.embed_metadata || payload.embed_source_url { cmd.arg("--embed-metadata"); }
+    if payload.embed_source_url { cmd.arg("--parse-metadata").arg("webpage_url:%(meta_comment)s"); }
+    if let Some(cats) = &payload.sponsorblock_remove { cmd.arg("--sponsorblock-remove").arg(cats); }
+    if let Some(cats) = &payload.sponsorblock_mark { cmd.arg("--sponsorblock-mark").arg(cats); }
+    if let Some(title) = &payload.sponsorblock_chapter_title { cmd.arg("--sponsorblock-chapter-title").arg(title); }
+    if payload.write_subs || payload.write_live_chat { cmd.arg("--write-subs"); }
+    if payload.write_auto_subs { cmd.arg("--write-auto-subs"); }
+    if let Some(langs) = sub_langs(payload) { cmd.arg("--sub-langs").arg(langs); }
+    if let Some(format) = &payload.sub_format { cmd.arg("--sub-format").arg(format); }
+    if let Some(format) = &payload.convert_subs { cmd.arg("--convert-subs").arg(format); }
+    if state.config.read().unwrap().block_age_restricted { cmd.arg("--age-limit").arg((ADULT_AGE_LIMIT - 1).to_string()); }
+    // Job group children leave the archive to their parent, see `archive::record`.
+    let use_archive = state.config.read().unwrap().use_download_archive;
+    if use_archive && payload.load_info_json.is_none() && !payload.skip_download {
+        cmd.arg("--download-archive").arg(archive::prepare(state)?);
+    }
+    match &payload.session_id {
+        Some(id) => add_session_cookies(&mut cmd, state, id)?,
+        None if with_cookies => add_cookie_args(&mut cmd, state),
+        None => {}
+    }
+    if let Some(path) = provenance_path { cmd.arg("--print-to-file").arg(history::PROVENANCE_TEMPLATE).arg(path); }
+    add_source_arg(&mut cmd, payload);
+    cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+
+    // Keep yt-dlp out of the terminal's process group so Ctrl-C on `server run`
+    // doesn't kill it before the server has a chance to drain.
+    #[cfg(unix)]
+    cmd.process_group(0);
+    Ok(cmd)
+}
+
+/// Kills a yt-dlp process together with the helpers it started, such as ffmpeg, which would
+/// otherwise keep its output pipes open. On Unix yt-dlp leads its own process group.
+async fn kill_process_group(child: &mut tokio::process::Child) {
+    #[cfg(unix)]
+    if let Some(pid) = child.id() {
+        // SAFETY: kill only sends a signal; the group is the one `process_group(0)` created.
+        unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) };
+    }
+    let _ = child.kill().await;
+}
+
+/// A format of a job group, ready to run as a child job.
+struct GroupChild {
+    key: String,
+    request: DownloadRequest,
+    template: String,
+    format_decision: Option<String>,
+}
+
+/// Runs a job group: extracts the metadata once, then downloads every child format from it
+/// concurrently while keeping the parent's aggregate progress up to date. The group is
+/// recorded in history as one item per video.
+async fn run_group_task(state: AppState, parent_key: String, payload: DownloadRequest, children: Vec<GroupChild>) {
+    let child_keys: Vec<String> = children.iter().map(|c| c.key.clone()).collect();
+    let cancel = CancelGuard::new(&state, &parent_key);
+    let extracted = tokio::select! {
+        extracted = extract_group_info(&state, &payload) => extracted,
+        _ = cancel.token.cancelled() => {
+            for key in child_keys.iter().chain(std::iter::once(&parent_key)) {
+                mark_cancelled(&state.downloads, key);
+            }
+            return;
+        }
+    };
+    let info_path = match extracted {
+        Ok(Some(path)) => path,
+        Ok(None) => {
+            tracing::info!("{} is in the download archive; skipping the group", parent_key);
+            let mut map = state.downloads.lock();
+            for key in &child_keys {
+                if let Some(status) = map.get_mut(key) {
+                    status.status = "completed_no_output".to_string();
+                    status.note = Some(NO_OUTPUT_NOTE.to_string());
+                    status.progress = 100.0;
+                }
+            }
+            drop(map);
+            update_group_status(&state.downloads, &parent_key, &child_keys, true);
+            return;
+        }
+        Err(e) => {
+            let message = format!("Metadata extraction failed: {}", e);
+            for key in child_keys.iter().chain(std::iter::once(&parent_key)) {
+                update_status_to_failed(&state.downloads, key, message.clone());
+            }
+            return;
+        }
+    };
+    crate::webhook::download_started(&state, &parent_key);
+
+    let group_history = Arc::new(Mutex::new(Vec::new()));
+    let tasks = futures::future::join_all(children.into_iter().map(|child| {
+        let mut request = child.request;
+        request.load_info_json = Some(info_path.to_string_lossy().to_string());
+        run_download_task(state.clone(), child.key, request, child.template, Some(group_history.clone()))
+    }));
+    tokio::pin!(tasks);
+    let mut ticker = tokio::time::interval(GROUP_PROGRESS_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = &mut tasks => break,
+            _ = ticker.tick() => update_group_status(&state.downloads, &parent_key, &child_keys, false),
+        }
+    }
+    let _ = tokio::fs::remove_file(&info_path).await;
+
+    let child_entries = std::mem::take(&mut *group_history.lock().unwrap());
+    let merged = history::merge_group(&parent_key, child_entries.clone());
+    record_history(&state, &parent_key, &merged, &child_entries).await;
+    // As yt-dlp would, only once every format of the videos was downloaded, and before the
+    // group is settled so that a download started after it skips them.
+    let completed = {
+        let map = state.downloads.lock();
+        child_keys.iter().all(|key| map.get(key).is_some_and(|status| status.status == "completed"))
+    };
+    if completed && state.config.read().unwrap().use_download_archive {
+        if let Err(e) = archive::record(&merged).await {
+            tracing::error!("Failed to record {} in the download archive: {:?}", parent_key, e);
+        }
+    }
+    update_group_status(&state.downloads, &parent_key, &child_keys, true);
+    if cancel.token.is_cancelled() {
+        mark_cancelled(&state.downloads, &parent_key);
+    }
+}
+
+/// A download task's cancellation token, taken from `AppState::cancels` so a cancel that
+/// arrives before the task starts still reaches it. Forgotten when the task ends.
+struct CancelGuard {
+    cancels: crate::CancelState,
+    key: String,
+    token: CancellationToken,
+}
+
+impl CancelGuard {
+    fn new(state: &AppState, key: &str) -> Self {
+        let token = state.cancels.lock().unwrap().entry(key.to_string()).or_default().clone();
+        CancelGuard { cancels: state.cancels.clone(), key: key.to_string(), token }
+    }
+}
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        self.cancels.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Settles a download as cancelled, e.g. one stopped while it waited for a slot.
+fn mark_cancelled(downloads: &DownloadState, key: &str) {
+    if let Some(status) = downloads.lock().get_mut(key) {
+        status.status = "cancelled".to_string();
+        status.error = None;
+        status.queue_reason = None;
+        status.queue_position = None;
+        status.estimated_start_at = None;
+        status.estimated_completion_at = None;
+        status.speed_bytes_per_sec = None;
+    }
+}
+
+/// # POST /download/:key/cancel - Stops a queued or running download, killing its yt-dlp.
+/// Cancelling a job group's parent cancels its children too. The key is percent-encoded.
+pub async fn cancel_download(ApiCaller(_caller): ApiCaller, State(state): State<AppState>, Path(key): Path<String>) -> Result<impl IntoResponse, AppError> {
+    let mut map = state.downloads.lock();
+    let Some(status) = map.get(&key) else {
+        return Err(AppError::NotFound(format!("No download for '{}'", key)));
+    };
+    if status.status == "scheduled" {
+        return Err(AppError::Conflict(format!("'{}' is scheduled; cancel it with DELETE /schedule", key)));
+    }
+    if !is_active(&status.status) {
+        return Err(AppError::Conflict(format!("'{}' has already finished ({})", key, status.status)));
+    }
+    let children = status.children.clone();
+    let mut cancels = state.cancels.lock().unwrap();
+    for key in children.iter().chain(std::iter::once(&key)) {
+        if let Some(status) = map.get_mut(key).filter(|status| is_active(&status.status)) {
+            status.status = "cancelled".to_string();
+            cancels.entry(key.clone()).or_default().cancel();
+        }
+    }
+    drop(cancels);
+    tracing::info!("Cancelled download {}", key);
+    Ok(Json(map[&key].clone()))
+}
+
+/// Whether a download is waiting for a slot or running.
+pub(crate) fn is_active(status: &str) -> bool {
+    matches!(status, "queued" | "starting" | "downloading")
+}
+
+/// Extracts a job group's metadata once with `--dump-single-json`, for the children to load
+/// with `--load-info-json`. Returns the path of the saved info file, or `None` if the video is
+/// in the download archive: yt-dlp then prints nothing. Archived playlist items are left out.
+async fn extract_group_info(state: &AppState, payload: &DownloadRequest) -> anyhow::Result<Option<PathBuf>> {
+    let dir = config::data_dir()?.join("groups");
+    tokio::fs::create_dir_all(&dir).await?;
+    let path = dir.join(format!("{}.info.json", uuid::Uuid::new_v4()));
+
+    let mut cmd = state.runner.command("yt-dlp");
+    cmd.arg("--dump-single-json");
+    if let Some(items) = &payload.playlist_items { cmd.arg("--playlist-items").arg(items); }
+    add_network_args(&mut cmd, payload);
+    add_extractor_args(&mut cmd, &payload.extractor_args);
+    add_http_args(&mut cmd, state, &payload.url, &payload.http_headers, payload.user_agent.as_deref());
+    let use_archive = state.config.read().unwrap().use_download_archive;
+    if use_archive {
+        cmd.arg("--download-archive").arg(archive::prepare(state)?);
+    }
+    let output = cmd.arg(&payload.url).stdin(Stdio::null()).output().await?;
+    if !output.status.success() {
+        anyhow::bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    if use_archive && output.stdout.trim_ascii().is_empty() {
+        return Ok(None);
+    }
+    tokio::fs::write(&path, &output.stdout).await?;
+    Ok(Some(path))
+}
+
+/// Rolls the children's statuses up into the parent: average progress, summed bytes and
+/// all files. With `finished`, also settles the parent's final status.
+fn update_group_status(downloads: &DownloadState, parent_key: &str, child_keys: &[String], finished: bool) {
+    let mut map = downloads.lock();
+    let children: Vec<DownloadStatus> = child_keys.iter().filter_map(|k| map.get(k).cloned()).collect();
+    let Some(parent) = map.get_mut(parent_key) else {
+        return;
+    };
+    if children.is_empty() {
+        return;
+    }
+    parent.progress = children.iter().map(|c| c.progress).sum::<f64>() / children.len() as f64;
+    parent.downloaded_bytes = children.iter().filter_map(|c| c.downloaded_bytes).reduce(|a, b| a + b);
+    parent.total_bytes = children.iter().filter_map(|c| c.total_bytes).reduce(|a, b| a + b);
+    parent.files = children.iter().flat_map(|c| c.files.iter().cloned()).collect();
+    parent.checksums = children.iter().flat_map(|c| c.checksums.iter().cloned()).collect();
+    parent.file_refs = children.iter().flat_map(|c| c.file_refs.iter().cloned()).collect();
+    // RFC 3339 times in UTC sort chronologically as strings.
+    parent.estimated_completion_at = children.iter().filter_map(|c| c.estimated_completion_at.clone()).max();
+    // A download shared by an audio and a video output splits its files between them by what
+    // wrote them: the audio output gets what `--extract-audio` wrote, or left as it was.
+    let sharing: Vec<String> = parent.outputs.iter().map(|output| output.download_key.clone()).collect();
+    for output in parent.outputs.iter_mut() {
+        let Some(child) = child_keys.iter().position(|key| *key == output.download_key).and_then(|i| children.get(i)) else {
+            continue;
+        };
+        let shared = sharing.iter().filter(|key| **key == output.download_key).count() > 1;
+        output.status = child.status.clone();
+        output.files = child
+            .files
+            .iter()
+            .filter(|file| {
+                let converted = child.converted_audio.contains(file);
+                match (shared, output.extract_audio) {
+                    (false, _) => true,
+                    (true, true) => converted || child.unconverted_audio.contains(file),
+                    (true, false) => !converted,
+                }
+            })
+            .cloned()
+            .collect();
+    }
+
+    if !finished {
+        if children.iter().any(|c| c.status == "downloading") {
+            parent.status = "downloading".to_string();
+        }
+        return;
+    }
+    let failed = children.iter().filter(|c| c.status != "completed" && c.status != "completed_no_output").count();
+    if failed == 0 && parent.files.is_empty() {
+        parent.status = "completed_no_output".to_string();
+        parent.note = Some(NO_OUTPUT_NOTE.to_string());
+        parent.progress = 100.0;
+    } else if failed == 0 {
+        parent.status = "completed".to_string();
+        parent.progress = 100.0;
+    } else {
+        parent.status = "failed".to_string();
+        parent.error = Some(format!("{} of {} formats failed", failed, children.len()));
+    }
+}
+
+/// # GET /schedule - Lists downloads waiting for their scheduled time, soonest first.
+pub async fn list_scheduled(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.scheduler.list())
+}
+
+/// # DELETE /schedule?key= - Cancels a scheduled download before it starts.
+pub async fn cancel_scheduled(
+    ApiCaller(_caller): ApiCaller,
+    State(state): State<AppState>,
+    Query(query): Query<ScheduleQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let Some(job) = state.scheduler.cancel(&query.key).await? else {
+        return Err(AppError::NotFound(format!("No scheduled download for '{}'", query.key)));
+    };
+    // Cancelling a job group's parent cancels its children too.
+    let mut map = state.downloads.lock();
+    let children = map.get(&job.download_key).map(|s| s.children.clone()).unwrap_or_default();
+    for key in children.iter().chain(std::iter::once(&job.download_key)) {
+        if let Some(status) = map.get_mut(key) {
+            status.status = "cancelled".to_string();
+        }
+    }
+    drop(map);
+    tracing::info!("Cancelled scheduled download {}", job.download_key);
+    Ok(Json(job))
+}
+
+/// # POST /download/:key/retry - Starts a finished download again with the same request.
+/// With `only_failed=true`, a playlist that stopped partway only downloads the items that
+/// failed or weren't reached. The key is percent-encoded.
+pub async fn retry_download(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    ClientOrigin(origin): ClientOrigin,
+    Query(query): Query<RetryQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let (mut request, retry_items) = {
+        let map = state.downloads.lock();
+        let Some(status) = map.get(&key) else {
+            return Err(AppError::NotFound(format!("No download for '{}'", key)));
+        };
+        if status.parent.is_some() {
+            return Err(AppError::BadRequest("Retry the job group's parent instead of one of its formats".to_string()));
+        }
+        let Some(request) = status.request.clone() else {
+            return Err(AppError::Unprocessable(format!("The request of '{}' is no longer known", key)));
+        };
+        (request, status.retry_playlist_items.clone())
+    };
+    if query.only_failed {
+        let Some(items) = retry_items else {
+            return Err(AppError::Unprocessable(format!(
+                "'{}' has no failed playlist items to retry, or they can't be mapped to playlist indices",
+                key
+            )));
+        };
+        request.playlist_items = Some(items);
+    }
+    request.origin = Some(origin);
+    tracing::info!("Retrying {} (only_failed: {})", key, query.only_failed);
+    let response = enqueue_client_download(&state, request).await?;
+    Ok((StatusCode::ACCEPTED, Json(response)))
+}
+
+/// # GET /download/:key/log/ws - Streams a running download's yt-dlp output over a WebSocket,
+/// one text message per line, and closes when the download ends. The key is percent-encoded.
+pub async fn tail_download_log(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, AppError> {
+    let Some(lines) = state.live_logs.subscribe(&key) else {
+        return Err(AppError::NotFound(format!("No running download for '{}'", key)));
+    };
+    Ok(ws.on_upgrade(move |socket| stream_log_lines(socket, lines)))
+}
+
+/// Forwards log lines to the socket. A client that falls too far behind loses the oldest
+/// lines and is told how many.
+async fn stream_log_lines(mut socket: WebSocket, mut lines: broadcast::Receiver<String>) {
+    loop {
+        let text = match lines.recv().await {
+            Ok(line) => line,
+            Err(RecvError::Lagged(skipped)) => format!("[yt-agent] {} lines dropped because the client fell behind", skipped),
+            Err(RecvError::Closed) => break,
+        };
+        if socket.send(Message::Text(text)).await.is_err() {
+            return;
+        }
+    }
+    let _ = socket.send(Message::Close(None)).await;
+}
+
+/// # GET /download/:key/log/stream - Streams a running download's yt-dlp output as
+/// Server-Sent Events: the latest buffered lines first, then new lines as they arrive.
+/// Each line is a `log` event; a `dropped` event tells a client that fell behind how many
+/// lines it lost, and an `end` event closes the stream when the download ends.
+pub async fn stream_download_log(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AppError> {
+    let Some((backlog, lines)) = state.live_logs.follow(&key) else {
+        return Err(AppError::NotFound(format!("No running download for '{}'", key)));
+    };
+    let replay = stream::iter(backlog).map(|line| Event::default().event("log").data(line));
+    let live = stream::unfold(Some(lines), |lines| async move {
+        let mut lines = lines?;
+        let event = match lines.recv().await {
+            Ok(line) => Event::default().event("log").data(line),
+            Err(RecvError::Lagged(skipped)) => Event::default().event("dropped").data(skipped.to_string()),
+            Err(RecvError::Closed) => return Some((Event::default().event("end").data(""), None)),
+        };
+        Some((event, Some(lines)))
+    });
+    Ok(Sse::new(replay.chain(live).map(Ok)).keep_alive(KeepAlive::default()))
+}
+
+// ===================================================================
+//                          HELPER FUNCTIONS
+// ===================================================================
+
+/// Maps 1-based positions among the selected playlist items to playlist indices. Without
+/// `playlist_items` they are the same. Returns `None` if the selection uses more than plain
+/// indices, `a-b` ranges and `a:b[:step]` slices (e.g. negative or open-ended ones), so it
+/// can't be mapped. Only the selected items up to `positions.end()` are worked out, so a huge
+/// range costs no more than a small one.
+fn playlist_indices(playlist_items: Option<&str>, positions: RangeInclusive<u64>) -> Option<Vec<u64>> {
+    let Some(spec) = playlist_items else {
+        return Some(positions.collect());
+    };
+    let wanted = usize::try_from(*positions.end()).ok()?;
+    let mut selected = Vec::new();
+    for part in spec.split(',').map(str::trim) {
+        let (start, end, step) = match part.split_once(['-', ':']) {
+            Some((start, rest)) if part.as_bytes()[start.len()] == b':' => match rest.split_once(':') {
+                Some((end, step)) => (start, end, step.parse::<u64>().ok().filter(|&step| step > 0)?),
+                None => (start, rest, 1),
+            },
+            Some((start, end)) => (start, end, 1),
+            None => (part, part, 1),
+        };
+        let (start, end) = (start.parse::<u64>().ok()?, end.parse::<u64>().ok()?);
+        let room = wanted.saturating_sub(selected.len());
+        selected.extend((start..=end).step_by(step as usize).take(room));
+    }
+    positions.map(|position| selected.get(position.checked_sub(1)? as usize).copied()).collect()
+}
+
+/// Formats playlist indices for `--playlist-items`, collapsing consecutive runs into ranges.
+fn format_playlist_items(indices: &[u64]) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < indices.len() {
+        let start = indices[i];
+        while i + 1 < indices.len() && indices[i + 1] == indices[i] + 1 {
+            i += 1;
+        }
+        parts.push(if indices[i] == start { start.to_string() } else { format!("{}-{}", start, indices[i]) });
+        i += 1;
+    }
+    parts.join(",")
+}
+
+/// The first `ERROR:` line of yt-dlp's error output, which belongs to the first item that
+/// failed, or all of it if there is none.
+fn first_error_line(stderr: &str) -> String {
+    stderr.lines().find(|line| line.starts_with("ERROR:")).unwrap_or(stderr.trim()).to_string()
+}
+
+/// Whether yt-dlp passed over the current playlist item on purpose, rather than failing it.
+fn is_passed_over(line: &str) -> bool {
+    line.starts_with("[download] ") && (line.ends_with("has already been recorded in the archive") || line.contains(" does not pass filter "))
+}
+
+/// The playlist items of a download that didn't finish.
+struct Unfinished {
+    /// How many of the `total` selected items didn't finish.
+    count: u64,
+    total: u64,
+    /// Their playlist indices, if they could be worked out from `playlist_items`.
+    indices: Option<Vec<u64>>,
+}
+
+/// Works out which of the `total` selected items didn't finish, yt-dlp having stopped at
+/// `position`: those before it that aren't among the `finished` indices from the provenance
+/// capture, and those it never reached. Without a capture, every item from `position` on.
+fn unfinished_items(playlist_items: Option<&str>, position: u64, total: u64, finished: Option<&[u64]>, passed_over: &[u64]) -> Unfinished {
+    let from_position = || {
+        let count = total.saturating_sub(position) + 1;
+        Unfinished { count, total, indices: playlist_indices(playlist_items, position..=total) }
+    };
+    let Some(finished) = finished else { return from_position() };
+    let Some(selected) = playlist_indices(playlist_items, 1..=total) else {
+        // Without the indices, only how many is known.
+        let done = (finished.len() + passed_over.len()) as u64;
+        return if done < total { Unfinished { count: total - done, total, indices: None } } else { from_position() };
+    };
+    let indices: Vec<u64> = (1..=total)
+        .zip(selected)
+        .filter(|(position, index)| !passed_over.contains(position) && !finished.contains(index))
+        .map(|(_, index)| index)
+        .collect();
+    // The capture may miss an item whose failure came after its file was moved into place.
+    if indices.is_empty() {
+        return from_position();
+    }
+    Unfinished { count: indices.len() as u64, total, indices: Some(indices) }
+}
+
+/// The directory downloads without an explicit template go to: the root itself, or with
+/// `date_folders` its subfolder for today's local date, e.g. `2024-05-31`.
+fn default_output_dir(state: &AppState, root: PathBuf) -> PathBuf {
+    if state.config.read().unwrap().date_folders {
+        root.join(chrono::Local::now().format("%Y-%m-%d").to_string())
+    } else {
+        root
+    }
+}
+
+/// Helper to keep an output template inside the given root.
+/// Relative templates are joined onto the root; absolute ones must already lie within it,
+/// as must the templates built from a relative root.
+fn confine_template(root: &FsPath, template: &str) -> Result<String, AppError> {
+    let template_path = FsPath::new(template);
+    if template_path.components().any(|c| c == Component::ParentDir) {
+        return Err(AppError::BadRequest("Output template must not contain '..' components".to_string()));
+    }
+    if template_path.starts_with(root) {
+        return Ok(template.to_string());
+    }
+    if template_path.is_absolute() {
+        return Err(AppError::Forbidden(format!(
+            "Output template '{}' is outside the download root '{}'",
+            template,
+            root.display()
+        )));
+    }
+    Ok(root.join(template_path).to_string_lossy().to_string())
+}
+
+/// Parses `scheduled_at`. Returns `None` when absent or already in the past, so the
+/// download starts right away.
+fn parse_scheduled_at(scheduled_at: Option<&str>) -> Result<Option<DateTime<Utc>>, AppError> {
+    let Some(text) = scheduled_at else {
+        return Ok(None);
+    };
+    let time = DateTime::parse_from_rfc3339(text)
+        .map_err(|e| AppError::BadRequest(format!("Invalid scheduled_at '{}' (expected RFC 3339): {}", text, e)))?
+        .with_timezone(&Utc);
+    Ok(Some(time).filter(|t| *t > Utc::now()))
+}
+
+/// Checks request fields that yt-dlp would otherwise reject only after the process starts.
+fn validate_download_request(payload: &DownloadRequest) -> Result<(), AppError> {
+    validate_extractor_args(&payload.extractor_args)?;
+    validate_http_headers(&payload.http_headers, payload.user_agent.as_deref())?;
+    if !payload.formats.is_empty() && !payload.outputs.is_empty() {
+        return Err(AppError::BadRequest("Pass either formats or outputs, not both".to_string()));
+    }
+    if payload.formats.is_empty() && payload.outputs.is_empty() && payload.format_id.trim().is_empty() {
+        return Err(AppError::BadRequest("format_id is required unless formats or outputs is given".to_string()));
+    }
+    if payload.fallback_format.as_deref().is_some_and(|format| format.trim().is_empty()) {
+        return Err(AppError::BadRequest("fallback_format must not be empty".to_string()));
+    }
+    let specs = payload.formats.iter().chain(&payload.outputs);
+    if specs.clone().any(|spec| spec.format_id.trim().is_empty()) {
+        return Err(AppError::BadRequest("Every entry in formats or outputs needs a format_id".to_string()));
+    }
+    // Suffixes are added after the template is confined to the download root, so they must
+    // stay within the file name.
+    if let Some(suffix) = specs.clone().filter_map(|spec| spec.template_suffix.as_deref()).find(|s| s.contains(['/', '\\']) || s.contains("..")) {
+        return Err(AppError::BadRequest(format!("template_suffix '{}' must not contain '/', '\\' or '..'", suffix)));
+    }
+    let templates = payload.output_template.iter().chain(specs.filter_map(|spec| spec.template_suffix.as_ref()));
+    for template in templates {
+        if let Some(field) = unbounded_template_field(template) {
+            return Err(AppError::BadRequest(format!(
+                "Output template field '{}' can expand to an unbounded length and is not allowed in file names",
+                field
+            )));
+        }
+    }
+    if let (Some(min), Some(max)) = (payload.min_duration_secs, payload.max_duration_secs) {
+        if min > max {
+            return Err(AppError::BadRequest(format!(
+                "min_duration_secs ({}) must not be greater than max_duration_secs ({})",
+                min, max
+            )));
+        }
+    }
+    if let Some(text) = &payload.title_contains {
+        if text.trim().is_empty() || text.contains(['\r', '\n']) {
+            return Err(AppError::BadRequest("title_contains must be non-empty text on a single line".to_string()));
+        }
+    }
+    if let Some(title) = &payload.sponsorblock_chapter_title {
+        if payload.sponsorblock_mark.is_none() {
+            return Err(AppError::BadRequest("sponsorblock_chapter_title requires sponsorblock_mark".to_string()));
+        }
+        if title.trim().is_empty() || title.contains(['\r', '\n']) {
+            return Err(AppError::BadRequest("sponsorblock_chapter_title must be non-empty text on a single line".to_string()));
+        }
+        if let Some(field) = template_fields(title).into_iter().find(|f| !SPONSORBLOCK_CHAPTER_FIELDS.contains(&f.as_str())) {
+            return Err(AppError::BadRequest(format!(
+                "Unsupported sponsorblock_chapter_title field '{}'. Supported fields: {}",
+                field,
+                SPONSORBLOCK_CHAPTER_FIELDS.join(", ")
+            )));
+        }
+    }
+    if let Some(lang) = &payload.audio_lang {
+        if !LANGUAGE_CODE_REGEX.is_match(lang) {
+            return Err(AppError::BadRequest(format!(
+                "Invalid audio_lang '{}'. Expected a language code such as 'en' or 'pt-BR'",
+                lang
+            )));
+        }
+    }
+    if let Some(format) = &payload.sub_format {
+        // `--sub-format` takes a preference list such as "srt/vtt/best".
+        if let Some(bad) = format.split('/').find(|f| !SUB_FORMATS.contains(f)) {
+            return Err(AppError::BadRequest(format!(
+                "Unsupported sub_format '{}'. Supported formats: {}",
+                bad,
+                SUB_FORMATS.join(", ")
+            )));
+        }
+    }
+    if let Some(format) = &payload.convert_subs {
+        if !CONVERT_SUB_FORMATS.contains(&format.as_str()) {
+            return Err(AppError::BadRequest(format!(
+                "Unsupported convert_subs '{}'. Supported formats: {}",
+                format,
+                CONVERT_SUB_FORMATS.join(", ")
+            )));
+        }
+    }
+    if payload.auto_sub_langs {
+        if payload.preferred_sub_langs.is_empty() {
+            return Err(AppError::BadRequest("auto_sub_langs requires preferred_sub_langs".to_string()));
+        }
+        if payload.sub_langs.is_some() {
+            return Err(AppError::BadRequest("auto_sub_langs picks sub_langs itself; set only one of them".to_string()));
+        }
+        if !payload.write_subs && !payload.write_auto_subs {
+            return Err(AppError::BadRequest("auto_sub_langs requires write_subs or write_auto_subs".to_string()));
+        }
+    }
+    if let Some(lang) = payload.preferred_sub_langs.iter().find(|lang| !LANGUAGE_CODE_REGEX.is_match(lang)) {
+        return Err(AppError::BadRequest(format!(
+            "Invalid preferred_sub_langs entry '{}'. Expected a language code such as 'en' or 'pt-BR'",
+            lang
+        )));
+    }
+    if payload.convert_live_chat && !payload.write_live_chat {
+        return Err(AppError::BadRequest("convert_live_chat requires write_live_chat".to_string()));
+    }
+    Ok(())
+}
+
+/// The `--sub-langs` value: the requested languages, plus `live_chat` for `write_live_chat`.
+/// Subtitles requested without `sub_langs` keep yt-dlp's default of English. Without
+/// `write_subs` or `write_auto_subs`, only the chat is requested.
+fn sub_langs(payload: &DownloadRequest) -> Option<String> {
+    if !payload.write_live_chat {
+        return payload.sub_langs.clone();
+    }
+    if !(payload.write_subs || payload.write_auto_subs) {
+        return Some("live_chat".to_string());
+    }
+    Some(format!("{},live_chat", payload.sub_langs.as_deref().unwrap_or("en")))
+}
+
+/// The subtitle languages of a video, from `%(.{subtitles,automatic_captions})j`.
+#[derive(Deserialize)]
+struct SubtitleRecord {
+    #[serde(default)]
+    subtitles: Option<serde_json::Map<String, serde_json::Value>>,
+    #[serde(default)]
+    automatic_captions: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+/// Probes the subtitle languages of the video (the first item of a playlist) and sets
+/// `sub_langs` to the first of `preferred_sub_langs` it has, uploaded subtitles before
+/// automatic ones with `write_auto_subs`. A preference also matches its regional variants,
+/// e.g. "en" matches "en-US". If none is available, subtitles are skipped; if the probe
+/// fails, the whole preference list is passed on and yt-dlp takes what exists.
+async fn select_sub_langs(state: &AppState, download_key: &str, payload: &mut DownloadRequest) {
+    let preferred = payload.preferred_sub_langs.join(",");
+    let mut cmd = state.runner.command("yt-dlp");
+    cmd.arg("--skip-download")
+       .arg("--playlist-items").arg("1")
+       .arg("--print").arg("%(.{subtitles,automatic_captions})j");
+    if let Some(path) = &payload.load_info_json { cmd.arg("--load-info-json").arg(path); }
+    add_extractor_args(&mut cmd, &payload.extractor_args);
+    add_http_args(&mut cmd, state, &payload.url, &payload.http_headers, payload.user_agent.as_deref());
+    if let Some(id) = &payload.session_id { let _ = add_session_cookies(&mut cmd, state, id); }
+    add_source_arg(&mut cmd, payload);
+    if payload.load_info_json.is_none() { cmd.arg(&payload.url); }
+    cmd.stdin(Stdio::null()).kill_on_drop(true);
+
+    let record = match cmd.output().await {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).lines().find_map(|line| serde_json::from_str::<SubtitleRecord>(line).ok())
+        }
+        Ok(output) => {
+            tracing::warn!("Subtitle probe failed for {}: {}", download_key, String::from_utf8_lossy(&output.stderr).trim());
+            None
+        }
+        Err(e) => {
+            tracing::warn!("Subtitle probe failed for {}: {}", download_key, e);
+            None
+        }
+    };
+    let Some(record) = record else {
+        tracing::info!("Subtitles for {}: passing all preferred languages ({}) to yt-dlp", download_key, preferred);
+        payload.sub_langs = Some(preferred);
+        return;
+    };
+
+    let uploaded: Vec<&String> = record.subtitles.iter().flatten().map(|(lang, _)| lang).collect();
+    let automatic: Vec<&String> = if payload.write_auto_subs {
+        record.automatic_captions.iter().flatten().map(|(lang, _)| lang).collect()
+    } else {
+        Vec::new()
+    };
+    let find = |available: &[&String], wanted: &str| {
+        let mut matches: Vec<&String> = available
+            .iter()
+            .copied()
+            .filter(|lang| lang.eq_ignore_ascii_case(wanted) || lang.to_lowercase().starts_with(&format!("{}-", wanted.to_lowercase())))
+            .collect();
+        // An exact match first, then the shortest variant, e.g. "en-US" before "en-US-x-auto".
+        matches.sort_by_key(|lang| (!lang.eq_ignore_ascii_case(wanted), lang.len(), lang.to_string()));
+        matches.first().map(|lang| lang.to_string())
+    };
+    let chosen = payload.preferred_sub_langs.iter().find_map(|wanted| {
+        find(&uploaded, wanted).map(|lang| (lang, "uploaded")).or_else(|| find(&automatic, wanted).map(|lang| (lang, "automatic")))
+    });
+
+    match chosen {
+        Some((lang, kind)) => {
+            tracing::info!("Subtitles for {}: chose {} ({}) from the preferred languages {}", download_key, lang, kind, preferred);
+            payload.sub_langs = Some(lang);
+        }
+        None => {
+            let mut available: Vec<&str> = uploaded.iter().chain(&automatic).map(|lang| lang.as_str()).collect();
+            available.sort_unstable();
+            available.dedup();
+            tracing::warn!(
+                "Subtitles for {}: none of the preferred languages {} is available (available: {}); downloading without subtitles",
+                download_key,
+                preferred,
+                if available.is_empty() { "none".to_string() } else { available.join(", ") }
+            );
+            payload.write_subs = false;
+            payload.write_auto_subs = false;
+        }
+    }
+}
+
+/// Fills in the config's network defaults and checks `source_address`: it must be an IP
+/// address of the family `force_ip` selects and, on Unix, be assigned to a local interface.
+fn resolve_network_selection(state: &AppState, payload: &mut DownloadRequest) -> Result<(), AppError> {
+    let verify_local = {
+        let config = state.config.read().unwrap();
+        if payload.source_address.is_none() { payload.source_address = config.default_source_address.clone(); }
+        if payload.force_ip.is_none() { payload.force_ip = config.default_force_ip; }
+        config.verify_source_address
+    };
+    let Some(address) = &payload.source_address else {
+        return Ok(());
+    };
+    let ip: IpAddr = address
+        .parse()
+        .map_err(|_| AppError::BadRequest(format!("source_address '{}' is not a valid IP address", address)))?;
+    match payload.force_ip {
+        Some(ForceIp::Ipv4) if !ip.is_ipv4() => {
+            return Err(AppError::BadRequest(format!("source_address '{}' is not an IPv4 address but force_ip is ipv4", address)));
+        }
+        Some(ForceIp::Ipv6) if !ip.is_ipv6() => {
+            return Err(AppError::BadRequest(format!("source_address '{}' is not an IPv6 address but force_ip is ipv6", address)));
+        }
+        _ => {}
+    }
+    // Binding fails with "address not available" unless the address belongs to this host.
+    #[cfg(unix)]
+    if verify_local {
+        if let Err(e) = std::net::UdpSocket::bind((ip, 0)) {
+            return Err(AppError::Unprocessable(format!(
+                "source_address '{}' is not assigned to a local interface: {}",
+                address, e
+            )));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = verify_local;
+    Ok(())
+}
+
+/// Builds the request a job group child runs: the parent's options with the format and
+/// post-processing of `spec`.
+fn group_child_request(parent: &DownloadRequest, spec: &FormatSpec) -> DownloadRequest {
+    DownloadRequest {
+        format_id: spec.format_id.clone(),
+        formats: Vec::new(),
+        outputs: Vec::new(),
+        scheduled_at: None,
+        extract_audio: spec.extract_audio,
+        keep_video: spec.keep_video,
+        audio_format: spec.audio_format.clone(),
+        audio_quality: spec.audio_quality.clone(),
+        remux_video: spec.remux_video.clone(),
+        fallback_format: None,
+        ..parent.clone()
+    }
+}
+
+/// Turns `outputs` into job group formats. An audio extraction and a plain download of the
+/// same `format_id` become one format that keeps the video (`--keep-video`), so their streams
+/// are fetched once. Also returns, for each output, the index of the format producing it.
+fn plan_outputs(outputs: &[OutputSpec]) -> (Vec<FormatSpec>, Vec<usize>) {
+    let mut formats: Vec<FormatSpec> = Vec::new();
+    let mut sources = Vec::new();
+    for output in outputs {
+        match formats.iter().position(|format| can_share_download(format, output)) {
+            Some(index) => {
+                let format = &mut formats[index];
+                if output.extract_audio {
+                    format.extract_audio = true;
+                    format.audio_format = output.audio_format.clone();
+                    format.audio_quality = output.audio_quality.clone();
+                }
+                format.keep_video = true;
+                sources.push(index);
+            }
+            None => {
+                sources.push(formats.len());
+                formats.push(output.clone());
+            }
+        }
+    }
+    (formats, sources)
+}
+
+/// Whether one yt-dlp run can produce both outputs: the same streams, with one extracting the
+/// audio and the other keeping them as downloaded. yt-dlp applies one set of post-processing
+/// per run, so anything else needs a download of its own.
+fn can_share_download(a: &FormatSpec, b: &FormatSpec) -> bool {
+    a.format_id == b.format_id
+        && a.template_suffix == b.template_suffix
+        && a.extract_audio != b.extract_audio
+        && !a.keep_video
+        && !b.keep_video
+        && a.remux_video.is_none()
+        && b.remux_video.is_none()
+}
+
+/// Inserts `suffix` before the extension field of an output template, or appends it.
+fn with_template_suffix(template: &str, suffix: Option<&str>) -> String {
+    let Some(suffix) = suffix.filter(|s| !s.is_empty()) else {
+        return template.to_string();
+    };
+    match template.strip_suffix(".%(ext)s") {
+        Some(stem) => format!("{}{}.%(ext)s", stem, suffix),
+        None => format!("{}{}", template, suffix),
+    }
+}
+
+/// Adds `--source-address` and `-4`/`-6` from the request.
+fn add_network_args(cmd: &mut Command, payload: &DownloadRequest) {
+    if let Some(address) = &payload.source_address { cmd.arg("--source-address").arg(address); }
+    match payload.force_ip {
+        Some(ForceIp::Ipv4) => { cmd.arg("-4"); }
+        Some(ForceIp::Ipv6) => { cmd.arg("-6"); }
+        None => {}
+    }
+}
+
+/// Adds what yt-dlp should download: the URL, or a job group's pre-extracted metadata.
+fn add_source_arg(cmd: &mut Command, payload: &DownloadRequest) {
+    match &payload.load_info_json {
+        Some(path) => { cmd.arg("--load-info-json").arg(path); }
+        None => { cmd.arg(&payload.url); }
+    }
+}
+
+/// The `-f` value for a download. With `audio_lang`, every `bestaudio`/`ba` is narrowed to
+/// tracks whose language starts with it, e.g. "137+bestaudio[language^=de]/137+bestaudio";
+/// the original selection follows as the fallback when no such track exists.
+/// Selections without `bestaudio`/`ba` (a fixed audio format id) are left alone.
+fn format_selector(payload: &DownloadRequest) -> String {
+    let Some(lang) = &payload.audio_lang else {
+        return payload.format_id.clone();
+    };
+    if !BEST_AUDIO_REGEX.is_match(&payload.format_id) {
+        return payload.format_id.clone();
+    }
+    let preferred = BEST_AUDIO_REGEX.replace_all(&payload.format_id, |caps: &regex::Captures| {
+        format!("{}[language^={}]", &caps[1], lang)
+    });
+    format!("{}/{}", preferred, payload.format_id)
+}
+
+/// Compiles `match_filter` and the filter helpers into one `--match-filters` expression,
+/// e.g. "duration >= 60 & title ~= '(?i)live'". All conditions must hold.
+fn match_filter(payload: &DownloadRequest) -> Option<String> {
+    let mut conditions: Vec<String> = payload.match_filter.iter().cloned().collect();
+    if let Some(min) = payload.min_duration_secs { conditions.push(format!("duration >= {}", min)); }
+    if let Some(max) = payload.max_duration_secs { conditions.push(format!("duration <= {}", max)); }
+    if let Some(views) = payload.min_views { conditions.push(format!("view_count >= {}", views)); }
+    if let Some(text) = &payload.title_contains {
+        // yt-dlp splits the expression on unescaped '&' and ends the value at an unescaped quote.
+        let pattern = regex::escape(text).replace('\'', "\\'");
+        conditions.push(format!("title ~= '(?i){}'", pattern));
+    }
+    (!conditions.is_empty()).then(|| conditions.join(" & "))
+}
+
+/// Holds the yt-dlp binary for the whole download, so a managed version switch waits for it.
+/// While a switch is pending the download waits for it as "queued".
+async fn hold_binary(state: &AppState, download_key: &str) -> OwnedRwLockReadGuard<()> {
+    let set_queue_reason = |reason: Option<&str>| {
+        if let Some(status) = state.downloads.lock().get_mut(download_key) {
+            status.status = if reason.is_some() { "queued" } else { "starting" }.to_string();
+            status.queue_reason = reason.map(str::to_string);
+        }
+    };
+    let pending = state.ytdlp.pending().is_some();
+    if pending {
+        set_queue_reason(Some(WAITING_FOR_YTDLP_SWITCH));
+    }
+    let guard = state.ytdlp.hold().await;
+    if pending {
+        set_queue_reason(None);
+    }
+    guard
+}
+
+/// Takes a download slot, showing the download as "queued" while all of them are in use.
+/// A download that bypasses the queue takes an express slot instead.
+/// A download with CPU-heavy post-processing first takes a post-processing slot, so it
+/// doesn't hold a download slot while waiting for one.
+async fn wait_for_slots(state: &AppState, download_key: &str, payload: &DownloadRequest) -> Vec<SlotGuard> {
+    let set_queue_reason = |reason: Option<&str>| {
+        if let Some(status) = state.downloads.lock().get_mut(download_key) {
+            status.status = if reason.is_some() { "queued" } else { "starting" }.to_string();
+            status.queue_reason = reason.map(str::to_string);
+        }
+    };
+    let mut slots = Vec::new();
+    let mut queued = false;
+    if needs_postprocess_slot(payload) {
+        if state.slots.postprocess_full(&state.config) {
+            set_queue_reason(Some(WAITING_FOR_POSTPROCESS_SLOT));
+            queued = true;
+        }
+        slots.push(state.slots.acquire_postprocess(&state.config).await);
+    }
+    if payload.bypass_queue {
+        slots.push(state.slots.acquire_express(&state.config).await);
+    } else {
+        let report = state.slots.report(&state.config);
+        if report.waiting > 0 || report.limit.is_some_and(|limit| report.running >= limit) {
+            set_queue_reason(Some(WAITING_FOR_SLOT));
+            queued = true;
+        }
+        let show_estimate = |estimate: QueueEstimate| {
+            if let Some(status) = state.downloads.lock().get_mut(download_key) {
+                status.queue_position = Some(estimate.position);
+                status.estimated_start_at = estimate.estimated_start_at.map(|at| at.to_rfc3339());
+            }
+        };
+        slots.push(state.slots.acquire(&state.config, show_estimate).await);
+    }
+    if queued {
+        set_queue_reason(None);
+        if let Some(status) = state.downloads.lock().get_mut(download_key) {
+            status.queue_position = None;
+            status.estimated_start_at = None;
+        }
+    }
+    slots
+}
+
+/// Whether yt-dlp failed because the selected format doesn't exist for the video.
+fn is_format_unavailable(stderr: &str) -> bool {
+    stderr.to_lowercase().contains(FORMAT_UNAVAILABLE_ERROR)
+}
+
+/// Whether a download runs ffmpeg steps heavy enough to count against `max_concurrent_postprocess`:
+/// audio extraction, remuxing and cutting out SponsorBlock segments.
+fn needs_postprocess_slot(payload: &DownloadRequest) -> bool {
+    payload.extract_audio || payload.remux_video.is_some() || payload.sponsorblock_remove.is_some()
+}
+
+/// Renders a command as a shell-style line for logs and the status entry.
+/// Values of secret-looking `--add-headers` (e.g. `Authorization`) are redacted.
+fn render_command(cmd: &Command) -> String {
+    let cmd = cmd.as_std();
+    let mut previous = None;
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| {
+            let mut arg = arg.to_string_lossy();
+            if previous.replace(arg.to_string()).as_deref() == Some("--add-headers") {
+                if let Some(name) = arg.split_once(':').map(|(name, _)| name.to_string()).filter(|name| redact::is_secret_key(name)) {
+                    arg = format!("{}:{}", name, redact::REDACTED).into();
+                }
+            }
+            if !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./:=+,%@".contains(c)) {
+                arg.to_string()
+            } else {
+                format!("'{}'", arg.replace('\'', "'\\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Looks up a single requested format in the cached `/formats` result and handles formats
+/// that would produce a file without audio (or a bare audio stream). Depending on
+/// `auto_merge_audio` the request is adjusted or rejected; the adjustment is returned so it
+/// can be recorded on the status. Uncached URLs and compound selectors are left alone.
+fn check_format_streams(state: &AppState, payload: &mut DownloadRequest) -> Result<Option<String>, AppError> {
+    let Some(info) = state.formats_cache.get(&payload.url) else {
+        return Ok(None);
+    };
+    let Some(format) = info.formats.iter().find(|f| f.format_id == payload.format_id) else {
+        return Ok(None);
+    };
+    let auto_merge = state.config.read().unwrap().auto_merge_audio;
+    let has_stream = |codec: &str| !codec.is_empty() && codec != "none";
+    let id = payload.format_id.clone();
+
+    if has_stream(&format.vcodec) && format.acodec == "none" {
+        if !auto_merge {
+            return Err(AppError::Unprocessable(format!(
+                "Format '{}' is video-only and has no audio. Use '{}+bestaudio' (or '{}+<audio format id>') to merge audio in.",
+                id, id, id
+            )));
+        }
+        payload.format_id = format!("{}+bestaudio", id);
+        return Ok(Some(format!("Format '{}' is video-only; merged with bestaudio as '{}'", id, payload.format_id)));
+    }
+
+    if format.vcodec == "none" && has_stream(&format.acodec) && !payload.extract_audio {
+        if !auto_merge {
+            return Err(AppError::Unprocessable(format!(
+                "Format '{}' is audio-only. Set extract_audio to true for an audio file, or use 'bestvideo+{}' to merge it with video.",
+                id, id
+            )));
+        }
+        payload.extract_audio = true;
+        return Ok(Some(format!("Format '{}' is audio-only; enabled extract_audio", id)));
+    }
+
+    Ok(None)
+}
+
+/// Enforces `max_height`: a format ID the cached `/formats` result lists as taller is refused,
+/// and every part of the selector gets a `[height<=?N]` filter so yt-dlp can't pick a taller
+/// format either. Formats without a known height, such as audio, still pass the filter.
+fn enforce_max_height(state: &AppState, payload: &mut DownloadRequest) -> Result<(), AppError> {
+    let Some(max_height) = state.config.read().unwrap().max_height else {
+        return Ok(());
+    };
+    if let Some(info) = state.formats_cache.get(&payload.url) {
+        for id in payload.format_id.split(['+', '/', ',']) {
+            let Some(format) = info.formats.iter().find(|f| f.format_id == id) else { continue };
+            if let Some(height) = format.height.filter(|height| *height > max_height) {
+                return Err(AppError::Unprocessable(format!(
+                    "Format '{}' is {}p, taller than the max_height of {}p allowed by this server. Pick a format of {}p or less.",
+                    id, height, max_height, max_height
+                )));
+            }
+        }
+    }
+    payload.format_id = limit_selector_height(&payload.format_id, max_height);
+    payload.fallback_format = payload.fallback_format.as_deref().map(|format| limit_selector_height(format, max_height));
+    Ok(())
+}
+
+/// Enforces `max_playlist_items`: a URL that lists more items is refused unless the request
+/// has `override_playlist_limit` or narrows it with `playlist_items`. Only URLs that look like
+/// a playlist are probed, and not those `/formats` found to be a single video.
+async fn check_playlist_size(state: &AppState, payload: &DownloadRequest) -> Result<(), AppError> {
+    let limit = state.config.read().unwrap().max_playlist_items;
+    if limit == 0
+        || payload.override_playlist_limit
+        || payload.playlist_items.is_some()
+        || !looks_like_playlist(&payload.url)
+        || state.formats_cache.get(&payload.url).is_some()
+    {
+        return Ok(());
+    }
+    match playlist_item_count(state, payload).await? {
+        Some(items) if items > limit => Err(AppError::PlaylistTooLong { items, limit }),
+        _ => Ok(()),
+    }
+}
+
+/// Whether `url` may list several items, judged from its shape so a plain video link starts
+/// without a probe: a `list` query parameter, a playlist, channel or user page, or a
+/// `ytsearchN:`-style search. Anything that isn't an http(s) URL is probed to be safe.
+fn looks_like_playlist(url: &str) -> bool {
+    let Some(parsed) = reqwest::Url::parse(url).ok().filter(|url| matches!(url.scheme(), "http" | "https")) else {
+        return true;
+    };
+    if parsed.query_pairs().any(|(name, _)| name == "list") {
+        return true;
+    }
+    parsed.path_segments().into_iter().flatten().any(|segment| {
+        segment.starts_with('@')
+            || matches!(
+                segment.to_ascii_lowercase().as_str(),
+                "playlist" | "playlists" | "channel" | "c" | "user" | "videos" | "shorts" | "streams" | "sets" | "album"
+            )
+    })
+}
+
+/// How many items the URL lists, probed with `--flat-playlist` so the items aren't resolved,
+/// and cached for a resubmission. A single video counts as one. `None` if the probe failed;
+/// the download then reports the problem itself.
+async fn playlist_item_count(state: &AppState, payload: &DownloadRequest) -> Result<Option<u64>, AppError> {
+    if let Some(items) = state.playlist_counts.get(&payload.url) {
+        return Ok(Some(items));
+    }
+    let mut cmd = state.runner.command("yt-dlp");
+    cmd.arg("--flat-playlist").arg("--print").arg("%(playlist_count)s");
+    add_extractor_args(&mut cmd, &payload.extractor_args);
+    add_http_args(&mut cmd, state, &payload.url, &payload.http_headers, payload.user_agent.as_deref());
+    add_network_args(&mut cmd, payload);
+    if let Some(id) = &payload.session_id {
+        add_session_cookies(&mut cmd, state, id)?;
+    }
+    let output = cmd.arg(&payload.url).stdin(Stdio::null()).output().await?;
+    if !output.status.success() {
+        tracing::warn!("Failed to count the playlist items of {}: {}", payload.url, String::from_utf8_lossy(&output.stderr).trim());
+        return Ok(None);
+    }
+    // One line per item. Some extractors don't report a count, so the larger of the two is used.
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout.lines().filter(|line| !line.trim().is_empty()).collect();
+    let reported = lines.first().and_then(|line| line.trim().parse::<u64>().ok());
+    let items = reported.unwrap_or(0).max(lines.len() as u64);
+    state.playlist_counts.insert(payload.url.clone(), items);
+    Ok(Some(items))
+}
+
+/// Enforces `large_download_threshold`: a download estimated above it is refused unless the
+/// request has `confirm_large`. Sizes come from the cached `/formats` result; when only some
+/// parts are known (e.g. "313+bestaudio"), their sum still triggers the check. Returns whether
+/// the size was unknown, and the confirmation to record for a confirmed large download.
+fn check_download_size(
+    state: &AppState,
+    payload: &DownloadRequest,
+    children: &[GroupChild],
+) -> Result<(bool, Option<SizeConfirmation>), AppError> {
+    let Some(threshold) = state.config.read().unwrap().large_download_threshold else {
+        return Ok((false, None));
+    };
+    let sizes: Vec<Option<u64>> = if children.is_empty() {
+        format_part_sizes(state, payload)
+    } else {
+        children
+            .iter()
+            .flat_map(|child| Some(format_part_sizes(state, &child.request)).filter(|sizes| !sizes.is_empty()).unwrap_or(vec![None]))
+            .collect()
+    };
+    let known: u64 = sizes.iter().flatten().sum();
+    let complete = !sizes.is_empty() && sizes.iter().all(Option::is_some);
+    if known <= threshold {
+        return Ok((!complete, None));
+    }
+    if !payload.confirm_large {
+        return Err(AppError::ConfirmationRequired { estimated_bytes: known, threshold_bytes: threshold });
+    }
+    Ok((false, Some(SizeConfirmation { estimated_bytes: known, threshold_bytes: threshold, confirmed_at: Utc::now().to_rfc3339() })))
+}
+
+/// Appends `[height<=?N]` to every format in a selector, e.g. "bv+ba/b" becomes
+/// "bv[height<=?1080]+ba[height<=?1080]/b[height<=?1080]", including those inside groups such
+/// as "(bv/b)". Formats that have the filter already, e.g. in the stored request of a retried
+/// download, keep just the one. A selector this can't take apart safely, with unbalanced
+/// brackets or quotes in a filter, is wrapped as a whole: "(<selector>)[height<=?N]".
+fn limit_selector_height(selector: &str, max_height: u32) -> String {
+    let filter = format!("[height<=?{}]", max_height);
+    let cap = |format: &str| {
+        let name = format.trim_end();
+        if name.trim().is_empty() || name.trim_start().starts_with('[') || name.contains(&filter) {
+            format.to_string()
+        } else {
+            format!("{}{}{}", name, filter, &format[name.len()..])
+        }
+    };
+    let wrap = || {
+        if selector.starts_with('(') && selector.ends_with(&format!("){}", filter)) {
+            selector.to_string()
+        } else {
+            format!("({}){}", selector, filter)
+        }
+    };
+    let mut limited = String::new();
+    let mut format = String::new();
+    let (mut brackets, mut groups) = (0i32, 0i32);
+    for c in selector.chars() {
+        match c {
+            '\'' | '"' => return wrap(),
+            '[' => brackets += 1,
+            ']' => brackets -= 1,
+            '+' | '/' | ',' | '(' | ')' if brackets == 0 => {
+                groups += match c {
+                    '(' => 1,
+                    ')' => -1,
+                    _ => 0,
+                };
+                limited.push_str(&cap(&format));
+                limited.push(c);
+                format.clear();
+                if groups < 0 {
+                    break;
+                }
+                continue;
+            }
+            _ => {}
+        }
+        if brackets < 0 {
+            break;
+        }
+        format.push(c);
+    }
+    if brackets != 0 || groups != 0 {
+        return wrap();
+    }
+    limited.push_str(&cap(&format));
+    limited
+}
+
+/// Checks that the codecs of the selected format(s) fit the `remux_video` target container,
+/// using the cached `/formats` result. Depending on `remux_check` an incompatibility is
+/// returned as a warning or rejects the request. Formats not in the cache are not checked.
+fn check_remux_compatibility(state: &AppState, payload: &DownloadRequest) -> Result<Vec<String>, AppError> {
+    let Some(target) = payload.remux_video.as_deref().filter(|_| !payload.extract_audio) else {
+        return Ok(Vec::new());
+    };
+    let Some((_, video_codecs, audio_codecs)) = REMUX_COMPATIBILITY.iter().find(|(c, _, _)| c.eq_ignore_ascii_case(target)) else {
+        return Ok(Vec::new());
+    };
+    let Some(info) = state.formats_cache.get(&payload.url) else {
+        return Ok(Vec::new());
+    };
+
+    let fits = |codec: &str, allowed: &[&str]| {
+        codec.is_empty() || codec == "none" || allowed.iter().any(|prefix| codec.to_lowercase().starts_with(prefix))
+    };
+    let mut problems = Vec::new();
+    for id in payload.format_id.split('+') {
+        let Some(format) = info.formats.iter().find(|f| f.format_id == id) else { continue };
+        if !fits(&format.vcodec, video_codecs) {
+            problems.push(format!("video codec '{}' of format '{}' cannot be remuxed into {}", format.vcodec, id, target));
+        }
+        if !fits(&format.acodec, audio_codecs) {
+            problems.push(format!("audio codec '{}' of format '{}' cannot be remuxed into {}", format.acodec, id, target));
+        }
+    }
+    if problems.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let message = format!("{} (consider remux_video \"mkv\")", problems.join("; "));
+    match state.config.read().unwrap().remux_check {
+        RemuxCheck::Reject => Err(AppError::BadRequest(format!("Incompatible remux target: {}", message))),
+        RemuxCheck::Warn => {
+            tracing::warn!("Remux warning for {}: {}", payload.url, message);
+            Ok(vec![message])
+        }
+    }
+}
+
+/// One line of the expected-files preview.
+#[derive(Deserialize)]
+struct PreviewRecord {
+    id: Option<String>,
+    title: Option<String>,
+    filename: Option<String>,
+}
+
+/// Runs a quick `--skip-download` pass with the download's format, template and playlist
+/// filters to list the files it will produce. Disabled by `preview_expected_files`; gives
+/// up (returning what it has, usually nothing) after `expected_files_budget_ms`.
+async fn preview_expected_files(
+    state: &AppState,
+    download_key: &str,
+    payload: &DownloadRequest,
+    output_template: &str,
+) -> Vec<ExpectedFile> {
+    let (enabled, budget) = {
+        let config = state.config.read().unwrap();
+        (config.preview_expected_files, Duration::from_millis(config.expected_files_budget_ms))
+    };
+    // A fetch doesn't produce the media files the preview lists.
+    if !enabled || payload.skip_download {
+        return Vec::new();
+    }
+
+    let mut cmd = state.runner.command("yt-dlp");
+    cmd.arg("--skip-download")
+       .arg("--print").arg("%(.{id,title,filename})j")
+       .arg("-f").arg(format_selector(payload))
+       .arg("-o").arg(output_template);
+    if payload.restrict_filenames { cmd.arg("--restrict-filenames"); }
+    if let Some(items) = &payload.playlist_items { cmd.arg("--playlist-items").arg(items); }
+    if let Some(filter) = match_filter(payload) { cmd.arg("--match-filters").arg(filter); }
+    add_extractor_args(&mut cmd, &payload.extractor_args);
+    add_http_args(&mut cmd, state, &payload.url, &payload.http_headers, payload.user_agent.as_deref());
+    // An expired session fails the download itself, with a clearer error than the preview's.
+    if let Some(id) = &payload.session_id { let _ = add_session_cookies(&mut cmd, state, id); }
+    add_source_arg(&mut cmd, payload);
+    cmd.stdin(Stdio::null()).kill_on_drop(true);
+
+    let output = match tokio::time::timeout(budget, cmd.output()).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(e)) => {
+            tracing::warn!("Expected-files preview failed for {}: {}", download_key, e);
+            return Vec::new();
+        }
+        Err(_) => {
+            tracing::info!("Expected-files preview for {} exceeded {:?}; skipping it", download_key, budget);
+            return Vec::new();
+        }
+    };
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| serde_json::from_str::<PreviewRecord>(line).ok())
+        .filter_map(|record| {
+            Some(ExpectedFile { path: record.filename?, video_id: record.id, title: record.title, completed: false })
+        })
+        .collect()
+}
+
+/// Returns the names of the fields `template` uses, including every alternative (`%(a,b)s`)
+/// and object key (`%(.{a,b})j`), ignoring `%%` escapes.
+fn template_fields(template: &str) -> Vec<String> {
+    TEMPLATE_FIELD_REGEX
+        .captures_iter(template)
+        .filter_map(|captures| captures.get(1))
+        .flat_map(|field| {
+            // What follows `&`, `|` or `>` is a replacement, default or date format, not a field.
+            let fields = field.as_str().split(['&', '|', '>']).next().unwrap_or_default().to_string();
+            fields
+                .split(',')
+                .map(|name| {
+                    let name = name.trim_start_matches(['.', '{']);
+                    let end = name.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(name.len());
+                    name[..end].to_string()
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Returns the first field of `template` listed in `UNBOUNDED_TEMPLATE_FIELDS`, if any.
+fn unbounded_template_field(template: &str) -> Option<String> {
+    template_fields(template).into_iter().find(|name| UNBOUNDED_TEMPLATE_FIELDS.contains(&name.as_str()))
+}
+
+/// Applies `long_filename_policy` to the previewed files. Returns the template to download
+/// with, or why the download can't start. A single file is renamed to its truncated path;
+/// when several files share the template, the title field is capped at a byte length instead.
+/// Without a preview the names aren't known, so with the "truncate" policy the title is capped
+/// from an estimate of the rest of the file name (see `cap_unpreviewed_title`).
+fn fit_filename_lengths(state: &AppState, expected: &mut [ExpectedFile], template: String) -> Result<String, String> {
+    let (max_bytes, policy) = {
+        let config = state.config.read().unwrap();
+        (config.max_filename_bytes, config.long_filename_policy)
+    };
+    if expected.is_empty() {
+        return Ok(match policy {
+            LongFilenamePolicy::Truncate => cap_unpreviewed_title(&template, max_bytes),
+            LongFilenamePolicy::Error => template,
+        });
+    }
+    let longest = |path: &str| {
+        FsPath::new(path).components().map(|c| c.as_os_str().to_string_lossy().to_string()).max_by_key(String::len).unwrap_or_default()
+    };
+    let Some(name) = expected.iter().map(|f| longest(&f.path)).filter(|name| name.len() > max_bytes).max_by_key(String::len) else {
+        return Ok(template);
+    };
+    if policy == LongFilenamePolicy::Error {
+        return Err(format!(
+            "The file name '{}' is {} bytes long, over the {}-byte limit (max_filename_bytes). Shorten the output template.",
+            name,
+            name.len(),
+            max_bytes
+        ));
+    }
+
+    if let [file] = expected {
+        let path = FsPath::new(&file.path);
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let mut truncated = PathBuf::new();
+        for component in path.parent().into_iter().flat_map(|p| p.components()) {
+            let text = component.as_os_str().to_string_lossy();
+            match component {
+                Component::Normal(_) => truncated.push(truncate_utf8(&text, max_bytes)),
+                _ => truncated.push(component.as_os_str()),
+            }
+        }
+        truncated.push(truncate_file_name(&file_name, max_bytes, file.video_id.as_deref()));
+        file.path = truncated.to_string_lossy().to_string();
+        tracing::info!("Truncated a long file name to '{}'", file.path);
+        // The rendered name goes in as a literal; the extension stays a field so
+        // post-processing can still change it.
+        if template.ends_with(".%(ext)s") && truncated.extension().is_some() {
+            return Ok(format!("{}.%(ext)s", truncated.with_extension("").to_string_lossy().replace('%', "%%")));
+        }
+        return Ok(file.path.replace('%', "%%"));
+    }
+
+    // The overhead is what the file name holds besides the title. Sanitizing usually makes the
+    // rendered title longer than the raw one, which errs towards a shorter cap.
+    let overhead = expected
+        .iter()
+        .map(|f| {
+            let file_name = FsPath::new(&f.path).file_name().unwrap_or_default().to_string_lossy().len();
+            file_name.saturating_sub(f.title.as_deref().map_or(0, str::len))
+        })
+        .max()
+        .unwrap_or_default();
+    let directory_too_long = expected.iter().any(|f| {
+        FsPath::new(&f.path).parent().is_some_and(|p| p.components().any(|c| c.as_os_str().len() > max_bytes))
+    });
+    if directory_too_long || !template.contains("%(title)s") || overhead >= max_bytes {
+        return Err(format!(
+            "The file name '{}' is {} bytes long, over the {}-byte limit (max_filename_bytes), and the output template can't be shortened automatically. Shorten the output template.",
+            name,
+            name.len(),
+            max_bytes
+        ));
+    }
+    let title_bytes = max_bytes - overhead;
+    tracing::info!("Capping titles at {} bytes to keep file names within {} bytes", title_bytes, max_bytes);
+    Ok(template.replace("%(title)s", &format!("%(title).{}B", title_bytes)))
+}
+
+/// Caps each `%(title)s` in the file name part of `template` so the name fits in `max_bytes`,
+/// counting the literal text and `UNPREVIEWED_FIELD_BYTES` for every other field. Returns the
+/// template unchanged when it has no plain title field or there is no room left for one.
+fn cap_unpreviewed_title(template: &str, max_bytes: usize) -> String {
+    let file_name = template.rsplit(['/', '\\']).next().unwrap_or(template);
+    let titles = file_name.matches("%(title)s").count();
+    if titles == 0 {
+        return template.to_string();
+    }
+    let fields = TEMPLATE_FIELD_REGEX.find_iter(file_name).filter(|field| field.as_str() != "%%").count();
+    let literal = TEMPLATE_FIELD_REGEX.replace_all(file_name, "").len();
+    let rest = literal.saturating_sub(titles) + (fields - titles) * UNPREVIEWED_FIELD_BYTES;
+    let title_bytes = max_bytes.saturating_sub(rest) / titles;
+    if title_bytes == 0 {
+        return template.to_string();
+    }
+    let directory = &template[..template.len() - file_name.len()];
+    format!("{}{}", directory, file_name.replace("%(title)s", &format!("%(title).{}B", title_bytes)))
+}
+
+/// Shortens a file name to at most `max_bytes` bytes without splitting a character, keeping
+/// its extension and a trailing ` [id]` if it has them. Always gives the same result for the same input.
+fn truncate_file_name(name: &str, max_bytes: usize, video_id: Option<&str>) -> String {
+    if name.len() <= max_bytes {
+        return name.to_string();
+    }
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && ext.len() <= 10 && !ext.contains(' ') => (stem, &name[stem.len()..]),
+        _ => (name, ""),
+    };
+    let id_suffix = video_id.map(|id| format!(" [{}]", id)).filter(|suffix| stem.ends_with(suffix.as_str())).unwrap_or_default();
+    let head = &stem[..stem.len() - id_suffix.len()];
+    // Without room for a character of the title, keep the start of the name rather than an
+    // extension with nothing in front of it.
+    let kept = max_bytes.checked_sub(extension.len() + id_suffix.len()).map(|budget| truncate_utf8(head, budget).trim_end());
+    match kept {
+        Some(kept) if !kept.is_empty() => format!("{}{}{}", kept, id_suffix, extension),
+        _ => truncate_utf8(name, max_bytes).to_string(),
+    }
+}
+
+/// The longest prefix of `text` that fits in `max_bytes` and ends on a character boundary.
+fn truncate_utf8(text: &str, max_bytes: usize) -> &str {
+    let mut end = max_bytes.min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// Marks the expected file matching `path` as completed. Post-processing (merging, audio
+/// extraction, remuxing) can change the extension, so files match on their path without it;
+/// the entry then takes the actual path.
+fn mark_expected_file(status: &mut DownloadStatus, path: &str) {
+    let stem = FsPath::new(path).with_extension("");
+    if let Some(expected) = status.expected_files.iter_mut().find(|f| FsPath::new(&f.path).with_extension("") == stem) {
+        expected.path = path.to_string();
+        expected.completed = true;
+    }
+}
+
+/// Reduces the files yt-dlp announced to the ones that still exist on disk.
+/// Subtitles converted with `--convert-subs` are announced under their original
+/// extension, so a missing file is also looked up with the converted extension.
+async fn resolve_output_files(announced: Vec<String>, convert_subs: Option<&str>) -> Vec<String> {
+    let mut files = Vec::new();
+    for path in announced {
+        if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+            files.push(path);
+            continue;
+        }
+        if let Some(ext) = convert_subs {
+            let converted = PathBuf::from(&path).with_extension(ext).to_string_lossy().to_string();
+            if !files.contains(&converted) && tokio::fs::try_exists(&converted).await.unwrap_or(false) {
+                files.push(converted);
+            }
+        }
+    }
+    files
+}
+
+/// Writes a readable `.live_chat.txt` next to each chat replay and adds it to `files`.
+/// A chat that can't be converted is logged and kept as JSON only.
+async fn convert_live_chats(download_key: &str, files: &mut Vec<String>) {
+    let chats: Vec<String> = files.iter().filter(|path| path.ends_with(chat::LIVE_CHAT_SUFFIX)).cloned().collect();
+    for path in chats {
+        match chat::to_text(FsPath::new(&path)).await {
+            Ok(text_path) => files.push(text_path.to_string_lossy().to_string()),
+            Err(e) => tracing::warn!("Failed to convert the live chat of {} to text: {}", download_key, e),
+        }
+    }
+}
+
+/// Applies `output_file_mode` to a completed download's files. A file that can't be changed
+/// is logged and left as is.
+#[cfg(unix)]
+async fn set_output_file_mode(files: &[String], mode: u32) {
+    use std::os::unix::fs::PermissionsExt;
+    for path in files {
+        if let Err(e) = tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).await {
+            tracing::warn!("Failed to set the mode of {} to {:o}: {}", path, mode, e);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn set_output_file_mode(_files: &[String], _mode: u32) {
+    tracing::info!("output_file_mode is only supported on Unix; leaving file permissions unchanged.");
+}
+
+/// Appends a finished download's entries to history and writes the per-file sidecars if
+/// configured. A job group records merged entries but writes one sidecar per file.
+async fn record_history(state: &AppState, download_key: &str, entries: &[HistoryEntry], sidecar_entries: &[HistoryEntry]) {
+    if let Err(e) = history::append(entries).await {
+        tracing::error!("Failed to record history for {}: {:?}", download_key, e);
+    }
+    let max_history_bytes = state.config.read().unwrap().max_history_bytes;
+    match history::compact(max_history_bytes).await {
+        Ok(0) => {}
+        Ok(dropped) => tracing::info!("History reached max_history_bytes; dropped its {} oldest entries.", dropped),
+        Err(e) => tracing::error!("Failed to compact the history: {:?}", e),
+    }
+    if state.config.read().unwrap().write_provenance_sidecar {
+        for entry in sidecar_entries {
+            if let Err(e) = history::write_sidecar(entry).await {
+                tracing::warn!("Failed to write provenance sidecar for {}: {:?}", download_key, e);
+            }
+        }
+    }
+}
+
+/// Reads yt-dlp's stderr while it runs, publishing each line to the download's live log and
+/// signalling `progress` for progress lines, and returns all of it for the error message.
+async fn collect_stderr(stderr: ChildStderr, live_log: LogSender, progress: Arc<Notify>) -> String {
+    let mut reader = BufReader::new(stderr);
+    let mut collected = String::new();
+    while let Ok(Some((line, _))) = read_capped_line(&mut reader, MAX_OUTPUT_LINE_BYTES).await {
+        live_log.publish(&line);
+        if progress::parse_line(&line).is_some() {
+            progress.notify_one();
+        }
+        collected.push_str(&line);
+        collected.push('\n');
+    }
+    collected
+}
+
+/// Reads the next line from `reader`, buffering at most `max_len` bytes of it.
+/// The rest of an overlong line is consumed and discarded. Returns `None` at EOF,
+/// otherwise the line (without its terminator) and whether it was truncated.
+async fn read_capped_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_len: usize,
+) -> std::io::Result<Option<(String, bool)>> {
+    let mut line = Vec::new();
+    let mut truncated = false;
+    let mut read_any = false;
+
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            break;
+        }
+        read_any = true;
+
+        let newline = available.iter().position(|&b| b == b'\n');
+        let chunk = &available[..newline.unwrap_or(available.len())];
+        let room = max_len.saturating_sub(line.len());
+        if chunk.len() > room {
+            truncated = true;
+        }
+        line.extend_from_slice(&chunk[..chunk.len().min(room)]);
+
+        let consumed = chunk.len() + usize::from(newline.is_some());
+        reader.consume(consumed);
+        if newline.is_some() {
+            break;
+        }
+    }
+
+    if !read_any {
+        return Ok(None);
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some((String::from_utf8_lossy(&line).into_owned(), truncated)))
+}
+
+/// Applies a parsed progress tick to a download's status entry.
+/// Also counts the newly downloaded bytes against the quota, and returns how many there were.
+fn apply_progress(state: &DownloadState, quota: &QuotaTracker, key: &str, update: ProgressUpdate) -> u64 {
+    let mut map = state.lock();
+    let mut new_bytes = 0;
+    if let Some(status) = map.get_mut(key) {
+        if let Some(now) = update.downloaded_bytes {
+            // A lower count means yt-dlp moved on to the next file (e.g. audio after video).
+            let before = status.downloaded_bytes.unwrap_or(0);
+            new_bytes = if now >= before { now - before } else { now };
+            quota.add(new_bytes);
+        }
+        status.status = "downloading".to_string();
+        status.progress = update.percent;
+        status.estimated_completion_at = progress::estimated_completion(&update).map(|at| at.to_rfc3339());
+        status.last_progress_at = Some(Utc::now().to_rfc3339());
+        status.eta = update.eta;
+        status.speed_bytes_per_sec = update.speed_bytes_per_sec;
+        status.speed = update.speed;
+        status.downloaded_bytes = update.downloaded_bytes;
+        status.total_bytes = update.total_bytes;
+    }
+    new_bytes
+}
+
+/// Keeps `bytes_on_disk` of a running download up to date with the size of its current file,
+/// checked every `interval`, and flags a `progress_mismatch` when that size and the parsed `downloaded_bytes` disagree.
+/// yt-dlp writes into `<file>.part` and renames it when done, so both names are tried; while
+/// neither exists, e.g. right after the rename into a merged file, the last size is kept.
+async fn watch_bytes_on_disk(downloads: DownloadState, key: String, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut warned = false;
+    loop {
+        ticker.tick().await;
+        let path = match downloads.lock().get(&key) {
+            // The task is also aborted when the download ends; this covers early failures.
+            Some(status) if matches!(status.status.as_str(), "starting" | "downloading") => status.current_file.clone(),
+            _ => return,
+        };
+        let Some(path) = path else { continue };
+        let size = match tokio::fs::metadata(format!("{}.part", path)).await {
+            Ok(metadata) => metadata.len(),
+            Err(_) => match tokio::fs::metadata(&path).await {
+                Ok(metadata) => metadata.len(),
+                Err(_) => continue,
+            },
+        };
+        let mut map = downloads.lock();
+        let Some(status) = map.get_mut(&key).filter(|s| s.current_file.as_deref() == Some(path.as_str())) else { continue };
+        status.bytes_on_disk = Some(size);
+        status.progress_mismatch = status.downloaded_bytes.is_some_and(|parsed| {
+            let difference = parsed.abs_diff(size);
+            difference > PROGRESS_MISMATCH_MIN_BYTES && difference as f64 > parsed.max(size) as f64 * PROGRESS_MISMATCH_RATIO
+        });
+        if status.progress_mismatch && !warned {
+            warned = true;
+            tracing::warn!(
+                "Progress of {} disagrees with the file on disk: {} bytes parsed, {} bytes in {}",
+                key,
+                status.downloaded_bytes.unwrap_or_default(),
+                size,
+                path
+            );
+        }
+    }
+}
+
+/// Helper to update a download's status to "failed" with a specific message.
+pub(crate) fn update_status_to_failed(state: &DownloadState, key: &str, error_message: String) {
+    let mut map = state.lock();
+    // A download cancelled while it was failing stays cancelled.
+    if let Some(status) = map.get_mut(key).filter(|status| status.status != "cancelled") {
+        status.status = "failed".to_string();
+        status.error = Some(error_message);
+        status.estimated_completion_at = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ApiKey;
+    use crate::test_support::{app, fake_ytdlp, finished, first_chunk, lock_files, send, send_with, state, FakeRunner};
+    use axum::http::{Method, Request, StatusCode};
+    use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+    use serde_json::json;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn encode(key: &str) -> String {
+        utf8_percent_encode(key, NON_ALPHANUMERIC).to_string()
+    }
+
+    #[tokio::test]
+    async fn reads_past_a_line_longer_than_the_cap() {
+        let long = "x".repeat(10_000);
+        let input = format!("first\r\n{}\nlast", long);
+        // A small buffer, so the long line arrives over many reads.
+        let mut reader = tokio::io::BufReader::with_capacity(64, input.as_bytes());
+        let mut lines = Vec::new();
+        while let Some(line) = super::read_capped_line(&mut reader, 1000).await.unwrap() {
+            lines.push(line);
+        }
+        assert_eq!(lines, [("first".to_string(), false), ("x".repeat(1000), true), ("last".to_string(), false)]);
+    }
+
+    #[tokio::test]
+    async fn a_long_output_line_doesnt_stop_the_download() {
+        // 200 KB of JSON on one line, as a broken extractor might print, before the usual output.
+        let script = format!("case \"$*\" in *--dump-json*|*--flat-playlist*|*\"--print %(.\"*) ;; *) head -c 200000 /dev/zero | tr '\\0' '{{'; echo ;; esac\n{}", crate::test_support::FAKE_YTDLP);
+        let state = state(FakeRunner::new().script("yt-dlp", &script));
+        let key = "https://example.com/long-line";
+        let started = send(&app(&state), Method::POST, "/download", Some(json!({ "url": key, "format_id": "best" }))).await;
+        assert_eq!(started.status, StatusCode::ACCEPTED, "{}", started.body);
+        let status = finished(&state, key).await;
+        assert_eq!(status.status, "completed", "{:?}", status.error);
+        assert_eq!(status.files.len(), 1);
+        assert_eq!(status.progress, 100.0);
+    }
+
+    fn expected(path: &str, video_id: &str, title: &str) -> crate::models::ExpectedFile {
+        crate::models::ExpectedFile { path: path.to_string(), video_id: Some(video_id.to_string()), title: Some(title.to_string()), completed: false }
+    }
+
+    #[test]
+    fn fits_long_file_names_with_or_without_a_preview() {
+        use crate::config::LongFilenamePolicy;
+        let state = state(FakeRunner::new());
+        state.config.write().unwrap().max_filename_bytes = 100;
+        let template = "/srv/%(uploader)s/%(title)s [%(id)s].%(ext)s".to_string();
+        let fit = |expected: &mut [crate::models::ExpectedFile]| super::fit_filename_lengths(&state, expected, template.clone());
+
+        // Several files share the template: the title is capped by the longest overhead.
+        let (long, longer) = ("a".repeat(120), "b".repeat(150));
+        let mut playlist = [
+            expected(&format!("/srv/up/{} [id1].mp4", long), "id1", &long),
+            expected(&format!("/srv/up/{} [id22].webm", longer), "id22", &longer),
+        ];
+        assert_eq!(fit(&mut playlist).unwrap(), "/srv/%(uploader)s/%(title).88B [%(id)s].%(ext)s");
+        assert!(playlist[0].path.ends_with(&format!("{} [id1].mp4", long)), "the previewed paths are left as they were");
+
+        // One file is renamed to its truncated path.
+        let mut single = [expected(&format!("/srv/up/{} [id1].mp4", long), "id1", &long)];
+        let renamed = fit(&mut single).unwrap();
+        assert!(single[0].path.ends_with(" [id1].mp4") && Path::new(&single[0].path).file_name().unwrap().len() <= 100);
+        assert_eq!(renamed, format!("{}.%(ext)s", single[0].path.trim_end_matches(".mp4")));
+
+        // A directory that is too long can't be fixed by capping the title.
+        let mut deep = playlist.clone();
+        deep[1].path = format!("/srv/{}/{} [id22].webm", "d".repeat(101), longer);
+        assert!(fit(&mut deep).unwrap_err().contains("can't be shortened automatically"));
+
+        // Without a preview the title is capped from an estimate of the rest of the name:
+        // 100 bytes less " [", "].", the "s" of each other field and 32 bytes each for id and ext.
+        assert_eq!(fit(&mut []).unwrap(), "/srv/%(uploader)s/%(title).30B [%(id)s].%(ext)s");
+        assert_eq!(super::cap_unpreviewed_title("%(id)s.%(ext)s", 100), "%(id)s.%(ext)s");
+        assert_eq!(super::cap_unpreviewed_title("%(title)s - %(title)s.%(ext)s", 100), "%(title).31B - %(title).31B.%(ext)s");
+        assert_eq!(super::cap_unpreviewed_title("%(title)s.%(ext)s", 20), "%(title)s.%(ext)s");
+
+        state.config.write().unwrap().long_filename_policy = LongFilenamePolicy::Error;
+        assert_eq!(fit(&mut []).unwrap(), template);
+        assert!(fit(&mut playlist).unwrap_err().contains("over the 100-byte limit"));
+    }
+
+    #[test]
+    fn adds_the_live_chat_to_the_requested_subtitles() {
+        use crate::models::DownloadRequest;
+        let request = |write_subs, sub_langs: Option<&str>| DownloadRequest {
+            write_live_chat: true,
+            write_subs,
+            sub_langs: sub_langs.map(str::to_string),
+            ..Default::default()
+        };
+        assert_eq!(super::sub_langs(&request(true, Some("de,fr"))).as_deref(), Some("de,fr,live_chat"));
+        assert_eq!(super::sub_langs(&request(true, None)).as_deref(), Some("en,live_chat"));
+        assert_eq!(super::sub_langs(&DownloadRequest { write_auto_subs: true, ..request(false, Some("pt-BR")) }).as_deref(), Some("pt-BR,live_chat"));
+        // Only the chat was asked for.
+        assert_eq!(super::sub_langs(&request(false, Some("de"))).as_deref(), Some("live_chat"));
+        assert_eq!(super::sub_langs(&DownloadRequest { write_live_chat: false, ..request(true, Some("de")) }).as_deref(), Some("de"));
+    }
+
+    #[test]
+    fn truncating_file_names_never_splits_a_character() {
+        let names = [
+            format!("{} [abc].mp4", "日本語のタイトル".repeat(8)),
+            format!("{}.webm", "é".repeat(100)),
+            "🎵".repeat(40),
+            format!("a{} [abc].m4a", "ü🎵".repeat(30)),
+        ];
+        for name in &names {
+            for max_bytes in 1..=name.len() {
+                let truncated = super::truncate_file_name(name, max_bytes, Some("abc"));
+                assert!(truncated.len() <= max_bytes, "{} bytes for a limit of {}: {}", truncated.len(), max_bytes, truncated);
+                // Cut on the character before the limit, never further back.
+                assert!(max_bytes - truncated.len() < 4, "{} is needlessly short for {}", truncated, max_bytes);
+                // Either the start of the title with the id and extension, or the start of the name.
+                let suffix = name.find(" [abc]").or_else(|| name.rfind('.')).map_or("", |at| &name[at..]);
+                let head = truncated.strip_suffix(suffix).filter(|head| !head.is_empty()).unwrap_or(&truncated);
+                assert!(name.starts_with(head), "{} isn't a prefix of {}", head, name);
+                assert!(!truncated.starts_with('.') && !truncated.starts_with(' '), "{}", truncated);
+            }
+        }
+    }
+
+    /// A yt-dlp whose downloads fail with `without_cookies` or `with_cookies` on stderr, depending
+    /// on whether it got `--cookies`, and otherwise work like `FAKE_YTDLP`. Each download run
+    /// is appended to `runs`.
+    fn cookie_gated_ytdlp(runs: &Path, without_cookies: &str, with_cookies: &str) -> String {
+        format!(
+            r#"case "$*" in *--dump-json*|*--flat-playlist*|*"--print %(."*) ;; *)
+    echo "$*" >> '{}'
+    case "$*" in *--cookies*) error='{}' ;; *) error='{}' ;; esac
+    if [ -n "$error" ]; then echo "ERROR: [youtube] abc: $error" >&2; exit 1; fi
+esac
+{}"#,
+            runs.display(),
+            with_cookies,
+            without_cookies,
+            crate::test_support::FAKE_YTDLP
+        )
+    }
+
+    #[tokio::test]
+    async fn retries_an_age_restricted_download_once_with_the_configured_cookies() {
+        const AGE_GATE: &str = "Sign in to confirm your age. This video may be inappropriate for some users.";
+        let cases = [
+            // Retryable: the cookies get it through.
+            ("https://example.com/age-ok", AGE_GATE, "", "completed", None, 2),
+            // Not retryable: only the age gate is worth a second attempt.
+            ("https://example.com/gone", "HTTP Error 404: Not Found", "", "failed", None, 1),
+            // Out of attempts: the cookies don't help either.
+            ("https://example.com/age-still", AGE_GATE, AGE_GATE, "failed", Some("age_restricted"), 2),
+        ];
+        for (key, without_cookies, with_cookies, outcome, error_code, attempts) in cases {
+            let runs = tempfile::NamedTempFile::new().unwrap();
+            let state = state(FakeRunner::new().script("yt-dlp", &cookie_gated_ytdlp(runs.path(), without_cookies, with_cookies)));
+            state.config.write().unwrap().cookies_file = Some("/srv/cookies.txt".to_string());
+            let started = send(&app(&state), Method::POST, "/download", Some(json!({ "url": key, "format_id": "best" }))).await;
+            assert_eq!(started.status, StatusCode::ACCEPTED, "{}", started.body);
+            let status = finished(&state, key).await;
+            assert_eq!(status.status, outcome, "{}: {:?}", key, status.error);
+            assert_eq!(serde_json::to_value(status.error_code).unwrap(), json!(error_code), "{}", key);
+            let runs = std::fs::read_to_string(runs.path()).unwrap();
+            assert_eq!(runs.lines().count(), attempts, "{}: {}", key, runs);
+            assert!(!runs.lines().next().unwrap().contains("--cookies"), "{}", runs);
+            if attempts == 2 {
+                assert!(runs.lines().nth(1).unwrap().contains("--cookies /srv/cookies.txt"), "{}", runs);
+            }
+            if error_code.is_some() {
+                assert!(status.error_hint.unwrap().contains("cookies"));
+            }
+        }
+
+        // Without cookies to try, an age-restricted download fails at once.
+        let runs = tempfile::NamedTempFile::new().unwrap();
+        let state = state(FakeRunner::new().script("yt-dlp", &cookie_gated_ytdlp(runs.path(), AGE_GATE, "")));
+        let key = "https://example.com/age-no-cookies";
+        send(&app(&state), Method::POST, "/download", Some(json!({ "url": key, "format_id": "best" }))).await;
+        let status = finished(&state, key).await;
+        assert_eq!((status.status.as_str(), serde_json::to_value(status.error_code).unwrap()), ("failed", json!("age_restricted")));
+        assert_eq!(std::fs::read_to_string(runs.path()).unwrap().lines().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_fallback_format_only_when_the_format_is_unavailable() {
+        // Format 137 is gone, 22 fails for another reason and everything else downloads.
+        let script = |runs: &Path| {
+            format!(
+                r#"case "$*" in *--dump-json*|*--flat-playlist*|*"--print %(."*) ;; *)
+    echo "$*" >> '{}'
+    case "$*" in
+        *"-f 137 "*) echo "ERROR: [youtube] abc: Requested format is not available. Use --list-formats for a list of available formats" >&2; exit 1 ;;
+        *"-f 22 "*) echo "ERROR: [youtube] abc: HTTP Error 403: Forbidden" >&2; exit 1 ;;
+    esac
+esac
+{}"#,
+                runs.display(),
+                crate::test_support::FAKE_YTDLP
+            )
+        };
+        let cases = [
+            ("https://example.com/fb-used", "137", Some("18"), "completed", Some("18"), 2),
+            ("https://example.com/fb-none", "137", None, "failed", None, 1),
+            ("https://example.com/fb-other-error", "22", Some("18"), "failed", None, 1),
+            ("https://example.com/fb-unavailable-too", "137", Some("137"), "failed", Some("137"), 2),
+        ];
+        for (key, format_id, fallback, outcome, used, attempts) in cases {
+            let runs = tempfile::NamedTempFile::new().unwrap();
+            let state = state(FakeRunner::new().script("yt-dlp", &script(runs.path())));
+            let body = json!({ "url": key, "format_id": format_id, "fallback_format": fallback });
+            let started = send(&app(&state), Method::POST, "/download", Some(body)).await;
+            assert_eq!(started.status, StatusCode::ACCEPTED, "{}", started.body);
+            let status = finished(&state, key).await;
+            assert_eq!(status.status, outcome, "{}: {:?}", key, status.error);
+            assert_eq!(status.fallback_format.as_deref(), used, "{}", key);
+            let runs = std::fs::read_to_string(runs.path()).unwrap();
+            assert_eq!(runs.lines().count(), attempts, "{}: {}", key, runs);
+            if let (2, Some(fallback)) = (attempts, fallback) {
+                assert!(runs.lines().nth(1).unwrap().contains(&format!("-f {} ", fallback)), "{}", runs);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn watches_the_size_of_the_file_being_written() {
+        let state = state(FakeRunner::new());
+        let key = "https://example.com/on-disk";
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("Video [abc].mp4");
+        let part = dir.path().join("Video [abc].mp4.part");
+        let status = crate::models::DownloadStatus {
+            status: "downloading".to_string(),
+            current_file: Some(file.to_string_lossy().to_string()),
+            downloaded_bytes: Some(10),
+            ..Default::default()
+        };
+        state.downloads.lock().insert(key.to_string(), status);
+        let watcher = tokio::spawn(super::watch_bytes_on_disk(state.downloads.clone(), key.to_string(), Duration::from_millis(10)));
+        let on_disk = |expected: u64, mismatch: bool| {
+            let downloads = state.downloads.clone();
+            async move {
+                tokio::time::timeout(Duration::from_secs(5), async {
+                    loop {
+                        if let Some(status) = downloads.lock().get(key).filter(|s| s.bytes_on_disk == Some(expected)) {
+                            assert_eq!(status.progress_mismatch, mismatch, "at {} bytes", expected);
+                            return;
+                        }
+                        tokio::time::sleep(Duration::from_millis(5)).await;
+                    }
+                })
+                .await
+                .unwrap_or_else(|_| panic!("bytes_on_disk never became {}", expected));
+            }
+        };
+
+        std::fs::write(&part, [0; 12]).unwrap();
+        on_disk(12, false).await;
+        // A parsed count far from the file's size points at the wrong stream.
+        state.downloads.lock().get_mut(key).unwrap().downloaded_bytes = Some(100 * 1024 * 1024);
+        std::fs::write(&part, [0; 16]).unwrap();
+        on_disk(16, true).await;
+        // Renamed when done: the final name is checked too.
+        state.downloads.lock().get_mut(key).unwrap().downloaded_bytes = Some(20);
+        std::fs::remove_file(&part).unwrap();
+        std::fs::write(&file, [0; 20]).unwrap();
+        on_disk(20, false).await;
+        // Neither name exists for a moment, e.g. while merging: the last size stays.
+        std::fs::remove_file(&file).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(state.downloads.lock()[key].bytes_on_disk, Some(20));
+        assert!(!watcher.is_finished());
+
+        state.downloads.lock().get_mut(key).unwrap().status = "completed".to_string();
+        tokio::time::timeout(Duration::from_secs(5), watcher).await.unwrap().unwrap();
+    }
+
+    /// A yt-dlp that downloads a five-item playlist, or the `--playlist-items` given, failing
+    /// items 2 and 4 and carrying on past them as yt-dlp does by default.
+    const FLAKY_PLAYLIST_YTDLP: &str = r#"
+for arg; do
+    case "$prev" in -o) template=$arg ;; --playlist-items) items=$arg ;; esac
+    case "$prev2" in --print-to-file) capture=$arg ;; esac
+    prev2=$prev; prev=$arg
+done
+case "$*" in
+    *--flat-playlist*) echo NA; exit 0 ;;
+    *--dump-json*) echo "$VIDEO_JSON"; exit 0 ;;
+    *"--print %(."*) exit 0 ;;
+esac
+dir=$(dirname "$template")
+mkdir -p "$dir"
+set -- $(echo "${items:-1,2,3,4,5}" | tr ',' ' ')
+total=$#; position=0; failed=0
+for index; do
+    position=$((position + 1))
+    echo "[download] Downloading item $position of $total"
+    case "$index" in
+        2|4) echo "ERROR: [fake] v$index: Video unavailable" >&2; failed=1; continue ;;
+    esac
+    file="$dir/v$index.mp4"
+    echo "[download] Destination: $file"
+    printf 'fake' > "$file"
+    printf '{"id": "v%s", "playlist_index": %s, "filepath": "%s"}\n' "$index" "$index" "$file" >> "$capture"
+done
+exit $failed
+"#;
+
+    #[tokio::test]
+    async fn retries_exactly_the_playlist_items_that_failed() {
+        let _files = lock_files().await;
+        let state = state(FakeRunner::new().script("yt-dlp", FLAKY_PLAYLIST_YTDLP));
+        let app = app(&state);
+        let url = "https://example.com/playlist?list=flaky";
+        let started = send(&app, Method::POST, "/download", Some(json!({ "url": url, "format_id": "best" }))).await;
+        assert_eq!(started.status, StatusCode::ACCEPTED, "{}", started.body);
+
+        let status = finished(&state, url).await;
+        assert_eq!(status.status, "completed_partially");
+        assert_eq!((status.items_total, status.items_completed, status.items_failed), (Some(5), Some(3), Some(2)));
+        let failed_item = status.failed_item.unwrap();
+        assert_eq!(failed_item.playlist_index, Some(2));
+        assert_eq!(failed_item.error, "ERROR: [fake] v2: Video unavailable");
+        assert_eq!(status.retry_playlist_items.as_deref(), Some("2,4"));
+        assert_eq!(status.files.len(), 3);
+
+        let retried = send(&app, Method::POST, &format!("/download/{}/retry?only_failed=true", encode(url)), None).await;
+        assert_eq!(retried.status, StatusCode::ACCEPTED, "{}", retried.body);
+        let status = finished(&state, url).await;
+        assert!(status.command.unwrap().contains("--playlist-items 2,4"));
+        assert_eq!(status.status, "failed");
+        assert_eq!((status.items_total, status.items_failed), (Some(2), Some(2)));
+        assert_eq!(status.retry_playlist_items.as_deref(), Some("2,4"));
+    }
+
+    #[test]
+    fn caps_the_height_of_every_format_in_a_selector() {
+        for (selector, limited) in [
+            ("best", "best[height<=?720]"),
+            ("bv+ba/b", "bv[height<=?720]+ba[height<=?720]/b[height<=?720]"),
+            ("bv*[ext=mp4] + ba / b", "bv*[ext=mp4][height<=?720] + ba[height<=?720] / b[height<=?720]"),
+            ("(bv*+ba/b)[ext=mp4]", "(bv*[height<=?720]+ba[height<=?720]/b[height<=?720])[ext=mp4]"),
+            ("(137/136)+140,sb0", "(137[height<=?720]/136[height<=?720])+140[height<=?720],sb0[height<=?720]"),
+            // One format having the filter doesn't stop the others from getting it.
+            ("bv[height<=?720]+ba/b", "bv[height<=?720]+ba[height<=?720]/b[height<=?720]"),
+            ("bv[height<=?1080]/b", "bv[height<=?1080][height<=?720]/b[height<=?720]"),
+            // What can't be taken apart is capped as a whole.
+            ("bv[format_note*='a/b']+ba", "(bv[format_note*='a/b']+ba)[height<=?720]"),
+            ("(bv+ba", "((bv+ba)[height<=?720]"),
+            ("bv]+ba", "(bv]+ba)[height<=?720]"),
+        ] {
+            assert_eq!(super::limit_selector_height(selector, 720), limited, "{}", selector);
+            assert_eq!(super::limit_selector_height(limited, 720), limited, "{} again", selector);
+        }
+    }
+
+    #[test]
+    fn works_out_the_unfinished_items() {
+        // Stopped at the third item without a capture: that one and those after it.
+        let unfinished = super::unfinished_items(None, 3, 5, None, &[]);
+        assert_eq!((unfinished.count, unfinished.indices), (3, Some(vec![3, 4, 5])));
+        // Items the archive had are neither done again nor counted as failed.
+        let unfinished = super::unfinished_items(Some("10-15"), 6, 6, Some(&[10, 13, 15]), &[2]);
+        assert_eq!((unfinished.count, unfinished.indices), (2, Some(vec![12, 14])));
+        // Selections that can't be mapped to indices still give the count.
+        let unfinished = super::unfinished_items(Some("::2"), 4, 4, Some(&[1]), &[]);
+        assert_eq!((unfinished.count, unfinished.indices), (3, None));
+    }
+
+    #[test]
+    fn maps_positions_to_playlist_indices() {
+        assert_eq!(super::playlist_indices(Some("3,7-9"), 2..=4), Some(vec![7, 8, 9]));
+        // yt-dlp slices include their stop and may step.
+        assert_eq!(super::playlist_indices(Some("1:3,10:20:5"), 1..=6), Some(vec![1, 2, 3, 10, 15, 20]));
+        // A huge range is only read as far as the positions go.
+        assert_eq!(super::playlist_indices(Some("1-18446744073709551615"), 2..=3), Some(vec![2, 3]));
+        assert_eq!(super::playlist_indices(Some("5:18446744073709551615:2"), 1..=2), Some(vec![5, 7]));
+        // Open-ended, negative and zero-step selections can't be mapped.
+        for spec in ["::2", "2:", "-5", "1:9:0", "1-inf"] {
+            assert_eq!(super::playlist_indices(Some(spec), 1..=2), None, "{}", spec);
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_output_templates_inside_the_primary_root_without_download_root() {
+        let state = state(fake_ytdlp());
+        let app = app(&state);
+        let download = |template: &str| {
+            send(&app, Method::POST, "/download", Some(json!({ "url": "https://example.com/t1", "format_id": "best", "output_template": template })))
+        };
+        assert_eq!(download("/etc/%(id)s.%(ext)s").await.status, StatusCode::FORBIDDEN);
+        assert_eq!(download("../%(id)s.%(ext)s").await.status, StatusCode::BAD_REQUEST);
+        assert!(state.downloads.lock().is_empty());
+
+        assert_eq!(download("videos/%(id)s.%(ext)s").await.status, StatusCode::ACCEPTED);
+        finished(&state, "https://example.com/t1").await;
+        let root = state.config.read().unwrap().download_directory.clone();
+        assert!(Path::new(&root).join("videos").is_dir());
+    }
+
+    #[tokio::test]
+    async fn only_keys_with_allow_express_bypass_the_queue() {
+        let state = state(fake_ytdlp());
+        {
+            let mut config = state.config.write().unwrap();
+            config.api_keys.insert("alice".to_string(), ApiKey::user("alice-secret"));
+            config.api_keys.insert("bot".to_string(), ApiKey { allow_express: true, ..ApiKey::user("bot-secret") });
+            config.api_keys.insert("ops".to_string(), ApiKey::admin("ops-secret"));
+        }
+        let app = app(&state);
+        let express = |url: &str, secret: &str| {
+            let request = Request::builder().method(Method::POST).uri("/download").header("x-api-key", secret);
+            send_with(&app, request, Some(json!({ "url": url, "format_id": "best", "bypass_queue": true })))
+        };
+        for secret in ["alice-secret", "ops-secret"] {
+            let refused = express("https://example.com/e1", secret).await;
+            assert_eq!(refused.status, StatusCode::FORBIDDEN, "{}", refused.body);
+        }
+        assert!(state.downloads.lock().is_empty());
+
+        assert_eq!(express("https://example.com/e1", "bot-secret").await.status, StatusCode::ACCEPTED);
+        let status = finished(&state, "https://example.com/e1").await;
+        assert!(status.express);
+        assert_eq!(status.origin.unwrap().api_key.as_deref(), Some("bot"));
+    }
+
+    #[tokio::test]
+    async fn counts_downloads_and_bytes_against_the_api_key_quota() {
+        let _files = lock_files().await;
+        let state = state(fake_ytdlp());
+        {
+            let mut config = state.config.write().unwrap();
+            config.api_keys.insert("alice".to_string(), ApiKey::user("alice-secret"));
+            config.api_keys.insert("bob".to_string(), ApiKey::user("bob-secret"));
+            config.api_keys.insert("ops".to_string(), ApiKey::admin("ops-secret"));
+            config.max_downloads_per_day = Some(2);
+        }
+        let app = app(&state);
+        let download = |url: &str, secret: Option<&str>| {
+            let mut request = Request::builder().method(Method::POST).uri("/download");
+            if let Some(secret) = secret {
+                request = request.header("x-api-key", secret);
+            }
+            send_with(&app, request, Some(json!({ "url": url, "format_id": "best" })))
+        };
+        assert_eq!(download("https://example.com/q0", None).await.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(download("https://example.com/q0", Some("wrong")).await.status, StatusCode::UNAUTHORIZED);
+
+        // A request that fails validation doesn't use up the quota.
+        let express = json!({ "url": "https://example.com/q1", "format_id": "best", "bypass_queue": true });
+        let request = Request::builder().method(Method::POST).uri("/download").header("x-api-key", "alice-secret");
+        assert_eq!(send_with(&app, request, Some(express)).await.status, StatusCode::FORBIDDEN);
+        assert_eq!(download("https://example.com/q1", Some("alice-secret")).await.status, StatusCode::ACCEPTED);
+        finished(&state, "https://example.com/q1").await;
+        assert_eq!(download("https://example.com/q2", Some("alice-secret")).await.status, StatusCode::ACCEPTED);
+        finished(&state, "https://example.com/q2").await;
+
+        let over = download("https://example.com/q3", Some("alice-secret")).await;
+        assert_eq!(over.status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(over.body["quota"]["remaining_downloads"], 0);
+        assert_eq!(over.headers["x-quota-remaining-downloads"], "0");
+        assert!(over.headers.contains_key("retry-after") && over.headers.contains_key("x-quota-reset"));
+        assert_eq!(over.body["quota"]["resets_at"].as_str(), over.headers["x-quota-reset"].to_str().ok());
+        // Other keys have their own quota.
+        assert_eq!(download("https://example.com/q3", Some("bob-secret")).await.status, StatusCode::ACCEPTED);
+        finished(&state, "https://example.com/q3").await;
+
+        let own = send_with(&app, Request::builder().uri("/quota").header("x-api-key", "alice-secret"), None).await;
+        assert_eq!(own.body["key"], "alice");
+        assert_eq!(own.body["downloads"], 2);
+        assert!(own.body["bytes"].as_u64().unwrap() > 0, "{}", own.body);
+        let all = send_with(&app, Request::builder().uri("/admin/quota").header("x-api-key", "ops-secret"), None).await;
+        let keys: Vec<_> = all.body["keys"].as_array().unwrap().iter().map(|quota| (quota["key"].clone(), quota["downloads"].clone())).collect();
+        assert_eq!(keys, [(json!("alice"), json!(2)), (json!("bob"), json!(1)), (json!("ops"), json!(0))]);
+
+        // Bytes count too: a key past max_bytes_per_day is refused even with downloads left.
+        {
+            let mut config = state.config.write().unwrap();
+            config.max_downloads_per_day = None;
+            config.max_bytes_per_day = Some(1);
+        }
+        let over = download("https://example.com/q4", Some("bob-secret")).await;
+        assert_eq!(over.status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(over.headers["x-quota-remaining-bytes"], "0");
+    }
+
+    /// A yt-dlp that prints a line, waits for `gate` to exist, then prints another and exits.
+    fn gated_ytdlp(gate: &Path) -> FakeRunner {
+        let script = format!(
+            r#"case "$*" in *--flat-playlist*) echo NA; exit 0 ;; *"--print %(."*) exit 0 ;; esac
+echo "[info] first line"
+while [ ! -e '{}' ]; do sleep 0.02; done
+echo "[info] second line""#,
+            gate.display()
+        );
+        FakeRunner::new().script("yt-dlp", &script)
+    }
+
+    #[tokio::test]
+    async fn queue_positions_count_down_as_the_queue_drains() {
+        // Each download runs until a file named after the last part of its URL appears.
+        let gates = tempfile::tempdir().unwrap();
+        let script = format!(
+            r#"case "$*" in *--flat-playlist*) echo NA; exit 0 ;; *"--print %(."*) exit 0 ;; esac
+for arg; do url=$arg; done
+echo "[info] waiting for the gate"
+while [ ! -e '{}/'"${{url##*/}}" ]; do sleep 0.02; done"#,
+            gates.path().display()
+        );
+        let state = state(FakeRunner::new().script("yt-dlp", &script));
+        state.config.write().unwrap().max_concurrent_downloads = Some(1);
+        let app = app(&state);
+        let keys: Vec<String> = (0..5).map(|i| format!("https://example.com/queue/{}", i)).collect();
+        for (i, key) in keys.iter().enumerate() {
+            let started = send(&app, Method::POST, "/download", Some(json!({ "url": key, "format_id": "best" }))).await;
+            assert_eq!(started.status, StatusCode::ACCEPTED, "{}", started.body);
+            if i == 0 {
+                assert!(started.body["queue_position"].is_null());
+                wait_for_live_log(&state, key).await;
+            } else {
+                assert_eq!(started.body["queue_position"], i, "{}", started.body);
+                // Nothing has finished yet, so there's no rate to estimate from.
+                assert!(started.body["estimated_start_at"].is_null(), "{}", started.body);
+                // The response counts the downloads already waiting for a slot; wait until this
+                // one is, so the next is told its place behind it.
+                wait_for_position(&state, key, i).await;
+            }
+        }
+
+        // Let the downloads finish one by one, noting every position the statuses show.
+        let mut seen: Vec<Vec<usize>> = vec![Vec::new(); keys.len()];
+        for (i, key) in keys.iter().enumerate() {
+            std::fs::write(gates.path().join(i.to_string()), "").unwrap();
+            let finishing = tokio::spawn({
+                let (state, key) = (state.clone(), key.clone());
+                async move { finished(&state, &key).await }
+            });
+            while !finishing.is_finished() {
+                for (index, key) in keys.iter().enumerate() {
+                    let position = state.downloads.lock().get(key).and_then(|status| status.queue_position);
+                    if let Some(position) = position.filter(|position| seen[index].last() != Some(position)) {
+                        seen[index].push(position);
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(2)).await;
+            }
+            finishing.await.unwrap();
+            // Whoever is still waiting has moved up by one, with a start time now that a
+            // download finished.
+            for (index, waiting) in keys.iter().enumerate().skip(i + 2) {
+                wait_for_position(&state, waiting, index - i - 1).await;
+                assert!(state.downloads.lock()[waiting].estimated_start_at.is_some(), "{}", waiting);
+            }
+        }
+        for (index, positions) in seen.iter().enumerate() {
+            assert!(positions.windows(2).all(|pair| pair[1] < pair[0]), "{} went {:?}", keys[index], positions);
+            assert!(state.downloads.lock()[&keys[index]].queue_position.is_none());
+        }
+    }
+
+    /// Waits until the download's status shows it at `position` in the queue.
+    async fn wait_for_position(state: &crate::AppState, key: &str, position: usize) {
+        for _ in 0..500 {
+            if state.downloads.lock()[key].queue_position == Some(position) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("{} never reached position {}: {:?}", key, position, state.downloads.lock()[key].queue_position);
+    }
+
+    /// Waits until the download's live log is open.
+    async fn wait_for_live_log(state: &crate::AppState, key: &str) {
+        for _ in 0..500 {
+            if state.live_logs.follow(key).is_some_and(|(backlog, _)| !backlog.is_empty()) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("{} never started logging: {:?}", key, state.downloads.lock().get(key));
+    }
+
+    /// `yt-dlp --flat-playlist --print %(playlist_count)s` for a playlist of 150 videos: the
+    /// count once per item.
+    const PLAYLIST_OF_150: &str = "i=0; while [ $i -lt 150 ]; do echo 150; i=$((i + 1)); done";
+
+    #[tokio::test]
+    async fn probes_only_urls_that_look_like_playlists() {
+        let probes = tempfile::NamedTempFile::new().unwrap();
+        let script = format!(
+            r#"case "$*" in *--flat-playlist*) echo "$*" >> '{}'; {}; exit 0 ;; esac
+{}"#,
+            probes.path().display(),
+            PLAYLIST_OF_150,
+            crate::test_support::FAKE_YTDLP
+        );
+        let state = state(FakeRunner::new().script("yt-dlp", &script));
+        let app = app(&state);
+
+        let playlist = "https://example.com/playlist?list=PL150";
+        let refused = send(&app, Method::POST, "/download", Some(json!({ "url": playlist, "format_id": "best" }))).await;
+        assert_eq!(refused.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(refused.body["error_code"], "playlist_too_long");
+        assert_eq!(refused.body["playlist_count"], 150);
+
+        let video = "https://example.com/watch?v=single-video";
+        let started = send(&app, Method::POST, "/download", Some(json!({ "url": video, "format_id": "best" }))).await;
+        assert_eq!(started.status, StatusCode::ACCEPTED);
+        assert_eq!(finished(&state, video).await.status, "completed");
+        let probed = std::fs::read_to_string(probes.path()).unwrap();
+        assert_eq!(probed.lines().count(), 1, "{}", probed);
+        assert!(probed.contains(playlist));
+    }
+
+    #[test]
+    fn tells_playlists_from_single_videos() {
+        for url in [
+            "https://www.youtube.com/playlist?list=PL123",
+            "https://www.youtube.com/watch?v=abc&list=PL123",
+            "https://www.youtube.com/@someone",
+            "https://www.youtube.com/channel/UC123/videos",
+            "https://soundcloud.com/artist/sets/mix",
+            "ytsearch10:cats",
+        ] {
+            assert!(super::looks_like_playlist(url), "{}", url);
+        }
+        for url in ["https://www.youtube.com/watch?v=abc", "https://youtu.be/abc", "https://vimeo.com/12345"] {
+            assert!(!super::looks_like_playlist(url), "{}", url);
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_template_suffixes_that_leave_the_file_name() {
+        let state = state(fake_ytdlp());
+        let app = app(&state);
+        for suffix in ["/../../../../tmp/x", "..", "\\x", "/x"] {
+            let formats = json!([{ "format_id": "137" }, { "format_id": "140", "template_suffix": suffix }]);
+            let refused = send(&app, Method::POST, "/download", Some(json!({ "url": "https://example.com/s1", "formats": formats }))).await;
+            assert_eq!(refused.status, StatusCode::BAD_REQUEST, "{}", suffix);
+            let outputs = json!([{ "format_id": "137", "template_suffix": suffix }]);
+            let refused = send(&app, Method::POST, "/download", Some(json!({ "url": "https://example.com/s1", "outputs": outputs }))).await;
+            assert_eq!(refused.status, StatusCode::BAD_REQUEST, "{}", suffix);
+        }
+        assert!(state.downloads.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancelling_a_running_group_cancels_its_children() {
+        let script = r#"case "$*" in
+    *--flat-playlist*) echo NA; exit 0 ;;
+    *--dump-single-json*) echo "$VIDEO_JSON"; exit 0 ;;
+    *"--print %(."*) exit 0 ;;
+esac
+echo "[download]  10.0% of 4.00KiB at 1.00KiB/s ETA 00:04"
+sleep 60"#;
+        let state = state(FakeRunner::new().script("yt-dlp", script));
+        let app = app(&state);
+        let key = "https://example.com/cancel-group";
+        let formats = json!([{ "format_id": "137" }, { "format_id": "140", "template_suffix": ".audio" }]);
+        let started = send(&app, Method::POST, "/download", Some(json!({ "url": key, "formats": formats }))).await;
+        assert_eq!(started.status, StatusCode::ACCEPTED, "{}", started.body);
+        let children = [format!("{}#1", key), format!("{}#2", key)];
+        for _ in 0..500 {
+            let downloading = {
+                let map = state.downloads.lock();
+                children.iter().all(|child| map[child].status == "downloading")
+            };
+            if downloading {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let cancelled = send(&app, Method::POST, &format!("/download/{}/cancel", encode(key)), None).await;
+        assert_eq!(cancelled.status, StatusCode::OK, "{}", cancelled.body);
+        // The tasks forget their cancellation tokens once yt-dlp is gone and they've settled.
+        for _ in 0..500 {
+            if state.cancels.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(state.cancels.lock().unwrap().is_empty(), "the group is still running");
+        assert!(state.processes.lock().unwrap().is_empty());
+        for key in children.iter().map(String::as_str).chain([key]) {
+            let status = state.downloads.lock()[key].clone();
+            assert_eq!(status.status, "cancelled", "{}: {:?}", key, status.error);
+        }
+
+        let again = send(&app, Method::POST, &format!("/download/{}/cancel", encode(key)), None).await;
+        assert_eq!(again.status, StatusCode::CONFLICT);
+        let unknown = send(&app, Method::POST, "/download/https%3A%2F%2Fexample.com%2Fnope/cancel", None).await;
+        assert_eq!(unknown.status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn every_download_shares_one_archive_and_a_group_records_it_once() {
+        let _files = lock_files().await;
+        let runs = tempfile::NamedTempFile::new().unwrap();
+        // Skips a video in the archive, as yt-dlp does, and records the ones it downloads.
+        let script = format!(
+            r#"for arg; do
+    case "$prev" in -o) template=$arg ;; --download-archive) archive=$arg ;; esac
+    case "$prev2" in --print-to-file) capture=$arg ;; esac
+    prev2=$prev; prev=$arg; url=$arg
+done
+echo "$*" >> '{runs}'
+case "$*" in
+    *--flat-playlist*) echo NA; exit 0 ;;
+    *"--print %(."*) exit 0 ;;
+esac
+if [ -n "$archive" ] && grep -qx 'example abc' "$archive"; then
+    case "$*" in *--dump-single-json*) ;; *) echo "[download] abc: has already been recorded in the archive" ;; esac
+    exit 0
+fi
+case "$*" in *--dump-single-json*) echo '{{"id": "abc", "extractor_key": "Example", "title": "Video"}}'; exit 0 ;; esac
+file=$(printf '%s' "$template" | sed -e 's/%(title)\(s\|\.[0-9]*B\)/Video/g' -e 's/%(id)s/abc/g' -e 's/%(ext)s/mp4/g')
+echo "[download] Destination: $file"
+printf 'fake' > "$file"
+printf '{{"id": "abc", "extractor_key": "Example", "title": "Video", "webpage_url": "%s", "filepath": "%s"}}\n' "$url" "$file" >> "$capture"
+if [ -n "$archive" ]; then echo 'example abc' >> "$archive"; fi"#,
+            runs = runs.path().display()
+        );
+        let state = state(FakeRunner::new().script("yt-dlp", &script));
+        state.config.write().unwrap().use_download_archive = true;
+        let archive = crate::archive::path().unwrap();
+        let _ = std::fs::remove_file(&archive);
+        let app = app(&state);
+        let key = "https://example.com/archived-group";
+        let group = json!({ "url": key, "formats": [{ "format_id": "137" }, { "format_id": "140", "template_suffix": ".audio" }] });
+        let runs_of = |needle: &str| std::fs::read_to_string(runs.path()).unwrap().lines().filter(|run| run.contains(needle)).count();
+
+        let started = send(&app, Method::POST, "/download", Some(group.clone())).await;
+        assert_eq!(started.status, StatusCode::ACCEPTED, "{}", started.body);
+        let status = finished(&state, key).await;
+        assert_eq!(status.status, "completed", "{:?}", status.error);
+        assert_eq!(status.files.len(), 2);
+        // The children preview and download without the archive, or the second would skip the
+        // video; the group checks it once while extracting and records the video once.
+        assert_eq!(runs_of("--load-info-json"), 4);
+        assert_eq!(runs_of("--download-archive"), 1);
+        assert_eq!(std::fs::read_to_string(&archive).unwrap(), "example abc\n");
+
+        let again = send(&app, Method::POST, "/download", Some(group)).await;
+        assert_eq!(again.status, StatusCode::ACCEPTED, "{}", again.body);
+        let status = finished(&state, key).await;
+        assert_eq!(status.status, "completed_no_output");
+        assert!(status.note.unwrap().contains("download archive"));
+        assert_eq!(state.downloads.lock()[&format!("{}#1", key)].status, "completed_no_output");
+        assert_eq!(runs_of("--load-info-json"), 4, "an archived group downloads nothing");
+
+        // Any other download, whichever key or session starts it, skips the video too.
+        state.config.write().unwrap().api_keys.insert("other".to_string(), ApiKey::user("other-secret"));
+        let single = "https://example.com/archived-single";
+        let request = Request::builder().method(Method::POST).uri("/download").header("x-api-key", "other-secret");
+        let started = send_with(&app, request, Some(json!({ "url": single, "format_id": "best" }))).await;
+        assert_eq!(started.status, StatusCode::ACCEPTED, "{}", started.body);
+        assert_eq!(finished(&state, single).await.status, "completed_no_output");
+        assert_eq!(std::fs::read_to_string(&archive).unwrap(), "example abc\n");
+        std::fs::remove_file(&archive).unwrap();
+    }
+
+    #[tokio::test]
+    async fn outputs_of_the_same_streams_share_a_download() {
+        let runs = tempfile::NamedTempFile::new().unwrap();
+        let script = format!(
+            r#"for arg; do
+    case "$prev" in -o) template=$arg ;; --audio-format) audio_format=$arg ;; esac
+    prev=$arg
+done
+case "$*" in
+    *--flat-playlist*) echo NA; exit 0 ;;
+    *--dump-single-json*) echo "$VIDEO_JSON"; exit 0 ;;
+    *"--print %(."*) exit 0 ;;
+esac
+echo "$*" >> '{}'
+file=$(printf '%s' "$template" | sed -e 's/%(title)\(s\|\.[0-9]*B\)/Video/g' -e 's/%(id)s/abc/g' -e 's/%(ext)s/mp4/g')
+echo "[download] Destination: $file"
+printf 'fake' > "$file"
+case "$*" in *--extract-audio*)
+    audio="${{file%.mp4}}.$audio_format"
+    echo "[ExtractAudio] Destination: $audio"
+    printf 'fake' > "$audio"
+    case "$*" in *--keep-video*) ;; *) rm "$file" ;; esac
+esac"#,
+            runs.path().display()
+        );
+        let state = state(FakeRunner::new().script("yt-dlp", &script));
+        let app = app(&state);
+        let key = "https://example.com/outputs";
+        let outputs = json!([
+            { "format_id": "137+140" },
+            { "format_id": "137+140", "extract_audio": true, "audio_format": "mp3" },
+            { "format_id": "140", "extract_audio": true, "audio_format": "opus", "template_suffix": ".small" },
+        ]);
+        let started = send(&app, Method::POST, "/download", Some(json!({ "url": key, "outputs": outputs }))).await;
+        assert_eq!(started.status, StatusCode::ACCEPTED, "{}", started.body);
+        let status = finished(&state, key).await;
+        assert_eq!(status.status, "completed", "{:?}", status.error);
+
+        let runs = std::fs::read_to_string(runs.path()).unwrap();
+        assert_eq!(runs.lines().count(), 2, "{}", runs);
+        assert_eq!(runs.lines().filter(|run| run.contains("--keep-video")).count(), 1, "{}", runs);
+        let outputs = &status.outputs;
+        assert_eq!(outputs[0].download_key, format!("{}#1", key));
+        assert_eq!(outputs[1].download_key, outputs[0].download_key);
+        assert_eq!(outputs[2].download_key, format!("{}#2", key));
+        assert!(outputs.iter().all(|output| output.status == "completed"));
+        let names = |files: &[String]| files.iter().map(|f| Path::new(f).file_name().unwrap().to_string_lossy().to_string()).collect::<Vec<_>>();
+        assert_eq!(names(&outputs[0].files), ["Video [abc].mp4"]);
+        assert_eq!(names(&outputs[1].files), ["Video [abc].mp3"]);
+        assert_eq!(names(&outputs[2].files), ["Video [abc].small.opus"]);
+
+        let both = json!({ "url": "https://example.com/both", "formats": [{ "format_id": "140" }], "outputs": [{ "format_id": "140" }] });
+        assert_eq!(send(&app, Method::POST, "/download", Some(both)).await.status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn shared_audio_downloads_split_their_files_by_what_wrote_them() {
+        let script = r#"for arg; do
+    case "$prev" in -o) template=$arg ;; --audio-format) audio_format=$arg ;; esac
+    prev=$arg
+done
+case "$*" in
+    *--flat-playlist*) echo NA; exit 0 ;;
+    *--dump-single-json*) echo "$VIDEO_JSON"; exit 0 ;;
+    *"--print %(."*) exit 0 ;;
+esac
+file=$(printf '%s' "$template" | sed -e 's/%(title)\(s\|\.[0-9]*B\)/Video/g' -e 's/%(id)s/abc/g' -e 's/%(ext)s/webm/g')
+echo "[download] Destination: $file"
+printf 'fake' > "$file"
+case "$*" in *--extract-audio*)
+    if [ "${audio_format:-best}" = best ]; then
+        echo "[ExtractAudio] Not converting audio $file; file is already in target format webm"
+    else
+        audio="${file%.webm}.$audio_format"
+        echo "[ExtractAudio] Destination: $audio"
+        printf 'fake' > "$audio"
+    fi
+esac"#;
+        let state = state(FakeRunner::new().script("yt-dlp", script));
+        let app = app(&state);
+        let names = |files: &[String]| files.iter().map(|f| Path::new(f).file_name().unwrap().to_string_lossy().to_string()).collect::<Vec<_>>();
+
+        let converted = "https://example.com/converted-audio";
+        let outputs = json!([
+            { "format_id": "bestaudio" },
+            { "format_id": "bestaudio", "extract_audio": true, "audio_format": "mp3" },
+        ]);
+        let started = send(&app, Method::POST, "/download", Some(json!({ "url": converted, "outputs": outputs }))).await;
+        assert_eq!(started.status, StatusCode::ACCEPTED, "{}", started.body);
+        let status = finished(&state, converted).await;
+        assert_eq!(status.status, "completed", "{:?}", status.error);
+        assert_eq!(status.outputs[1].download_key, status.outputs[0].download_key);
+        assert_eq!(names(&status.outputs[0].files), ["Video [abc].webm"]);
+        assert_eq!(names(&status.outputs[1].files), ["Video [abc].mp3"]);
+
+        let kept = "https://example.com/kept-audio";
+        let outputs = json!([
+            { "format_id": "bestaudio" },
+            { "format_id": "bestaudio", "extract_audio": true },
+        ]);
+        let started = send(&app, Method::POST, "/download", Some(json!({ "url": kept, "outputs": outputs }))).await;
+        assert_eq!(started.status, StatusCode::ACCEPTED, "{}", started.body);
+        let status = finished(&state, kept).await;
+        assert_eq!(status.status, "completed", "{:?}", status.error);
+        assert_eq!(status.outputs[1].download_key, status.outputs[0].download_key);
+        assert_eq!(names(&status.outputs[0].files), ["Video [abc].webm"]);
+        assert_eq!(names(&status.outputs[1].files), ["Video [abc].webm"]);
+    }
+
+    #[tokio::test]
+    async fn only_host_failures_open_the_circuit() {
+        let script = r#"case "$*" in
+    *--flat-playlist*) echo NA; exit 0 ;;
+    *--dump-single-json*) echo "$VIDEO_JSON"; exit 0 ;;
+    *"--print %(."*) exit 0 ;;
+    *gone.example.com*) echo "ERROR: [generic] Unable to download webpage: HTTP Error 404: Not Found" >&2 ;;
+    *busy.example.com*) echo "ERROR: [generic] Unable to download webpage: HTTP Error 503: Service Unavailable" >&2 ;;
+esac
+exit 1"#;
+        let state = state(FakeRunner::new().script("yt-dlp", script));
+        state.config.write().unwrap().circuit_breaker_threshold = Some(2);
+        let app = app(&state);
+        let download = |url: String| json!({ "url": url, "format_id": "best" });
+
+        for (host, failures) in [("gone.example.com", 3), ("busy.example.com", 2)] {
+            for attempt in 0..failures {
+                let url = format!("https://{}/{}", host, attempt);
+                let started = send(&app, Method::POST, "/download", Some(download(url.clone()))).await;
+                assert_eq!(started.status, StatusCode::ACCEPTED, "{}", started.body);
+                assert_eq!(finished(&state, &url).await.status, "failed");
+            }
+        }
+        let gone = send(&app, Method::POST, "/download", Some(download("https://gone.example.com/3".to_string()))).await;
+        assert_eq!(gone.status, StatusCode::ACCEPTED, "a missing video says nothing about the host");
+        finished(&state, "https://gone.example.com/3").await;
+        let busy = send(&app, Method::POST, "/download", Some(download("https://busy.example.com/2".to_string()))).await;
+        assert_eq!(busy.status, StatusCode::SERVICE_UNAVAILABLE, "{}", busy.body);
+        assert_eq!(busy.body["error_code"], "circuit_open");
+        assert_eq!(busy.body["host"], "busy.example.com");
+    }
+
+    #[tokio::test]
+    async fn imports_an_uploaded_url_list() {
+        let state = state(fake_ytdlp());
+        state.config.write().unwrap().allow_uploads = true;
+        let app = app(&state);
+        let list = "# weekend\nhttps://example.com/import-1\n\nhttps://example.com/import-2\nhttps://example.com/import-1\n";
+        let created = send(&app, Method::POST, "/uploads", Some(json!({ "size": list.len() }))).await;
+        let id = created.body["upload_id"].as_str().unwrap().to_string();
+        let chunk = Request::builder()
+            .method(Method::PUT)
+            .uri(format!("/uploads/{}", id))
+            .header("content-range", format!("bytes 0-{}/{}", list.len() - 1, list.len()))
+            .body(axum::body::Body::from(list))
+            .unwrap();
+        assert_eq!(tower::ServiceExt::oneshot(app.clone(), chunk).await.unwrap().status(), StatusCode::OK);
+
+        let import = json!({ "upload_id": id, "request": { "format_id": "best" } });
+        let imported = send(&app, Method::POST, "/download/import", Some(import)).await;
+        assert_eq!(imported.status, StatusCode::ACCEPTED, "{}", imported.body);
+        assert_eq!(imported.body["started"], json!(["https://example.com/import-1", "https://example.com/import-2"]));
+        for key in ["https://example.com/import-1", "https://example.com/import-2"] {
+            let status = finished(&state, key).await;
+            assert_eq!(status.status, "completed", "{:?}", status.error);
+            assert_eq!(status.origin.unwrap().endpoint, "POST /download/import");
+        }
+
+        let bad_options = json!({ "urls": "https://example.com/import-3", "request": { "format_id": 5 } });
+        assert_eq!(send(&app, Method::POST, "/download/import", Some(bad_options)).await.status, StatusCode::BAD_REQUEST);
+        let missing_format = json!({ "urls": "https://example.com/import-3" });
+        let partial = send(&app, Method::POST, "/download/import", Some(missing_format)).await;
+        assert_eq!(partial.body["failed"][0]["url"], "https://example.com/import-3");
+    }
+
+    #[tokio::test]
+    async fn downloads_and_retries() {
+        let state = state(fake_ytdlp());
+        let app = app(&state);
+        let key = "https://example.com/download-route";
+        let started = send(&app, Method::POST, "/download", Some(json!({ "url": key, "format_id": "137+140" }))).await;
+        assert_eq!(started.status, StatusCode::ACCEPTED);
+        assert_eq!(started.body["download_key"], key);
+
+        let status = finished(&state, key).await;
+        assert_eq!(status.status, "completed", "{:?}", status.error);
+        assert!(status.files[0].ends_with("Video [abc].mp4"));
+        assert_eq!(std::fs::read(&status.files[0]).unwrap(), b"fake");
+        assert_eq!(status.origin.unwrap().endpoint, "POST /download");
+
+        let retried = send(&app, Method::POST, &format!("/download/{}/retry", encode(key)), None).await;
+        assert_eq!(retried.status, StatusCode::ACCEPTED);
+        assert_eq!(finished(&state, key).await.status, "completed");
+        let unknown = send(&app, Method::POST, "/download/https%3A%2F%2Fexample.com%2Fnope/retry", None).await;
+        assert_eq!(unknown.status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn rips_audio() {
+        let state = state(fake_ytdlp());
+        let app = app(&state);
+        let key = "https://example.com/rip-route";
+        assert_eq!(send(&app, Method::POST, "/rip", Some(json!({ "url": key, "format": "wav" }))).await.status, StatusCode::BAD_REQUEST);
+
+        let started = send(&app, Method::POST, "/rip", Some(json!({ "url": key, "format": "opus" }))).await;
+        assert_eq!(started.status, StatusCode::ACCEPTED);
+        let status = finished(&state, key).await;
+        assert_eq!(status.status, "completed");
+        assert!(status.command.unwrap().contains("--extract-audio --audio-format opus"));
+        assert!(status.files[0].ends_with("Artist - Video.mp4"));
+    }
+
+    #[tokio::test]
+    async fn fetches_side_files_only() {
+        let state = state(fake_ytdlp());
+        let app = app(&state);
+        let key = "https://example.com/fetch-route";
+        let invalid = [json!({ "url": key, "kinds": [] }), json!({ "url": key, "kinds": ["video"] }), json!({ "url": key, "kinds": ["thumbnail"], "target_dir": "/etc" })];
+        for body in invalid {
+            assert_eq!(send(&app, Method::POST, "/fetch", Some(body)).await.status, StatusCode::BAD_REQUEST);
+        }
+
+        let body = json!({ "url": key, "kinds": ["thumbnail", "info_json"], "target_dir": "extras" });
+        assert_eq!(send(&app, Method::POST, "/fetch", Some(body)).await.status, StatusCode::ACCEPTED);
+        let status = finished(&state, key).await;
+        let command = status.command.unwrap();
+        assert!(command.contains("--write-info-json --write-thumbnail --skip-download"), "{}", command);
+        assert!(Path::new(&status.files[0]).parent().unwrap().ends_with("extras"));
+    }
+
+    #[tokio::test]
+    async fn lists_and_cancels_scheduled_downloads() {
+        let state = state(fake_ytdlp());
+        let app = app(&state);
+        let key = "https://example.com/schedule-route";
+        let at = (chrono::Utc::now() + chrono::Duration::hours(1)).to_rfc3339();
+        let scheduled = send(&app, Method::POST, "/download", Some(json!({ "url": key, "format_id": "best", "scheduled_at": at }))).await;
+        assert_eq!(scheduled.status, StatusCode::ACCEPTED, "{}", scheduled.body);
+        assert_eq!(state.downloads.lock()[key].status, "scheduled");
+
+        let listed = send(&app, Method::GET, "/schedule", None).await;
+        assert_eq!(listed.body[0]["download_key"], key);
+
+        let uri = format!("/schedule?key={}", encode(key));
+        let cancelled = send(&app, Method::DELETE, &uri, None).await;
+        assert_eq!(cancelled.status, StatusCode::OK);
+        assert_eq!(state.downloads.lock()[key].status, "cancelled");
+        assert_eq!(send(&app, Method::DELETE, &uri, None).await.status, StatusCode::NOT_FOUND);
+        assert_eq!(send(&app, Method::GET, "/schedule", None).await.body, json!([]));
+    }
+
+    #[tokio::test]
+    async fn streams_the_log_as_server_sent_events() {
+        let gate = PathBuf::from(format!("{}/sse.gate", crate::test_support::isolate_home().display()));
+        let state = state(gated_ytdlp(&gate));
+        let app = app(&state);
+        let key = "https://example.com/sse-route";
+        send(&app, Method::POST, "/download", Some(json!({ "url": key, "format_id": "best" }))).await;
+        wait_for_live_log(&state, key).await;
+
+        let uri = format!("/download/{}/log/stream", encode(key));
+        let (status, headers, chunk) = first_chunk(&app, Request::builder().uri(&uri)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers["content-type"], "text/event-stream");
+        assert_eq!(chunk, "event: log\ndata: [info] first line\n\n");
+
+        std::fs::write(&gate, "").unwrap();
+        finished(&state, key).await;
+        assert_eq!(send(&app, Method::GET, &uri, None).await.status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn tails_the_log_over_a_websocket() {
+        let gate = PathBuf::from(format!("{}/ws.gate", crate::test_support::isolate_home().display()));
+        let state = state(gated_ytdlp(&gate));
+        let key = "https://example.com/ws-route";
+        send(&app(&state), Method::POST, "/download", Some(json!({ "url": key, "format_id": "best" }))).await;
+        wait_for_live_log(&state, key).await;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = app(&state);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let mut socket = tokio::net::TcpStream::connect(address).await.unwrap();
+        let handshake = format!(
+            "GET /download/{}/log/ws HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            encode(key),
+            address
+        );
+        socket.write_all(handshake.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            response.push(socket.read_u8().await.unwrap());
+        }
+        assert!(response.starts_with(b"HTTP/1.1 101"), "{}", String::from_utf8_lossy(&response));
+
+        // Lines printed from now on arrive as unmasked text frames, then the socket closes.
+        std::fs::write(&gate, "").unwrap();
+        let mut frames = Vec::new();
+        loop {
+            let (opcode, length) = (socket.read_u8().await.unwrap() & 0x0f, socket.read_u8().await.unwrap());
+            let mut payload = vec![0; usize::from(length & 0x7f)];
+            socket.read_exact(&mut payload).await.unwrap();
+            if opcode == 0x8 {
+                break;
+            }
+            frames.push(String::from_utf8(payload).unwrap());
+        }
+        assert_eq!(frames, ["[info] second line"]);
+    }
+
+    /// A state whose yt-dlp answers the probes and downloads with `body`, with a one-second
+    /// stall timeout.
+    fn stall_state(body: &str) -> crate::AppState {
+        let script = format!("case \"$*\" in *--flat-playlist*) echo NA; exit 0 ;; *\"--print %(.\"*) exit 0 ;; esac\n{}", body);
+        let state = state(FakeRunner::new().script("yt-dlp", &script));
+        state.config.write().unwrap().stall_timeout_secs = Some(1);
+        state
+    }
+
+    const PROGRESS: &str = "echo '[download]  10.0% of 4.00KiB at 1.00KiB/s ETA 00:09'";
+
+    #[tokio::test]
+    async fn output_without_progress_doesnt_keep_a_stalled_download_alive() {
+        let state = stall_state(&format!("{}\nwhile true; do echo '[info] still here'; sleep 0.2; done", PROGRESS));
+        let key = "https://example.com/stalled";
+        send(&app(&state), Method::POST, "/download", Some(json!({ "url": key, "format_id": "best" }))).await;
+        let status = finished(&state, key).await;
+        assert_eq!(status.status, "failed");
+        assert_eq!(status.error_code, Some(crate::models::ErrorCode::Stalled));
+        assert!(status.error.unwrap().ends_with("yt-dlp reported no progress for 1 seconds and was stopped."));
+    }
+
+    #[tokio::test]
+    async fn the_stall_timer_waits_for_the_download_to_start() {
+        let state = stall_state(&format!("sleep 1.5\n{}\necho '[download] Destination: /dev/null'", PROGRESS));
+        let key = "https://example.com/slow-to-start";
+        send(&app(&state), Method::POST, "/download", Some(json!({ "url": key, "format_id": "best" }))).await;
+        let status = finished(&state, key).await;
+        assert_eq!(status.status, "completed", "{:?}", status.error);
+        assert!(status.last_progress_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn progress_on_stderr_resets_the_stall_timer() {
+        let body = format!("{}\nfor i in 1 2 3 4 5 6; do {} >&2; sleep 0.3; done\necho '[download] Destination: /dev/null'", PROGRESS, PROGRESS);
+        let state = stall_state(&body);
+        let key = "https://example.com/progress-on-stderr";
+        send(&app(&state), Method::POST, "/download", Some(json!({ "url": key, "format_id": "best" }))).await;
+        let status = finished(&state, key).await;
+        assert_eq!(status.status, "completed", "{:?}", status.error);
+    }
+}
@@ -0,0 +1,1004 @@
+use crate::{
+    error::AppError,
+    checksum, fs_util, history, library_index,
+    media_index::{self, MediaInfo},
+    models::{ChecksumAlgorithm, FileEntry, FileRef, FilesQuery, HistoryEntry, HistorySearchQuery, ListQuery, Storyboard, StoryboardQuery},
+    negotiate::{self, CsvRecord, HistoryExportRow, ListFormat},
+    throttle, AppState,
+};
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, Query, State},
+    Extension,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use futures::StreamExt;
+use percent_encoding::{percent_encode, utf8_percent_encode, AsciiSet, CONTROLS, NON_ALPHANUMERIC};
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::{Path as FsPath, PathBuf};
+use std::process::Stdio;
+use walkdir::WalkDir;
+
+use super::{resolve_download_root, AdminKey, ApiCaller, SeesOrigins, Uncompressed};
+
+/// Characters escaped when a file path is placed in a URI; `/` is kept as the separator.
+const PATH_ENCODE_SET: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'#').add(b'%').add(b'?').add(b'<').add(b'>').add(b'`').add(b'{').add(b'}');
+
+/// Characters escaped in the listed path of a file whose name isn't valid UTF-8, on top of
+/// every non-ASCII byte. `%` is escaped so decoding the path gives back the exact bytes.
+const RAW_PATH_ENCODE_SET: &AsciiSet = &CONTROLS.add(b'%');
+
+/// Characters escaped in file links, path and `?root=` alike: all but the unreserved ones and `/`.
+const LINK_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~').remove(b'/');
+
+/// Characters escaped in the `filename*` of a `Content-Disposition`: all but RFC 5987's `attr-char`.
+const DISPOSITION_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!').remove(b'#').remove(b'$').remove(b'&').remove(b'+').remove(b'-')
+    .remove(b'.').remove(b'^').remove(b'_').remove(b'`').remove(b'|').remove(b'~');
+
+/// Seconds between storyboard frames when `?interval=` isn't given.
+const DEFAULT_STORYBOARD_INTERVAL: u64 = 10;
+
+/// The width of each storyboard frame; the height follows the video's aspect ratio.
+const STORYBOARD_TILE_WIDTH: u32 = 160;
+
+/// Frames per row of a storyboard sprite sheet.
+const STORYBOARD_COLUMNS: u64 = 10;
+
+/// The most frames a storyboard holds, which keeps the sprite sheet within what JPEG and
+/// browsers handle (about 100 rows of 90 pixels).
+const MAX_STORYBOARD_TILES: u64 = 1000;
+
+/// Routes for browsing and serving downloaded files and their history.
+pub fn build_router(state: AppState) -> Router {
+    Router::new()
+        .route("/files", get(list_files))
+        .route("/files/*path", get(get_file).post(post_file))
+        .route("/media-index/rebuild", post(rebuild_media_index))
+        .route("/admin/reindex", post(reindex_library))
+        .route("/history", get(get_history))
+        .route("/history/search", get(search_history))
+        .route("/history/export", get(export_history))
+        .with_state(state)
+}
+
+// ===================================================================
+//                          FILE HANDLERS
+// ===================================================================
+
+/// # GET /files - Lists all downloaded files in the primary directory, or in `?root=`.
+/// With `?metadata=true`, each file is joined with its download history.
+/// With `?media_info=true`, media files carry their duration, resolution and codecs from the
+/// media index; files not indexed yet get `null` fields and are indexed in the background.
+/// Responds with CSV for `Accept: text/csv` or `?format=csv`.
+pub async fn list_files(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<FilesQuery>,
+) -> Result<Response, AppError> {
+    let format = negotiate::list_format(&headers, query.format.as_deref())?;
+    let download_dir = resolve_download_root(&state, query.root.as_deref())?;
+    let files = walk_files(&state, &download_dir).await?;
+
+    if !query.metadata && !query.media_info {
+        let files: Vec<String> = files.iter().map(|path| api_path(path)).collect();
+        if format == ListFormat::Csv {
+            return negotiate::csv_response(&files);
+        }
+        return Ok(Json(files).into_response());
+    }
+
+    let sources = if query.metadata { history::by_file(history::load().await?) } else { HashMap::new() };
+    let sizes = file_sizes(files.iter().map(|path| download_dir.join(path)).collect()).await?;
+    let mut unindexed = Vec::new();
+    let entries: Vec<FileEntry> = files
+        .into_iter()
+        .zip(sizes)
+        .map(|(path, size)| {
+            let full_path = download_dir.join(&path);
+            let source = sources.get(&full_path).cloned();
+            let media_info = (query.media_info && media_index::is_media(&full_path)).then(|| {
+                state.media_index.lookup(&full_path).unwrap_or_else(|| {
+                    unindexed.push(full_path.clone());
+                    MediaInfo::default()
+                })
+            });
+            FileEntry { file: file_ref(&state, query.root.as_deref(), &path, size), source, media_info }
+        })
+        .collect();
+    media_index::index_in_background(&state, unindexed);
+    if format == ListFormat::Csv {
+        return negotiate::csv_response(&entries);
+    }
+    Ok(Json(entries).into_response())
+}
+
+/// # POST /media-index/rebuild - Drops the media index and re-probes every media file in the
+/// primary directory, or in `?root=`. Probing runs in the background; responds with 202 Accepted.
+pub async fn rebuild_media_index(
+    AdminKey(_admin): AdminKey,
+    State(state): State<AppState>,
+    Query(query): Query<FilesQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let download_dir = resolve_download_root(&state, query.root.as_deref())?;
+    let files = walk_files(&state, &download_dir).await?;
+    let cleared = state.media_index.clear().await?;
+    let queued = media_index::index_in_background(&state, files.iter().map(|path| download_dir.join(path)).collect());
+    tracing::info!("Rebuilding the media index: {} entries cleared, {} files queued", cleared, queued);
+    Ok((StatusCode::ACCEPTED, Json(json!({ "cleared": cleared, "queued": queued }))))
+}
+
+/// # POST /admin/reindex - Walks every download root again for the library index, right away
+/// instead of at the next `library_index_interval_secs` refresh.
+pub async fn reindex_library(AdminKey(_admin): AdminKey, State(state): State<AppState>) -> Result<impl IntoResponse, AppError> {
+    if !library_index::enabled(&state) {
+        return Err(AppError::Conflict("The library index is off; set library_index_interval_secs to turn it on".to_string()));
+    }
+    let files = library_index::refresh(&state).await?;
+    tracing::info!("Reindexed the library: {} files", files);
+    let reconciliation = library_index::reconcile(&state).await?;
+    library_index::log_reconciliation(&reconciliation);
+    Ok(Json(json!({ "files": files, "roots": state.library_index.built_at(), "reconciled": reconciliation })))
+}
+
+/// # GET /files/:path - Serves a single downloaded file.
+/// `GET /files/:path/frame?at=`, `GET /files/:path/probe` and `GET /files/:path/checksum` are
+/// routed here too (axum wildcards must end the route).
+pub async fn get_file(
+    State(state): State<AppState>,
+    Path(path): Path<String>,
+    Query(query): Query<FilesQuery>,
+) -> Result<Response, AppError> {
+    if let (Some(video_path), Some(at)) = (path.strip_suffix("/frame"), query.at.as_deref()) {
+        return get_frame(&state, video_path, at, &query).await;
+    }
+    let download_dir = resolve_download_root(&state, query.root.as_deref())?;
+    // A file can't have children, so `<file>/probe` never names a real file.
+    if let Some(probed) = path.strip_suffix("/probe") {
+        if let Ok(file) = served_file(&state, &download_dir, probed).await {
+            return get_probe(&state, &file, probed).await;
+        }
+    }
+    if let Some(hashed) = path.strip_suffix("/checksum") {
+        if let Ok(file) = served_file(&state, &download_dir, hashed).await {
+            return get_checksum(&state, &file, hashed, query.algorithm).await;
+        }
+    }
+    let canonical_file = served_file(&state, &download_dir, &path).await?;
+    let canonical_base = tokio::fs::canonicalize(&download_dir).await?;
+
+    let mut headers = HeaderMap::new();
+    // Named after the requested path rather than the canonical one, which may be a link target.
+    let relative = fs_util::decode(&path)?;
+    headers.insert(header::CONTENT_DISPOSITION, content_disposition(relative.file_name().unwrap_or_default())?);
+
+    // Hand the transfer off to the fronting web server if configured.
+    let (sendfile_header, sendfile_prefix, rate_limit, global_rate_limit) = {
+        let config = state.config.read().unwrap();
+        (
+            config.sendfile_header.clone(),
+            config.sendfile_prefix.clone(),
+            config.file_serve_rate_limit.as_deref().and_then(throttle::parse_rate),
+            config.file_serve_global_rate_limit.as_deref().and_then(throttle::parse_rate),
+        )
+    };
+    if let Some(header_name) = sendfile_header {
+        let relative = canonical_file.strip_prefix(&canonical_base).unwrap_or(&canonical_file);
+        let value = sendfile_header_value(&header_name, sendfile_prefix.as_deref(), relative, &canonical_file);
+        let name = header::HeaderName::from_bytes(header_name.as_bytes())
+            .map_err(|e| anyhow::anyhow!("Invalid sendfile_header '{}': {}", header_name, e))?;
+        let value = HeaderValue::from_str(&value)
+            .map_err(|e| anyhow::anyhow!("Cannot express '{}' as a {} header: {}", canonical_file.display(), header_name, e))?;
+        headers.insert(name, value);
+        return Ok((headers, Body::empty()).into_response());
+    }
+
+    let file = tokio::fs::File::open(&canonical_file).await?;
+    let stream = tokio_util::io::ReaderStream::new(file);
+    let body = if rate_limit.is_some() || global_rate_limit.is_some() {
+        let global = global_rate_limit.map(|rate| (state.serve_pacer.clone(), rate));
+        Body::from_stream(throttle::throttle(stream, rate_limit, global))
+    } else {
+        Body::from_stream(stream)
+    };
+
+    Ok((headers, Extension(Uncompressed), body).into_response())
+}
+
+/// # GET /files/:path/checksum - Hashes a downloaded file, with `?algorithm=sha256|md5` or
+/// the config's `default_checksum`. The file is read in chunks, so any size works.
+async fn get_checksum(state: &AppState, file: &FsPath, path: &str, algorithm: Option<ChecksumAlgorithm>) -> Result<Response, AppError> {
+    let algorithm = algorithm.or(state.config.read().unwrap().default_checksum).unwrap_or(ChecksumAlgorithm::Sha256);
+    let digest = checksum::file_digest(file, algorithm).await?;
+    let size = tokio::fs::metadata(file).await?.len();
+    Ok(Json(json!({ "path": path, "algorithm": algorithm, "digest": digest, "size": size })).into_response())
+}
+
+/// # GET /files/:path/probe - Returns ffprobe's details of a downloaded file: container,
+/// duration, bitrate, codecs and every stream. Cached until the file's size or mtime changes.
+async fn get_probe(state: &AppState, file: &FsPath, path: &str) -> Result<Response, AppError> {
+    let details = media_index::probe_file(state, file).await.map_err(|e| match e.downcast_ref::<std::io::Error>() {
+        Some(io) if io.kind() == std::io::ErrorKind::NotFound => {
+            AppError::Unprocessable("Probing requires ffprobe, which was not found on PATH.".to_string())
+        }
+        _ => AppError::Unprocessable(format!("Could not probe '{}': {}", path, e)),
+    })?;
+    Ok(Json(details).into_response())
+}
+
+/// # GET /files/:path/frame?at= - Extracts a single frame of a downloaded video with ffmpeg.
+/// Returns a JPEG (or a PNG with `?image=png`); nothing is written to disk.
+async fn get_frame(state: &AppState, path: &str, at: &str, query: &FilesQuery) -> Result<Response, AppError> {
+    let seconds = parse_timestamp(at).ok_or_else(|| {
+        AppError::BadRequest(format!("Invalid timestamp '{}'. Use HH:MM:SS, MM:SS or seconds, e.g. 00:01:30", at))
+    })?;
+    let (codec, content_type) = match query.image.as_deref().unwrap_or("jpeg") {
+        "jpeg" | "jpg" => ("mjpeg", "image/jpeg"),
+        "png" => ("png", "image/png"),
+        other => return Err(AppError::BadRequest(format!("Unsupported image '{}'. Supported: jpeg, png", other))),
+    };
+    let download_dir = resolve_download_root(state, query.root.as_deref())?;
+    let video = served_file(state, &download_dir, path).await?;
+
+    let output = state
+        .runner
+        .command("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error", "-ss", &seconds.to_string(), "-i"])
+        .arg(&video)
+        .args(["-frames:v", "1", "-f", "image2pipe", "-c:v", codec, "-"])
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => AppError::Unprocessable("Frame extraction requires ffmpeg, which was not found on PATH.".to_string()),
+            _ => AppError::Internal(e.into()),
+        })?;
+    if !output.status.success() || output.stdout.is_empty() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(AppError::Unprocessable(format!(
+            "Could not extract a frame at {} from '{}': {}",
+            at,
+            path,
+            if stderr.trim().is_empty() { "the timestamp is past the end of the video" } else { stderr.trim() }
+        )));
+    }
+
+    Ok(([(header::CONTENT_TYPE, content_type)], output.stdout).into_response())
+}
+
+/// # POST /files/:path/storyboard?interval= - Writes a storyboard for scrubbing previews.
+/// Alongside the video, `<name>.storyboard.jpg` holds one frame every `interval` seconds in a
+/// grid, and `<name>.storyboard.vtt` maps each stretch of time to its tile. Both replace any
+/// earlier storyboard and are served like other files.
+pub async fn post_file(
+    ApiCaller(_caller): ApiCaller,
+    State(state): State<AppState>,
+    Path(path): Path<String>,
+    Query(query): Query<StoryboardQuery>,
+) -> Result<Response, AppError> {
+    let Some(video_path) = path.strip_suffix("/storyboard") else {
+        return Err(AppError::NotFound(format!("No action at '{}'. POST /files/:path/storyboard writes a storyboard.", path)));
+    };
+    let interval = query.interval.unwrap_or(DEFAULT_STORYBOARD_INTERVAL);
+    if !(1..=3600).contains(&interval) {
+        return Err(AppError::BadRequest("interval must be between 1 and 3600 seconds".to_string()));
+    }
+    let download_dir = resolve_download_root(&state, query.root.as_deref())?;
+    let video = served_file(&state, &download_dir, video_path).await?;
+
+    let probe = media_index::probe_file(&state, &video).await.map_err(|e| match e.downcast_ref::<std::io::Error>() {
+        Some(io) if io.kind() == std::io::ErrorKind::NotFound => {
+            AppError::Unprocessable("Storyboards require ffprobe, which was not found on PATH.".to_string())
+        }
+        _ => AppError::Unprocessable(format!("Could not probe '{}': {}", video_path, e)),
+    })?;
+    let (Some(duration), Some(width), Some(height)) = (probe.duration.filter(|d| *d > 0.0), probe.width, probe.height) else {
+        return Err(AppError::Unprocessable(format!("'{}' has no video stream with a known duration", video_path)));
+    };
+    let tiles = ((duration / interval as f64).ceil() as u64).max(1);
+    if tiles > MAX_STORYBOARD_TILES {
+        let shortest = (duration / MAX_STORYBOARD_TILES as f64).ceil() as u64;
+        return Err(AppError::BadRequest(format!(
+            "An interval of {}s gives {} frames, more than the {} a storyboard holds. Use an interval of at least {}s.",
+            interval, tiles, MAX_STORYBOARD_TILES, shortest
+        )));
+    }
+    let columns = tiles.min(STORYBOARD_COLUMNS);
+    let rows = tiles.div_ceil(columns);
+    let tile_width = STORYBOARD_TILE_WIDTH;
+    // Even, as most encoders want, and at least 2 for extremely wide videos.
+    let tile_height = ((tile_width as f64 * height as f64 / width.max(1) as f64 / 2.0).round() as u32 * 2).max(2);
+
+    let stem = video.file_stem().unwrap_or_default().to_string_lossy().to_string();
+    let sprite_path = video.with_file_name(format!("{}.storyboard.jpg", stem));
+    let vtt_path = video.with_file_name(format!("{}.storyboard.vtt", stem));
+    let partial = video.with_file_name(format!("{}.storyboard.jpg.partial", stem));
+    let output = state
+        .runner
+        .command("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error", "-y", "-i"])
+        .arg(&video)
+        .arg("-vf")
+        .arg(format!("fps=1/{},scale={}:{},tile={}x{}", interval, tile_width, tile_height, columns, rows))
+        .args(["-frames:v", "1", "-q:v", "5", "-f", "image2", "-update", "1", "-c:v", "mjpeg"])
+        .arg(&partial)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => AppError::Unprocessable("Storyboards require ffmpeg, which was not found on PATH.".to_string()),
+            _ => AppError::Internal(e.into()),
+        })?;
+    if !output.status.success() || !tokio::fs::metadata(&partial).await.is_ok_and(|meta| meta.is_file()) {
+        let _ = tokio::fs::remove_file(&partial).await;
+        return Err(AppError::Unprocessable(format!(
+            "Could not make a storyboard of '{}': {}",
+            video_path,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    tokio::fs::rename(&partial, &sprite_path).await?;
+
+    let canonical_base = tokio::fs::canonicalize(&download_dir).await?;
+    let describe = |file: &FsPath, size: u64| {
+        let relative = file.strip_prefix(&canonical_base).unwrap_or(file);
+        file_ref(&state, query.root.as_deref(), relative, Some(size))
+    };
+    let sprite = describe(&sprite_path, tokio::fs::metadata(&sprite_path).await?.len());
+    let vtt_content = storyboard_vtt(&sprite.url, duration, interval, tiles, columns, tile_width, tile_height);
+    tokio::fs::write(&vtt_path, &vtt_content).await?;
+    let vtt = describe(&vtt_path, vtt_content.len() as u64);
+    tracing::info!("Wrote a storyboard of {} frames for {}", tiles, video.display());
+
+    Ok(Json(Storyboard { sprite, vtt, interval, tiles, columns, tile_width, tile_height }).into_response())
+}
+
+/// The WebVTT track of a storyboard: one cue per tile, pointing into the sprite sheet with a
+/// media fragment. The sprite is referenced relative to the track, which sits next to it.
+fn storyboard_vtt(sprite_url: &str, duration: f64, interval: u64, tiles: u64, columns: u64, width: u32, height: u32) -> String {
+    let (link, query) = sprite_url.split_once('?').map_or((sprite_url, None), |(link, query)| (link, Some(query)));
+    let mut sprite = link.rsplit('/').next().unwrap_or(link).to_string();
+    if let Some(query) = query {
+        sprite.push('?');
+        sprite.push_str(query);
+    }
+    let mut vtt = String::from("WEBVTT\n");
+    for tile in 0..tiles {
+        let start = (tile * interval) as f64;
+        let end = ((tile + 1) * interval) as f64;
+        let (x, y) = ((tile % columns) as u32 * width, (tile / columns) as u32 * height);
+        vtt.push_str(&format!(
+            "\n{} --> {}\n{}#xywh={},{},{},{}\n",
+            vtt_timestamp(start),
+            vtt_timestamp(end.min(duration)),
+            sprite,
+            x,
+            y,
+            width,
+            height
+        ));
+    }
+    vtt
+}
+
+/// Formats seconds as a WebVTT timestamp, "HH:MM:SS.mmm".
+fn vtt_timestamp(seconds: f64) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    format!("{:02}:{:02}:{:02}.{:03}", millis / 3_600_000, millis / 60_000 % 60, millis / 1000 % 60, millis % 1000)
+}
+
+// ===================================================================
+//                          HISTORY HANDLERS
+// ===================================================================
+
+/// # GET /history - Returns every completed download item, oldest first.
+/// Responds with CSV for `Accept: text/csv` or `?format=csv`.
+pub async fn get_history(
+    State(state): State<AppState>,
+    SeesOrigins(sees_origins): SeesOrigins,
+    headers: HeaderMap,
+    Query(query): Query<ListQuery>,
+) -> Result<Response, AppError> {
+    let entries = visible_origins(history::load().await?, sees_origins);
+    if negotiate::list_format(&headers, query.format.as_deref())? == ListFormat::Csv {
+        return negotiate::csv_response(&entries);
+    }
+    Ok(Json(with_file_refs(&state, entries).await?).into_response())
+}
+
+/// # GET /history/export - Downloads the whole history as a JSON array, or as CSV for
+/// `?format=csv` or `Accept: text/csv`. Entries are read and sent one at a time, so a large
+/// history is never held in memory.
+pub async fn export_history(SeesOrigins(sees_origins): SeesOrigins, headers: HeaderMap, Query(query): Query<ListQuery>) -> Result<Response, AppError> {
+    let format = negotiate::list_format(&headers, query.format.as_deref())?;
+    let entries = history::stream().await?.map(move |mut entry| {
+        if !sees_origins {
+            entry.origin = None;
+        }
+        entry
+    });
+    let (content_type, filename, body) = match format {
+        ListFormat::Csv => {
+            let header_line = futures::stream::once(async { negotiate::csv_line(HistoryExportRow::HEADERS) });
+            let rows = entries.map(|entry| negotiate::csv_line(&HistoryExportRow(entry).record()));
+            ("text/csv; charset=utf-8", "history.csv", Body::from_stream(header_line.chain(rows).map(|line| line.map(Bytes::from))))
+        }
+        ListFormat::Json => {
+            let open = futures::stream::once(async { Ok(Bytes::from_static(b"[")) });
+            let items = entries.enumerate().map(|(i, entry)| {
+                let mut item = if i == 0 { Vec::new() } else { b",\n".to_vec() };
+                serde_json::to_writer(&mut item, &entry)?;
+                Ok::<_, anyhow::Error>(Bytes::from(item))
+            });
+            let close = futures::stream::once(async { Ok(Bytes::from_static(b"]\n")) });
+            ("application/json", "history.json", Body::from_stream(open.chain(items).chain(close)))
+        }
+    };
+
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    let disposition = format!("attachment; filename=\"{}\"", filename);
+    response_headers.insert(header::CONTENT_DISPOSITION, HeaderValue::from_str(&disposition).map_err(anyhow::Error::from)?);
+    Ok((response_headers, body).into_response())
+}
+
+/// # GET /history/search - Searches history by URL, video ID, title, uploader or file path.
+pub async fn search_history(
+    State(state): State<AppState>,
+    SeesOrigins(sees_origins): SeesOrigins,
+    Query(query): Query<HistorySearchQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    if query.q.trim().is_empty() {
+        return Err(AppError::BadRequest("Query parameter 'q' cannot be empty".to_string()));
+    }
+    let results: Vec<HistoryEntry> = history::load()
+        .await?
+        .into_iter()
+        .filter(|entry| history::matches(entry, query.q.trim()))
+        .collect();
+    Ok(Json(with_file_refs(&state, visible_origins(results, sees_origins)).await?))
+}
+
+// ===================================================================
+//                          HELPER FUNCTIONS
+// ===================================================================
+
+/// Leaves out who started each download for callers that may not see it.
+fn visible_origins(mut entries: Vec<HistoryEntry>, sees_origins: bool) -> Vec<HistoryEntry> {
+    if !sees_origins {
+        entries.iter_mut().for_each(|entry| entry.origin = None);
+    }
+    entries
+}
+
+/// Lists the files under `download_dir`, relative to it, from the library index if it is on.
+pub(crate) async fn walk_files(state: &AppState, download_dir: &FsPath) -> Result<Vec<PathBuf>, AppError> {
+    if let Some(files) = library_index::indexed(state, download_dir).await? {
+        return Ok(files.into_iter().map(|file| file.path).collect());
+    }
+    let (walking, dir) = (state.clone(), download_dir.to_path_buf());
+    tokio::task::spawn_blocking(move || walk_dir(&walking, &dir)).await?
+}
+
+/// Walks `download_dir` for the files under it, relative to it.
+pub(crate) fn walk_dir(state: &AppState, download_dir: &FsPath) -> Result<Vec<PathBuf>, AppError> {
+    let mut files = Vec::new();
+    if !download_dir.exists() {
+        return Ok(files);
+    }
+    let follow_symlinks = state.config.read().unwrap().follow_symlinks;
+    // Without following, symlinks show up as neither files nor directories and are skipped.
+    // WalkDir reports symlink loops as errors, which are skipped as well.
+    let walker = WalkDir::new(download_dir).min_depth(1).follow_links(follow_symlinks);
+    for entry in walker.into_iter().filter_map(|e| e.ok()) {
+        if entry.file_type().is_file() {
+            let Ok(relative_path) = entry.path().strip_prefix(download_dir) else { continue };
+            if follow_symlinks && fs_util::confine(download_dir, relative_path).is_err() {
+                continue;
+            }
+            files.push(relative_path.to_path_buf());
+        }
+    }
+    Ok(files)
+}
+
+/// Describes a file under a download root for a response: `relative` to the root, which is
+/// `None` for the primary directory and otherwise one of `allowed_download_roots`.
+pub(crate) fn file_ref(state: &AppState, root: Option<&str>, relative: &FsPath, size: Option<u64>) -> FileRef {
+    let path = api_path(relative);
+    let base = state.config.read().unwrap().public_base_url.clone().unwrap_or_default();
+    let mut url = format!("{}/files/{}", base.trim_end_matches('/'), utf8_percent_encode(&path, LINK_ENCODE_SET));
+    if let Some(root) = root {
+        url.push_str("?root=");
+        url.extend(utf8_percent_encode(root, LINK_ENCODE_SET));
+    }
+    FileRef { path, size, url }
+}
+
+/// Describes a file given by any path, e.g. as yt-dlp wrote it, if it still exists under one
+/// of the download roots.
+pub(crate) fn locate_file(state: &AppState, file: &FsPath) -> Option<FileRef> {
+    let canonical = std::fs::canonicalize(file).ok()?;
+    let size = std::fs::metadata(&canonical).ok().map(|meta| meta.len());
+    let (primary, alternates) = {
+        let config = state.config.read().unwrap();
+        (config.download_directory.clone(), config.allowed_download_roots.clone())
+    };
+    std::iter::once((None, primary)).chain(alternates.into_iter().map(|root| (Some(root.clone()), root))).find_map(|(root, dir)| {
+        let base = std::fs::canonicalize(&dir).ok()?;
+        let relative = canonical.strip_prefix(&base).ok()?;
+        Some(file_ref(state, root.as_deref(), relative, size))
+    })
+}
+
+/// The sizes of `files`, `None` for those that are gone, looked up on a blocking thread.
+async fn file_sizes(files: Vec<PathBuf>) -> Result<Vec<Option<u64>>, AppError> {
+    let sizes = tokio::task::spawn_blocking(move || files.iter().map(|file| std::fs::metadata(file).ok().map(|meta| meta.len())).collect()).await?;
+    Ok(sizes)
+}
+
+/// Fills in the `file_refs` of history entries, on a blocking thread as every file is looked up.
+async fn with_file_refs(state: &AppState, entries: Vec<HistoryEntry>) -> Result<Vec<HistoryEntry>, AppError> {
+    let state = state.clone();
+    let entries = tokio::task::spawn_blocking(move || {
+        entries
+            .into_iter()
+            .map(|mut entry| {
+                // A job group's `files` starts with its `filepath`.
+                let files = if entry.files.is_empty() { entry.filepath.iter().collect::<Vec<_>>() } else { entry.files.iter().collect() };
+                entry.missing = !files.is_empty() && !files.iter().any(|file| FsPath::new(file).exists());
+                entry.file_refs = files.into_iter().filter_map(|file| locate_file(&state, FsPath::new(file))).collect();
+                entry
+            })
+            .collect()
+    })
+    .await?;
+    Ok(entries)
+}
+
+/// The path `GET /files` lists for a file. Every path is percent-decoded once more after the
+/// URL (see `fs_util::decode`), so `%` is always escaped, and a path that isn't valid UTF-8 is
+/// percent-encoded byte by byte instead of losing the invalid bytes. Either way
+/// `GET /files/*path` finds exactly this file again.
+pub(crate) fn api_path(relative: &FsPath) -> String {
+    if let Some(path) = relative.to_str() {
+        return path.replace('%', "%25");
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        percent_encode(relative.as_os_str().as_bytes(), RAW_PATH_ENCODE_SET).to_string()
+    }
+    #[cfg(not(unix))]
+    {
+        relative.to_string_lossy().to_string()
+    }
+}
+
+/// The `Content-Disposition` of a served file, as RFC 6266 has it: the name's exact bytes,
+/// percent-encoded, in `filename*`, and for clients that don't read that a `filename` with every
+/// byte that isn't printable ASCII, and `"` and `\`, replaced by `_`.
+fn content_disposition(name: &std::ffi::OsStr) -> Result<HeaderValue, AppError> {
+    #[cfg(unix)]
+    let bytes = std::os::unix::ffi::OsStrExt::as_bytes(name).to_vec();
+    #[cfg(not(unix))]
+    let bytes = name.to_string_lossy().as_bytes().to_vec();
+    let fallback: String = bytes
+        .iter()
+        .map(|&byte| if (b' '..=b'~').contains(&byte) && byte != b'"' && byte != b'\\' { byte as char } else { '_' })
+        .collect();
+    let value = format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, percent_encode(&bytes, DISPOSITION_ENCODE_SET));
+    Ok(HeaderValue::from_str(&value).map_err(anyhow::Error::from)?)
+}
+
+/// Resolves a requested path to an existing file inside `download_dir` with
+/// [`fs_util::safe_path`]. Paths that pass through a symlink are refused unless
+/// `follow_symlinks` is set, and even then the link target must stay inside the directory.
+/// The path is checked on a blocking thread.
+async fn served_file(state: &AppState, download_dir: &FsPath, user_path: &str) -> Result<PathBuf, AppError> {
+    let follow_symlinks = state.config.read().unwrap().follow_symlinks;
+    let (download_dir, user_path) = (download_dir.to_path_buf(), user_path.to_string());
+    tokio::task::spawn_blocking(move || {
+        let file = fs_util::safe_path(&download_dir, &user_path)?;
+        let not_found = || AppError::NotFound(format!("File '{}' not found.", user_path));
+        if !follow_symlinks {
+            let mut current = download_dir.clone();
+            for component in fs_util::decode(&user_path)?.components() {
+                current.push(component);
+                if std::fs::symlink_metadata(&current).is_ok_and(|m| m.file_type().is_symlink()) {
+                    return Err(not_found());
+                }
+            }
+        }
+        if !file.is_file() {
+            return Err(not_found());
+        }
+        Ok(file)
+    })
+    .await?
+}
+
+/// Parses "HH:MM:SS(.fff)", "MM:SS(.fff)" or plain seconds into seconds.
+fn parse_timestamp(text: &str) -> Option<f64> {
+    let mut seconds = 0.0;
+    let parts: Vec<&str> = text.trim().split(':').collect();
+    if parts.len() > 3 {
+        return None;
+    }
+    for (i, part) in parts.iter().enumerate() {
+        let value: f64 = part.parse().ok()?;
+        let is_last = i == parts.len() - 1;
+        // Only the seconds field may carry a fraction; minutes and seconds stay below 60.
+        if !value.is_finite() || value < 0.0 || (!is_last && value.fract() != 0.0) || (i > 0 && value >= 60.0) {
+            return None;
+        }
+        seconds = seconds * 60.0 + value;
+    }
+    Some(seconds)
+}
+
+/// Builds the value of the sendfile header for a file.
+/// nginx's X-Accel-Redirect takes an internal URI (prefix + encoded relative path);
+/// X-Sendfile and similar headers take the absolute filesystem path.
+fn sendfile_header_value(header_name: &str, prefix: Option<&str>, relative: &FsPath, absolute: &FsPath) -> String {
+    if header_name.eq_ignore_ascii_case("x-accel-redirect") {
+        let prefix = prefix.unwrap_or("/").trim_end_matches('/');
+        let relative = relative.to_string_lossy().replace('\\', "/");
+        format!("{}/{}", prefix, utf8_percent_encode(&relative, PATH_ENCODE_SET))
+    } else {
+        absolute.to_string_lossy().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::history;
+    use crate::models::HistoryEntry;
+    use crate::test_support::{app, lock_files, send, send_with, state, FakeRunner};
+    use axum::http::{Method, Request, StatusCode};
+    use std::path::{Path, PathBuf};
+
+    const FFPROBE: &str = r#"echo '{"format": {"format_name": "mp4", "duration": "25.0", "size": "4"},
+"streams": [{"index": 0, "codec_type": "video", "codec_name": "h264", "width": 640, "height": 360}]}'"#;
+
+    /// Writes `name` under the state's download directory and returns its full path.
+    fn download_file(state: &crate::AppState, name: &str, content: &[u8]) -> PathBuf {
+        let path = Path::new(&state.config.read().unwrap().download_directory).join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn listed_paths_that_are_not_utf8_decode_to_the_same_bytes() {
+        use std::os::unix::ffi::OsStrExt;
+        for bytes in [&b"caf\xe9.mp4"[..], b"100%\xff.mp4", b"sub/\x80\x81%41/clip\x01.webm", b"%25\xfe%2e%2e"] {
+            let path = Path::new(std::ffi::OsStr::from_bytes(bytes));
+            let listed = super::api_path(path);
+            assert!(listed.is_ascii(), "{}", listed);
+            let decoded = crate::fs_util::decode(&listed).unwrap_or_else(|e| panic!("{}: {}", listed, e));
+            assert_eq!(decoded.as_os_str().as_bytes(), bytes, "{}", listed);
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_a_utf8_file_with_a_percent_sign_by_its_listed_path() {
+        let state = state(FakeRunner::new());
+        download_file(&state, "100%41 done.mp4", b"percent");
+        download_file(&state, "100A done.mp4", b"letter");
+        let app = app(&state);
+
+        let listed = send(&app, Method::GET, "/files", None).await;
+        let mut paths: Vec<&str> = listed.body.as_array().unwrap().iter().map(|path| path.as_str().unwrap()).collect();
+        paths.sort();
+        assert_eq!(paths, ["100%2541 done.mp4", "100A done.mp4"]);
+        assert_eq!(crate::fs_util::decode(paths[0]).ok().as_deref(), Some(Path::new("100%41 done.mp4")));
+        let encoded = percent_encoding::utf8_percent_encode(paths[0], super::PATH_ENCODE_SET).to_string();
+        let served = send(&app, Method::GET, &format!("/files/{}", encoded), None).await;
+        assert_eq!(served.status, StatusCode::OK);
+        assert_eq!(served.body, "percent");
+
+        let file = super::file_ref(&state, None, Path::new("100%41 done.mp4"), Some(7));
+        assert_eq!(file.path, "100%2541 done.mp4");
+        assert_eq!(file.url, "/files/100%252541%20done.mp4");
+        assert_eq!(send(&app, Method::GET, &file.url, None).await.body, "percent");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serves_a_file_whose_name_is_not_utf8_by_its_listed_path() {
+        use std::os::unix::ffi::OsStrExt;
+        let state = state(FakeRunner::new());
+        let directory = PathBuf::from(&state.config.read().unwrap().download_directory);
+        std::fs::write(directory.join(std::ffi::OsStr::from_bytes(b"caf\xe9 50%.mp4")), b"fake").unwrap();
+        let app = app(&state);
+
+        let listed = send(&app, Method::GET, "/files", None).await;
+        let path = listed.body[0].as_str().unwrap().to_string();
+        assert_eq!(path, "caf%E9 50%25.mp4");
+        // URL-encoded like any other listed path.
+        let encoded = percent_encoding::utf8_percent_encode(&path, super::PATH_ENCODE_SET).to_string();
+        assert_eq!(encoded, "caf%25E9%2050%2525.mp4");
+        let served = send(&app, Method::GET, &format!("/files/{}", encoded), None).await;
+        assert_eq!(served.status, StatusCode::OK);
+        assert_eq!(served.body, "fake");
+        assert_eq!(served.headers["content-disposition"], "attachment; filename=\"caf_ 50%.mp4\"; filename*=UTF-8''caf%E9%2050%25.mp4");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serves_names_with_control_bytes_and_quotes() {
+        use std::os::unix::ffi::OsStrExt;
+        let state = state(FakeRunner::new());
+        let directory = PathBuf::from(&state.config.read().unwrap().download_directory);
+        std::fs::write(directory.join(std::ffi::OsStr::from_bytes(b"clip\x01 \"x\".webm")), b"fake").unwrap();
+        let app = app(&state);
+
+        let listed = send(&app, Method::GET, "/files", None).await;
+        let path = listed.body[0].as_str().unwrap().to_string();
+        let encoded = percent_encoding::utf8_percent_encode(&path, super::PATH_ENCODE_SET).to_string();
+        let served = send(&app, Method::GET, &format!("/files/{}", encoded), None).await;
+        assert_eq!(served.status, StatusCode::OK);
+        assert_eq!(served.headers["content-disposition"], "attachment; filename=\"clip_ _x_.webm\"; filename*=UTF-8''clip%01%20%22x%22.webm");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn never_serves_or_lists_through_a_symlink_that_leaves_the_download_directory() {
+        use std::os::unix::fs::symlink;
+        let state = state(FakeRunner::new());
+        let directory = PathBuf::from(&state.config.read().unwrap().download_directory);
+        let outside = tempfile::tempdir_in(crate::test_support::isolate_home()).unwrap();
+        std::fs::write(outside.path().join("secret.txt"), "secret").unwrap();
+        download_file(&state, "clip.mp4", b"fake");
+        symlink(outside.path().join("secret.txt"), directory.join("escape.txt")).unwrap();
+        symlink(outside.path(), directory.join("escape-dir")).unwrap();
+        symlink(directory.join("clip.mp4"), directory.join("alias.mp4")).unwrap();
+        let app = app(&state);
+
+        for follow_symlinks in [false, true] {
+            state.config.write().unwrap().follow_symlinks = follow_symlinks;
+            for path in ["escape.txt", "escape-dir/secret.txt", "escape-dir%2Fsecret.txt"] {
+                let response = send(&app, Method::GET, &format!("/files/{}", path), None).await;
+                assert_eq!(response.status, StatusCode::NOT_FOUND, "{} with follow_symlinks = {}", path, follow_symlinks);
+                assert_ne!(response.body, "secret");
+            }
+            let listed = send(&app, Method::GET, "/files", None).await;
+            let alias = send(&app, Method::GET, "/files/alias.mp4", None).await.status;
+            if follow_symlinks {
+                assert_eq!(listed.body, serde_json::json!(["alias.mp4", "clip.mp4"]));
+                assert_eq!(alias, StatusCode::OK);
+            } else {
+                assert_eq!(listed.body, serde_json::json!(["clip.mp4"]));
+                assert_eq!(alias, StatusCode::NOT_FOUND);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn generated_file_urls_lead_back_to_the_file() {
+        let _files = lock_files().await;
+        let state = state(FakeRunner::new());
+        let alternate = tempfile::tempdir_in(crate::test_support::isolate_home()).unwrap();
+        let alternate_root = alternate.path().to_str().unwrap().to_string();
+        {
+            let mut config = state.config.write().unwrap();
+            config.public_base_url = Some("https://media.example.com/yt/".to_string());
+            config.allowed_download_roots = vec![alternate_root.clone()];
+        }
+        let names = ["clip one.mp4", "a#b?c=d&e.mp4", "100% done+more;x.mp4", "sub dir/é 日本語 🎵.mp4", "%41%2F%2e%2e.mp4", "Tab\tName[1].mp4"];
+        let mut files = Vec::new();
+        for (index, name) in names.iter().enumerate() {
+            let content = format!("file {}", index);
+            files.push((download_file(&state, name, content.as_bytes()), content.clone()));
+            let path = alternate.path().join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, format!("alternate {}", content)).unwrap();
+            files.push((path, format!("alternate {}", content)));
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            let name = std::ffi::OsStr::from_bytes(b"caf\xe9 #1?.mp4");
+            let path = PathBuf::from(&state.config.read().unwrap().download_directory).join(name);
+            std::fs::write(&path, "not utf-8").unwrap();
+            files.push((path, "not utf-8".to_string()));
+        }
+        let app = app(&state);
+
+        // Strips the base, as a reverse proxy in front of the server would.
+        let fetch = |url: String| {
+            let app = app.clone();
+            async move {
+                let route = url.strip_prefix("https://media.example.com/yt").unwrap_or_else(|| panic!("{} isn't under the base URL", url));
+                assert!(route.starts_with("/files/"), "{}", url);
+                assert!(!route.contains(['#', ' ']) && route.is_ascii(), "{} isn't percent-encoded", url);
+                send(&app, Method::GET, route, None).await
+            }
+        };
+        for (path, content) in &files {
+            let file = super::locate_file(&state, path).unwrap_or_else(|| panic!("{} wasn't located", path.display()));
+            let served = fetch(file.url.clone()).await;
+            assert_eq!(served.status, StatusCode::OK, "{} for {}", file.url, path.display());
+            assert_eq!(served.body, content.as_str(), "{}", file.url);
+            assert_eq!(file.size, Some(content.len() as u64));
+        }
+
+        // The listing links to the same files.
+        let listed = send(&app, Method::GET, "/files?metadata=true", None).await;
+        let entries = listed.body.as_array().unwrap();
+        assert_eq!(entries.len(), names.len() + usize::from(cfg!(unix)));
+        for entry in entries {
+            let served = fetch(entry["url"].as_str().unwrap().to_string()).await;
+            assert_eq!(served.status, StatusCode::OK, "{}", entry);
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_and_serves_files() {
+        let state = state(FakeRunner::new());
+        download_file(&state, "sub/clip one.mp4", b"fake");
+        let app = app(&state);
+
+        let listed = send(&app, Method::GET, "/files", None).await;
+        assert_eq!(listed.status, StatusCode::OK);
+        assert_eq!(listed.body, serde_json::json!(["sub/clip one.mp4"]));
+        let csv = send(&app, Method::GET, "/files?format=csv", None).await;
+        assert!(csv.headers["content-type"].to_str().unwrap().starts_with("text/csv"));
+
+        let served = send(&app, Method::GET, "/files/sub/clip%20one.mp4", None).await;
+        assert_eq!(served.status, StatusCode::OK);
+        assert_eq!(served.body, "fake");
+        assert_eq!(served.headers["content-disposition"], "attachment; filename=\"clip one.mp4\"; filename*=UTF-8''clip%20one.mp4");
+        assert_eq!(send(&app, Method::GET, "/files/missing.mp4", None).await.status, StatusCode::NOT_FOUND);
+
+        let hashed = send(&app, Method::GET, "/files/sub/clip%20one.mp4/checksum?algorithm=md5", None).await;
+        assert_eq!(hashed.body["digest"], "144c9defac04969c7bfad8efaa8ea194");
+        assert_eq!(hashed.body["size"], 4);
+    }
+
+    #[tokio::test]
+    async fn lists_the_size_source_and_media_info_of_each_file() {
+        let _files = lock_files().await;
+        let state = state(FakeRunner::new().script("ffprobe", FFPROBE));
+        let video = download_file(&state, "listed.mp4", b"fake");
+        download_file(&state, "notes.txt", b"hello!");
+        let entry = HistoryEntry {
+            download_key: "https://example.com/listed".to_string(),
+            url: "https://example.com/listed".to_string(),
+            downloaded_at: "2024-01-01T00:00:00Z".to_string(),
+            filepath: Some(video.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        history::append(&[entry]).await.unwrap();
+        let app = app(&state);
+
+        let listed = send(&app, Method::GET, "/files?metadata=true&media_info=true", None).await;
+        assert_eq!(listed.status, StatusCode::OK);
+        let entries = listed.body.as_array().unwrap();
+        let entry = |path: &str| entries.iter().find(|entry| entry["path"] == path).unwrap_or_else(|| panic!("{} not listed", path));
+        assert_eq!(entry("listed.mp4")["size"], 4);
+        assert_eq!(entry("listed.mp4")["source"]["url"], "https://example.com/listed");
+        assert!(entry("listed.mp4")["media_info"].is_object());
+        assert_eq!(entry("notes.txt")["size"], 6);
+        assert!(entry("notes.txt")["source"].is_null());
+        assert!(entry("notes.txt").get("media_info").is_none());
+    }
+
+    #[tokio::test]
+    async fn probes_and_extracts_frames_with_the_ffmpeg_tools() {
+        let runner = FakeRunner::new()
+            .script("ffprobe", FFPROBE)
+            .script("ffmpeg", r#"for last; do :; done; case "$last" in -) printf 'JPEG' ;; *) printf 'JPEG' > "$last" ;; esac"#);
+        let state = state(runner);
+        download_file(&state, "clip.mp4", b"fake");
+        let app = app(&state);
+
+        let probed = send(&app, Method::GET, "/files/clip.mp4/probe", None).await;
+        assert_eq!(probed.status, StatusCode::OK);
+        assert_eq!(probed.body["video_codec"], "h264");
+        assert_eq!(probed.body["duration"], 25.0);
+
+        let frame = send(&app, Method::GET, "/files/clip.mp4/frame?at=00:00:05", None).await;
+        assert_eq!(frame.status, StatusCode::OK);
+        assert_eq!(frame.headers["content-type"], "image/jpeg");
+        assert_eq!(send(&app, Method::GET, "/files/clip.mp4/frame?at=soon", None).await.status, StatusCode::BAD_REQUEST);
+
+        let storyboard = send(&app, Method::POST, "/files/clip.mp4/storyboard?interval=10", None).await;
+        assert_eq!(storyboard.status, StatusCode::OK);
+        assert_eq!(storyboard.body["tiles"], 3);
+        assert_eq!(storyboard.body["sprite"]["size"], 4);
+        let vtt = send(&app, Method::GET, "/files/clip.storyboard.vtt", None).await;
+        assert!(vtt.body.as_str().unwrap().contains("clip.storyboard.jpg#xywh=160,0,160,90"));
+        assert_eq!(send(&app, Method::POST, "/files/clip.mp4", None).await.status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn rebuilds_the_media_index_and_reindexes_the_library() {
+        let _files = lock_files().await;
+        let state = state(FakeRunner::new().script("ffprobe", FFPROBE));
+        download_file(&state, "clip.mp4", b"fake");
+        let app = app(&state);
+
+        let rebuilt = send(&app, Method::POST, "/media-index/rebuild", None).await;
+        assert_eq!(rebuilt.status, StatusCode::ACCEPTED);
+        assert_eq!(rebuilt.body["queued"], 1);
+
+        assert_eq!(send(&app, Method::POST, "/admin/reindex", None).await.status, StatusCode::CONFLICT);
+        state.config.write().unwrap().library_index_interval_secs = Some(3600);
+        let reindexed = send(&app, Method::POST, "/admin/reindex", None).await;
+        assert_eq!(reindexed.status, StatusCode::OK);
+        assert_eq!(reindexed.body["files"], 1);
+    }
+
+    #[tokio::test]
+    async fn shows_history_origins_only_to_callers_with_a_key() {
+        let _files = lock_files().await;
+        let state = state(FakeRunner::new());
+        let entry = HistoryEntry {
+            download_key: "https://example.com/history-origin".to_string(),
+            url: "https://example.com/history-origin".to_string(),
+            title: Some("History origin".to_string()),
+            downloaded_at: "2024-01-01T00:00:00Z".to_string(),
+            origin: Some(crate::models::DownloadOrigin {
+                endpoint: "POST /download".to_string(),
+                user_agent: Some("curl/8.0".to_string()),
+                ip: Some("192.0.2.7".to_string()),
+                api_key: Some("alice".to_string()),
+            }),
+            ..Default::default()
+        };
+        history::append(&[entry]).await.unwrap();
+        state.config.write().unwrap().api_keys.insert("alice".to_string(), crate::config::ApiKey::admin("alice-secret"));
+        let app = app(&state);
+        let ours = |body: &serde_json::Value| {
+            body.as_array().unwrap().iter().find(|e| e["url"] == "https://example.com/history-origin").unwrap().clone()
+        };
+
+        for uri in ["/history", "/history/search?q=history%20origin", "/history/export"] {
+            let anonymous = send(&app, Method::GET, uri, None).await;
+            assert!(ours(&anonymous.body)["origin"].is_null(), "{}: {}", uri, anonymous.body);
+            let keyed = send_with(&app, Request::builder().uri(uri).header("x-api-key", "alice-secret"), None).await;
+            assert_eq!(ours(&keyed.body)["origin"]["ip"], "192.0.2.7", "{}", uri);
+        }
+        let csv = send_with(&app, Request::builder().uri("/history/export").header("accept", "text/csv"), None).await;
+        assert!(!csv.body.as_str().unwrap().contains("192.0.2.7"));
+    }
+
+    #[tokio::test]
+    async fn lists_searches_and_exports_the_history() {
+        let _files = lock_files().await;
+        let state = state(FakeRunner::new());
+        let file = download_file(&state, "history-route.mp4", b"fake");
+        let entry = HistoryEntry {
+            download_key: "https://example.com/history-route".to_string(),
+            url: "https://example.com/history-route".to_string(),
+            title: Some("History route".to_string()),
+            downloaded_at: "2024-01-01T00:00:00Z".to_string(),
+            filepath: Some(file.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        history::append(&[entry]).await.unwrap();
+        let app = app(&state);
+
+        let listed = send(&app, Method::GET, "/history", None).await;
+        let listed = listed.body.as_array().unwrap();
+        let ours = listed.iter().find(|e| e["url"] == "https://example.com/history-route").unwrap();
+        assert_eq!(ours["file_refs"][0]["path"], "history-route.mp4");
+
+        let found = send(&app, Method::GET, "/history/search?q=history%20route", None).await;
+        assert_eq!(found.body.as_array().unwrap().len(), 1);
+        assert_eq!(send(&app, Method::GET, "/history/search?q=", None).await.status, StatusCode::BAD_REQUEST);
+
+        let exported = send(&app, Method::GET, "/history/export", None).await;
+        assert_eq!(exported.headers["content-disposition"], "attachment; filename=\"history.json\"");
+        assert_eq!(exported.body.as_array().unwrap().len(), listed.len());
+        let csv = send_with(&app, Request::builder().uri("/history/export").header("accept", "text/csv"), None).await;
+        assert!(csv.body.as_str().unwrap().contains("https://example.com/history-route"));
+    }
+}
@@ -0,0 +1,386 @@
+use crate::{
+    error::AppError,
+    models::{
+        BatchFormatRequest, BatchFormatResult, DumpJsonEntry, ExtractorArgs, Format, FormatRequest, FormatsResponse,
+        PlaylistEntryFormats, PlaylistFormats, PreviousDownload, VideoInfo,
+    },
+    history, AppState,
+};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use futures::{stream, StreamExt};
+use std::collections::HashMap;
+
+use super::{
+    add_extractor_args, add_http_args, add_session_cookies, check_session, classify_error, is_blocked_age_restricted, validate_extractor_args,
+    validate_http_headers,
+};
+
+/// Routes for probing the formats of one or more URLs.
+pub fn build_router(state: AppState) -> Router {
+    Router::new()
+        .route("/formats", get(list_formats).post(list_formats_post))
+        .route("/formats/batch", post(list_formats_batch))
+        .with_state(state)
+}
+
+// ===================================================================
+//                          FORMATS HANDLER
+// ===================================================================
+
+/// # GET /formats - Fetches available formats for a given video URL.
+/// Each video is looked up in the download history by ID and reported with
+/// `already_downloaded`, plus `previous_download` when it was; `?include_history=false` skips it.
+pub async fn list_formats(
+    State(state): State<AppState>,
+    Query(params): Query<FormatRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    formats_for_request(&state, params).await
+}
+
+/// # POST /formats - Like `GET /formats`, with the request as a JSON body so it can carry
+/// `http_headers`.
+pub async fn list_formats_post(
+    State(state): State<AppState>,
+    Json(params): Json<FormatRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    formats_for_request(&state, params).await
+}
+
+/// Probes a URL like `GET /formats` without extra headers, for the `formats` CLI command.
+pub(crate) async fn formats_for_url(state: &AppState, url: &str, include_all: bool) -> Result<FormatsResponse, AppError> {
+    let mut info = probe_formats(state, url, &ExtractorArgs::new(), (&HashMap::new(), None), None).await?;
+    if state.config.read().unwrap().filter_formats && !include_all {
+        filter_downloadable_formats(&mut info);
+    }
+    Ok(info)
+}
+
+async fn formats_for_request(state: &AppState, params: FormatRequest) -> Result<impl IntoResponse, AppError> {
+    validate_http_headers(&params.http_headers, params.user_agent.as_deref())?;
+    validate_extractor_args(&params.extractor_args)?;
+    let http = (&params.http_headers, params.user_agent.as_deref());
+    let mut info = probe_formats(state, &params.url, &params.extractor_args, http, params.session_id.as_deref()).await?;
+    if state.config.read().unwrap().filter_formats && !params.include_all {
+        filter_downloadable_formats(&mut info);
+    }
+    if params.include_history {
+        match &mut info {
+            FormatsResponse::Video(video) => mark_previous_download(video).await,
+            FormatsResponse::Playlist(playlist) => {
+                for entry in &mut playlist.entries {
+                    mark_previous_download(&mut entry.info).await;
+                }
+            }
+        }
+    }
+    Ok((StatusCode::OK, Json(info)))
+}
+
+/// Sets `already_downloaded` and `previous_download` from the history entry for the video's
+/// ID. Without an ID, or when the history can't be read, the video counts as not downloaded.
+async fn mark_previous_download(info: &mut VideoInfo) {
+    let Some(id) = info.id.as_deref() else { return };
+    match history::latest_for_video(id).await {
+        Ok(entry) => {
+            info.already_downloaded = entry.is_some();
+            info.previous_download = entry.map(|entry| PreviousDownload {
+                files: if entry.files.is_empty() { entry.filepath.into_iter().collect() } else { entry.files },
+                download_key: entry.download_key,
+                downloaded_at: entry.downloaded_at,
+                format: entry.format,
+            });
+        }
+        Err(e) => tracing::warn!("Could not look up {} in the download history: {:?}", id, e),
+    }
+}
+
+/// # POST /formats/batch - Fetches formats for several URLs, bounded by `max_concurrent_probes`.
+pub async fn list_formats_batch(
+    State(state): State<AppState>,
+    Json(payload): Json<BatchFormatRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    if payload.urls.is_empty() {
+        return Err(AppError::BadRequest("At least one URL is required".to_string()));
+    }
+    validate_extractor_args(&payload.extractor_args)?;
+    check_session(&state, payload.session_id.as_deref())?;
+    let extractor_args = &payload.extractor_args;
+    let session_id = payload.session_id.as_deref();
+    let no_headers = &HashMap::new();
+    let concurrency = state.config.read().unwrap().max_concurrent_probes.max(1);
+    let filter = state.config.read().unwrap().filter_formats && !payload.include_all;
+
+    let mut results: Vec<(usize, BatchFormatResult)> = stream::iter(payload.urls.into_iter().enumerate())
+        .map(|(index, url)| {
+            let state = state.clone();
+            async move {
+                let result = match probe_formats(&state, &url, extractor_args, (no_headers, None), session_id).await {
+                    Ok(mut info) => {
+                        if filter {
+                            filter_downloadable_formats(&mut info);
+                        }
+                        BatchFormatResult { url, info: Some(info), error: None }
+                    }
+                    Err(e) => {
+                        if let AppError::Internal(inner) = &e {
+                            tracing::error!("Batch probe for {} failed: {:?}", url, inner);
+                        }
+                        BatchFormatResult { url, info: None, error: Some(e.to_string()) }
+                    }
+                };
+                (index, result)
+            }
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+
+    // Probes finish in any order; report them in request order.
+    results.sort_by_key(|(index, _)| *index);
+    let results: Vec<BatchFormatResult> = results.into_iter().map(|(_, r)| r).collect();
+    Ok((StatusCode::OK, Json(results)))
+}
+
+/// Runs `yt-dlp --dump-json` for a URL and parses the result.
+/// Single-video results are cached so download validation can inspect the chosen format.
+/// Playlists are only probed up to `playlist_probe_entries` entries.
+/// `http` holds the request's own headers and user agent, applied over the config's.
+async fn probe_formats(
+    state: &AppState,
+    url: &str,
+    extractor_args: &ExtractorArgs,
+    http: (&HashMap<String, String>, Option<&str>),
+    session_id: Option<&str>,
+) -> Result<FormatsResponse, AppError> {
+    if url.is_empty() {
+        return Err(AppError::BadRequest("URL parameter cannot be empty".to_string()));
+    }
+    tracing::info!("Fetching formats for URL: {}", url);
+
+    let limit = state.config.read().unwrap().playlist_probe_entries.max(1);
+    let mut cmd = state.runner.command("yt-dlp");
+    cmd.arg("--dump-json")
+       .arg("--playlist-items").arg(format!("1:{}", limit));
+    add_extractor_args(&mut cmd, extractor_args);
+    add_http_args(&mut cmd, state, url, http.0, http.1);
+    if let Some(id) = session_id {
+        add_session_cookies(&mut cmd, state, id)?;
+    }
+    let output = cmd.arg(url).output().await?;
+
+    if !output.status.success() {
+        let error_message = String::from_utf8_lossy(&output.stderr).to_string();
+        tracing::error!("yt-dlp failed: {}", error_message);
+        return Err(match classify_error(&error_message) {
+            Some(code) => AppError::Classified(code, error_message),
+            None => AppError::YtDlp(error_message),
+        });
+    }
+
+    let mut response = parse_dump_json(&output.stdout, limit)?;
+    match &mut response {
+        FormatsResponse::Video(info) => {
+            tracing::info!("Successfully fetched {} formats for '{}'", info.formats.len(), info.title);
+            // Cached even when blocked, so a download of the same URL is refused without a re-probe.
+            state.formats_cache.insert(url.to_string(), info.clone());
+            if is_blocked_age_restricted(state, info) {
+                return Err(AppError::Forbidden(format!("'{}' is age-restricted and block_age_restricted is set", info.title)));
+            }
+        }
+        FormatsResponse::Playlist(playlist) => {
+            playlist.entries.retain(|entry| !is_blocked_age_restricted(state, &entry.info));
+            tracing::info!(
+                "Successfully fetched formats for {} playlist entries of '{}'",
+                playlist.entries.len(),
+                playlist.playlist_title.as_deref().unwrap_or(url)
+            );
+        }
+    }
+    Ok(response)
+}
+
+/// Drops formats a user can't meaningfully pick: storyboards, entries with neither a video
+/// nor an audio stream, and zero-bitrate entries. The cache keeps the full list.
+fn filter_downloadable_formats(response: &mut FormatsResponse) {
+    let is_downloadable = |f: &Format| {
+        let storyboard = f.ext == "mhtml"
+            || f.format_note.as_deref().is_some_and(|n| n.eq_ignore_ascii_case("storyboard"));
+        let no_streams = f.vcodec == "none" && f.acodec == "none";
+        let zero_bitrate = f.tbr == Some(0.0);
+        !(storyboard || no_streams || zero_bitrate)
+    };
+    match response {
+        FormatsResponse::Video(info) => info.formats.retain(is_downloadable),
+        FormatsResponse::Playlist(playlist) => {
+            for entry in &mut playlist.entries {
+                entry.info.formats.retain(is_downloadable);
+            }
+        }
+    }
+}
+
+/// Parses `--dump-json` output, which holds one JSON object per line for playlists.
+/// A single object without playlist context is a plain video.
+fn parse_dump_json(stdout: &[u8], limit: usize) -> Result<FormatsResponse, AppError> {
+    let mut entries = Vec::new();
+    for line in stdout.split(|&b| b == b'\n').filter(|l| !l.iter().all(u8::is_ascii_whitespace)) {
+        if entries.len() == limit {
+            break;
+        }
+        entries.push(serde_json::from_slice::<DumpJsonEntry>(line)?);
+    }
+
+    if entries.len() == 1 && entries[0].playlist_count.is_none() && entries[0].playlist_index.is_none() {
+        return Ok(FormatsResponse::Video(entries.remove(0).info));
+    }
+    if entries.is_empty() {
+        return Err(AppError::YtDlp("yt-dlp returned no entries for this URL".to_string()));
+    }
+
+    let playlist_title = entries[0].playlist_title.clone();
+    let total_entries = entries[0].playlist_count;
+    let truncated = total_entries.map_or(entries.len() == limit, |total| total as usize > entries.len());
+    let entries = entries
+        .into_iter()
+        .map(|entry| PlaylistEntryFormats {
+            url: entry.webpage_url,
+            playlist_index: entry.playlist_index,
+            info: entry.info,
+        })
+        .collect();
+
+    Ok(FormatsResponse::Playlist(PlaylistFormats { playlist_title, total_entries, entries, truncated }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::history;
+    use crate::models::{FormatsResponse, HistoryEntry};
+    use crate::test_support::{app, fake_ytdlp, lock_files, send, state, FakeRunner, VIDEO_JSON};
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn lists_the_downloadable_formats() {
+        let state = state(fake_ytdlp());
+        let app = app(&state);
+        let response = send(&app, Method::GET, "/formats?url=https%3A%2F%2Fexample.com%2Fformats&include_history=false", None).await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.body["title"], "Video");
+        let ids: Vec<&str> = response.body["formats"].as_array().unwrap().iter().map(|f| f["format_id"].as_str().unwrap()).collect();
+        assert_eq!(ids, ["137", "140"]);
+        assert_eq!(state.formats_cache.get("https://example.com/formats").unwrap().formats.len(), 3);
+
+        let body = json!({ "url": "https://example.com/formats", "include_all": true, "include_history": false });
+        let response = send(&app, Method::POST, "/formats", Some(body)).await;
+        assert_eq!(response.body["formats"].as_array().unwrap().len(), 3);
+        assert_eq!(response.body["already_downloaded"], false);
+    }
+
+    #[tokio::test]
+    async fn probes_with_the_extractor_args_of_the_download() {
+        // Lists the formats only for the player client asked for.
+        let runner = FakeRunner::new().script(
+            "yt-dlp",
+            r#"case "$*" in *"--extractor-args youtube:player_client=android "*) echo "$VIDEO_JSON" ;; *) echo "ERROR: $*" >&2; exit 1 ;; esac"#,
+        );
+        let app = app(&state(runner));
+        let body = json!({ "url": "https://example.com/client", "extractor_args": { "youtube": { "player_client": "android" } }, "include_history": false });
+        let response = send(&app, Method::POST, "/formats", Some(body)).await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        assert_eq!(response.body["title"], "Video");
+
+        let invalid = json!({ "url": "https://example.com/client", "extractor_args": { "you tube": { "player_client": "android" } } });
+        assert_eq!(send(&app, Method::POST, "/formats", Some(invalid)).await.status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn always_says_whether_the_video_was_downloaded_before() {
+        let _files = lock_files().await;
+        let state = state(fake_ytdlp());
+        let entry = HistoryEntry {
+            download_key: "https://example.com/before".to_string(),
+            url: "https://example.com/before".to_string(),
+            video_id: Some("abc".to_string()),
+            downloaded_at: "2024-01-01T00:00:00Z".to_string(),
+            ..Default::default()
+        };
+        history::append(&[entry]).await.unwrap();
+        let seen = send(&app(&state), Method::GET, "/formats?url=https%3A%2F%2Fexample.com%2Fbefore", None).await;
+        assert_eq!(seen.body["already_downloaded"], true);
+        assert_eq!(seen.body["previous_download"]["download_key"], "https://example.com/before");
+
+        let without_id = VIDEO_JSON.replace(r#""id": "abc", "#, "");
+        let script = format!(r#"case "$*" in *--dump-json*) printf '%s' '{}' ;; *) exit 1 ;; esac"#, without_id);
+        let app = app(&crate::test_support::state(FakeRunner::new().script("yt-dlp", &script)));
+        let unseen = send(&app, Method::GET, "/formats?url=https%3A%2F%2Fexample.com%2Fno-id", None).await;
+        assert_eq!(unseen.status, StatusCode::OK, "{}", unseen.body);
+        assert_eq!(unseen.body["already_downloaded"], false);
+        assert!(unseen.body.get("previous_download").is_none());
+    }
+
+    /// `--dump-json` output for the first three entries of a 25-entry playlist.
+    const PLAYLIST_DUMP: &str = concat!(
+        r#"{"id": "a1", "title": "First", "thumbnail": null, "webpage_url": "https://example.com/a1", "playlist_title": "Mix", "playlist_count": 25, "playlist_index": 1, "formats": [{"format_id": "18", "ext": "mp4", "resolution": "640x360", "vcodec": "avc1", "acodec": "mp4a"}]}"#,
+        "\n",
+        r#"{"id": "a2", "title": "Second", "thumbnail": null, "webpage_url": "https://example.com/a2", "playlist_title": "Mix", "playlist_count": 25, "playlist_index": 2, "formats": [{"format_id": "22", "ext": "mp4", "resolution": "1280x720", "vcodec": "avc1", "acodec": "mp4a"}]}"#,
+        "\n",
+        r#"{"id": "a3", "title": "Third", "thumbnail": null, "webpage_url": "https://example.com/a3", "playlist_title": "Mix", "playlist_count": 25, "playlist_index": 3, "formats": []}"#,
+        "\n",
+    );
+
+    #[tokio::test]
+    async fn summarizes_the_first_entries_of_a_playlist() {
+        // Answers only a probe limited to the configured number of entries, and prints more
+        // than it was asked for, as some extractors do.
+        let script = format!(r#"case "$*" in *"--playlist-items 1:2"*) printf '%s' '{}' ;; *) exit 1 ;; esac"#, PLAYLIST_DUMP);
+        let state = state(FakeRunner::new().script("yt-dlp", &script));
+        state.config.write().unwrap().playlist_probe_entries = 2;
+        let response = send(&app(&state), Method::GET, "/formats?url=https%3A%2F%2Fexample.com%2Fmix&include_history=false", None).await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        assert_eq!(response.body["playlist_title"], "Mix");
+        assert_eq!(response.body["total_entries"], 25);
+        assert_eq!(response.body["truncated"], true);
+        let entries = response.body["entries"].as_array().unwrap();
+        let summary: Vec<_> = entries.iter().map(|e| (e["playlist_index"].clone(), e["url"].clone(), e["title"].clone())).collect();
+        assert_eq!(summary, [
+            (json!(1), json!("https://example.com/a1"), json!("First")),
+            (json!(2), json!("https://example.com/a2"), json!("Second")),
+        ]);
+        assert_eq!(entries[1]["formats"][0]["format_id"], "22");
+        // Only single videos are cached for download validation.
+        assert!(state.formats_cache.get("https://example.com/mix").is_none());
+    }
+
+    #[test]
+    fn a_playlist_without_a_count_is_truncated_when_the_limit_is_reached() {
+        let without_count = PLAYLIST_DUMP.replace(r#""playlist_count": 25, "#, "");
+        let Ok(FormatsResponse::Playlist(limited)) = super::parse_dump_json(without_count.as_bytes(), 3) else { panic!("not a playlist") };
+        assert!(limited.truncated && limited.total_entries.is_none() && limited.entries.len() == 3);
+        let Ok(FormatsResponse::Playlist(all)) = super::parse_dump_json(without_count.as_bytes(), 4) else { panic!("not a playlist") };
+        assert!(!all.truncated && all.entries.len() == 3);
+    }
+
+    #[tokio::test]
+    async fn batch_results_keep_the_request_order() {
+        let runner = FakeRunner::new().script("yt-dlp", r#"case "$*" in *fail*) echo 'ERROR: gone' >&2; exit 1 ;; *) echo "$VIDEO_JSON" ;; esac"#);
+        let app = app(&state(runner));
+        let body = json!({ "urls": ["https://example.com/a", "https://example.com/fail", "https://example.com/c"] });
+        let response = send(&app, Method::POST, "/formats/batch", Some(body)).await;
+        assert_eq!(response.status, StatusCode::OK);
+        let results = response.body.as_array().unwrap();
+        let urls: Vec<&str> = results.iter().map(|r| r["url"].as_str().unwrap()).collect();
+        assert_eq!(urls, ["https://example.com/a", "https://example.com/fail", "https://example.com/c"]);
+        assert!(results[1]["error"].as_str().unwrap().contains("gone"));
+        assert_eq!(results[2]["info"]["title"], "Video");
+
+        let empty = send(&app, Method::POST, "/formats/batch", Some(json!({ "urls": [] }))).await;
+        assert_eq!(empty.status, StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,176 @@
+use crate::{
+    error::AppError,
+    history, library_index,
+    models::{HistoryEntry, LibraryGroup, LibraryItem, LibraryQuery},
+    AppState,
+};
+use axum::{
+    extract::{Path, Query, State},
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use std::collections::BTreeMap;
+
+use super::{files::{file_ref, walk_files}, resolve_download_root};
+
+/// The group of files whose history doesn't name an uploader or playlist.
+const UNKNOWN_GROUP: &str = "unknown";
+
+/// Routes for browsing downloaded files as collections, grouped by their download history.
+pub fn build_router(state: AppState) -> Router {
+    Router::new()
+        .route("/library/uploaders", get(list_uploaders))
+        .route("/library/uploaders/:name", get(get_uploader))
+        .route("/library/playlists", get(list_playlists))
+        .route("/library/playlists/:name", get(get_playlist))
+        .with_state(state)
+}
+
+// ===================================================================
+//                          LIBRARY HANDLERS
+// ===================================================================
+
+/// # GET /library/uploaders - Lists the uploaders of the downloaded files, with counts and sizes.
+pub async fn list_uploaders(
+    State(state): State<AppState>,
+    Query(query): Query<LibraryQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let items = library_items(&state, &query).await?;
+    Ok(Json(summarize_groups(items, |entry| entry.uploader.clone())))
+}
+
+/// # GET /library/uploaders/:name - Returns one uploader's group with its files.
+pub async fn get_uploader(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(query): Query<LibraryQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let items = library_items(&state, &query).await?;
+    Ok(Json(find_group(items, &name, |entry| entry.uploader.clone())?))
+}
+
+/// # GET /library/playlists - Lists the playlists the downloaded files came from, with counts and sizes.
+pub async fn list_playlists(
+    State(state): State<AppState>,
+    Query(query): Query<LibraryQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let items = library_items(&state, &query).await?;
+    Ok(Json(summarize_groups(items, |entry| entry.playlist.clone())))
+}
+
+/// # GET /library/playlists/:name - Returns one playlist's group with its files.
+pub async fn get_playlist(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(query): Query<LibraryQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let items = library_items(&state, &query).await?;
+    Ok(Json(find_group(items, &name, |entry| entry.playlist.clone())?))
+}
+
+// ===================================================================
+//                          HELPER FUNCTIONS
+// ===================================================================
+
+/// Every file in the download root, joined with its history entry.
+async fn library_items(state: &AppState, query: &LibraryQuery) -> Result<Vec<LibraryItem>, AppError> {
+    let download_dir = resolve_download_root(state, query.root.as_deref())?;
+    let sources = history::by_file(history::load().await?);
+    let mut items = Vec::new();
+    if let Some(files) = library_index::indexed(state, &download_dir).await? {
+        for file in files {
+            let full_path = download_dir.join(&file.path);
+            let file_ref = file_ref(state, query.root.as_deref(), &file.path, Some(file.size));
+            items.push(LibraryItem { file: file_ref, size_bytes: file.size, source: sources.get(&full_path).cloned() });
+        }
+        return Ok(items);
+    }
+    for path in walk_files(state, &download_dir).await? {
+        let full_path = download_dir.join(&path);
+        let size_bytes = tokio::fs::metadata(&full_path).await.map(|m| m.len()).unwrap_or_default();
+        let file_ref = file_ref(state, query.root.as_deref(), &path, Some(size_bytes));
+        items.push(LibraryItem { file: file_ref, size_bytes, source: sources.get(&full_path).cloned() });
+    }
+    Ok(items)
+}
+
+/// Splits the items by the name `key` takes from their history; the rest go to "unknown".
+fn group_items(items: Vec<LibraryItem>, key: fn(&HistoryEntry) -> Option<String>) -> BTreeMap<String, Vec<LibraryItem>> {
+    let mut groups: BTreeMap<String, Vec<LibraryItem>> = BTreeMap::new();
+    for item in items {
+        let name = item.source.as_ref().and_then(key).filter(|name| !name.trim().is_empty());
+        groups.entry(name.unwrap_or_else(|| UNKNOWN_GROUP.to_string())).or_default().push(item);
+    }
+    groups
+}
+
+fn summarize(name: String, items: &[LibraryItem]) -> LibraryGroup {
+    LibraryGroup {
+        name,
+        count: items.len(),
+        total_bytes: items.iter().map(|item| item.size_bytes).sum(),
+        latest_download: items.iter().filter_map(|item| item.source.as_ref()).map(|e| e.downloaded_at.clone()).max(),
+        items: None,
+    }
+}
+
+/// The groups sorted by name, without their items.
+fn summarize_groups(items: Vec<LibraryItem>, key: fn(&HistoryEntry) -> Option<String>) -> Vec<LibraryGroup> {
+    group_items(items, key).into_iter().map(|(name, items)| summarize(name, &items)).collect()
+}
+
+fn find_group(items: Vec<LibraryItem>, name: &str, key: fn(&HistoryEntry) -> Option<String>) -> Result<LibraryGroup, AppError> {
+    let items = group_items(items, key)
+        .remove(name)
+        .ok_or_else(|| AppError::NotFound(format!("No downloaded files belong to '{}'.", name)))?;
+    let mut group = summarize(name.to_string(), &items);
+    group.items = Some(items);
+    Ok(group)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::history;
+    use crate::models::HistoryEntry;
+    use crate::test_support::{app, send, state, FakeRunner};
+    use axum::http::{Method, StatusCode};
+    use std::path::Path;
+
+    #[tokio::test]
+    async fn groups_files_by_uploader_and_playlist() {
+        let state = state(FakeRunner::new());
+        let download_dir = state.config.read().unwrap().download_directory.clone();
+        let known = Path::new(&download_dir).join("known.mp4");
+        std::fs::write(&known, b"12345").unwrap();
+        std::fs::write(Path::new(&download_dir).join("stray.mp4"), b"1").unwrap();
+        history::append(&[HistoryEntry {
+            download_key: "https://example.com/v".to_string(),
+            url: "https://example.com/v".to_string(),
+            uploader: Some("Alice".to_string()),
+            playlist: Some("Mix".to_string()),
+            downloaded_at: "2024-01-01T00:00:00Z".to_string(),
+            filepath: Some(known.to_string_lossy().to_string()),
+            ..Default::default()
+        }])
+        .await
+        .unwrap();
+        let app = app(&state);
+
+        let uploaders = send(&app, Method::GET, "/library/uploaders", None).await;
+        assert_eq!(uploaders.status, StatusCode::OK);
+        let names: Vec<&str> = uploaders.body.as_array().unwrap().iter().map(|g| g["name"].as_str().unwrap()).collect();
+        assert_eq!(names, ["Alice", "unknown"]);
+
+        let alice = send(&app, Method::GET, "/library/uploaders/Alice", None).await;
+        assert_eq!(alice.body["count"], 1);
+        assert_eq!(alice.body["total_bytes"], 5);
+
+        let playlists = send(&app, Method::GET, "/library/playlists", None).await;
+        assert_eq!(playlists.body[0]["name"], "Mix");
+        let mix = send(&app, Method::GET, "/library/playlists/Mix", None).await;
+        assert_eq!(mix.body["items"].as_array().unwrap().len(), 1);
+
+        assert_eq!(send(&app, Method::GET, "/library/playlists/Nope", None).await.status, StatusCode::NOT_FOUND);
+    }
+}
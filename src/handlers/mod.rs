@@ -0,0 +1,648 @@
+//! HTTP handlers, grouped by area. Each module exposes a `build_router` that
+//! `run_server` merges into the application router.
+
+use crate::{
+    config::{http_header_problems, ApiKey},
+    error::{AppError, RequestContext, CORRELATION_HEADER, REQUEST},
+    models::{DownloadOrigin, DownloadRequest, ErrorCode, ExtractorArgs, VideoInfo},
+    AppState,
+};
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts, MatchedPath, Request, State},
+    http::{header, request::Parts, Extensions, HeaderMap, HeaderValue, StatusCode, Version},
+    middleware::Next,
+    response::Response,
+};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::path::{Path as FsPath, PathBuf};
+use tokio::process::Command;
+use tower_http::compression::{predicate::DefaultPredicate, CompressionLayer, Predicate};
+
+pub mod caches;
+pub mod channel;
+pub mod config;
+pub mod download;
+pub mod files;
+pub mod formats;
+pub mod library;
+pub mod session;
+pub mod setup;
+pub mod status;
+pub mod uploads;
+pub mod ytdlp;
+
+/// Marks a response that `compress_responses` leaves alone, such as media files and archives
+/// that are compressed already.
+#[derive(Clone, Copy, Debug)]
+pub struct Uncompressed;
+
+/// Compresses responses with gzip or brotli when the client's `Accept-Encoding` allows it and
+/// `compress_responses` is on. Responses marked [`Uncompressed`] are left alone, and so are
+/// images, event streams and tiny bodies.
+pub fn compression_layer(state: &AppState) -> CompressionLayer<impl Predicate> {
+    let state = state.clone();
+    let wanted = move |_: StatusCode, _: Version, _: &HeaderMap, extensions: &Extensions| {
+        extensions.get::<Uncompressed>().is_none() && state.config.read().unwrap().compress_responses
+    };
+    CompressionLayer::new().compress_when(DefaultPredicate::new().and(wanted))
+}
+
+/// The longest correlation ID taken over from a client's `X-Request-Id`.
+const MAX_CORRELATION_ID_CHARS: usize = 128;
+
+/// Gives each request a correlation ID, which error bodies and their log lines carry and every
+/// response returns as `X-Request-Id`. A client's own `X-Request-Id` is kept if it is short
+/// and plain; otherwise a new one is made up. Internal errors only carry their error chain for
+/// requests with an admin key.
+pub async fn correlate(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let correlation_id = request
+        .headers()
+        .get(CORRELATION_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_CORRELATION_ID_CHARS
+                && id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        })
+        .map_or_else(|| uuid::Uuid::new_v4().to_string(), str::to_string);
+    let admin = matches!(authenticate(&state, request.headers()), Ok(Some((_, key))) if key.is_admin());
+    let debug_errors = admin && state.config.read().unwrap().debug_errors;
+    let context = RequestContext { correlation_id: correlation_id.clone(), debug_errors };
+    let mut response = REQUEST.scope(context, next.run(request)).await;
+    if let Ok(value) = HeaderValue::from_str(&correlation_id) {
+        response.headers_mut().insert(CORRELATION_HEADER, value);
+    }
+    response
+}
+
+/// The longest `User-Agent` kept in a download's origin.
+const MAX_ORIGIN_USER_AGENT_CHARS: usize = 256;
+
+/// The header clients send their API key in.
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// Extracts who sent a request that starts downloads: the route, the `User-Agent`, the API key
+/// and, unless `record_client_ip` is off, the peer address. Refuses the request when `api_keys`
+/// are set and it doesn't carry one of them.
+pub struct ClientOrigin(pub DownloadOrigin);
+
+/// Returns the name and entry of the API key in `headers`. Fails with 401 for an unknown key,
+/// and for a missing one once any `api_keys` are set.
+fn authenticate(state: &AppState, headers: &HeaderMap) -> Result<Option<(String, ApiKey)>, AppError> {
+    let config = state.config.read().unwrap();
+    let Some(secret) = headers.get(API_KEY_HEADER) else {
+        if config.api_keys.is_empty() {
+            return Ok(None);
+        }
+        return Err(AppError::Unauthorized("An API key is required; send it in the X-Api-Key header.".to_string()));
+    };
+    match find_api_key(&config.api_keys, secret.as_bytes()) {
+        Some((name, key)) => Ok(Some((name.clone(), key.clone()))),
+        None => Err(AppError::Unauthorized("Unknown API key".to_string())),
+    }
+}
+
+/// Finds the key whose secret is `secret`. Compares digests of the secrets, and compares against
+/// every key, so the time taken doesn't tell how much of a guess was right.
+fn find_api_key<'a>(keys: &'a BTreeMap<String, ApiKey>, secret: &[u8]) -> Option<(&'a String, &'a ApiKey)> {
+    let wanted = Sha256::digest(secret);
+    let mut found = None;
+    for (name, key) in keys {
+        let digest = Sha256::digest(key.secret.as_bytes());
+        let difference = wanted.iter().zip(digest.iter()).fold(0u8, |acc, (a, b)| acc | (a ^ b));
+        if difference == 0 && found.is_none() {
+            found = Some((name, key));
+        }
+    }
+    found
+}
+
+/// Returns the name of the API key in `headers`. Fails for an unknown key, and for a missing
+/// one once any `api_keys` are set.
+pub(crate) fn api_key(state: &AppState, headers: &HeaderMap) -> Result<Option<String>, AppError> {
+    Ok(authenticate(state, headers)?.map(|(name, _)| name))
+}
+
+/// Requires an API key with the admin role once any `api_keys` are set: 401 without a known
+/// key, 403 with a user key. Holds the admin key's name, or `None` while no keys are set.
+pub struct AdminKey(pub Option<String>);
+
+#[async_trait]
+impl FromRequestParts<AppState> for AdminKey {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        match authenticate(state, &parts.headers)? {
+            None => Ok(AdminKey(None)),
+            Some((name, key)) if key.is_admin() => Ok(AdminKey(Some(name))),
+            Some((name, _)) => Err(AppError::Forbidden(format!("API key '{}' is not an admin key", name))),
+        }
+    }
+}
+
+/// Requires a known API key of any role once any `api_keys` are set, for routes that change
+/// downloads without starting one. Holds the key's name.
+pub struct ApiCaller(pub Option<String>);
+
+#[async_trait]
+impl FromRequestParts<AppState> for ApiCaller {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        Ok(ApiCaller(api_key(state, &parts.headers)?))
+    }
+}
+
+/// Whether the caller may see who started each download: anyone while no `api_keys` are set,
+/// otherwise only callers with a known key. Refuses an unknown key.
+pub struct SeesOrigins(pub bool);
+
+#[async_trait]
+impl FromRequestParts<AppState> for SeesOrigins {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        if parts.headers.get(API_KEY_HEADER).is_none() {
+            return Ok(SeesOrigins(state.config.read().unwrap().api_keys.is_empty()));
+        }
+        authenticate(state, &parts.headers)?;
+        Ok(SeesOrigins(true))
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for ClientOrigin {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let path = parts.extensions.get::<MatchedPath>().map_or_else(|| parts.uri.path().to_string(), |p| p.as_str().to_string());
+        let user_agent = parts
+            .headers
+            .get(header::USER_AGENT)
+            .map(|value| String::from_utf8_lossy(value.as_bytes()).chars().take(MAX_ORIGIN_USER_AGENT_CHARS).collect());
+        let ip = if state.config.read().unwrap().record_client_ip {
+            parts.extensions.get::<ConnectInfo<SocketAddr>>().map(|info| info.0.ip().to_string())
+        } else {
+            None
+        };
+        let api_key = api_key(state, &parts.headers)?;
+        Ok(ClientOrigin(DownloadOrigin { endpoint: format!("{} {}", parts.method, path), user_agent, ip, api_key }))
+    }
+}
+
+/// Helper to resolve a requested download root against the config.
+/// `None` selects the primary directory; anything else must be the primary
+/// directory or one of `allowed_download_roots`.
+pub(crate) fn resolve_download_root(state: &AppState, requested: Option<&str>) -> Result<PathBuf, AppError> {
+    let config = state.config.read().unwrap();
+    let Some(requested) = requested else {
+        return Ok(PathBuf::from(&config.download_directory));
+    };
+
+    let allowed: Vec<&String> = std::iter::once(&config.download_directory)
+        .chain(config.allowed_download_roots.iter())
+        .collect();
+    if allowed.iter().any(|root| FsPath::new(root.as_str()) == FsPath::new(requested)) {
+        return Ok(PathBuf::from(requested));
+    }
+
+    let allowed_list = allowed.iter().map(|r| r.as_str()).collect::<Vec<_>>().join(", ");
+    Err(AppError::Forbidden(format!(
+        "Download root '{}' is not allowed. Allowed roots: {}",
+        requested, allowed_list
+    )))
+}
+
+/// Checks that extractor args can be rendered unambiguously: names and keys are plain
+/// identifiers and values don't contain the `;` that separates arguments.
+pub(crate) fn validate_extractor_args(args: &ExtractorArgs) -> Result<(), AppError> {
+    let is_identifier = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    for (extractor, values) in args {
+        if !is_identifier(extractor) {
+            return Err(AppError::BadRequest(format!("Invalid extractor name '{}' in extractor_args", extractor)));
+        }
+        if values.is_empty() {
+            return Err(AppError::BadRequest(format!("extractor_args for '{}' must not be empty", extractor)));
+        }
+        for (key, value) in values {
+            if !is_identifier(key) {
+                return Err(AppError::BadRequest(format!("Invalid key '{}' in extractor_args for '{}'", key, extractor)));
+            }
+            if value.contains(';') {
+                return Err(AppError::BadRequest(format!(
+                    "Value of '{}:{}' in extractor_args must not contain ';'",
+                    extractor, key
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Renders extractor args as `extractor:key=value;key=value`, one string per extractor,
+/// sorted so the command line is stable.
+fn render_extractor_args(args: &ExtractorArgs) -> Vec<String> {
+    let mut rendered: Vec<String> = args
+        .iter()
+        .map(|(extractor, values)| {
+            let mut pairs: Vec<String> = values.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+            pairs.sort();
+            format!("{}:{}", extractor, pairs.join(";"))
+        })
+        .collect();
+    rendered.sort();
+    rendered
+}
+
+/// Adds an `--extractor-args` option per extractor.
+pub(crate) fn add_extractor_args(cmd: &mut Command, args: &ExtractorArgs) {
+    for value in render_extractor_args(args) {
+        cmd.arg("--extractor-args").arg(value);
+    }
+}
+
+/// Rejects request headers or a user agent that `http_header_problems` finds fault with.
+pub(crate) fn validate_http_headers(headers: &HashMap<String, String>, user_agent: Option<&str>) -> Result<(), AppError> {
+    match http_header_problems(headers, user_agent).first() {
+        Some(problem) => Err(AppError::BadRequest(problem.clone())),
+        None => Ok(()),
+    }
+}
+
+/// Adds `--user-agent` and one `--add-headers` per header: the config's defaults, with the
+/// request's own headers replacing any of the same name (compared case-insensitively).
+/// Without a `Referer` among them, the `host_referers` entry for `url`'s host is sent.
+pub(crate) fn add_http_args(cmd: &mut Command, state: &AppState, url: &str, headers: &HashMap<String, String>, user_agent: Option<&str>) {
+    let config = state.config.read().unwrap();
+    let mut merged: Vec<(&String, &String)> = config
+        .http_headers
+        .iter()
+        .filter(|(name, _)| !headers.keys().any(|n| n.eq_ignore_ascii_case(name)))
+        .chain(headers.iter())
+        .collect();
+    merged.sort_by_key(|(name, _)| name.to_ascii_lowercase());
+    if let Some(agent) = user_agent.or(config.user_agent.as_deref()) { cmd.arg("--user-agent").arg(agent); }
+    if !merged.iter().any(|(name, _)| name.eq_ignore_ascii_case("referer")) {
+        if let Some(referer) = host_referer(&config.host_referers, url) {
+            cmd.arg("--referer").arg(referer);
+        }
+    }
+    for (name, value) in merged {
+        cmd.arg("--add-headers").arg(format!("{}:{}", name, value));
+    }
+}
+
+/// The `host_referers` value for the host of `url`, or of its closest parent domain listed.
+/// Values that aren't safe to send (see `config::is_safe_referer`) are ignored.
+fn host_referer<'a>(referers: &'a HashMap<String, String>, url: &str) -> Option<&'a str> {
+    let host = reqwest::Url::parse(url).ok()?.host_str()?.to_ascii_lowercase();
+    referers
+        .iter()
+        .filter(|(listed, referer)| {
+            let listed = listed.to_ascii_lowercase();
+            (host == listed || host.ends_with(&format!(".{}", listed))) && crate::config::is_safe_referer(referer)
+        })
+        .max_by_key(|(listed, _)| listed.len())
+        .map(|(_, referer)| referer.as_str())
+}
+
+/// Adds the configured cookies: `cookies_file` if set, otherwise `cookies_from_browser`.
+pub(crate) fn add_cookie_args(cmd: &mut Command, state: &AppState) {
+    let config = state.config.read().unwrap();
+    if let Some(path) = &config.cookies_file {
+        cmd.arg("--cookies").arg(path);
+    } else if let Some(browser) = &config.cookies_from_browser {
+        cmd.arg("--cookies-from-browser").arg(browser);
+    }
+}
+
+/// Whether cookies are configured for `add_cookie_args`.
+pub(crate) fn has_cookies(state: &AppState) -> bool {
+    let config = state.config.read().unwrap();
+    config.cookies_file.is_some() || config.cookies_from_browser.is_some()
+}
+
+/// Phrases in yt-dlp's error output for a video that needs an age-verified account.
+const AGE_RESTRICTED_PATTERNS: &[&str] = &[
+    "sign in to confirm your age",
+    "age-restricted",
+    "age restricted",
+    "inappropriate for some users",
+];
+
+/// Lowercase fragments of yt-dlp errors for a site that couldn't be reached.
+const NETWORK_ERROR_PATTERNS: &[&str] = &[
+    "connection refused",
+    "connection reset",
+    "connection aborted",
+    "timed out",
+    "temporary failure in name resolution",
+    "name or service not known",
+    "network is unreachable",
+    "remote end closed connection",
+];
+
+/// The lowest `age_limit` treated as age-restricted.
+pub(crate) const ADULT_AGE_LIMIT: u32 = 18;
+
+/// The size of a download's format(s) according to the cached `/formats` result, or `None`
+/// if any of them is unknown.
+pub(crate) fn estimated_size(state: &AppState, request: &DownloadRequest) -> Option<u64> {
+    let sizes = format_part_sizes(state, request);
+    if sizes.is_empty() { None } else { sizes.into_iter().sum() }
+}
+
+/// The exact or approximate size of each `+`-joined part of a download's format, from the
+/// cached `/formats` result. Parts that aren't plain format IDs, e.g. "bestaudio", are `None`;
+/// filters like `[height<=?1080]` are ignored. Empty when the URL's formats aren't cached.
+pub(crate) fn format_part_sizes(state: &AppState, request: &DownloadRequest) -> Vec<Option<u64>> {
+    let Some(info) = state.formats_cache.get(&request.url) else {
+        return Vec::new();
+    };
+    request
+        .format_id
+        .split('+')
+        .map(|id| {
+            let id = id.split('[').next().unwrap_or(id);
+            info.formats.iter().find(|f| f.format_id == id).and_then(|f| f.filesize.or(f.filesize_approx))
+        })
+        .collect()
+}
+
+/// Recognizes the cause of a yt-dlp failure from its error output, if it's a known one.
+pub(crate) fn classify_error(stderr: &str) -> Option<ErrorCode> {
+    let stderr = stderr.to_lowercase();
+    let http_error = |status: &str| stderr.match_indices("http error ").any(|(at, prefix)| stderr[at + prefix.len()..].starts_with(status));
+    if AGE_RESTRICTED_PATTERNS.iter().any(|p| stderr.contains(p)) {
+        Some(ErrorCode::AgeRestricted)
+    } else if http_error("429") {
+        Some(ErrorCode::RateLimited)
+    } else if http_error("5") {
+        Some(ErrorCode::ServerError)
+    } else if NETWORK_ERROR_PATTERNS.iter().any(|p| stderr.contains(p)) {
+        Some(ErrorCode::NetworkError)
+    } else {
+        None
+    }
+}
+
+/// Whether `block_age_restricted` is set and the video is age-restricted.
+pub(crate) fn is_blocked_age_restricted(state: &AppState, info: &VideoInfo) -> bool {
+    state.config.read().unwrap().block_age_restricted && info.age_limit.is_some_and(|age| age >= ADULT_AGE_LIMIT)
+}
+
+/// Passes a session's cookie jar to yt-dlp, which reads it and writes updated cookies back.
+/// Fails if the session is unknown or has expired; using it extends its lifetime.
+pub(crate) fn add_session_cookies(cmd: &mut Command, state: &AppState, session_id: &str) -> Result<(), AppError> {
+    let session = state.sessions.touch(state, session_id).ok_or_else(|| unknown_session(session_id))?;
+    cmd.arg("--cookies").arg(session.cookies_path);
+    Ok(())
+}
+
+/// Checks that a request's session exists, before any work is queued for it.
+pub(crate) fn check_session(state: &AppState, session_id: Option<&str>) -> Result<(), AppError> {
+    match session_id {
+        Some(id) if state.sessions.touch(state, id).is_none() => Err(unknown_session(id)),
+        _ => Ok(()),
+    }
+}
+
+fn unknown_session(session_id: &str) -> AppError {
+    AppError::NotFound(format!("Unknown or expired session '{}'", session_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The arguments a command was given, as strings.
+    fn argv(cmd: &Command) -> Vec<String> {
+        cmd.as_std().get_args().map(|arg| arg.to_string_lossy().to_string()).collect()
+    }
+
+    fn extractor_args(args: &[(&str, &[(&str, &str)])]) -> ExtractorArgs {
+        args.iter()
+            .map(|(extractor, values)| (extractor.to_string(), values.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn classifies_yt_dlp_errors() {
+        let cases = [
+            ("ERROR: [youtube] abc: Sign in to confirm your age.", Some(ErrorCode::AgeRestricted)),
+            ("ERROR: [youtube] abc: Unable to download webpage: HTTP Error 429: Too Many Requests", Some(ErrorCode::RateLimited)),
+            ("ERROR: unable to download video data: HTTP Error 503: Service Unavailable", Some(ErrorCode::ServerError)),
+            ("ERROR: [generic] Unable to download webpage: <urlopen error [Errno 111] Connection refused>", Some(ErrorCode::NetworkError)),
+            ("ERROR: Read timed out.", Some(ErrorCode::NetworkError)),
+            ("ERROR: [generic] Unable to download webpage: HTTP Error 404: Not Found", None),
+            ("ERROR: [youtube] abc: Private video. Sign in if you've been granted access", None),
+            ("ERROR: [youtube] abc: Requested format is not available", None),
+        ];
+        for (stderr, code) in cases {
+            assert_eq!(classify_error(stderr), code, "{}", stderr);
+        }
+        assert!(!ErrorCode::AgeRestricted.is_host_failure() && ErrorCode::ServerError.is_host_failure());
+    }
+
+    #[test]
+    fn renders_extractor_args_in_a_stable_order() {
+        let args = extractor_args(&[
+            ("youtube", &[("skip", "dash,hls"), ("player_client", "android,web")]),
+            ("generic", &[("impersonate", "chrome")]),
+        ]);
+        validate_extractor_args(&args).unwrap_or_else(|e| panic!("{}", e));
+        let mut cmd = Command::new("yt-dlp");
+        add_extractor_args(&mut cmd, &args);
+        assert_eq!(argv(&cmd), [
+            "--extractor-args",
+            "generic:impersonate=chrome",
+            "--extractor-args",
+            "youtube:player_client=android,web;skip=dash,hls",
+        ]);
+
+        let mut empty = Command::new("yt-dlp");
+        add_extractor_args(&mut empty, &ExtractorArgs::new());
+        assert!(argv(&empty).is_empty());
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn passes_every_header_once_in_a_stable_order() {
+        let state = crate::test_support::state(crate::test_support::FakeRunner::new());
+        {
+            let mut config = state.config.write().unwrap();
+            config.http_headers = headers(&[("X-Trace", "1"), ("Authorization", "Bearer abc"), ("Accept-Language", "en")]);
+            config.user_agent = Some("config-agent".to_string());
+            config.host_referers = headers(&[("example.com", "https://example.com/")]);
+        }
+
+        // The config's headers alone, with the host's referer.
+        let mut cmd = Command::new("yt-dlp");
+        add_http_args(&mut cmd, &state, "https://www.example.com/v", &HashMap::new(), None);
+        assert_eq!(argv(&cmd), [
+            "--user-agent", "config-agent",
+            "--referer", "https://example.com/",
+            "--add-headers", "Accept-Language:en",
+            "--add-headers", "Authorization:Bearer abc",
+            "--add-headers", "X-Trace:1",
+        ]);
+
+        // The request's headers replace those of the same name whatever their case, and its
+        // own Referer wins over the host's.
+        let request = headers(&[("accept-language", "de"), ("Referer", "https://other.example/"), ("X-Extra", "a:b")]);
+        let mut cmd = Command::new("yt-dlp");
+        add_http_args(&mut cmd, &state, "https://www.example.com/v", &request, Some("request-agent"));
+        assert_eq!(argv(&cmd), [
+            "--user-agent", "request-agent",
+            "--add-headers", "accept-language:de",
+            "--add-headers", "Authorization:Bearer abc",
+            "--add-headers", "Referer:https://other.example/",
+            "--add-headers", "X-Extra:a:b",
+            "--add-headers", "X-Trace:1",
+        ]);
+
+        // Hosts without a referer entry get none.
+        let mut cmd = Command::new("yt-dlp");
+        add_http_args(&mut cmd, &state, "https://elsewhere.test/v", &HashMap::new(), None);
+        assert!(!argv(&cmd).contains(&"--referer".to_string()));
+    }
+
+    #[test]
+    fn refuses_extractor_args_that_would_render_ambiguously() {
+        for args in [
+            extractor_args(&[("you tube", &[("skip", "dash")])]),
+            extractor_args(&[("youtube:x", &[("skip", "dash")])]),
+            extractor_args(&[("youtube", &[("skip=1", "dash")])]),
+            extractor_args(&[("youtube", &[("skip", "dash;player_client=web")])]),
+            extractor_args(&[("youtube", &[])]),
+        ] {
+            assert!(validate_extractor_args(&args).is_err(), "{:?}", args);
+        }
+        // Values may hold anything but the separator, including `=` and `:`.
+        assert!(validate_extractor_args(&extractor_args(&[("youtube", &[("po_token", "web.gvs+a=b:c")])])).is_ok());
+    }
+
+    #[tokio::test]
+    async fn shows_the_error_chain_only_to_admin_keys() {
+        use crate::test_support::{send_with, state, FakeRunner};
+        use axum::{http::Request, routing::get, Router};
+
+        let state = state(FakeRunner::new());
+        {
+            let mut config = state.config.write().unwrap();
+            config.api_keys.insert("ops".to_string(), ApiKey::admin("ops-secret"));
+            config.api_keys.insert("client".to_string(), ApiKey::user("client-secret"));
+        }
+        let app = Router::new()
+            .route("/broken", get(|| async { Err::<(), _>(AppError::Internal(anyhow::anyhow!("disk full at /srv/private").context("saving the history"))) }))
+            .route("/refused", get(|| async { Err::<(), _>(AppError::BadRequest("not like that".to_string())) }))
+            .layer(axum::middleware::from_fn_with_state(state.clone(), correlate));
+        let request = |uri: &str, key: Option<&str>| {
+            let request = Request::builder().uri(uri).header(CORRELATION_HEADER, "req-1");
+            match key {
+                Some(key) => request.header(API_KEY_HEADER, key),
+                None => request,
+            }
+        };
+
+        for debug_errors in [false, true] {
+            state.config.write().unwrap().debug_errors = debug_errors;
+            for key in [None, Some("wrong-secret"), Some("client-secret"), Some("ops-secret")] {
+                let response = send_with(&app, request("/broken", key), None).await;
+                assert_eq!(response.status, StatusCode::INTERNAL_SERVER_ERROR);
+                assert_eq!(response.headers[CORRELATION_HEADER], "req-1");
+                assert_eq!(response.body["correlation_id"], "req-1");
+                assert_eq!(response.body["error"], "An internal server error occurred");
+                if debug_errors && key == Some("ops-secret") {
+                    assert_eq!(response.body["debug"], serde_json::json!(["saving the history", "disk full at /srv/private"]));
+                } else {
+                    assert!(response.body.get("debug").is_none(), "{:?}: {}", key, response.body);
+                    assert!(!response.body.to_string().contains("/srv/private"), "{:?}: {}", key, response.body);
+                }
+
+                // Errors the client caused say all there is to say either way.
+                let response = send_with(&app, request("/refused", key), None).await;
+                assert_eq!(response.status, StatusCode::BAD_REQUEST);
+                assert!(response.body.get("debug").is_none(), "{}", response.body);
+            }
+        }
+
+        // Without any keys, nobody is an admin.
+        state.config.write().unwrap().api_keys.clear();
+        let response = send_with(&app, request("/broken", None), None).await;
+        assert!(response.body.get("debug").is_none(), "{}", response.body);
+    }
+
+    #[tokio::test]
+    async fn admin_routes_need_an_admin_key() {
+        use crate::test_support::{app, send_with, state, FakeRunner};
+        use axum::http::{Method, Request};
+        use serde_json::json;
+
+        let state = state(FakeRunner::new());
+        {
+            let mut config = state.config.write().unwrap();
+            config.api_keys.insert("ops".to_string(), ApiKey::admin("ops-secret"));
+            config.api_keys.insert("alice".to_string(), ApiKey::user("alice-secret"));
+        }
+        let app = app(&state);
+        let request = |method: &Method, uri: &str, key: Option<&str>| {
+            let request = Request::builder().method(method.clone()).uri(uri);
+            match key {
+                Some(key) => request.header(API_KEY_HEADER, key),
+                None => request,
+            }
+        };
+        let admin_routes = [
+            (Method::POST, "/config", Some(json!({ "api_keys": {} }))),
+            (Method::GET, "/config/effective", None),
+            (Method::DELETE, "/caches", None),
+            (Method::POST, "/admin/quota/reset", None),
+            (Method::POST, "/admin/reindex", None),
+            (Method::POST, "/setup", Some(json!({}))),
+            (Method::POST, "/cookies", Some(json!({ "cookies": "" }))),
+            (Method::POST, "/uploads", Some(json!({ "size": 1 }))),
+            (Method::DELETE, "/session/abc", None),
+            (Method::POST, "/ytdlp/install", Some(json!({}))),
+        ];
+        for (method, uri, body) in &admin_routes {
+            let anonymous = send_with(&app, request(method, uri, None), body.clone()).await;
+            assert_eq!(anonymous.status, StatusCode::UNAUTHORIZED, "{} {}", method, uri);
+            let wrong = send_with(&app, request(method, uri, Some("guess")), body.clone()).await;
+            assert_eq!(wrong.status, StatusCode::UNAUTHORIZED, "{} {}", method, uri);
+            let user = send_with(&app, request(method, uri, Some("alice-secret")), body.clone()).await;
+            assert_eq!(user.status, StatusCode::FORBIDDEN, "{} {}", method, uri);
+        }
+        for uri in ["/config", "/caches", "/admin/quota"] {
+            let admin = send_with(&app, request(&Method::GET, uri, Some("ops-secret")), None).await;
+            assert_eq!(admin.status, StatusCode::OK, "{} {}", uri, admin.body);
+        }
+        assert_eq!(state.config.read().unwrap().api_keys.len(), 2);
+
+        // Changing downloads takes a key, but any key will do.
+        for (method, uri) in [(Method::POST, "/download/missing/cancel"), (Method::DELETE, "/schedule?key=missing")] {
+            let anonymous = send_with(&app, request(&method, uri, None), None).await;
+            assert_eq!(anonymous.status, StatusCode::UNAUTHORIZED, "{} {}", method, uri);
+            let user = send_with(&app, request(&method, uri, Some("alice-secret")), None).await;
+            assert_eq!(user.status, StatusCode::NOT_FOUND, "{} {} {}", method, uri, user.body);
+        }
+    }
+
+    #[test]
+    fn finds_api_keys_by_their_secret() {
+        let keys = BTreeMap::from([
+            ("alice".to_string(), ApiKey::user("alice-secret")),
+            ("ops".to_string(), ApiKey::admin("ops-secret")),
+        ]);
+        assert_eq!(find_api_key(&keys, b"ops-secret").map(|(name, _)| name.as_str()), Some("ops"));
+        assert_eq!(find_api_key(&keys, b"alice-secret").map(|(name, _)| name.as_str()), Some("alice"));
+        assert!(find_api_key(&keys, b"alice-secre").is_none());
+        assert!(find_api_key(&keys, b"").is_none());
+    }
+}
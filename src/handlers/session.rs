@@ -0,0 +1,76 @@
+use crate::{error::AppError, models::SessionRequest, AppState};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{delete, post},
+    Json, Router,
+};
+use serde_json::json;
+
+use super::AdminKey;
+
+/// Routes for sessions: cookie jars that downloads and probes share.
+pub fn build_router(state: AppState) -> Router {
+    Router::new()
+        .route("/session", post(create_session))
+        .route("/session/:id", delete(delete_session))
+        .with_state(state)
+}
+
+// ===================================================================
+//                          SESSION HANDLERS
+// ===================================================================
+
+/// # POST /session - Creates a session with its own cookie jar, optionally seeded with cookies.
+/// The body may be empty.
+pub async fn create_session(
+    AdminKey(_admin): AdminKey,
+    State(state): State<AppState>,
+    payload: Option<Json<SessionRequest>>,
+) -> Result<impl IntoResponse, AppError> {
+    let Json(payload) = payload.unwrap_or_default();
+    let session = state.sessions.create(&state, payload.cookies.as_deref()).await?;
+    tracing::info!("Created session {}", session.session_id);
+    Ok((StatusCode::CREATED, Json(session)))
+}
+
+/// # DELETE /session/:id - Ends a session and deletes its cookie jar.
+pub async fn delete_session(
+    AdminKey(_admin): AdminKey,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    if !state.sessions.remove(&id).await? {
+        return Err(AppError::NotFound(format!("Unknown or expired session '{}'", id)));
+    }
+    tracing::info!("Deleted session {}", id);
+    Ok(Json(json!({ "deleted": id })))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::{app, send, state, FakeRunner};
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn creates_and_deletes_a_session() {
+        let app = app(&state(FakeRunner::new()));
+        let cookies = ".example.com\tTRUE\t/\tFALSE\t0\tid\tabc";
+        let created = send(&app, Method::POST, "/session", Some(json!({ "cookies": cookies }))).await;
+        assert_eq!(created.status, StatusCode::CREATED);
+        let id = created.body["session_id"].as_str().unwrap().to_string();
+
+        let deleted = send(&app, Method::DELETE, &format!("/session/{}", id), None).await;
+        assert_eq!(deleted.status, StatusCode::OK);
+        assert_eq!(deleted.body["deleted"], id.as_str());
+        assert_eq!(send(&app, Method::DELETE, &format!("/session/{}", id), None).await.status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn the_body_is_optional() {
+        let app = app(&state(FakeRunner::new()));
+        assert_eq!(send(&app, Method::POST, "/session", None).await.status, StatusCode::CREATED);
+    }
+}
@@ -0,0 +1,123 @@
+use crate::{
+    config::{self, Config},
+    debug_bundle,
+    error::AppError,
+    models::SetupRequest,
+    storage, AppState,
+};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use serde_json::json;
+use std::path::Path;
+
+use super::config::{apply_config, redact_secrets, validate_config};
+use super::AdminKey;
+
+/// Routes for the first-run setup of a new install.
+pub fn build_router(state: AppState) -> Router {
+    Router::new()
+        .route("/setup", post(complete_setup))
+        .route("/setup/status", get(setup_status))
+        .with_state(state)
+}
+
+// ===================================================================
+//                          SETUP HANDLERS
+// ===================================================================
+
+/// # GET /setup/status - Reports what a new install still needs: a config file, yt-dlp and
+/// ffmpeg on PATH, and a writable download directory.
+pub async fn setup_status(State(state): State<AppState>) -> Result<impl IntoResponse, AppError> {
+    let config_exists = config::config_file_exists().await?;
+    let download_directory = state.config.read().unwrap().download_directory.clone();
+    let directory_problem = storage::check_writable_without_creating(Path::new(&download_directory)).await.err();
+    Ok(Json(json!({
+        "config_exists": config_exists,
+        "setup_required": !config_exists,
+        "yt_dlp_version": debug_bundle::tool_version("yt-dlp", "--version").await,
+        "ffmpeg_version": debug_bundle::tool_version("ffmpeg", "-version").await,
+        "download_directory": download_directory,
+        "download_directory_writable": directory_problem.is_none(),
+        "download_directory_problem": directory_problem,
+    })))
+}
+
+/// # POST /setup - Writes the first config file from a few settings, validated like
+/// `POST /config`, and makes it the running config. Only allowed while no config file exists.
+pub async fn complete_setup(
+    AdminKey(_admin): AdminKey,
+    State(state): State<AppState>,
+    Json(payload): Json<SetupRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let already_set_up = || AppError::Conflict("The server is already set up; use POST /config to change settings.".to_string());
+    if config::config_file_exists().await? {
+        return Err(already_set_up());
+    }
+    let mut config = Config::default();
+    if let Some(dir) = payload.download_directory {
+        config.download_directory = dir;
+    }
+    if let Some(limit) = payload.max_concurrent_downloads {
+        config.max_concurrent_downloads = Some(limit);
+    }
+    if let Some(limit) = payload.max_concurrent_probes {
+        config.max_concurrent_probes = limit;
+    }
+    let problems = validate_config(&state, &config).await;
+    if !problems.is_empty() {
+        return Err(AppError::BadRequest(format!("Invalid configuration: {}", problems.join("; "))));
+    }
+    // Another setup may have written the file since the check above.
+    if !config::create_config(&config).await? {
+        return Err(already_set_up());
+    }
+    apply_config(&state, config.clone());
+    tracing::info!("Setup complete; configuration saved.");
+    Ok((StatusCode::CREATED, Json(redact_secrets(config))))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::{app, lock_files, remove_config_file, send, state, FakeRunner};
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn sets_up_a_fresh_install_once() {
+        let _files = lock_files().await;
+        remove_config_file();
+        let state = state(FakeRunner::new());
+        let app = app(&state);
+
+        let status = send(&app, Method::GET, "/setup/status", None).await;
+        assert_eq!(status.status, StatusCode::OK);
+        assert_eq!(status.body["setup_required"], true);
+        assert_eq!(status.body["download_directory_writable"], true);
+
+        let directory = state.config.read().unwrap().download_directory.clone();
+        let body = json!({ "download_directory": directory, "max_concurrent_downloads": 2 });
+        let created = send(&app, Method::POST, "/setup", Some(body.clone())).await;
+        assert_eq!(created.status, StatusCode::CREATED);
+        assert_eq!(state.config.read().unwrap().max_concurrent_downloads, Some(2));
+        assert_eq!(send(&app, Method::GET, "/setup/status", None).await.body["setup_required"], false);
+
+        assert_eq!(send(&app, Method::POST, "/setup", Some(body)).await.status, StatusCode::CONFLICT);
+        remove_config_file();
+    }
+
+    #[tokio::test]
+    async fn rejects_an_invalid_setup() {
+        let _files = lock_files().await;
+        remove_config_file();
+        let app = app(&state(FakeRunner::new()));
+        let body = json!({ "download_directory": "/proc/not-writable" });
+        assert_eq!(send(&app, Method::POST, "/setup", Some(body)).await.status, StatusCode::BAD_REQUEST);
+        let unknown = json!({ "download_dir": "/tmp" });
+        assert_eq!(send(&app, Method::POST, "/setup", Some(unknown)).await.status, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+}
@@ -0,0 +1,733 @@
+use crate::{
+    cache, debug_bundle,
+    error::AppError,
+    history, library_index,
+    models::{DownloadStatus, ExtractorCount, KeyedStatus, LibraryScan, StatusQuery, StatusShape},
+    negotiate::{self, ListFormat},
+    quota, storage, AppState,
+};
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::path::Path as FsPath;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+
+use super::{estimated_size, files::walk_files, resolve_download_root, AdminKey, SeesOrigins, Uncompressed};
+
+/// How many extractors `GET /stats` lists, most used first.
+const TOP_EXTRACTORS: usize = 10;
+
+/// How often `GET /status` streams check for status changes.
+const STATUS_STREAM_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Routes for download status, health, metrics, admin actions and debugging.
+pub fn build_router(state: AppState) -> Router {
+    Router::new()
+        .route("/status", get(get_status))
+        .route("/status/summary", get(get_status_summary))
+        .route("/stats", get(get_stats))
+        .route("/health", get(get_health))
+        .route("/ready", get(get_ready))
+        .route("/metrics", get(get_metrics))
+        .route("/quota", get(get_key_quota))
+        .route("/admin/quota", get(get_admin_quota))
+        .route("/admin/quota/reset", post(reset_quota))
+        .route("/debug/bundle", get(get_debug_bundle))
+        .with_state(state)
+}
+
+// ===================================================================
+//                          STATUS HANDLERS
+// ===================================================================
+
+/// # GET /status - Returns the status of all downloads.
+/// Lists them under `downloads`, newest first, or by key with `?shape=map`. Either way the
+/// output only changes when a status does. Responds with CSV for `Accept: text/csv` or
+/// `?format=csv`, and with a stream of status updates for `Accept: application/x-ndjson`.
+/// `?origin_key=` keeps the downloads started with that API key. Once `api_keys` are set,
+/// `origin` is left out for callers without a key, and they can't filter by it.
+pub async fn get_status(
+    State(state): State<AppState>,
+    SeesOrigins(sees_origins): SeesOrigins,
+    headers: HeaderMap,
+    Query(query): Query<StatusQuery>,
+) -> Result<Response, AppError> {
+    if query.origin_key.is_some() && !sees_origins {
+        return Err(AppError::Unauthorized("origin_key requires an API key; send it in the X-Api-Key header.".to_string()));
+    }
+    if query.format.is_none() && negotiate::accepts(&headers, negotiate::NDJSON) {
+        return Ok(stream_status(state, query.origin_key, sees_origins));
+    }
+    let mut map = state.downloads.lock().clone();
+    map.retain(|_, status| started_with(status, query.origin_key.as_deref()));
+    if !sees_origins {
+        map.values_mut().for_each(|status| status.origin = None);
+    }
+    if negotiate::list_format(&headers, query.format.as_deref())? == ListFormat::Csv {
+        let mut rows: Vec<(String, DownloadStatus)> = map.into_iter().collect();
+        rows.sort_by(|a, b| a.0.cmp(&b.0));
+        return negotiate::csv_response(&rows);
+    }
+    if query.shape == StatusShape::Map {
+        let sorted: BTreeMap<String, DownloadStatus> = map.into_iter().collect();
+        return Ok((StatusCode::OK, Json(sorted)).into_response());
+    }
+    let mut downloads: Vec<KeyedStatus> = map.into_iter().map(|(download_key, status)| KeyedStatus { download_key, status }).collect();
+    // Newest first; statuses restored without a creation time go last. Keys break ties.
+    downloads.sort_by(|a, b| {
+        b.status.created_at.cmp(&a.status.created_at).then_with(|| a.download_key.cmp(&b.download_key))
+    });
+    Ok((StatusCode::OK, Json(json!({ "downloads": downloads }))).into_response())
+}
+
+/// # GET /status/summary - Counts the running, queued and scheduled downloads against the
+/// queue limits. A job group counts once.
+pub async fn get_status_summary(State(state): State<AppState>) -> impl IntoResponse {
+    let (max_queue_length, max_concurrent_downloads) = {
+        let config = state.config.read().unwrap();
+        (config.max_queue_length, config.max_concurrent_downloads)
+    };
+    let map = state.downloads.lock();
+    let count = |states: &[&str]| map.values().filter(|s| s.parent.is_none() && states.contains(&s.status.as_str())).count();
+    Json(json!({
+        "running": count(&["starting", "downloading"]),
+        "queued": count(&["queued"]),
+        "scheduled": count(&["scheduled"]),
+        "queue_length": crate::queue_length(&map),
+        "max_queue_length": max_queue_length,
+        "max_concurrent_downloads": max_concurrent_downloads,
+    }))
+}
+
+/// Whether a download was started with the API key named `origin_key`, or any download
+/// without a filter.
+fn started_with(status: &DownloadStatus, origin_key: Option<&str>) -> bool {
+    origin_key.is_none_or(|name| status.origin.as_ref().and_then(|origin| origin.api_key.as_deref()) == Some(name))
+}
+
+/// Streams one JSON object per line: every download's status first, then each status again
+/// whenever it changes. Each object is the status with its `download_key` added. The stream
+/// runs until the client disconnects.
+fn stream_status(state: AppState, origin_key: Option<String>, sees_origins: bool) -> Response {
+    let mut ticker = tokio::time::interval(STATUS_STREAM_INTERVAL);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let lines = futures::stream::unfold(
+        (state, ticker, HashMap::<String, serde_json::Value>::new(), origin_key),
+        move |(state, mut ticker, mut sent, origin_key)| async move {
+            loop {
+                ticker.tick().await;
+                let snapshot = state.downloads.lock().clone();
+                let mut chunk = String::new();
+                for (key, mut status) in snapshot {
+                    if !started_with(&status, origin_key.as_deref()) {
+                        continue;
+                    }
+                    if !sees_origins {
+                        status.origin = None;
+                    }
+                    let Ok(mut value) = serde_json::to_value(&status) else { continue };
+                    if sent.get(&key) == Some(&value) {
+                        continue;
+                    }
+                    sent.insert(key.clone(), value.clone());
+                    value["download_key"] = json!(key);
+                    chunk.push_str(&value.to_string());
+                    chunk.push('\n');
+                }
+                if !chunk.is_empty() {
+                    return Some((Ok::<_, Infallible>(chunk), (state, ticker, sent, origin_key)));
+                }
+            }
+        },
+    );
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(negotiate::NDJSON));
+    (headers, Body::from_stream(lines)).into_response()
+}
+
+/// # GET /stats - Summarizes the library, downloads and history for a dashboard.
+/// The library figures come from a scan of the primary directory, cached in `library_scans`.
+pub async fn get_stats(State(state): State<AppState>) -> Result<impl IntoResponse, AppError> {
+    let download_dir = resolve_download_root(&state, None)?;
+    let library = scan_library(&state, &download_dir).await?;
+
+    let mut by_status: BTreeMap<String, usize> = BTreeMap::new();
+    let mut express = 0;
+    let mut remaining_bytes = 0;
+    let mut current_rate = 0.0;
+    let speeds: Vec<f64> = {
+        let map = state.downloads.lock();
+        for status in map.values() {
+            *by_status.entry(status.status.clone()).or_insert(0) += 1;
+            express += usize::from(status.express);
+            // A job group's parent only sums up its children.
+            if !status.children.is_empty() {
+                continue;
+            }
+            match status.status.as_str() {
+                "downloading" => {
+                    remaining_bytes += status.total_bytes.unwrap_or(0).saturating_sub(status.downloaded_bytes.unwrap_or(0));
+                    current_rate += status.speed_bytes_per_sec.unwrap_or(0.0);
+                }
+                "queued" | "starting" => {
+                    remaining_bytes += status.request.as_ref().and_then(|request| estimated_size(&state, request)).unwrap_or(0);
+                }
+                _ => {}
+            }
+        }
+        map.values().filter_map(|status| status.average_speed_bytes_per_sec).collect()
+    };
+    let completed = by_status.get("completed").copied().unwrap_or(0);
+    let finished = completed
+        + by_status.get("failed").copied().unwrap_or(0)
+        + by_status.get("completed_partially").copied().unwrap_or(0)
+        + by_status.get("completed_no_output").copied().unwrap_or(0);
+    let success_rate = (finished > 0).then(|| completed as f64 / finished as f64);
+    let queue_estimated_drain_at = (current_rate > 0.0).then(|| {
+        let secs = remaining_bytes as f64 / current_rate;
+        (chrono::Utc::now() + chrono::Duration::milliseconds((secs * 1000.0) as i64)).to_rfc3339()
+    });
+    let average_speed = (!speeds.is_empty()).then(|| speeds.iter().sum::<f64>() / speeds.len() as f64);
+
+    let history = history::load().await?;
+    let mut extractors: HashMap<String, usize> = HashMap::new();
+    for entry in history.iter().filter(|entry| entry.outcome.is_none()) {
+        *extractors.entry(entry.extractor.clone().unwrap_or_else(|| "unknown".to_string())).or_insert(0) += 1;
+    }
+    let mut top_extractors: Vec<ExtractorCount> =
+        extractors.into_iter().map(|(extractor, count)| ExtractorCount { extractor, count }).collect();
+    top_extractors.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.extractor.cmp(&b.extractor)));
+    top_extractors.truncate(TOP_EXTRACTORS);
+
+    Ok(Json(json!({
+        "library": library,
+        "downloads": {
+            "by_status": by_status,
+            "express": express,
+            "queue_estimated_drain_at": queue_estimated_drain_at,
+            "success_rate": success_rate,
+            "average_speed_bytes_per_sec": average_speed,
+        },
+        "history": {
+            "items": history.len(),
+            "top_extractors": top_extractors,
+        },
+    })))
+}
+
+/// Counts the files in `dir` and their size, reusing a recent count from the cache.
+async fn scan_library(state: &AppState, dir: &FsPath) -> Result<LibraryScan, AppError> {
+    let key = dir.to_string_lossy().to_string();
+    if let Some(scan) = state.library_scans.get(&key) {
+        return Ok(scan);
+    }
+    let mut scan = LibraryScan::default();
+    if let Some(files) = library_index::indexed(state, dir).await? {
+        scan.files = files.len();
+        scan.total_bytes = files.iter().map(|file| file.size).sum();
+        return Ok(scan);
+    }
+    for path in walk_files(state, dir).await? {
+        scan.files += 1;
+        scan.total_bytes += tokio::fs::metadata(dir.join(path)).await.map_or(0, |m| m.len());
+    }
+    state.library_scans.insert(key, scan.clone());
+    Ok(scan)
+}
+
+// ===================================================================
+//                          HEALTH & METRICS HANDLERS
+// ===================================================================
+
+/// # GET /health - Reports whether the server can take downloads.
+/// Probes every download root; `status` is "degraded" if any is unwritable or low on space,
+/// or if the download quota is used up. Always 200, so it works as a liveness check: a restart
+/// fixes neither.
+pub async fn get_health(State(state): State<AppState>) -> impl IntoResponse {
+    let roots: Vec<String> = {
+        let config = state.config.read().unwrap();
+        std::iter::once(config.download_directory.clone())
+            .chain(config.allowed_download_roots.iter().cloned())
+            .collect()
+    };
+    let mut probes = Vec::new();
+    for root in roots {
+        probes.push(storage::probe(&state, FsPath::new(&root)).await);
+    }
+    let quota = quota::report(&state);
+    let reporting = state.config.read().unwrap().report_to.is_some();
+    let healthy = probes.iter().all(|p| p.condition.is_none()) && !quota.exceeded;
+
+    let body = json!({
+        "status": if healthy { "ok" } else { "degraded" },
+        "storage": probes,
+        "quota": quota,
+        "scheduler_paused": state.scheduler.is_paused(),
+        "download_slots": state.slots.report(&state.config),
+        "open_circuits": state.circuits.open(),
+        "usage_report": reporting.then(|| state.reporter.status()),
+    });
+    Json(body)
+}
+
+/// # GET /ready - Reports whether the server should be sent new downloads.
+/// Responds with 503 and the reasons while it shuts down, installs a managed yt-dlp release,
+/// or holds new downloads until a version switch is done. The state files are loaded before
+/// the server listens, so it isn't reachable until then.
+pub async fn get_ready(State(state): State<AppState>) -> impl IntoResponse {
+    let mut reasons = Vec::new();
+    if state.draining.load(Ordering::SeqCst) {
+        reasons.push("shutting_down");
+    }
+    if state.ytdlp.is_installing() {
+        reasons.push("installing_ytdlp");
+    }
+    if state.ytdlp.pending().is_some() {
+        reasons.push("ytdlp_switch_pending");
+    }
+    let code = if reasons.is_empty() { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (code, Json(json!({ "ready": reasons.is_empty(), "reasons": reasons })))
+}
+
+/// # GET /metrics - Exposes download and quota counters in the Prometheus text format.
+pub async fn get_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let quota = quota::report(&state);
+    let mut body = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+        body.push_str(&format!("# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"));
+    };
+    metric("yt_agent_active_downloads", "gauge", "Downloads currently queued, starting or downloading.", crate::count_active_downloads(&state) as u64);
+    metric("yt_agent_downloaded_bytes_total", "counter", "Bytes downloaded since the last quota reset.", quota.used_bytes);
+    if let Some(limit) = quota.limit_bytes {
+        metric("yt_agent_quota_limit_bytes", "gauge", "The configured total_bytes_quota.", limit);
+        metric("yt_agent_quota_remaining_bytes", "gauge", "Bytes left before new downloads are refused.", quota.remaining_bytes.unwrap_or_default());
+    }
+    let stats: Vec<_> = cache::registry(&state).into_iter().map(|(name, c)| c.stats(name)).collect();
+    let mut per_cache = |name: &str, kind: &str, help: &str, value: fn(&cache::CacheStats) -> u64| {
+        body.push_str(&format!("# HELP {name} {help}\n# TYPE {name} {kind}\n"));
+        for s in &stats {
+            body.push_str(&format!("{name}{{cache=\"{}\"}} {}\n", s.name, value(s)));
+        }
+    };
+    per_cache("yt_agent_cache_hits_total", "counter", "Cache lookups that found a fresh entry.", |s| s.hits);
+    per_cache("yt_agent_cache_misses_total", "counter", "Cache lookups that found nothing or an expired entry.", |s| s.misses);
+    per_cache("yt_agent_cache_entries", "gauge", "Entries currently held by the cache.", |s| s.entries as u64);
+
+    let mut by_state: BTreeMap<String, usize> = BTreeMap::new();
+    for status in state.downloads.lock().values() {
+        *by_state.entry(status.status.clone()).or_insert(0) += 1;
+    }
+    body.push_str("# HELP yt_agent_downloads Downloads known to the server, by state.\n# TYPE yt_agent_downloads gauge\n");
+    for (download_state, count) in by_state {
+        body.push_str(&format!("yt_agent_downloads{{state=\"{}\"}} {}\n", download_state, count));
+    }
+    let extractor_label = state.config.read().unwrap().metrics_extractor_label;
+    body.push_str(&state.metrics.render(extractor_label));
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+/// # GET /quota - Reports today's quota of the API key sent in `X-Api-Key`.
+pub async fn get_key_quota(State(state): State<AppState>, headers: HeaderMap) -> Result<impl IntoResponse, AppError> {
+    let api_key = super::api_key(&state, &headers)?
+        .ok_or_else(|| AppError::BadRequest("No API keys are configured; see GET /health for the download quota.".to_string()))?;
+    Ok(Json(quota::key_report(&state, &api_key)))
+}
+
+// ===================================================================
+//                          ADMIN HANDLERS
+// ===================================================================
+
+/// # GET /admin/quota - Reports the total download quota and today's quota of every API key.
+pub async fn get_admin_quota(AdminKey(_admin): AdminKey, State(state): State<AppState>) -> impl IntoResponse {
+    Json(json!({ "total": quota::report(&state), "keys": quota::key_reports(&state) }))
+}
+
+/// # POST /admin/quota/reset - Resets the downloaded-bytes counter so downloads are accepted again.
+pub async fn reset_quota(AdminKey(_admin): AdminKey, State(state): State<AppState>) -> Result<impl IntoResponse, AppError> {
+    let previous = state.quota.usage();
+    state.quota.reset().await?;
+    tracing::info!("Quota reset; {} bytes had been used since {}", previous.used_bytes, previous.since.to_rfc3339());
+    Ok(Json(quota::report(&state)))
+}
+
+// ===================================================================
+//                          DEBUG HANDLERS
+// ===================================================================
+
+/// # GET /debug/bundle - Returns a gzip tarball of the redacted config, status map, versions and recent logs.
+/// Refused unless `allow_debug_bundle` is set, and to requests without an admin key, even while
+/// no `api_keys` are set.
+pub async fn get_debug_bundle(AdminKey(admin): AdminKey, State(state): State<AppState>) -> Result<impl IntoResponse, AppError> {
+    if admin.is_none() {
+        return Err(AppError::Unauthorized("The debug bundle needs an admin key; add one to api_keys and send it in the X-Api-Key header.".to_string()));
+    }
+    if !state.config.read().unwrap().allow_debug_bundle {
+        return Err(AppError::Forbidden("The debug bundle is disabled; set allow_debug_bundle in the config to enable it.".to_string()));
+    }
+    let config = state.config.read().unwrap().clone();
+    let downloads = state.downloads.lock().clone();
+    let bundle = debug_bundle::build(&config, &downloads, &state.logs.snapshot()).await?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/gzip"));
+    let disposition = format!("attachment; filename=\"{}\"", debug_bundle::BUNDLE_FILENAME);
+    headers.insert(header::CONTENT_DISPOSITION, HeaderValue::from_str(&disposition).unwrap());
+
+    Ok((headers, Extension(Uncompressed), bundle))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ApiKey;
+    use crate::models::DownloadStatus;
+    use crate::test_support::{app, finished, first_chunk, lock_files, send, send_with, state, FakeRunner, FAKE_YTDLP};
+    use axum::http::{Method, Request, StatusCode};
+    use serde_json::json;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::sync::atomic::Ordering;
+
+    fn add_status(state: &crate::AppState, key: &str, status: &str, created_at: &str) {
+        let status = DownloadStatus { status: status.to_string(), created_at: Some(created_at.to_string()), ..Default::default() };
+        state.downloads.lock().insert(key.to_string(), status);
+    }
+
+    #[tokio::test]
+    async fn is_ready_when_nothing_holds_it_back() {
+        let app = app(&state(FakeRunner::new()));
+        let ready = send(&app, Method::GET, "/ready", None).await;
+        assert_eq!(ready.status, StatusCode::OK);
+        assert_eq!(ready.body, json!({ "ready": true, "reasons": [] }));
+    }
+
+    #[tokio::test]
+    async fn is_not_ready_while_draining() {
+        let state = state(FakeRunner::new());
+        state.draining.store(true, Ordering::SeqCst);
+        let ready = send(&app(&state), Method::GET, "/ready", None).await;
+        assert_eq!(ready.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(ready.body, json!({ "ready": false, "reasons": ["shutting_down"] }));
+    }
+
+    #[tokio::test]
+    async fn is_not_ready_while_installing_ytdlp() {
+        let state = state(FakeRunner::new());
+        let app = app(&state);
+        let install = state.ytdlp.hold_install().await;
+        let ready = send(&app, Method::GET, "/ready", None).await;
+        assert_eq!(ready.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(ready.body, json!({ "ready": false, "reasons": ["installing_ytdlp"] }));
+
+        drop(install);
+        assert_eq!(send(&app, Method::GET, "/ready", None).await.status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn keeps_serving_after_a_panic_while_the_statuses_were_locked() {
+        let state = state(FakeRunner::new());
+        add_status(&state, "https://example.com/v", "downloading", "2024-01-01T00:00:00Z");
+        let holder = state.clone();
+        let panicked = std::thread::spawn(move || {
+            let mut downloads = holder.downloads.lock();
+            downloads.get_mut("https://example.com/v").unwrap().progress = 50.0;
+            panic!("panicking with the statuses locked");
+        })
+        .join();
+        assert!(panicked.is_err());
+
+        let app = app(&state);
+        let listed = send(&app, Method::GET, "/status", None).await;
+        assert_eq!(listed.status, StatusCode::OK);
+        assert_eq!(listed.body["downloads"][0]["progress"], 50.0);
+        let health = send(&app, Method::GET, "/health", None).await;
+        assert_ne!(health.status, StatusCode::INTERNAL_SERVER_ERROR);
+        let metrics = send(&app, Method::GET, "/metrics", None).await;
+        let poisoned = metrics.body.as_str().unwrap().lines().find_map(|line| line.strip_prefix("yt_agent_poisoned_locks_total ")).unwrap();
+        assert!(poisoned.parse::<u64>().unwrap() >= 1);
+    }
+
+    #[tokio::test]
+    async fn reports_the_statuses_in_every_shape() {
+        let state = state(FakeRunner::new());
+        add_status(&state, "https://example.com/old", "completed", "2024-01-01T00:00:00Z");
+        add_status(&state, "https://example.com/new", "queued", "2024-02-01T00:00:00Z");
+        let app = app(&state);
+
+        let listed = send(&app, Method::GET, "/status", None).await;
+        assert_eq!(listed.body["downloads"][0]["download_key"], "https://example.com/new");
+        let map = send(&app, Method::GET, "/status?shape=map", None).await;
+        assert_eq!(map.body["https://example.com/old"]["status"], "completed");
+        let csv = send(&app, Method::GET, "/status?format=csv", None).await;
+        assert!(csv.body.as_str().unwrap().lines().nth(1).unwrap().starts_with("https://example.com/new"));
+
+        let request = Request::builder().uri("/status").header("accept", "application/x-ndjson");
+        let (status, headers, chunk) = first_chunk(&app, request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers["content-type"], "application/x-ndjson");
+        assert_eq!(chunk.lines().count(), 2);
+
+        let summary = send(&app, Method::GET, "/status/summary", None).await;
+        assert_eq!(summary.body["queued"], 1);
+        assert_eq!(summary.body["running"], 0);
+    }
+
+    #[tokio::test]
+    async fn filters_the_statuses_by_the_key_that_started_them() {
+        let _files = lock_files().await;
+        let state = state(crate::test_support::fake_ytdlp());
+        {
+            let mut config = state.config.write().unwrap();
+            config.api_keys.insert("alice".to_string(), ApiKey::user("alice-secret"));
+            config.api_keys.insert("bob".to_string(), ApiKey::user("bob-secret"));
+        }
+        let app = app(&state);
+        for (url, secret) in [("https://example.com/a1", "alice-secret"), ("https://example.com/b1", "bob-secret"), ("https://example.com/a2", "alice-secret")] {
+            let request = Request::builder().method(Method::POST).uri("/download").header("x-api-key", secret);
+            assert_eq!(send_with(&app, request, Some(json!({ "url": url, "format_id": "best" }))).await.status, StatusCode::ACCEPTED);
+            finished(&state, url).await;
+        }
+        add_status(&state, "https://example.com/restored", "completed", "2024-01-01T00:00:00Z");
+
+        let keys = |body: &serde_json::Value| -> BTreeSet<String> {
+            body["downloads"].as_array().unwrap().iter().map(|entry| entry["download_key"].as_str().unwrap().to_string()).collect()
+        };
+        let get = |uri: &str| send_with(&app, Request::builder().uri(uri).header("x-api-key", "alice-secret"), None);
+        let alice = get("/status?origin_key=alice").await;
+        assert_eq!(keys(&alice.body), BTreeSet::from(["https://example.com/a1".to_string(), "https://example.com/a2".to_string()]));
+        let bob = get("/status?origin_key=bob&shape=map").await;
+        assert_eq!(bob.body.as_object().unwrap().keys().collect::<Vec<_>>(), ["https://example.com/b1"]);
+        let csv = get("/status?origin_key=bob&format=csv").await;
+        let csv = csv.body.as_str().unwrap();
+        assert!(csv.contains("https://example.com/b1") && !csv.contains("https://example.com/a1"));
+        assert_eq!(keys(&get("/status?origin_key=carol").await.body), BTreeSet::new());
+        assert_eq!(keys(&get("/status").await.body).len(), 4);
+
+        let request = Request::builder().uri("/status?origin_key=bob").header("accept", "application/x-ndjson").header("x-api-key", "alice-secret");
+        let (_, _, chunk) = first_chunk(&app, request).await;
+        assert_eq!(chunk.lines().count(), 1);
+        assert!(chunk.contains("https://example.com/b1"));
+    }
+
+    #[tokio::test]
+    async fn shows_origins_only_to_callers_with_a_key() {
+        let state = state(FakeRunner::new());
+        let origin = crate::models::DownloadOrigin {
+            endpoint: "POST /download".to_string(),
+            user_agent: Some("curl/8.0".to_string()),
+            ip: Some("192.0.2.7".to_string()),
+            api_key: Some("alice".to_string()),
+        };
+        let status = DownloadStatus { status: "completed".to_string(), origin: Some(origin), ..Default::default() };
+        state.downloads.lock().insert("https://example.com/o1".to_string(), status);
+        let app = app(&state);
+        let origin_of = |body: &serde_json::Value| body["downloads"][0]["origin"].clone();
+
+        // Without any keys everyone sees them.
+        assert_eq!(origin_of(&send(&app, Method::GET, "/status", None).await.body)["ip"], "192.0.2.7");
+
+        state.config.write().unwrap().api_keys.insert("alice".to_string(), ApiKey::admin("alice-secret"));
+        let anonymous = send(&app, Method::GET, "/status", None).await;
+        assert_eq!(anonymous.status, StatusCode::OK);
+        assert!(origin_of(&anonymous.body).is_null(), "{}", anonymous.body);
+        let csv = send(&app, Method::GET, "/status?format=csv", None).await;
+        assert!(!csv.body.as_str().unwrap().contains("192.0.2.7"));
+        assert_eq!(send(&app, Method::GET, "/status?origin_key=alice", None).await.status, StatusCode::UNAUTHORIZED);
+        let (_, _, chunk) = first_chunk(&app, Request::builder().uri("/status").header("accept", "application/x-ndjson")).await;
+        assert!(!chunk.contains("192.0.2.7") && !chunk.contains("curl/8.0"), "{}", chunk);
+
+        let request = |uri: &str| Request::builder().uri(uri).header("x-api-key", "alice-secret");
+        let keyed = send_with(&app, request("/status"), None).await;
+        assert_eq!(origin_of(&keyed.body)["user_agent"], "curl/8.0");
+        assert_eq!(send_with(&app, request("/status?origin_key=alice"), None).await.status, StatusCode::OK);
+    }
+
+    /// The body of `GET uri` exactly as sent.
+    async fn raw_body(app: &axum::Router, uri: &str) -> String {
+        use tower::ServiceExt;
+        let response = app.clone().oneshot(Request::builder().uri(uri).body(axum::body::Body::empty()).unwrap()).await.unwrap();
+        String::from_utf8(axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn lists_the_same_statuses_in_the_same_order_every_time() {
+        let statuses = [
+            ("https://example.com/b", "completed", Some("2024-01-01T00:00:00Z")),
+            ("https://example.com/a", "failed", Some("2024-01-01T00:00:00Z")),
+            ("https://example.com/newest", "queued", Some("2024-03-01T00:00:00Z")),
+            ("https://example.com/restored", "completed", None),
+            ("https://example.com/middle", "downloading", Some("2024-02-01T00:00:00Z")),
+        ];
+        // Two states with the same statuses, added in opposite orders to maps seeded differently.
+        let forward = state(FakeRunner::new());
+        let backward = state(FakeRunner::new());
+        for (target, order) in [(&forward, statuses.to_vec()), (&backward, statuses.iter().rev().cloned().collect())] {
+            for (key, status, created_at) in order {
+                let status = DownloadStatus { status: status.to_string(), created_at: created_at.map(str::to_string), ..Default::default() };
+                target.downloads.lock().insert(key.to_string(), status);
+            }
+        }
+        let (forward, backward) = (app(&forward), app(&backward));
+
+        for uri in ["/status", "/status?shape=map", "/status?format=csv"] {
+            let body = raw_body(&forward, uri).await;
+            assert_eq!(body, raw_body(&forward, uri).await, "{} changed between requests", uri);
+            assert_eq!(body, raw_body(&backward, uri).await, "{} depends on the insertion order", uri);
+        }
+
+        // Newest first, keys breaking ties, and statuses without a creation time last.
+        let listed = send(&forward, Method::GET, "/status", None).await;
+        let keys: Vec<_> = listed.body["downloads"].as_array().unwrap().iter().map(|d| d["download_key"].as_str().unwrap()).collect();
+        assert_eq!(keys, [
+            "https://example.com/newest",
+            "https://example.com/middle",
+            "https://example.com/a",
+            "https://example.com/b",
+            "https://example.com/restored",
+        ]);
+        let map = raw_body(&forward, "/status?shape=map").await;
+        let positions: Vec<_> = ["/a\"", "/b\"", "/middle\"", "/newest\"", "/restored\""].iter().map(|key| map.find(key).unwrap()).collect();
+        assert!(positions.windows(2).all(|pair| pair[0] < pair[1]), "the map isn't sorted by key: {}", map);
+    }
+
+    #[tokio::test]
+    async fn summarizes_stats_health_and_metrics() {
+        let state = state(FakeRunner::new());
+        add_status(&state, "https://example.com/done", "completed", "2024-01-01T00:00:00Z");
+        add_status(&state, "https://example.com/broken", "failed", "2024-01-01T00:00:00Z");
+        let app = app(&state);
+
+        let stats = send(&app, Method::GET, "/stats", None).await;
+        assert_eq!(stats.status, StatusCode::OK);
+        assert_eq!(stats.body["downloads"]["success_rate"], 0.5);
+
+        let health = send(&app, Method::GET, "/health", None).await;
+        assert_eq!(health.status, StatusCode::OK);
+        assert_eq!(health.body["status"], "ok");
+
+        let metrics = send(&app, Method::GET, "/metrics", None).await;
+        let text = metrics.body.as_str().unwrap();
+        assert!(text.contains("yt_agent_downloads{state=\"failed\"} 1"));
+        assert!(text.contains("yt_agent_cache_entries{cache=\"formats\"} 0"));
+    }
+
+    /// The label names of every series in a Prometheus text body, and every label value seen.
+    fn labels(text: &str) -> (BTreeMap<String, BTreeSet<String>>, BTreeSet<String>) {
+        let (mut names, mut values) = (BTreeMap::new(), BTreeSet::new());
+        for line in text.lines().filter(|line| !line.starts_with('#')) {
+            let series = line.rsplit_once(' ').unwrap().0;
+            let (metric, labels) = series.split_once('{').map_or((series, ""), |(metric, labels)| (metric, labels.trim_end_matches('}')));
+            let entry: &mut BTreeSet<String> = names.entry(metric.to_string()).or_default();
+            for label in labels.split(',').filter(|label| !label.is_empty()) {
+                let (name, value) = label.split_once('=').unwrap();
+                entry.insert(name.to_string());
+                values.insert(value.trim_matches('"').to_string());
+            }
+        }
+        (names, values)
+    }
+
+    #[tokio::test]
+    async fn metrics_use_only_bounded_labels() {
+        let script = FAKE_YTDLP.replace(r#""title": "Video","#, r#""title": "Video", "extractor_key": "Youtube","#);
+        let state = state(FakeRunner::new().script("yt-dlp", &script));
+        let app = app(&state);
+        let urls = ["https://one.example.org/watch?v=first-id", "https://two.example.net/clip/second-id"];
+        for url in urls {
+            let started = send(&app, Method::POST, "/download", Some(json!({ "url": url, "format_id": "best" }))).await;
+            assert_eq!(started.status, StatusCode::ACCEPTED, "{}", started.body);
+            assert_eq!(finished(&state, url).await.status, "completed");
+        }
+
+        let metrics = send(&app, Method::GET, "/metrics", None).await;
+        let text = metrics.body.as_str().unwrap();
+        let (names, values) = labels(text);
+        let set = |labels: &[&str]| labels.iter().map(|label| label.to_string()).collect::<BTreeSet<_>>();
+        let expected: BTreeMap<String, BTreeSet<String>> = [
+            ("yt_agent_active_downloads", set(&[])),
+            ("yt_agent_downloaded_bytes_total", set(&[])),
+            ("yt_agent_cache_hits_total", set(&["cache"])),
+            ("yt_agent_cache_misses_total", set(&["cache"])),
+            ("yt_agent_cache_entries", set(&["cache"])),
+            ("yt_agent_downloads", set(&["state"])),
+            ("yt_agent_downloads_duration_seconds_bucket", set(&["extractor", "le", "outcome"])),
+            ("yt_agent_downloads_duration_seconds_sum", set(&["extractor", "outcome"])),
+            ("yt_agent_downloads_duration_seconds_count", set(&["extractor", "outcome"])),
+            ("yt_agent_bytes_downloaded_total", set(&["extractor"])),
+            ("yt_agent_poisoned_locks_total", set(&[])),
+        ]
+        .into_iter()
+        .map(|(name, labels)| (name.to_string(), labels))
+        .collect();
+        assert_eq!(names, expected, "{}", text);
+        assert!(text.contains(r#"yt_agent_bytes_downloaded_total{extractor="Youtube"}"#), "{}", text);
+        assert!(text.contains(r#"yt_agent_downloads_duration_seconds_count{extractor="Youtube",outcome="completed"} 2"#), "{}", text);
+        // Nothing from the URLs, download keys or titles makes it into a label.
+        for value in &values {
+            for leak in ["http", "example", "first-id", "second-id", "Video", "/", "?"] {
+                assert!(!value.contains(leak), "label value {:?} contains {:?}", value, leak);
+            }
+        }
+
+        // Without the extractor label the series of all extractors are summed.
+        state.config.write().unwrap().metrics_extractor_label = false;
+        let metrics = send(&app, Method::GET, "/metrics", None).await;
+        let text = metrics.body.as_str().unwrap();
+        let (names, _) = labels(text);
+        assert!(names.values().all(|labels| !labels.contains("extractor")), "{}", text);
+        assert_eq!(names["yt_agent_bytes_downloaded_total"], set(&[]));
+        assert!(text.contains(r#"yt_agent_downloads_duration_seconds_count{outcome="completed"} 2"#), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn a_used_up_quota_is_unhealthy_until_reset() {
+        let _files = lock_files().await;
+        let state = state(FakeRunner::new());
+        state.config.write().unwrap().total_bytes_quota = Some(100);
+        state.quota.add(100);
+        let app = app(&state);
+
+        // Degraded, but still 200: a restart wouldn't help.
+        let health = send(&app, Method::GET, "/health", None).await;
+        assert_eq!((health.status, health.body["status"].as_str()), (StatusCode::OK, Some("degraded")));
+        let reset = send(&app, Method::POST, "/admin/quota/reset", None).await;
+        assert_eq!(reset.status, StatusCode::OK);
+        assert_eq!(reset.body["used_bytes"], 0);
+        assert_eq!(send(&app, Method::GET, "/health", None).await.body["status"], "ok");
+    }
+
+    #[tokio::test]
+    async fn serves_the_debug_bundle_uncompressed() {
+        let state = state(FakeRunner::new());
+        let app = app(&state);
+        let request = |key: &str| Request::builder().uri("/debug/bundle").header("accept-encoding", "gzip").header("x-api-key", key);
+        state.config.write().unwrap().allow_debug_bundle = true;
+        // Without any keys there is no admin to hand the bundle to.
+        assert_eq!(send(&app, Method::GET, "/debug/bundle", None).await.status, StatusCode::UNAUTHORIZED);
+
+        {
+            let mut config = state.config.write().unwrap();
+            config.api_keys.insert("ops".to_string(), ApiKey::admin("ops-secret"));
+            config.api_keys.insert("alice".to_string(), ApiKey::user("alice-secret"));
+        }
+        assert_eq!(send(&app, Method::GET, "/debug/bundle", None).await.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(send_with(&app, request("alice-secret"), None).await.status, StatusCode::FORBIDDEN);
+
+        state.config.write().unwrap().allow_debug_bundle = false;
+        assert_eq!(send_with(&app, request("ops-secret"), None).await.status, StatusCode::FORBIDDEN);
+
+        state.config.write().unwrap().allow_debug_bundle = true;
+        let bundle = send_with(&app, request("ops-secret"), None).await;
+        assert_eq!(bundle.status, StatusCode::OK);
+        assert_eq!(bundle.headers["content-type"], "application/gzip");
+        assert!(bundle.headers.get("content-encoding").is_none());
+    }
+}
@@ -0,0 +1,209 @@
+use crate::{
+    config,
+    error::AppError,
+    models::{CookiesRequest, UploadRequest},
+    sessions::{self, NETSCAPE_HEADER},
+    AppState,
+};
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+
+use super::AdminKey;
+use serde_json::json;
+
+/// Routes for resumable uploads and the cookie file they can finish into.
+pub fn build_router(state: AppState) -> Router {
+    Router::new()
+        .route("/uploads", post(create_upload))
+        .route("/uploads/:id", get(get_upload).put(put_chunk))
+        .route("/cookies", post(replace_cookies))
+        .with_state(state)
+}
+
+// ===================================================================
+//                          UPLOAD HANDLERS
+// ===================================================================
+
+/// # POST /uploads - Starts a resumable upload of `size` bytes and returns its `upload_id`.
+pub async fn create_upload(AdminKey(_admin): AdminKey, State(state): State<AppState>, Json(payload): Json<UploadRequest>) -> Result<impl IntoResponse, AppError> {
+    let upload = state.uploads.create(&state, payload.size).await?;
+    tracing::info!("Started upload {} of {} bytes", upload.upload_id, upload.size);
+    Ok((StatusCode::CREATED, Json(upload)))
+}
+
+/// # GET /uploads/:id - Reports the byte ranges received so far, to resume after a failure.
+pub async fn get_upload(AdminKey(_admin): AdminKey, State(state): State<AppState>, Path(id): Path<String>) -> Result<impl IntoResponse, AppError> {
+    let upload = state.uploads.get(&id).ok_or_else(|| AppError::NotFound(format!("Unknown or expired upload '{}'", id)))?;
+    Ok(Json(upload))
+}
+
+/// # PUT /uploads/:id - Stores one chunk, placed by its `Content-Range` header.
+pub async fn put_chunk(
+    AdminKey(_admin): AdminKey,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    chunk: Bytes,
+) -> Result<impl IntoResponse, AppError> {
+    let content_range = headers
+        .get(header::CONTENT_RANGE)
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| AppError::BadRequest("A Content-Range header is required, e.g. 'bytes 0-1023/4096'".to_string()))?;
+    let upload = state.uploads.write_chunk(&state, &id, content_range, &chunk).await?;
+    Ok(Json(upload))
+}
+
+/// The text of an inline field or of a finished upload, whichever the request names.
+pub(crate) async fn uploaded_text(state: &AppState, inline: Option<String>, upload_id: Option<&str>, field: &str) -> Result<String, AppError> {
+    match (inline, upload_id) {
+        (Some(text), None) => Ok(text),
+        (None, Some(id)) => String::from_utf8(state.uploads.take(id).await?)
+            .map_err(|_| AppError::BadRequest(format!("Upload '{}' is not UTF-8 text", id))),
+        _ => Err(AppError::BadRequest(format!("Pass either {} or upload_id", field))),
+    }
+}
+
+// ===================================================================
+//                          COOKIE HANDLERS
+// ===================================================================
+
+/// # POST /cookies - Replaces the cookies file used for age-restricted downloads with a
+/// Netscape-format file, sent inline or as a finished upload, and points `cookies_file` at it.
+pub async fn replace_cookies(AdminKey(_admin): AdminKey, State(state): State<AppState>, Json(payload): Json<CookiesRequest>) -> Result<impl IntoResponse, AppError> {
+    let cookies = uploaded_text(&state, payload.cookies, payload.upload_id.as_deref(), "cookies").await?;
+    let count = count_cookies(&cookies)?;
+    let content = if cookies.trim_start().starts_with('#') { cookies } else { format!("{}\n{}", NETSCAPE_HEADER, cookies) };
+
+    // Written next to the final file and renamed over it, so a download never reads half a file.
+    let path = config::data_dir()?.join("cookies.txt");
+    let partial = path.with_extension("txt.partial");
+    let _ = tokio::fs::remove_file(&partial).await;
+    sessions::write_private(&partial, content.as_bytes()).await?;
+    tokio::fs::rename(&partial, &path).await?;
+
+    let mut config = state.config.read().unwrap().clone();
+    config.cookies_file = Some(path.to_string_lossy().to_string());
+    super::config::apply_config(&state, config.clone());
+    config::save_config(&config).await?;
+    tracing::info!("Replaced the cookies file with {} cookies", count);
+    Ok(Json(json!({ "cookies_file": config.cookies_file, "cookies": count })))
+}
+
+/// Counts the cookies in a Netscape-format file, refusing lines that are neither a cookie
+/// (seven tab-separated fields) nor a comment.
+fn count_cookies(content: &str) -> Result<usize, AppError> {
+    let mut count = 0;
+    for (index, line) in content.lines().enumerate() {
+        // `#HttpOnly_` marks a cookie line, not a comment.
+        let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.split('\t').count() != 7 {
+            return Err(AppError::BadRequest(format!(
+                "Line {} is not a Netscape-format cookie (seven tab-separated fields)",
+                index + 1
+            )));
+        }
+        count += 1;
+    }
+    if count == 0 {
+        return Err(AppError::BadRequest("The cookies file has no cookies".to_string()));
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::{app, config_file, lock_files, remove_config_file, send, state, FakeRunner};
+    use axum::{
+        body::Body,
+        http::{Method, Request, StatusCode},
+        Router,
+    };
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    const COOKIES: &str = "# Netscape HTTP Cookie File\n.example.com\tTRUE\t/\tFALSE\t0\tid\tabc\n#HttpOnly_.example.com\tTRUE\t/\tTRUE\t0\tsid\txyz\n";
+
+    async fn put_chunk(app: &Router, id: &str, range: &str, chunk: &str) -> StatusCode {
+        let request = Request::builder().method(Method::PUT).uri(format!("/uploads/{}", id)).header("content-range", range);
+        app.clone().oneshot(request.body(Body::from(chunk.to_string())).unwrap()).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn resumes_an_upload_and_finishes_it_into_the_cookies_file() {
+        let _files = lock_files().await;
+        remove_config_file();
+        let state = state(FakeRunner::new());
+        let app = app(&state);
+        let size = COOKIES.len();
+        assert_eq!(send(&app, Method::POST, "/uploads", Some(json!({ "size": size }))).await.status, StatusCode::FORBIDDEN);
+        state.config.write().unwrap().allow_uploads = true;
+
+        let created = send(&app, Method::POST, "/uploads", Some(json!({ "size": size }))).await;
+        assert_eq!(created.status, StatusCode::CREATED);
+        let id = created.body["upload_id"].as_str().unwrap().to_string();
+
+        let (head, tail) = COOKIES.split_at(20);
+        assert_eq!(put_chunk(&app, &id, &format!("bytes 20-{}/{}", size - 1, size), tail).await, StatusCode::OK);
+        let progress = send(&app, Method::GET, &format!("/uploads/{}", id), None).await;
+        assert_eq!(progress.body["received"], json!([{ "start": 20, "end": size - 1 }]));
+        assert_eq!(progress.body["complete"], false);
+
+        let early = send(&app, Method::POST, "/cookies", Some(json!({ "upload_id": id }))).await;
+        assert_eq!(early.status, StatusCode::CONFLICT);
+        assert_eq!(put_chunk(&app, &id, "bytes 0-19/999", head).await, StatusCode::BAD_REQUEST);
+        assert_eq!(put_chunk(&app, &id, &format!("bytes 0-19/{}", size), head).await, StatusCode::OK);
+        assert_eq!(send(&app, Method::GET, &format!("/uploads/{}", id), None).await.body["complete"], true);
+
+        let replaced = send(&app, Method::POST, "/cookies", Some(json!({ "upload_id": id }))).await;
+        assert_eq!(replaced.status, StatusCode::OK, "{}", replaced.body);
+        assert_eq!(replaced.body["cookies"], 2);
+        let path = state.config.read().unwrap().cookies_file.clone().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), COOKIES);
+        assert!(std::fs::read_to_string(config_file()).unwrap().contains("cookies_file"));
+        assert_eq!(send(&app, Method::GET, &format!("/uploads/{}", id), None).await.status, StatusCode::NOT_FOUND);
+        remove_config_file();
+    }
+
+    #[tokio::test]
+    async fn refuses_uploads_above_the_limits() {
+        let state = state(FakeRunner::new());
+        {
+            let mut config = state.config.write().unwrap();
+            config.allow_uploads = true;
+            config.max_upload_bytes = 100;
+            config.max_uploads = 2;
+        }
+        let app = app(&state);
+        assert_eq!(send(&app, Method::POST, "/uploads", Some(json!({ "size": 101 }))).await.status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(send(&app, Method::POST, "/uploads", Some(json!({ "size": 0 }))).await.status, StatusCode::BAD_REQUEST);
+
+        let first = send(&app, Method::POST, "/uploads", Some(json!({ "size": 100 }))).await;
+        assert_eq!(first.status, StatusCode::CREATED);
+        assert_eq!(send(&app, Method::POST, "/uploads", Some(json!({ "size": 10 }))).await.status, StatusCode::CREATED);
+        let third = send(&app, Method::POST, "/uploads", Some(json!({ "size": 10 }))).await;
+        assert_eq!(third.status, StatusCode::TOO_MANY_REQUESTS, "{}", third.body);
+
+        let id = first.body["upload_id"].as_str().unwrap();
+        assert_eq!(put_chunk(&app, id, "bytes 0-99/100", &"x".repeat(100)).await, StatusCode::OK);
+        let imported = send(&app, Method::POST, "/download/import", Some(json!({ "upload_id": id }))).await;
+        assert_ne!(imported.status, StatusCode::NOT_FOUND, "{}", imported.body);
+        assert_eq!(send(&app, Method::POST, "/uploads", Some(json!({ "size": 10 }))).await.status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn refuses_files_that_are_not_cookies() {
+        let app = app(&state(FakeRunner::new()));
+        let refused = send(&app, Method::POST, "/cookies", Some(json!({ "cookies": "id=abc; path=/" }))).await;
+        assert_eq!(refused.status, StatusCode::BAD_REQUEST);
+        assert_eq!(send(&app, Method::POST, "/cookies", Some(json!({}))).await.status, StatusCode::BAD_REQUEST);
+    }
+}
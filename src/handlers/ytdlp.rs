@@ -0,0 +1,111 @@
+use crate::{
+    config::{self, YtDlpChannel},
+    error::AppError,
+    models::YtDlpInstallRequest,
+    ytdlp::{self, Switch},
+    AppState,
+};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+
+use super::AdminKey;
+use serde_json::json;
+
+/// Routes for the yt-dlp releases the server manages itself (`ytdlp_channel = "managed"`).
+pub fn build_router(state: AppState) -> Router {
+    Router::new()
+        .route("/ytdlp", get(get_ytdlp))
+        .route("/ytdlp/install", post(install_ytdlp))
+        .with_state(state)
+}
+
+// ===================================================================
+//                          YT-DLP HANDLERS
+// ===================================================================
+
+/// # GET /ytdlp - Lists the installed managed versions and shows which binary is in use.
+pub async fn get_ytdlp(State(state): State<AppState>) -> Result<impl IntoResponse, AppError> {
+    let (channel, configured) = {
+        let config = state.config.read().unwrap();
+        (config.ytdlp_channel, config.ytdlp_version.clone())
+    };
+    Ok(Json(json!({
+        "channel": channel,
+        "configured_version": configured,
+        "active": ytdlp::active(),
+        "program": ytdlp::program(),
+        "pending": state.ytdlp.pending(),
+        "installing": state.ytdlp.is_installing(),
+        "installed": ytdlp::installed().await?,
+    })))
+}
+
+/// # POST /ytdlp/install - Downloads, verifies and switches to a yt-dlp release.
+/// Answers 200 once it is active, or 202 when it waits for queued and running downloads to
+/// finish first; downloads added meanwhile wait for the switch. The version is saved as
+/// `ytdlp_version`. A failed download or checksum leaves the active binary in place.
+pub async fn install_ytdlp(
+    AdminKey(_admin): AdminKey,
+    State(state): State<AppState>,
+    Json(payload): Json<YtDlpInstallRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    if state.config.read().unwrap().ytdlp_channel != YtDlpChannel::Managed {
+        return Err(AppError::Conflict("ytdlp_channel is \"system\"; set it to \"managed\" to install yt-dlp releases".to_string()));
+    }
+    if !ytdlp::is_valid_version(&payload.version) {
+        return Err(AppError::BadRequest(format!("'{}' is not a release name, e.g. \"2024.08.06\" or \"latest\"", payload.version)));
+    }
+
+    let switch = ytdlp::install_and_switch(&state, &payload.version).await?;
+    let config = {
+        let mut config = state.config.write().unwrap();
+        config.ytdlp_version = payload.version.clone();
+        config.clone()
+    };
+    // An unconfigured server keeps running on the defaults until `POST /setup`.
+    if config::config_file_exists().await? {
+        config::save_config(&config).await?;
+    }
+
+    match switch {
+        Switch::Active(installed) => Ok((StatusCode::OK, Json(json!({ "active": installed, "pending": null })))),
+        Switch::Pending(installed) => {
+            let waiting_for = crate::count_active_downloads(&state);
+            Ok((StatusCode::ACCEPTED, Json(json!({ "active": ytdlp::active(), "pending": installed, "waiting_for_downloads": waiting_for }))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::YtDlpChannel;
+    use crate::test_support::{app, send, state, FakeRunner};
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn shows_the_yt_dlp_in_use() {
+        let app = app(&state(FakeRunner::new()));
+        let response = send(&app, Method::GET, "/ytdlp", None).await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.body["channel"], "system");
+        assert_eq!(response.body["installing"], false);
+    }
+
+    #[tokio::test]
+    async fn installs_only_on_the_managed_channel() {
+        let state = state(FakeRunner::new());
+        let app = app(&state);
+        let body = json!({ "version": "2024.08.06" });
+        assert_eq!(send(&app, Method::POST, "/ytdlp/install", Some(body)).await.status, StatusCode::CONFLICT);
+
+        state.config.write().unwrap().ytdlp_channel = YtDlpChannel::Managed;
+        let body = json!({ "version": "../evil" });
+        assert_eq!(send(&app, Method::POST, "/ytdlp/install", Some(body)).await.status, StatusCode::BAD_REQUEST);
+    }
+}
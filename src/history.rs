@@ -0,0 +1,340 @@
+use crate::{config, models::HistoryEntry, persist};
+use anyhow::Result;
+use futures::{Stream, StreamExt};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tokio::fs;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio_stream::wrappers::LinesStream;
+
+/// The `--print-to-file` template that captures provenance for each finished item.
+/// `after_move` fires once per item with the final file path.
+pub const PROVENANCE_TEMPLATE: &str =
+    "after_move:%(.{id,title,uploader,playlist,playlist_index,extractor_key,upload_date,duration,webpage_url,filepath})j";
+
+static BY_VIDEO_ID: Lazy<Mutex<VideoIndex>> = Lazy::new(Default::default);
+
+/// Held while the history file is written, so an append can't land in the middle of
+/// `relink` rewriting it.
+static WRITE_LOCK: Lazy<tokio::sync::Mutex<()>> = Lazy::new(Default::default);
+
+/// The latest entry per video ID, with the size and mtime of the history file it was built
+/// from. Rebuilt when the file changes; appends always change its size.
+#[derive(Default)]
+struct VideoIndex {
+    built_from: Option<(u64, SystemTime)>,
+    entries: HashMap<String, HistoryEntry>,
+}
+
+/// One line written by `PROVENANCE_TEMPLATE`.
+#[derive(Deserialize)]
+struct ProvenanceRecord {
+    id: Option<String>,
+    title: Option<String>,
+    uploader: Option<String>,
+    playlist: Option<String>,
+    playlist_index: Option<u64>,
+    extractor_key: Option<String>,
+    upload_date: Option<String>,
+    duration: Option<f64>,
+    webpage_url: Option<String>,
+    filepath: Option<String>,
+}
+
+/// Returns the path of the history file (one JSON entry per line).
+fn history_path() -> Result<PathBuf> {
+    Ok(config::data_dir()?.join("history.jsonl"))
+}
+
+/// Returns a fresh path for a download's `--print-to-file` provenance capture.
+pub fn provenance_capture_path() -> Result<PathBuf> {
+    let dir = config::data_dir()?.join("provenance");
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir.join(format!("{}.jsonl", uuid::Uuid::new_v4())))
+}
+
+/// Turns a finished download's provenance capture into history entries and removes the capture file.
+/// A missing capture (e.g. nothing was downloaded) yields no entries.
+pub async fn collect_provenance(capture_path: &Path, download_key: &str, fallback_url: &str, format: &str) -> Vec<HistoryEntry> {
+    let content = fs::read_to_string(capture_path).await.unwrap_or_default();
+    let _ = fs::remove_file(capture_path).await;
+    let downloaded_at = chrono::Utc::now().to_rfc3339();
+
+    content
+        .lines()
+        .filter_map(|line| match serde_json::from_str::<ProvenanceRecord>(line) {
+            Ok(record) => Some(record),
+            Err(e) => {
+                tracing::warn!("Ignoring malformed provenance line for {}: {}", download_key, e);
+                None
+            }
+        })
+        .map(|record| HistoryEntry {
+            download_key: download_key.to_string(),
+            url: record.webpage_url.unwrap_or_else(|| fallback_url.to_string()),
+            video_id: record.id,
+            title: record.title,
+            uploader: record.uploader,
+            playlist: record.playlist,
+            playlist_index: record.playlist_index,
+            extractor: record.extractor_key,
+            upload_date: record.upload_date,
+            duration: record.duration,
+            started_at: None,
+            downloaded_at: downloaded_at.clone(),
+            filepath: record.filepath,
+            format: Some(format.to_string()),
+            origin: None,
+            filesize: None,
+            checksums: Vec::new(),
+            file_refs: Vec::new(),
+            missing: false,
+            files: Vec::new(),
+            outcome: None,
+        })
+        .collect()
+}
+
+/// Combines the entries of a job group's children into one entry per item, keyed to the
+/// parent job. The first child's file becomes `filepath`; all of them go into `files`.
+pub fn merge_group(parent_key: &str, entries: Vec<HistoryEntry>) -> Vec<HistoryEntry> {
+    let mut merged: Vec<HistoryEntry> = Vec::new();
+    for entry in entries {
+        let existing = merged.iter_mut().find(|m| m.video_id == entry.video_id && m.url == entry.url);
+        match existing {
+            Some(item) => {
+                item.files.extend(entry.filepath);
+                item.checksums.extend(entry.checksums);
+                item.filesize = item.filesize.into_iter().chain(entry.filesize).reduce(|a, b| a + b);
+            }
+            None => merged.push(HistoryEntry {
+                download_key: parent_key.to_string(),
+                files: entry.filepath.iter().cloned().collect(),
+                ..entry
+            }),
+        }
+    }
+    merged
+}
+
+/// Appends entries to the history file.
+pub async fn append(entries: &[HistoryEntry]) -> Result<()> {
+    if entries.is_empty() {
+        return Ok(());
+    }
+    let mut buffer = String::new();
+    for entry in entries {
+        buffer.push_str(&serde_json::to_string(entry)?);
+        buffer.push('\n');
+    }
+    let _lock = WRITE_LOCK.lock().await;
+    persist::append(&history_path()?, buffer.as_bytes()).await
+}
+
+/// Once the history file is larger than `max_bytes`, drops its oldest entries until it is at
+/// most three quarters of that, so it isn't rewritten again after the next download. Malformed
+/// lines are dropped too, as `load` skips them anyway. Returns how many entries were dropped.
+pub async fn compact(max_bytes: u64) -> Result<usize> {
+    let path = history_path()?;
+    match fs::metadata(&path).await {
+        Ok(meta) if meta.len() > max_bytes => {}
+        Ok(_) => return Ok(0),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    }
+    let _lock = WRITE_LOCK.lock().await;
+    // Locked across the read and the rewrite, so a CLI download appending meanwhile isn't lost.
+    let file = persist::lock(&path).await?;
+    let content = fs::read_to_string(&path).await?;
+    if content.len() as u64 <= max_bytes {
+        return Ok(0);
+    }
+    let lines: Vec<&str> = content.lines().filter(|line| serde_json::from_str::<HistoryEntry>(line).is_ok()).collect();
+    let target = max_bytes / 4 * 3;
+    let mut kept_bytes = 0;
+    let kept = lines.iter().rev().take_while(|line| {
+        kept_bytes += line.len() as u64 + 1;
+        kept_bytes <= target
+    });
+    let kept = kept.count();
+    let mut buffer = String::new();
+    for line in &lines[lines.len() - kept..] {
+        buffer.push_str(line);
+        buffer.push('\n');
+    }
+    file.write(buffer.as_bytes()).await?;
+    Ok(lines.len() - kept)
+}
+
+/// Points every entry that refers to a key of `moved` at its value instead, in `filepath`,
+/// `files` and `checksums`, and rewrites the history file in one go. The keys are absolute;
+/// relative paths in entries are taken relative to the working directory, as yt-dlp wrote
+/// them. Malformed lines are dropped, as `load` skips them anyway. Returns how many entries
+/// changed.
+pub async fn relink(moved: &HashMap<PathBuf, String>) -> Result<usize> {
+    if moved.is_empty() {
+        return Ok(0);
+    }
+    let _lock = WRITE_LOCK.lock().await;
+    // Locked across the load and the rewrite, so a CLI download appending meanwhile isn't lost.
+    let file = persist::lock(&history_path()?).await?;
+    let mut entries = load().await?;
+    let mut changed = 0;
+    for entry in &mut entries {
+        let mut touched = false;
+        let paths = entry.filepath.iter_mut().chain(entry.files.iter_mut()).chain(entry.checksums.iter_mut().map(|c| &mut c.path));
+        for path in paths {
+            if let Some(new_path) = std::path::absolute(path.as_str()).ok().and_then(|absolute| moved.get(&absolute)) {
+                *path = new_path.clone();
+                touched = true;
+            }
+        }
+        changed += usize::from(touched);
+    }
+    if changed > 0 {
+        let mut buffer = String::new();
+        for entry in &entries {
+            buffer.push_str(&serde_json::to_string(entry)?);
+            buffer.push('\n');
+        }
+        file.write(buffer.as_bytes()).await?;
+    }
+    Ok(changed)
+}
+
+/// Writes a `<file>.source.json` sidecar next to the entry's file.
+pub async fn write_sidecar(entry: &HistoryEntry) -> Result<()> {
+    if let Some(filepath) = &entry.filepath {
+        let sidecar = format!("{}.source.json", filepath);
+        fs::write(sidecar, serde_json::to_vec_pretty(entry)?).await?;
+    }
+    Ok(())
+}
+
+/// Loads every history entry, oldest first. A missing history file is an empty history.
+pub async fn load() -> Result<Vec<HistoryEntry>> {
+    let path = history_path()?;
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let content = fs::read_to_string(&path).await?;
+    Ok(content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+/// Reads the history entries one line at a time, oldest first, without loading the whole
+/// file. Malformed lines are skipped, as by `load`. A missing history file is an empty history.
+pub async fn stream() -> Result<impl Stream<Item = HistoryEntry>> {
+    let file = match fs::File::open(history_path()?).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(futures::stream::empty().left_stream()),
+        Err(e) => return Err(e.into()),
+    };
+    let entries = LinesStream::new(BufReader::new(file).lines())
+        .take_while(|line| {
+            if let Err(e) = line {
+                tracing::warn!("Stopped reading history: {}", e);
+            }
+            futures::future::ready(line.is_ok())
+        })
+        .filter_map(|line| async move { serde_json::from_str(&line.ok()?).ok() });
+    Ok(entries.right_stream())
+}
+
+/// Returns the latest entry for a video ID, from an index that is only rebuilt when the
+/// history file changed. A missing history file has no entries.
+pub async fn latest_for_video(video_id: &str) -> Result<Option<HistoryEntry>> {
+    let stamp = match fs::metadata(history_path()?).await {
+        Ok(meta) => (meta.len(), meta.modified()?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    {
+        let index = BY_VIDEO_ID.lock().unwrap();
+        if index.built_from == Some(stamp) {
+            return Ok(index.entries.get(video_id).cloned());
+        }
+    }
+    let mut entries = HashMap::new();
+    for entry in load().await? {
+        if let Some(id) = entry.video_id.clone() {
+            entries.insert(id, entry);
+        }
+    }
+    let found = entries.get(video_id).cloned();
+    *BY_VIDEO_ID.lock().unwrap() = VideoIndex { built_from: Some(stamp), entries };
+    Ok(found)
+}
+
+/// Maps every file path in `entries` to its entry. The latest entry wins if the same file
+/// was downloaded more than once.
+pub fn by_file(entries: Vec<HistoryEntry>) -> HashMap<PathBuf, HistoryEntry> {
+    let mut sources = HashMap::new();
+    for entry in entries {
+        for filepath in entry.files.iter().chain(entry.filepath.iter()) {
+            sources.insert(PathBuf::from(filepath), entry.clone());
+        }
+    }
+    sources
+}
+
+/// Case-insensitive substring search over the identifying fields of each entry.
+pub fn matches(entry: &HistoryEntry, query: &str) -> bool {
+    let query = query.to_lowercase();
+    [
+        Some(&entry.url),
+        Some(&entry.download_key),
+        entry.video_id.as_ref(),
+        entry.title.as_ref(),
+        entry.uploader.as_ref(),
+        entry.filepath.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    .chain(entry.files.iter())
+    .any(|field| field.to_lowercase().contains(&query))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{isolate_home, lock_files};
+
+    fn entry(n: usize) -> HistoryEntry {
+        HistoryEntry {
+            download_key: format!("https://example.com/compact{}", n),
+            url: format!("https://example.com/compact{}", n),
+            downloaded_at: "2024-01-01T00:00:00Z".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn compaction_drops_the_oldest_entries() {
+        let _files = lock_files().await;
+        isolate_home();
+        let path = history_path().unwrap();
+        let _ = std::fs::remove_file(&path);
+        append(&(0..100).map(entry).collect::<Vec<_>>()).await.unwrap();
+        // A line cut short by a crash is dropped along the way.
+        std::io::Write::write_all(&mut std::fs::OpenOptions::new().append(true).open(&path).unwrap(), b"{\"cut\n").unwrap();
+        let size = std::fs::metadata(&path).unwrap().len();
+
+        assert_eq!(compact(size).await.unwrap(), 0);
+        let dropped = compact(size / 2).await.unwrap();
+        let kept = load().await.unwrap();
+        assert_eq!(dropped + kept.len(), 100);
+        assert!(std::fs::metadata(&path).unwrap().len() <= size / 2 / 4 * 3);
+        // The newest entries stay, in order.
+        assert_eq!(kept.last().unwrap().url, "https://example.com/compact99");
+        assert_eq!(kept[0].url, format!("https://example.com/compact{}", dropped));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,329 @@
+use crate::{
+    checksum,
+    error::AppError,
+    handlers::files::{locate_file, walk_dir},
+    history, AppState,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+/// How often the refresh loop checks whether `library_index_interval_secs` was set.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A file of a download root as the index last saw it.
+#[derive(Clone, Debug)]
+pub struct IndexedFile {
+    /// Relative to the download root.
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+struct RootIndex {
+    /// The root's canonical path, to place the files of finished downloads.
+    canonical: Option<PathBuf>,
+    files: Vec<IndexedFile>,
+    /// Where each path is in `files`, so a finished playlist's files are found one by one.
+    positions: HashMap<PathBuf, usize>,
+    built_at: DateTime<Utc>,
+}
+
+/// The files of each download root, kept in memory when `library_index_interval_secs` is set
+/// so `GET /files`, `GET /stats` and the library don't walk the directory on every call.
+/// Refreshed periodically by `run`, on `POST /admin/reindex`, and with the files of each
+/// finished download. Keyed by the root as configured.
+#[derive(Clone, Default)]
+pub struct LibraryIndex {
+    roots: Arc<RwLock<HashMap<PathBuf, RootIndex>>>,
+}
+
+impl LibraryIndex {
+    fn get(&self, dir: &Path) -> Option<Vec<IndexedFile>> {
+        self.roots.read().unwrap().get(dir).map(|root| root.files.clone())
+    }
+
+    fn store(&self, dir: &Path, files: Vec<IndexedFile>) {
+        let positions = files.iter().enumerate().map(|(index, file)| (file.path.clone(), index)).collect();
+        let root = RootIndex { canonical: std::fs::canonicalize(dir).ok(), files, positions, built_at: Utc::now() };
+        self.roots.write().unwrap().insert(dir.to_path_buf(), root);
+    }
+
+    /// Drops every root, e.g. after the roots or `follow_symlinks` changed. They are walked
+    /// again when next needed.
+    pub fn clear(&self) {
+        self.roots.write().unwrap().clear();
+    }
+
+    /// Adds or updates the files of a finished download in the roots they are under.
+    pub fn add_files(&self, files: &[String]) {
+        let mut roots = self.roots.write().unwrap();
+        if roots.is_empty() {
+            return;
+        }
+        for file in files {
+            let (Ok(canonical), Ok(meta)) = (std::fs::canonicalize(file), std::fs::metadata(file)) else { continue };
+            for root in roots.values_mut() {
+                let Some(relative) = root.canonical.as_deref().and_then(|base| canonical.strip_prefix(base).ok()) else { continue };
+                let entry = IndexedFile { path: relative.to_path_buf(), size: meta.len(), modified: meta.modified().ok() };
+                match root.positions.get(&entry.path) {
+                    Some(&index) => root.files[index] = entry,
+                    None => {
+                        root.positions.insert(entry.path.clone(), root.files.len());
+                        root.files.push(entry);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Every indexed file by its full path under the root's canonical path.
+    fn canonical_files(&self) -> Vec<IndexedFile> {
+        let roots = self.roots.read().unwrap();
+        roots
+            .values()
+            .filter_map(|root| Some((root.canonical.as_deref()?, &root.files)))
+            .flat_map(|(base, files)| files.iter().map(|file| IndexedFile { path: base.join(&file.path), ..file.clone() }))
+            .collect()
+    }
+
+    /// When each root was last walked, for `POST /admin/reindex`.
+    pub fn built_at(&self) -> HashMap<String, DateTime<Utc>> {
+        let roots = self.roots.read().unwrap();
+        roots.iter().map(|(dir, root)| (dir.to_string_lossy().to_string(), root.built_at)).collect()
+    }
+}
+
+/// Whether the index is on, i.e. `library_index_interval_secs` is set.
+pub fn enabled(state: &AppState) -> bool {
+    state.config.read().unwrap().library_index_interval_secs.is_some()
+}
+
+/// The indexed files of `dir`, walking it first if it isn't indexed yet. `None` when the
+/// index is off.
+pub async fn indexed(state: &AppState, dir: &Path) -> Result<Option<Vec<IndexedFile>>, AppError> {
+    if !enabled(state) {
+        return Ok(None);
+    }
+    if let Some(files) = state.library_index.get(dir) {
+        return Ok(Some(files));
+    }
+    let (scanning, scanned_dir) = (state.clone(), dir.to_path_buf());
+    let files = tokio::task::spawn_blocking(move || scan(&scanning, &scanned_dir)).await??;
+    state.library_index.store(dir, files.clone());
+    Ok(Some(files))
+}
+
+/// Walks `dir` like `GET /files` does and records the size and mtime of each file.
+fn scan(state: &AppState, dir: &Path) -> Result<Vec<IndexedFile>, AppError> {
+    Ok(walk_dir(state, dir)?
+        .into_iter()
+        .map(|path| {
+            let meta = std::fs::metadata(dir.join(&path)).ok();
+            IndexedFile {
+                size: meta.as_ref().map_or(0, |m| m.len()),
+                modified: meta.and_then(|m| m.modified().ok()),
+                path,
+            }
+        })
+        .collect())
+}
+
+/// Walks every download root again and replaces the index. Returns how many files it holds.
+pub async fn refresh(state: &AppState) -> Result<usize, AppError> {
+    let roots: Vec<PathBuf> = {
+        let config = state.config.read().unwrap();
+        std::iter::once(&config.download_directory).chain(config.allowed_download_roots.iter()).map(PathBuf::from).collect()
+    };
+    let scanning = state.clone();
+    let scanned = tokio::task::spawn_blocking(move || {
+        roots.into_iter().map(|dir| scan(&scanning, &dir).map(|files| (dir, files))).collect::<Result<Vec<_>, AppError>>()
+    })
+    .await??;
+
+    let total = scanned.iter().map(|(_, files)| files.len()).sum();
+    state.library_index.clear();
+    for (dir, files) in scanned {
+        state.library_index.store(&dir, files);
+    }
+    Ok(total)
+}
+
+/// A history file found again under a new path by `reconcile`.
+#[derive(Serialize, Debug)]
+pub struct Relinked {
+    pub from: String,
+    pub to: String,
+}
+
+/// What `reconcile` did, for `POST /admin/reindex`.
+#[derive(Serialize, Debug, Default)]
+pub struct Reconciliation {
+    pub relinked: Vec<Relinked>,
+    /// History files that are gone and weren't found elsewhere.
+    pub missing: usize,
+}
+
+/// A file the history refers to that no longer exists where it was downloaded.
+struct LostFile {
+    /// Absolute, so the relative paths some entries and statuses hold compare equal.
+    path: PathBuf,
+    size: u64,
+    checksum: Option<crate::models::FileChecksum>,
+}
+
+/// Finds history files that were moved or renamed outside the server among the indexed
+/// files, and points the history and the download statuses at their new paths. A lost file
+/// matches an indexed file that no history entry refers to, with the same size and extension,
+/// and the same checksum if one was recorded. Without a checksum, only a single such file
+/// counts as a match. Files whose size wasn't recorded can't be matched.
+pub async fn reconcile(state: &AppState) -> Result<Reconciliation, AppError> {
+    let entries = history::load().await?;
+    let indexed = state.library_index.canonical_files();
+    let (lost, claimed) = tokio::task::spawn_blocking(move || {
+        let mut lost: HashMap<PathBuf, LostFile> = HashMap::new();
+        let mut claimed = HashSet::new();
+        for entry in &entries {
+            // A job group's size covers all its files, so only single files can be matched by it.
+            let single = entry.files.len() <= 1;
+            for path in entry.filepath.iter().chain(entry.files.iter()) {
+                match std::fs::canonicalize(path) {
+                    Ok(canonical) => {
+                        claimed.insert(canonical);
+                    }
+                    Err(_) => {
+                        let Ok(absolute) = std::path::absolute(path) else { continue };
+                        let size = entry.filesize.filter(|_| single && entry.filepath.as_ref() == Some(path));
+                        let checksum = entry.checksums.iter().find(|c| std::path::absolute(&c.path).ok().as_ref() == Some(&absolute)).cloned();
+                        lost.entry(absolute.clone()).or_insert(LostFile { path: absolute, size: size.unwrap_or_default(), checksum });
+                    }
+                }
+            }
+        }
+        (lost.into_values().collect::<Vec<_>>(), claimed)
+    })
+    .await?;
+
+    let mut taken = claimed;
+    let mut moved = HashMap::new();
+    let mut reconciliation = Reconciliation::default();
+    for file in lost {
+        let extension = file.path.extension();
+        let candidates: Vec<&IndexedFile> = indexed
+            .iter()
+            .filter(|c| file.size > 0 && c.size == file.size && c.path.extension() == extension && !taken.contains(&c.path))
+            .collect();
+        let found = match &file.checksum {
+            Some(recorded) => {
+                let mut found = None;
+                for candidate in candidates {
+                    if checksum::file_digest(&candidate.path, recorded.algorithm).await.is_ok_and(|digest| digest == recorded.digest) {
+                        found = Some(candidate);
+                        break;
+                    }
+                }
+                found
+            }
+            None if candidates.len() == 1 => candidates.first().copied(),
+            None => None,
+        };
+        match found {
+            Some(candidate) => {
+                taken.insert(candidate.path.clone());
+                let to = candidate.path.to_string_lossy().to_string();
+                reconciliation.relinked.push(Relinked { from: file.path.to_string_lossy().to_string(), to: to.clone() });
+                moved.insert(file.path, to);
+            }
+            None => reconciliation.missing += 1,
+        }
+    }
+
+    history::relink(&moved).await?;
+    if !moved.is_empty() {
+        let moved_to = |path: &str| std::path::absolute(path).ok().and_then(|absolute| moved.get(&absolute).cloned());
+        let mut map = state.downloads.lock();
+        for status in map.values_mut() {
+            if status.files.iter().any(|file| moved_to(file).is_some()) {
+                for file in status.files.iter_mut() {
+                    if let Some(to) = moved_to(file) {
+                        *file = to;
+                    }
+                }
+                for checksum in status.checksums.iter_mut() {
+                    if let Some(to) = moved_to(&checksum.path) {
+                        checksum.path = to;
+                    }
+                }
+                status.file_refs = status.files.iter().filter_map(|file| locate_file(state, Path::new(file))).collect();
+            }
+        }
+    }
+    Ok(reconciliation)
+}
+
+/// Refreshes the index every `library_index_interval_secs`, then reconciles the history with
+/// it. Runs until the server exits.
+pub async fn run(state: AppState) {
+    loop {
+        let Some(interval) = state.config.read().unwrap().library_index_interval_secs else {
+            tokio::time::sleep(IDLE_CHECK_INTERVAL).await;
+            continue;
+        };
+        match refresh(&state).await {
+            Ok(files) => {
+                tracing::info!("Refreshed the library index: {} files", files);
+                match reconcile(&state).await {
+                    Ok(reconciliation) => log_reconciliation(&reconciliation),
+                    Err(AppError::Internal(e)) => tracing::warn!("Failed to reconcile the history with the library: {:?}", e),
+                    Err(e) => tracing::warn!("Failed to reconcile the history with the library: {}", e),
+                }
+            }
+            Err(AppError::Internal(e)) => tracing::warn!("Failed to refresh the library index: {:?}", e),
+            Err(e) => tracing::warn!("Failed to refresh the library index: {}", e),
+        }
+        tokio::time::sleep(Duration::from_secs(interval.max(1))).await;
+    }
+}
+
+/// Logs each relinked file and a summary, when there is anything to report.
+pub fn log_reconciliation(reconciliation: &Reconciliation) {
+    for relinked in &reconciliation.relinked {
+        tracing::info!("Found moved history file {} at {}", relinked.from, relinked.to);
+    }
+    if !reconciliation.relinked.is_empty() || reconciliation.missing > 0 {
+        tracing::info!(
+            "Reconciled the history with the library: {} moved files relinked, {} missing",
+            reconciliation.relinked.len(),
+            reconciliation.missing
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::{state, FakeRunner};
+    use std::path::{Path, PathBuf};
+
+    #[tokio::test]
+    async fn adds_finished_files_once_and_updates_the_ones_it_has() {
+        let state = state(FakeRunner::new());
+        state.config.write().unwrap().library_index_interval_secs = Some(3600);
+        let dir = PathBuf::from(&state.config.read().unwrap().download_directory);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("old.mp4"), b"old").unwrap();
+        let Ok(Some(indexed)) = super::indexed(&state, &dir).await else { panic!("not indexed") };
+        assert_eq!(indexed.iter().map(|file| (file.path.clone(), file.size)).collect::<Vec<_>>(), [(PathBuf::from("old.mp4"), 3)]);
+
+        std::fs::write(dir.join("old.mp4"), b"replaced").unwrap();
+        std::fs::write(dir.join("new.mp4"), b"new").unwrap();
+        let finished: Vec<String> = ["old.mp4", "new.mp4", "old.mp4"].iter().map(|name| dir.join(name).to_string_lossy().to_string()).collect();
+        state.library_index.add_files(&finished);
+
+        let files = state.library_index.get(&dir).unwrap();
+        let listed: Vec<(&Path, u64)> = files.iter().map(|file| (file.path.as_path(), file.size)).collect();
+        assert_eq!(listed, [(Path::new("old.mp4"), 8), (Path::new("new.mp4"), 3)]);
+    }
+}
@@ -0,0 +1,86 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+/// How many lines a slow subscriber may fall behind before the oldest are dropped for it.
+const SUBSCRIBER_BUFFER_LINES: usize = 256;
+
+/// How many of a running download's latest lines are kept to replay to new followers.
+const BACKLOG_LINES: usize = 200;
+
+/// The output lines of running downloads, fanned out to anyone tailing them.
+#[derive(Clone, Default)]
+pub struct LiveLogs {
+    channels: Arc<Mutex<HashMap<String, LogSender>>>,
+}
+
+/// Publishes one download's lines while it runs; subscribers see the stream end when it drops.
+pub struct LogPublisher {
+    logs: LiveLogs,
+    key: String,
+    sender: LogSender,
+}
+
+/// A handle for publishing a download's lines, e.g. from the stderr reader.
+#[derive(Clone)]
+pub struct LogSender {
+    sender: broadcast::Sender<String>,
+    /// The latest lines. Lines are sent while it is locked, so a follower's backlog and
+    /// receiver never overlap or leave a gap.
+    backlog: Arc<Mutex<VecDeque<String>>>,
+}
+
+impl LiveLogs {
+    /// Starts a download's log stream, replacing any left from an earlier run of the same key.
+    pub fn open(&self, key: &str) -> LogPublisher {
+        let (sender, _) = broadcast::channel(SUBSCRIBER_BUFFER_LINES);
+        let sender = LogSender { sender, backlog: Arc::new(Mutex::new(VecDeque::new())) };
+        self.channels.lock().unwrap().insert(key.to_string(), sender.clone());
+        LogPublisher { logs: self.clone(), key: key.to_string(), sender }
+    }
+
+    /// Follows a running download's lines from now on, or `None` if it isn't running.
+    pub fn subscribe(&self, key: &str) -> Option<broadcast::Receiver<String>> {
+        self.channels.lock().unwrap().get(key).map(|sender| sender.sender.subscribe())
+    }
+
+    /// Like `subscribe`, but also returns the latest lines published so far.
+    pub fn follow(&self, key: &str) -> Option<(Vec<String>, broadcast::Receiver<String>)> {
+        let channels = self.channels.lock().unwrap();
+        let sender = channels.get(key)?;
+        let backlog = sender.backlog.lock().unwrap();
+        Some((backlog.iter().cloned().collect(), sender.sender.subscribe()))
+    }
+}
+
+impl LogPublisher {
+    /// A handle for publishing from another task, e.g. the stderr reader.
+    pub fn sender(&self) -> LogSender {
+        self.sender.clone()
+    }
+
+    pub fn publish(&self, line: &str) {
+        self.sender.publish(line);
+    }
+}
+
+impl LogSender {
+    pub fn publish(&self, line: &str) {
+        let mut backlog = self.backlog.lock().unwrap();
+        if backlog.len() == BACKLOG_LINES {
+            backlog.pop_front();
+        }
+        backlog.push_back(line.to_string());
+        // Fails only when nobody is listening.
+        let _ = self.sender.send(line.to_string());
+    }
+}
+
+impl Drop for LogPublisher {
+    fn drop(&mut self) {
+        let mut channels = self.logs.channels.lock().unwrap();
+        if channels.get(&self.key).is_some_and(|sender| sender.sender.same_channel(&self.sender.sender)) {
+            channels.remove(&self.key);
+        }
+    }
+}
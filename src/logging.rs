@@ -0,0 +1,101 @@
+use once_cell::sync::Lazy;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex};
+use tracing_subscriber::{filter::LevelFilter, fmt, fmt::MakeWriter, layer::SubscriberExt, util::SubscriberInitExt};
+
+/// How many formatted log lines are kept in memory for the debug bundle.
+const LOG_BUFFER_CAPACITY: usize = 500;
+
+/// Where and why the latest panic happened, e.g. "src/handlers/download.rs:120:9: index out of bounds".
+static LAST_PANIC: Lazy<Mutex<Option<String>>> = Lazy::new(Default::default);
+
+/// An in-memory ring buffer holding the most recent log lines.
+#[derive(Clone, Default)]
+pub struct LogBuffer {
+    lines: Arc<Mutex<VecDeque<String>>>,
+}
+
+impl LogBuffer {
+    /// Returns a copy of the buffered lines, oldest first.
+    pub fn snapshot(&self) -> Vec<String> {
+        self.lines.lock().unwrap().iter().cloned().collect()
+    }
+
+    fn push(&self, line: String) {
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() == LOG_BUFFER_CAPACITY {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+}
+
+/// A writer handed out per log event; the formatted event is committed to the buffer on drop.
+pub struct LogBufferWriter {
+    buffer: LogBuffer,
+    pending: Vec<u8>,
+}
+
+impl io::Write for LogBufferWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for LogBufferWriter {
+    fn drop(&mut self) {
+        let text = String::from_utf8_lossy(&self.pending);
+        for line in text.lines().filter(|l| !l.is_empty()) {
+            self.buffer.push(line.to_string());
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogBuffer {
+    type Writer = LogBufferWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        LogBufferWriter { buffer: self.clone(), pending: Vec::new() }
+    }
+}
+
+/// Installs the global tracing subscriber: human-readable logs on stdout,
+/// plus a plain-text copy kept in `buffer`. Also starts remembering panics for `last_panic`.
+pub fn init(buffer: LogBuffer) {
+    tracing_subscriber::registry()
+        .with(LevelFilter::INFO)
+        .with(fmt::layer())
+        .with(fmt::layer().with_ansi(false).with_writer(buffer))
+        .init();
+    record_panics();
+}
+
+/// Wraps the panic hook so the location and message of each panic are kept before the default
+/// hook prints them.
+fn record_panics() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let location = info.location().map_or_else(|| "unknown location".to_string(), ToString::to_string);
+        let payload = info.payload();
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "non-string panic payload".to_string());
+        if let Ok(mut last) = LAST_PANIC.lock() {
+            *last = Some(format!("{}: {}", location, message));
+        }
+        default_hook(info);
+    }));
+}
+
+/// The location and message of the latest panic, if any happened since startup.
+pub fn last_panic() -> Option<String> {
+    LAST_PANIC.lock().ok().and_then(|last| last.clone())
+}
@@ -1,37 +1,194 @@
-use axum::{
-    routing::{get, post},
-    Router,
-};
-use clap::{Parser, Subcommand};
-// The `daemonize` import has been removed.
+use axum::Router;
+use clap::{Args, Parser, Subcommand};
 use std::collections::HashMap;
 use std::env;
 use std::fs;
+use std::net::{Ipv4Addr, Ipv6Addr, TcpStream, ToSocketAddrs};
 use std::path::PathBuf;
 use std::process::Command;
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant};
 use sysinfo::{Pid, System};
 use tower_http::cors::{Any, CorsLayer};
 
+use crate::cache::TtlCache;
 use crate::config::{Config, load_config};
-use crate::models::DownloadStatus;
+use crate::library_index::LibraryIndex;
+use crate::logging::LogBuffer;
+use crate::models::{DownloadRequest, DownloadStatus, Format, FormatsResponse, LibraryScan, VideoInfo};
+use crate::media_index::FileProbe;
+use crate::metrics::DownloadMetrics;
+use crate::quota::{KeyQuotas, QuotaTracker};
+use crate::runner::{ProcessRunner, SystemRunner};
+use crate::scheduler::Scheduler;
+use crate::live_logs::LiveLogs;
+use crate::media_index::MediaIndex;
+use crate::report::Reporter;
+use crate::sessions::Sessions;
+use crate::uploads::Uploads;
+use crate::slots::DownloadSlots;
+use crate::circuits::CircuitBreakers;
+use crate::storage::StorageProbes;
+use crate::throttle::Pacer;
+use crate::ytdlp::YtDlpManager;
 
 // --- Modules ---
+pub mod archive;
+pub mod cache;
+pub mod channels;
+pub mod chat;
+pub mod checksum;
+pub mod circuits;
 pub mod config;
+pub mod debug_bundle;
+pub mod desktop_notify;
 pub mod error;
+pub mod fs_util;
 pub mod handlers;
+pub mod history;
+pub mod library_index;
+pub mod live_logs;
+pub mod logging;
+pub mod media_index;
+pub mod metrics;
 pub mod models;
+pub mod negotiate;
+pub mod outbound;
+pub mod persist;
+pub mod privileges;
+pub mod progress;
+pub mod quota;
+pub mod redact;
+pub mod report;
+pub mod runner;
+pub mod scheduler;
+pub mod sessions;
+pub mod slots;
+pub mod storage;
+#[cfg(test)]
+pub mod test_support;
+pub mod throttle;
+pub mod uploads;
+pub mod webhook;
+pub mod ytdlp;
 
-// --- State, CLI, and Main logic (No changes here) ---
-// ... (The AppState struct, Cli struct, Commands enums, and main function are identical to the previous version)
 // --- State Type Aliases ---
-pub type DownloadState = Arc<Mutex<HashMap<String, DownloadStatus>>>;
 pub type ConfigState = Arc<RwLock<Config>>;
+pub type FormatsCache = TtlCache<VideoInfo>;
+/// Maps a download key to the PID of its running yt-dlp process.
+pub type ProcessState = Arc<Mutex<HashMap<String, u32>>>;
+/// Maps a download key to the token that cancels its task, for `POST /download/:key/cancel`.
+pub type CancelState = Arc<Mutex<HashMap<String, tokio_util::sync::CancellationToken>>>;
+
+/// The status of every download, by key.
+#[derive(Clone, Default)]
+pub struct DownloadState {
+    statuses: Arc<Mutex<HashMap<String, DownloadStatus>>>,
+}
+
+impl DownloadState {
+    /// Locks the statuses. A panic while they were locked poisons the mutex; instead of failing
+    /// every later request, the statuses are taken over as the panic left them, and the
+    /// poisoning is logged with the panic's origin and counted in `GET /metrics`.
+    pub fn lock(&self) -> MutexGuard<'_, HashMap<String, DownloadStatus>> {
+        self.statuses.lock().unwrap_or_else(|poisoned| {
+            tracing::error!(
+                "A panic poisoned the download statuses (at {}); recovering them as they were left",
+                logging::last_panic().as_deref().unwrap_or("an unknown location")
+            );
+            metrics::record_poisoned_lock();
+            self.statuses.clear_poison();
+            poisoned.into_inner()
+        })
+    }
+}
 
 #[derive(Clone)]
 pub struct AppState {
     pub downloads: DownloadState,
     pub config: ConfigState,
+    pub logs: LogBuffer,
+    pub formats_cache: FormatsCache,
+    pub processes: ProcessState,
+    pub cancels: CancelState,
+    /// Set once shutdown has begun; new downloads are refused while draining.
+    pub draining: Arc<AtomicBool>,
+    pub scheduler: Scheduler,
+    pub storage_probes: StorageProbes,
+    /// File counts and sizes per download directory, for `GET /stats`.
+    pub library_scans: TtlCache<LibraryScan>,
+    /// The files of each download root, when `library_index_interval_secs` is set.
+    pub library_index: LibraryIndex,
+    /// ffprobe details of single files, for `GET /files/:path/probe`.
+    pub file_probes: TtlCache<FileProbe>,
+    /// How many items a URL's playlist has, for `max_playlist_items`.
+    pub playlist_counts: TtlCache<u64>,
+    pub quota: QuotaTracker,
+    pub key_quotas: KeyQuotas,
+    pub slots: DownloadSlots,
+    /// Per-host failure counts, for `circuit_breaker_threshold`.
+    pub circuits: CircuitBreakers,
+    pub reporter: Reporter,
+    /// Output lines of running downloads, for `GET /download/:key/log/ws`.
+    pub live_logs: LiveLogs,
+    /// Durations, resolutions and codecs of downloaded files, for `GET /files?media_info=true`.
+    pub media_index: MediaIndex,
+    /// Cookie jars shared across downloads and probes, for `POST /session`.
+    pub sessions: Sessions,
+    /// Chunked uploads of cookie files and URL lists, for `POST /uploads`.
+    pub uploads: Uploads,
+    /// Durations and bytes of finished downloads, for `GET /metrics`.
+    pub metrics: DownloadMetrics,
+    /// Shared by every `GET /files/:path` transfer, for `file_serve_global_rate_limit`.
+    pub serve_pacer: Pacer,
+    /// Managed yt-dlp installs and the gate that lets a version switch wait for downloads.
+    pub ytdlp: YtDlpManager,
+    /// Creates the yt-dlp processes; replaceable so yt-dlp can be faked.
+    pub runner: Arc<dyn ProcessRunner>,
+}
+
+impl AppState {
+    /// Builds the state for a server with the given config and log buffer.
+    pub fn new(config: Config, logs: LogBuffer, runner: Arc<dyn ProcessRunner>) -> Self {
+        let caches = config.caches;
+        let max_concurrent_ffprobes = config.max_concurrent_ffprobes;
+        AppState {
+            downloads: DownloadState::default(),
+            config: Arc::new(RwLock::new(config)),
+            logs,
+            formats_cache: TtlCache::new(caches.formats),
+            processes: Arc::new(Mutex::new(HashMap::new())),
+            cancels: Arc::new(Mutex::new(HashMap::new())),
+            draining: Arc::new(AtomicBool::new(false)),
+            scheduler: Scheduler::default(),
+            storage_probes: TtlCache::new(caches.storage_probes),
+            library_scans: TtlCache::new(caches.library_scans),
+            library_index: LibraryIndex::default(),
+            file_probes: TtlCache::new(caches.file_probes),
+            playlist_counts: TtlCache::new(caches.playlist_counts),
+            quota: QuotaTracker::default(),
+            key_quotas: KeyQuotas::default(),
+            slots: DownloadSlots::default(),
+            circuits: CircuitBreakers::default(),
+            reporter: Reporter::default(),
+            live_logs: LiveLogs::default(),
+            media_index: MediaIndex::new(max_concurrent_ffprobes),
+            sessions: Sessions::default(),
+            uploads: Uploads::default(),
+            metrics: DownloadMetrics::default(),
+            serve_pacer: Pacer::default(),
+            ytdlp: YtDlpManager::default(),
+            runner,
+        }
+    }
+
+    /// A state with the default config and the given runner, for exercising handlers
+    /// without a running server.
+    #[cfg(test)]
+    pub fn new_for_tests(runner: Arc<dyn ProcessRunner>) -> Self {
+        AppState::new(Config::default(), LogBuffer::default(), runner)
+    }
 }
 
 // --- Command-Line Argument Parsing ---
@@ -49,6 +206,61 @@ enum Commands {
         #[command(subcommand)]
         action: ServerAction,
     },
+    /// Downloads a URL in the foreground without the server, printing progress, and exits
+    /// when done. Uses the config file for defaults and the download directory.
+    Download(DownloadArgs),
+    /// Lists the formats of a URL as a table, like `GET /formats`, without the server.
+    Formats {
+        /// The video or playlist URL.
+        url: String,
+        /// Print the `GET /formats` JSON instead of a table.
+        #[arg(long)]
+        json: bool,
+        /// Include storyboards and formats with no streams.
+        #[arg(long)]
+        all: bool,
+    },
+    /// Writes a debug bundle (redacted config, status, versions, recent logs) for bug reports.
+    /// Fetched from the running server if there is one, otherwise built from on-disk state.
+    DebugBundle {
+        /// Where to write the gzip tarball.
+        #[arg(short, long, default_value = debug_bundle::BUNDLE_FILENAME)]
+        output: PathBuf,
+    },
+}
+
+/// The options of the `download` command, a subset of the `POST /download` fields.
+#[derive(Args, Debug)]
+struct DownloadArgs {
+    /// The video or playlist URL.
+    url: String,
+    /// The yt-dlp format selector.
+    #[arg(short, long, default_value = handlers::download::DEFAULT_FORMAT)]
+    format: String,
+    /// An output template; defaults to "%(title)s [%(id)s].%(ext)s" in the download directory.
+    #[arg(short, long)]
+    output_template: Option<String>,
+    /// One of the config's `allowed_download_roots` to download into.
+    #[arg(long)]
+    download_root: Option<String>,
+    /// Which playlist items to download, e.g. "1-3,7".
+    #[arg(long)]
+    playlist_items: Option<String>,
+    /// Keep only the audio.
+    #[arg(long)]
+    extract_audio: bool,
+    /// e.g. "mp3"; implies --extract-audio.
+    #[arg(long)]
+    audio_format: Option<String>,
+    /// Also download subtitles.
+    #[arg(long)]
+    write_subs: bool,
+    /// Also download the thumbnail.
+    #[arg(long)]
+    write_thumbnail: bool,
+    /// Write the title, uploader, etc. into the file's metadata.
+    #[arg(long)]
+    embed_metadata: bool,
 }
 
 #[derive(Subcommand, Debug)]
@@ -72,9 +284,16 @@ async fn main() -> anyhow::Result<()> {
 
     match &cli.command {
         Commands::Server { action } => match action {
-            ServerAction::Start => start_server()?,
-            ServerAction::Stop => stop_server()?,
+            ServerAction::Start => {
+                let _lock = lock_server_control()?;
+                start_server()?
+            }
+            ServerAction::Stop => {
+                let _lock = lock_server_control()?;
+                stop_server()?
+            }
             ServerAction::Restart => {
+                let _lock = lock_server_control()?;
                 stop_server()?;
                 std::thread::sleep(std::time::Duration::from_secs(1));
                 start_server()?;
@@ -82,6 +301,9 @@ async fn main() -> anyhow::Result<()> {
             ServerAction::Run => run_server().await?,
             ServerAction::Status => check_status()?,
         },
+        Commands::Download(args) => run_cli_download(args).await?,
+        Commands::Formats { url, json, all } => print_formats(url, *json, *all).await?,
+        Commands::DebugBundle { output } => write_debug_bundle(output).await?,
     }
 
     Ok(())
@@ -92,38 +314,221 @@ async fn main() -> anyhow::Result<()> {
 
 /// The core function that runs the Axum web server.
 async fn run_server() -> anyhow::Result<()> {
-    // This function is completely unchanged.
-    tracing_subscriber::fmt::init();
-    let config = load_config().await?;
-    let state = AppState {
-        downloads: Arc::new(Mutex::new(HashMap::new())),
-        config: Arc::new(RwLock::new(config)),
+    let logs = LogBuffer::default();
+    logging::init(logs.clone());
+    desktop_notify::detect_foreground();
+    // Without a config file the server starts with the defaults and waits for `POST /setup`.
+    let config = if config::config_file_exists().await? {
+        load_config().await?
+    } else {
+        tracing::info!("No config file yet; using the defaults until it is set up with POST /setup or POST /config");
+        Config::default()
     };
-    let host = env::var("HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
-    let port_str = env::var("PORT").unwrap_or_else(|_| "8080".to_string());
-    let addr = format!("{}:{}", host, port_str);
-    let app = Router::new()
-        .route("/formats", get(handlers::list_formats))
-        .route("/download", post(handlers::start_download))
-        .route("/status", get(handlers::get_status))
-        .route("/files", get(handlers::list_files))
-        .route("/files/*path", get(handlers::get_file))
-        .route("/config", get(handlers::get_config).post(handlers::update_config))
-        .layer(CorsLayer::new().allow_origin(Any).allow_headers(Any).allow_methods(Any))
-        .with_state(state);
+    for problem in config.validate() {
+        tracing::warn!("Config problem: {}", problem);
+    }
+    let state = AppState::new(config, logs, Arc::new(SystemRunner));
+    if let Err(e) = state.quota.load().await {
+        tracing::error!("Failed to load quota usage: {:?}", e);
+    }
+    if let Err(e) = state.key_quotas.load().await {
+        tracing::error!("Failed to load the API key quotas: {:?}", e);
+    }
+    if let Err(e) = state.media_index.load().await {
+        tracing::error!("Failed to load the media index: {:?}", e);
+    }
+    if let Err(e) = sessions::clear_stale_jars().await {
+        tracing::warn!("Failed to delete old session cookie jars: {:?}", e);
+    }
+    if let Err(e) = uploads::clear_stale_files().await {
+        tracing::warn!("Failed to delete unfinished uploads: {:?}", e);
+    }
+    ytdlp::sync(&state);
+    restore_schedule(&state).await;
+    tokio::spawn(scheduler::run(state.clone()));
+    tokio::spawn(sessions::run(state.clone()));
+    tokio::spawn(uploads::run(state.clone()));
+    tokio::spawn(report::run(state.clone()));
+    tokio::spawn(library_index::run(state.clone()));
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(state.clone()));
+    let addr = server_addr();
+    let app = router(&state);
     tracing::info!("Starting server in foreground, listening on {}", addr);
     let listener = tokio::net::TcpListener::bind(&addr).await?;
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app.into_make_service_with_connect_info::<std::net::SocketAddr>()).with_graceful_shutdown(shutdown_signal(state)).await?;
     Ok(())
 }
 
-// === THIS IS THE REWRITTEN FUNCTION ===
+/// Every route of the API with the middleware in front of them.
+fn router(state: &AppState) -> Router {
+    Router::new()
+        .merge(handlers::config::build_router(state.clone()))
+        .merge(handlers::formats::build_router(state.clone()))
+        .merge(handlers::download::build_router(state.clone()))
+        .merge(handlers::status::build_router(state.clone()))
+        .merge(handlers::files::build_router(state.clone()))
+        .merge(handlers::caches::build_router(state.clone()))
+        .merge(handlers::channel::build_router(state.clone()))
+        .merge(handlers::library::build_router(state.clone()))
+        .merge(handlers::session::build_router(state.clone()))
+        .merge(handlers::uploads::build_router(state.clone()))
+        .merge(handlers::setup::build_router(state.clone()))
+        .merge(handlers::ytdlp::build_router(state.clone()))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), handlers::correlate))
+        .layer(handlers::compression_layer(state))
+        .layer(CorsLayer::new().allow_origin(Any).allow_headers(Any).allow_methods(Any))
+}
+
+/// Reloads the config file on every SIGHUP, like `POST /config/reload`.
+/// A failed reload is logged and the running config stays in place.
+#[cfg(unix)]
+async fn reload_on_hangup(state: AppState) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            tracing::error!("Failed to install the SIGHUP handler: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        tracing::info!("Received SIGHUP, reloading the config file.");
+        if let Err(e) = handlers::config::reload(&state).await {
+            tracing::error!("Config reload failed, keeping the current config: {}", e);
+        }
+    }
+}
+
+/// Reloads persisted scheduled downloads and shows them in the status map.
+async fn restore_schedule(state: &AppState) {
+    if let Err(e) = state.scheduler.load().await {
+        tracing::error!("Failed to load scheduled downloads: {:?}", e);
+        return;
+    }
+    let mut map = state.downloads.lock();
+    for job in state.scheduler.list() {
+        map.insert(job.download_key.clone(), DownloadStatus {
+            status: "scheduled".to_string(),
+            scheduled_at: Some(job.scheduled_at.to_rfc3339()),
+            created_at: Some(chrono::Utc::now().to_rfc3339()),
+            ..Default::default()
+        });
+    }
+}
+
+/// Resolves when the server should stop accepting connections.
+/// The first Ctrl-C stops new downloads and waits for active ones to finish (if
+/// `drain_on_ctrl_c` is set); a second Ctrl-C kills them and exits immediately.
+async fn shutdown_signal(state: AppState) {
+    if tokio::signal::ctrl_c().await.is_err() {
+        // Without a signal handler there is nothing to wait for; keep serving.
+        std::future::pending::<()>().await;
+    }
+    let force_state = state.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            println!("Received second Ctrl-C, killing active downloads and exiting now.");
+            kill_active_downloads(&force_state);
+            std::process::exit(130);
+        }
+    });
+    drain(&state).await;
+}
+
+/// Stops taking new downloads, which also makes `GET /ready` fail, and then waits for the
+/// active ones to finish, or kills them unless `drain_on_ctrl_c` is set.
+async fn drain(state: &AppState) {
+    state.draining.store(true, Ordering::SeqCst);
+    state.scheduler.wake();
+
+    if !state.config.read().unwrap().drain_on_ctrl_c {
+        println!("Received Ctrl-C, stopping active downloads and shutting down.");
+        kill_active_downloads(state);
+        return;
+    }
+
+    let active = count_active_downloads(state);
+    if active == 0 {
+        println!("Received Ctrl-C, shutting down.");
+        return;
+    }
+    println!(
+        "Received Ctrl-C: no new downloads will be accepted. Waiting for {} active download(s) to finish. Press Ctrl-C again to force exit.",
+        active
+    );
+
+    let mut last_reported = active;
+    loop {
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        let remaining = count_active_downloads(state);
+        if remaining == 0 {
+            println!("All downloads finished, shutting down.");
+            return;
+        }
+        if remaining != last_reported {
+            println!("Waiting for {} active download(s) to finish...", remaining);
+            last_reported = remaining;
+        }
+    }
+}
+
+/// Counts downloads that are queued for a slot, starting or in progress.
+pub(crate) fn count_active_downloads(state: &AppState) -> usize {
+    let map = state.downloads.lock();
+    map.values().filter(|s| matches!(s.status.as_str(), "queued" | "starting" | "downloading")).count()
+}
+
+/// Counts the downloads running or waiting for a slot, the backlog `max_queue_length`
+/// bounds. A job group counts once, through its parent.
+pub(crate) fn queue_length(downloads: &HashMap<String, DownloadStatus>) -> usize {
+    downloads
+        .values()
+        .filter(|s| s.parent.is_none() && matches!(s.status.as_str(), "queued" | "starting" | "downloading"))
+        .count()
+}
+
+/// Kills every running yt-dlp process.
+fn kill_active_downloads(state: &AppState) {
+    let pids: Vec<u32> = state.processes.lock().unwrap().values().copied().collect();
+    let mut s = System::new();
+    for pid in pids {
+        let pid = Pid::from_u32(pid);
+        s.refresh_process(pid);
+        if let Some(process) = s.process(pid) {
+            process.kill();
+        }
+    }
+}
+
+/// How long `server start` waits for the new server to accept connections.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Takes the lock that serializes `server start`, `stop` and `restart`, so concurrent
+/// invocations can't start two servers or read a half-written PID file. Released on drop.
+fn lock_server_control() -> anyhow::Result<fs::File> {
+    let path = config::data_dir()?.join("server.lock");
+    let file = fs::OpenOptions::new().create(true).truncate(false).write(true).open(&path)?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(fs::TryLockError::WouldBlock) => {
+            anyhow::bail!("Another start, stop or restart of the server is in progress. Try again in a moment.")
+        }
+        Err(fs::TryLockError::Error(e)) => Err(anyhow::anyhow!("Failed to lock {}: {}", path.display(), e)),
+    }
+}
+
 /// Starts the server as a background process using std::process::Command.
+/// Callers must hold `lock_server_control`.
 fn start_server() -> anyhow::Result<()> {
     if is_running()? {
         println!("Server is already running.");
         return Ok(());
     }
+    let addr = server_addr();
+    if accepts_connections(&addr) {
+        anyhow::bail!("Something is already listening on {}; is a server running in the foreground?", addr);
+    }
 
     let pid_file = get_pid_path()?;
     let myself = env::current_exe()?;
@@ -132,7 +537,7 @@ fn start_server() -> anyhow::Result<()> {
 
     // Create a command to re-launch the current executable with the 'run' subcommand.
     let mut cmd = Command::new(&myself);
-    cmd.arg("server").arg("run");
+    cmd.arg("server").arg("run").env(desktop_notify::BACKGROUND_ENV, "1");
 
     // On Windows, we add a special flag to prevent a new console window from popping up.
     // This does not introduce any external dependencies.
@@ -144,10 +549,25 @@ fn start_server() -> anyhow::Result<()> {
     }
 
     // Spawn the child process.
-    let child = cmd.spawn()?;
+    let mut child = cmd.spawn()?;
+
+    // Only record the PID once the server is up, so a failed start leaves no PID file.
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    while !accepts_connections(&addr) {
+        if let Some(status) = child.try_wait()? {
+            anyhow::bail!("The server exited during startup ({}). Run `server run` to see why.", status);
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            anyhow::bail!("The server didn't accept connections on {} within {:?}; stopped it.", addr, STARTUP_TIMEOUT);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
 
-    // Save the new process's ID to the PID file.
-    fs::write(&pid_file, child.id().to_string())?;
+    // Save the new process's ID to the PID file, atomically so readers never see it half-written.
+    let tmp_file = pid_file.with_extension("pid.tmp");
+    fs::write(&tmp_file, child.id().to_string())?;
+    fs::rename(&tmp_file, &pid_file)?;
 
     println!("Server started successfully. PID file at: {}", pid_file.display());
     // The parent process (the 'start' command) exits here,
@@ -155,9 +575,8 @@ fn start_server() -> anyhow::Result<()> {
     Ok(())
 }
 
-/// Stops the background server process.
+/// Stops the background server process. Callers must hold `lock_server_control`.
 fn stop_server() -> anyhow::Result<()> {
-    // This function is completely unchanged.
     let pid_file = get_pid_path()?;
     if !pid_file.exists() {
         println!("Server is not running (no PID file).");
@@ -179,7 +598,6 @@ fn stop_server() -> anyhow::Result<()> {
 
 /// Checks if the server process is running.
 fn check_status() -> anyhow::Result<()> {
-    // This function is completely unchanged.
     if is_running()? {
         let pid_str = fs::read_to_string(get_pid_path()?)?;
         println!("Server is running with PID: {}", pid_str.trim());
@@ -189,15 +607,207 @@ fn check_status() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Runs one download in this process through the same code as `POST /download`, printing its
+/// progress to stderr and the produced files to stdout. Exits with status 1 if it fails, and
+/// with 130 on Ctrl-C after killing yt-dlp.
+async fn run_cli_download(args: &DownloadArgs) -> anyhow::Result<()> {
+    let config = if config::config_file_exists().await? { load_config().await? } else { Config::default() };
+    let state = AppState::new(config, LogBuffer::default(), Arc::new(SystemRunner));
+    if let Err(e) = state.quota.load().await {
+        eprintln!("Failed to load quota usage: {:?}", e);
+    }
+    ytdlp::sync_now(&state).await;
+    let request = DownloadRequest {
+        url: args.url.clone(),
+        format_id: args.format.clone(),
+        output_template: args.output_template.clone(),
+        download_root: args.download_root.clone(),
+        playlist_items: args.playlist_items.clone(),
+        extract_audio: args.extract_audio || args.audio_format.is_some(),
+        audio_format: args.audio_format.clone(),
+        write_subs: args.write_subs,
+        write_thumbnail: args.write_thumbnail,
+        embed_metadata: args.embed_metadata,
+        origin: Some(models::DownloadOrigin { endpoint: "download command".to_string(), ..Default::default() }),
+        ..Default::default()
+    };
+    let response = match handlers::download::enqueue_download(&state, request).await {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("Download refused: {}", e);
+            std::process::exit(1);
+        }
+    };
+    for warning in &response.warnings {
+        eprintln!("Warning: {}", warning);
+    }
+
+    let mut last_line = String::new();
+    let status = loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {
+                eprintln!("\nReceived Ctrl-C, stopping the download.");
+                kill_active_downloads(&state);
+                std::process::exit(130);
+            }
+            _ = tokio::time::sleep(Duration::from_millis(500)) => {}
+        }
+        let Some(status) = state.downloads.lock().get(&response.download_key).cloned() else { continue };
+        if !matches!(status.status.as_str(), "queued" | "starting" | "downloading") {
+            break status;
+        }
+        let mut line = format!("[{}] {:.1}%", status.status, status.progress);
+        if let Some(total) = status.total_bytes {
+            line.push_str(&format!(" of {:.1} MiB", total as f64 / (1024.0 * 1024.0)));
+        }
+        if !status.speed.is_empty() {
+            line.push_str(&format!(" at {}", status.speed));
+        }
+        if !status.eta.is_empty() {
+            line.push_str(&format!(" ETA {}", status.eta));
+        }
+        if line != last_line {
+            eprint!("\r{:<70}", line);
+            last_line = line;
+        }
+    };
+    if !last_line.is_empty() {
+        eprintln!();
+    }
+
+    for file in &status.files {
+        println!("{}", file);
+    }
+    match status.status.as_str() {
+        "completed" => eprintln!("Download completed."),
+        "completed_no_output" => eprintln!("{}", status.note.as_deref().unwrap_or("Download completed without producing files.")),
+        other => {
+            eprintln!("Download {}: {}", other.replace('_', " "), status.error.as_deref().unwrap_or("unknown error").trim());
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
+
+/// Probes a URL's formats and prints them as a table per video, or as JSON.
+/// Exits with status 1 if the probe fails.
+async fn print_formats(url: &str, json: bool, include_all: bool) -> anyhow::Result<()> {
+    let config = if config::config_file_exists().await? { load_config().await? } else { Config::default() };
+    let state = AppState::new(config, LogBuffer::default(), Arc::new(SystemRunner));
+    ytdlp::sync_now(&state).await;
+    let response = match handlers::formats::formats_for_url(&state, url, include_all).await {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("{}", e.to_string().trim());
+            std::process::exit(1);
+        }
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&response)?);
+        return Ok(());
+    }
+    match response {
+        FormatsResponse::Video(info) => {
+            println!("{}\n", info.title);
+            print!("{}", format_table(&info.formats));
+        }
+        FormatsResponse::Playlist(playlist) => {
+            println!("{}\n", playlist.playlist_title.as_deref().unwrap_or(url));
+            for entry in &playlist.entries {
+                let index = entry.playlist_index.map(|i| format!("{}. ", i)).unwrap_or_default();
+                println!("{}{} ({})", index, entry.info.title, entry.url.as_deref().unwrap_or("no URL"));
+                println!("{}", format_table(&entry.info.formats));
+            }
+            if playlist.truncated {
+                println!("Only the first {} entries were probed (playlist_probe_entries).", playlist.entries.len());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Renders formats as a table with aligned columns. An approximate size is prefixed with `~`.
+fn format_table(formats: &[Format]) -> String {
+    let mib = |bytes: u64| format!("{:.1}MiB", bytes as f64 / (1024.0 * 1024.0));
+    let mut rows = vec![["ID", "EXT", "RESOLUTION", "VCODEC", "ACODEC", "SIZE", "TBR"].map(String::from)];
+    for f in formats {
+        let size = match (f.filesize, f.filesize_approx) {
+            (Some(bytes), _) => mib(bytes),
+            (None, Some(bytes)) => format!("~{}", mib(bytes)),
+            (None, None) => String::new(),
+        };
+        let tbr = f.tbr.map(|tbr| format!("{:.0}k", tbr)).unwrap_or_default();
+        rows.push([f.format_id.clone(), f.ext.clone(), f.resolution.clone(), f.vcodec.clone(), f.acodec.clone(), size, tbr]);
+    }
+    let mut widths = [0; 7];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut table = String::new();
+    for row in &rows {
+        let cells: Vec<String> = row.iter().zip(widths).map(|(cell, width)| format!("{:<width$}", cell, width = width)).collect();
+        table.push_str(cells.join("  ").trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+/// Writes a debug bundle to `output`, preferring the running server's live state.
+/// The server only hands the bundle to an admin key, so the first one in the config is sent.
+async fn write_debug_bundle(output: &PathBuf) -> anyhow::Result<()> {
+    let config = load_config().await?;
+    let admin_key = config.api_keys.values().find(|key| key.is_admin()).map(|key| key.secret.clone());
+    if let (true, Some(admin_key)) = (is_running()?, admin_key) {
+        let url = format!("http://{}/debug/bundle", server_addr());
+        match fetch_debug_bundle(&url, &admin_key).await {
+            Ok(bundle) => {
+                fs::write(output, bundle)?;
+                println!("Debug bundle fetched from running server and written to: {}", output.display());
+                return Ok(());
+            }
+            Err(e) => println!("Could not fetch bundle from running server ({}), building it offline.", e),
+        }
+    }
+
+    let bundle = debug_bundle::build(&config, &HashMap::new(), &[]).await?;
+    fs::write(output, bundle)?;
+    println!("Offline debug bundle written to: {}", output.display());
+    Ok(())
+}
+
+/// Downloads the debug bundle from a running server.
+async fn fetch_debug_bundle(url: &str, admin_key: &str) -> anyhow::Result<Vec<u8>> {
+    let response = reqwest::Client::new().get(url).header(handlers::API_KEY_HEADER, admin_key).send().await?.error_for_status()?;
+    Ok(response.bytes().await?.to_vec())
+}
+
+
+// --- Helper Functions ---
+/// Gets the address the server listens on, from the `HOST` and `PORT` environment variables.
+fn server_addr() -> String {
+    let host = env::var("HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
+    let port_str = env::var("PORT").unwrap_or_else(|_| "8080".to_string());
+    format!("{}:{}", host, port_str)
+}
+
+/// Whether a server accepts TCP connections on `addr`. A wildcard host is probed on loopback.
+fn accepts_connections(addr: &str) -> bool {
+    let Ok(addrs) = addr.to_socket_addrs() else {
+        return false;
+    };
+    addrs.into_iter().any(|mut addr| {
+        if addr.ip().is_unspecified() {
+            addr.set_ip(if addr.is_ipv4() { Ipv4Addr::LOCALHOST.into() } else { Ipv6Addr::LOCALHOST.into() });
+        }
+        TcpStream::connect_timeout(&addr, Duration::from_millis(500)).is_ok()
+    })
+}
 
-// --- Helper Functions (Unchanged) ---
 /// Gets the path for the server's PID file.
 fn get_pid_path() -> anyhow::Result<PathBuf> {
-    let project_dirs = directories::ProjectDirs::from("com", "YourOrg", "YT-DLP-API")
-        .ok_or_else(|| anyhow::anyhow!("Could not find a valid project directory"))?;
-    let data_dir = project_dirs.data_local_dir();
-    fs::create_dir_all(data_dir)?;
-    Ok(data_dir.join("server.pid"))
+    Ok(config::data_dir()?.join("server.pid"))
 }
 
 /// Checks if the server is running by checking the PID file and the process list.
@@ -210,4 +820,141 @@ fn is_running() -> anyhow::Result<bool> {
     let pid: u32 = pid_str.trim().parse()?;
     let s = System::new_all();
     Ok(s.process(Pid::from_u32(pid)).is_some())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::YtDlpChannel;
+    use crate::test_support::{finished, lock_files, state, FakeRunner};
+    use axum::{extract::Path, routing::get};
+    use serde_json::{json, Value};
+    use sha2::{Digest, Sha256};
+
+    const RELEASE: &str = "2099.01.01";
+    const BINARY: &[u8] = b"#!/bin/sh\necho 2099.01.01\n";
+
+    /// Serves the release like GitHub does, holding every response back until `open` is true.
+    async fn release_server(open: tokio::sync::watch::Receiver<bool>) -> String {
+        let sums = format!("{}  {}\n", hex::encode(Sha256::digest(BINARY)), ytdlp::asset_name());
+        let app = Router::new().route(
+            "/download/:version/:asset",
+            get(move |Path((_, asset)): Path<(String, String)>| {
+                let (sums, mut open) = (sums.clone(), open.clone());
+                async move {
+                    open.wait_for(|open| *open).await.unwrap();
+                    if asset == "SHA2-256SUMS" { sums.into_bytes() } else { BINARY.to_vec() }
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    async fn get_json(client: &reqwest::Client, url: String) -> (u16, Value) {
+        let response = client.get(url).send().await.unwrap();
+        (response.status().as_u16(), response.json().await.unwrap())
+    }
+
+    /// Polls `GET /ready` until it gives `reasons`, and checks that `GET /health` still answers 200.
+    async fn wait_for_readiness(client: &reqwest::Client, base: &str, reasons: Value) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let (code, body) = get_json(client, format!("{}/ready", base)).await;
+            if body["reasons"] == reasons {
+                assert_eq!(code, if body["ready"] == true { 200 } else { 503 }, "{}", body);
+                break;
+            }
+            assert!(Instant::now() < deadline, "/ready still says {} instead of {}", body, reasons);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(get_json(client, format!("{}/health", base)).await.0, 200);
+    }
+
+    /// Waits until the download's yt-dlp runs, so it holds the binary.
+    async fn wait_until_running(state: &AppState, key: &str) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while state.downloads.lock().get(key).and_then(|status| status.command.clone()).is_none() {
+            assert!(Instant::now() < deadline, "{} never started", key);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn readiness_follows_a_self_update_and_a_graceful_shutdown() {
+        let _files = lock_files().await;
+        // Each download runs until a file named after the last part of its URL appears.
+        let gates = tempfile::tempdir().unwrap();
+        let script = format!(
+            r#"case "$*" in
+    *--flat-playlist*) echo NA; exit 0 ;;
+    *--dump-single-json*) echo "$VIDEO_JSON"; exit 0 ;;
+    *"--print %(."*) exit 0 ;;
+esac
+for arg; do url=$arg; done
+while [ ! -e '{}'/"${{url##*/}}" ]; do sleep 0.05; done"#,
+            gates.path().display()
+        );
+        let state = state(FakeRunner::new().script("yt-dlp", &script));
+        let (open_release, release_opened) = tokio::sync::watch::channel(false);
+        let releases = release_server(release_opened).await;
+        {
+            let mut config = state.config.write().unwrap();
+            config.ytdlp_channel = YtDlpChannel::Managed;
+            config.ytdlp_release_url = releases;
+            config.drain_on_ctrl_c = true;
+        }
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let app = router(&state).into_make_service_with_connect_info::<std::net::SocketAddr>();
+        let draining = state.clone();
+        let server = tokio::spawn(async move {
+            let shutdown = async move {
+                let _ = stopped.await;
+                drain(&draining).await;
+            };
+            axum::serve(listener, app).with_graceful_shutdown(shutdown).await
+        });
+        let client = reqwest::Client::new();
+        let download = |name: &str| json!({ "url": format!("https://example.com/{}", name), "format_id": "best" });
+
+        // Started.
+        wait_for_readiness(&client, &base, json!([])).await;
+        let started = client.post(format!("{}/download", base)).json(&download("a")).send().await.unwrap();
+        assert_eq!(started.status(), 202);
+        wait_until_running(&state, "https://example.com/a").await;
+
+        // Self-update: installing, then waiting for the running download to release the binary.
+        let install = tokio::spawn({
+            let (client, url) = (client.clone(), format!("{}/ytdlp/install", base));
+            async move { client.post(url).json(&json!({ "version": RELEASE })).send().await.unwrap().status().as_u16() }
+        });
+        wait_for_readiness(&client, &base, json!(["installing_ytdlp"])).await;
+        open_release.send(true).unwrap();
+        assert_eq!(install.await.unwrap(), 202);
+        wait_for_readiness(&client, &base, json!(["installing_ytdlp", "ytdlp_switch_pending"])).await;
+        std::fs::write(gates.path().join("a"), "").unwrap();
+        assert_eq!(finished(&state, "https://example.com/a").await.status, "completed_no_output");
+        wait_for_readiness(&client, &base, json!([])).await;
+        assert_eq!(get_json(&client, format!("{}/ytdlp", base)).await.1["active"]["version"], RELEASE);
+
+        // Graceful shutdown: not ready, but alive until the running download is done.
+        let started = client.post(format!("{}/download", base)).json(&download("b")).send().await.unwrap();
+        assert_eq!(started.status(), 202);
+        wait_until_running(&state, "https://example.com/b").await;
+        stop.send(()).unwrap();
+        wait_for_readiness(&client, &base, json!(["shutting_down"])).await;
+        let refused = client.post(format!("{}/download", base)).json(&download("c")).send().await.unwrap();
+        assert_eq!(refused.status(), 503);
+        assert!(!server.is_finished());
+        std::fs::write(gates.path().join("b"), "").unwrap();
+        tokio::time::timeout(Duration::from_secs(10), server).await.unwrap().unwrap().unwrap();
+
+        // Back to the yt-dlp on PATH for the other tests.
+        state.config.write().unwrap().ytdlp_channel = YtDlpChannel::System;
+        ytdlp::sync_now(&state).await;
+        assert!(ytdlp::active().is_none());
+    }
+}
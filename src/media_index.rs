@@ -0,0 +1,292 @@
+use crate::{config, persist, runner::ProcessRunner, AppState};
+use anyhow::Result;
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+use tokio::sync::Semaphore;
+
+/// File extensions that get probed for media info.
+const MEDIA_EXTENSIONS: &[&str] = &[
+    "mp4", "mkv", "webm", "mov", "avi", "flv", "m4v", "mp3", "m4a", "opus", "ogg", "flac", "wav", "aac",
+];
+
+/// Playback-relevant facts about a media file, as reported by ffprobe.
+/// Every field is `null` for a file that hasn't been indexed yet.
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct MediaInfo {
+    /// Duration in seconds.
+    pub duration: Option<f64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub video_codec: Option<String>,
+    pub audio_codec: Option<String>,
+}
+
+/// An index entry; it's stale once the file's size or modification time changes.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+struct IndexEntry {
+    modified: u64,
+    size: u64,
+    info: MediaInfo,
+}
+
+/// Media info of downloaded files, keyed by canonical path and persisted across restarts.
+/// Filled in the background, with at most `max_concurrent_ffprobes` ffprobe processes at a time.
+#[derive(Clone)]
+pub struct MediaIndex {
+    entries: Arc<Mutex<HashMap<String, IndexEntry>>>,
+    /// Files queued or being probed, so a file isn't probed twice at once.
+    pending: Arc<Mutex<HashSet<String>>>,
+    probes: Arc<Semaphore>,
+}
+
+/// Full technical details of one file, as reported by ffprobe, for `GET /files/:path/probe`.
+#[derive(Clone, Serialize, Debug)]
+pub struct FileProbe {
+    /// The container, e.g. "mov,mp4,m4a,3gp,3g2,mj2".
+    pub format_name: Option<String>,
+    /// Duration in seconds.
+    pub duration: Option<f64>,
+    pub size_bytes: Option<u64>,
+    /// Overall bitrate in bits per second.
+    pub bit_rate: Option<u64>,
+    pub video_codec: Option<String>,
+    pub audio_codec: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub streams: Vec<StreamProbe>,
+}
+
+/// One stream of a probed file.
+#[derive(Clone, Serialize, Debug)]
+pub struct StreamProbe {
+    pub index: u32,
+    /// "video", "audio", "subtitle", ...
+    pub codec_type: Option<String>,
+    pub codec_name: Option<String>,
+    pub profile: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Frames per second as a fraction, e.g. "30000/1001".
+    pub frame_rate: Option<String>,
+    pub bit_rate: Option<u64>,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u32>,
+    pub language: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FfprobeOutput {
+    #[serde(default)]
+    streams: Vec<FfprobeStream>,
+    format: Option<FfprobeFormat>,
+}
+
+/// ffprobe reports most numbers as strings; they are parsed when converting.
+#[derive(Deserialize)]
+struct FfprobeStream {
+    #[serde(default)]
+    index: u32,
+    codec_type: Option<String>,
+    codec_name: Option<String>,
+    profile: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    r_frame_rate: Option<String>,
+    bit_rate: Option<String>,
+    sample_rate: Option<String>,
+    channels: Option<u32>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct FfprobeFormat {
+    format_name: Option<String>,
+    duration: Option<String>,
+    size: Option<String>,
+    bit_rate: Option<String>,
+}
+
+/// Returns the path of the persisted index.
+fn index_path() -> Result<PathBuf> {
+    Ok(config::data_dir()?.join("media_index.json"))
+}
+
+/// Whether `path` looks like a media file, by its extension.
+pub fn is_media(path: &Path) -> bool {
+    path.extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+        .is_some_and(|ext| MEDIA_EXTENSIONS.contains(&ext.as_str()))
+}
+
+/// The index key and current (modified, size) of a file, or `None` if it can't be read.
+fn identify(path: &Path) -> Option<(String, u64, u64)> {
+    let canonical = std::fs::canonicalize(path).ok()?;
+    let metadata = std::fs::metadata(&canonical).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_secs();
+    Some((canonical.to_string_lossy().to_string(), modified, metadata.len()))
+}
+
+impl MediaIndex {
+    pub fn new(max_concurrent_probes: usize) -> Self {
+        MediaIndex {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            pending: Arc::new(Mutex::new(HashSet::new())),
+            probes: Arc::new(Semaphore::new(max_concurrent_probes.max(1))),
+        }
+    }
+
+    /// Restores the persisted index. Called once at startup.
+    pub async fn load(&self) -> Result<()> {
+        if let Some(entries) = persist::read_json(&index_path()?).await? {
+            *self.entries.lock().unwrap() = entries;
+        }
+        Ok(())
+    }
+
+    async fn persist(&self) -> Result<()> {
+        let snapshot = serde_json::to_vec(&*self.entries.lock().unwrap())?;
+        persist::write(&index_path()?, &snapshot).await
+    }
+
+    /// Returns the indexed info for `path` if it is still current.
+    pub fn lookup(&self, path: &Path) -> Option<MediaInfo> {
+        let (key, modified, size) = identify(path)?;
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(&key)?;
+        (entry.modified == modified && entry.size == size).then(|| entry.info.clone())
+    }
+
+    /// Forgets every entry. Returns how many there were.
+    pub async fn clear(&self) -> Result<usize> {
+        let count = std::mem::take(&mut *self.entries.lock().unwrap()).len();
+        self.persist().await?;
+        Ok(count)
+    }
+}
+
+/// Probes the media files among `paths` that aren't indexed (or have changed) in a background
+/// task, then persists the index. Returns how many files were queued.
+pub fn index_in_background(state: &AppState, paths: Vec<PathBuf>) -> usize {
+    let index = state.media_index.clone();
+    let queued: Vec<(PathBuf, String, u64, u64)> = {
+        let mut pending = index.pending.lock().unwrap();
+        paths
+            .into_iter()
+            .filter(|path| is_media(path) && index.lookup(path).is_none())
+            .filter_map(|path| identify(&path).map(|(key, modified, size)| (path, key, modified, size)))
+            .filter(|(_, key, _, _)| pending.insert(key.clone()))
+            .collect()
+    };
+    if queued.is_empty() {
+        return 0;
+    }
+    let count = queued.len();
+    let runner = state.runner.clone();
+    tokio::spawn(async move {
+        stream::iter(queued)
+            .for_each_concurrent(None, |(path, key, modified, size)| {
+                let (index, runner) = (index.clone(), runner.clone());
+                async move {
+                    let permit = index.probes.acquire().await;
+                    let result = probe(runner.as_ref(), &path).await;
+                    drop(permit);
+                    match result {
+                        Ok(info) => {
+                            index.entries.lock().unwrap().insert(key.clone(), IndexEntry { modified, size, info });
+                        }
+                        Err(e) => tracing::warn!("Failed to probe {}: {}", path.display(), e),
+                    }
+                    index.pending.lock().unwrap().remove(&key);
+                }
+            })
+            .await;
+        if let Err(e) = index.persist().await {
+            tracing::error!("Failed to persist the media index: {:?}", e);
+        }
+    });
+    count
+}
+
+/// Runs ffprobe on one file.
+async fn probe(runner: &dyn ProcessRunner, path: &Path) -> Result<MediaInfo> {
+    let parsed = run_ffprobe(runner, path).await?;
+    let video = parsed.streams.iter().find(|s| s.codec_type.as_deref() == Some("video"));
+    let audio = parsed.streams.iter().find(|s| s.codec_type.as_deref() == Some("audio"));
+    Ok(MediaInfo {
+        duration: parsed.format.and_then(|f| f.duration).and_then(|d| d.parse().ok()),
+        width: video.and_then(|s| s.width),
+        height: video.and_then(|s| s.height),
+        video_codec: video.and_then(|s| s.codec_name.clone()),
+        audio_codec: audio.and_then(|s| s.codec_name.clone()),
+    })
+}
+
+/// Probes one file for `GET /files/:path/probe`, reusing a result cached for the file's
+/// current size and modification time. Fails with an `io::ErrorKind::NotFound` error if
+/// ffprobe isn't installed.
+pub async fn probe_file(state: &AppState, path: &Path) -> Result<FileProbe> {
+    let key = identify(path).map(|(key, modified, size)| format!("{}:{}:{}", key, modified, size));
+    if let Some(cached) = key.as_deref().and_then(|key| state.file_probes.get(key)) {
+        return Ok(cached);
+    }
+    let parsed = run_ffprobe(state.runner.as_ref(), path).await?;
+    let streams: Vec<StreamProbe> = parsed
+        .streams
+        .iter()
+        .map(|s| StreamProbe {
+            index: s.index,
+            codec_type: s.codec_type.clone(),
+            codec_name: s.codec_name.clone(),
+            profile: s.profile.clone(),
+            width: s.width,
+            height: s.height,
+            frame_rate: s.r_frame_rate.clone().filter(|rate| s.codec_type.as_deref() == Some("video") && rate != "0/0"),
+            bit_rate: parse_number(&s.bit_rate),
+            sample_rate: parse_number(&s.sample_rate),
+            channels: s.channels,
+            language: s.tags.get("language").cloned(),
+        })
+        .collect();
+    let video = streams.iter().find(|s| s.codec_type.as_deref() == Some("video"));
+    let audio = streams.iter().find(|s| s.codec_type.as_deref() == Some("audio"));
+    let format = parsed.format.as_ref();
+    let details = FileProbe {
+        format_name: format.and_then(|f| f.format_name.clone()),
+        duration: format.and_then(|f| parse_number(&f.duration)),
+        size_bytes: format.and_then(|f| parse_number(&f.size)),
+        bit_rate: format.and_then(|f| parse_number(&f.bit_rate)),
+        video_codec: video.and_then(|s| s.codec_name.clone()),
+        audio_codec: audio.and_then(|s| s.codec_name.clone()),
+        width: video.and_then(|s| s.width),
+        height: video.and_then(|s| s.height),
+        streams,
+    };
+    if let Some(key) = key {
+        state.file_probes.insert(key, details.clone());
+    }
+    Ok(details)
+}
+
+fn parse_number<T: std::str::FromStr>(value: &Option<String>) -> Option<T> {
+    value.as_deref().and_then(|v| v.parse().ok())
+}
+
+async fn run_ffprobe(runner: &dyn ProcessRunner, path: &Path) -> Result<FfprobeOutput> {
+    let output = runner
+        .command("ffprobe")
+        .args(["-v", "error", "-print_format", "json", "-show_format", "-show_streams"])
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    if !output.status.success() {
+        anyhow::bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Upper bounds of the `yt_agent_downloads_duration_seconds` buckets.
+const DURATION_BUCKETS: &[f64] = &[1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0];
+
+/// How often a panic poisoned the download statuses' lock, for `yt_agent_poisoned_locks_total`.
+static POISONED_LOCKS: AtomicU64 = AtomicU64::new(0);
+
+/// Counts a lock that a panic left poisoned and that was recovered.
+pub fn record_poisoned_lock() {
+    POISONED_LOCKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The extractor label of a download whose extractor isn't known, or of every download when
+/// `metrics_extractor_label` is off.
+const UNKNOWN_EXTRACTOR: &str = "unknown";
+
+#[derive(Clone, Default, Debug)]
+struct Histogram {
+    /// Counts per bucket of `DURATION_BUCKETS`, not cumulative.
+    buckets: [u64; DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = DURATION_BUCKETS.iter().position(|bound| value <= *bound) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+#[derive(Default)]
+struct Recorded {
+    /// Keyed by (extractor, outcome).
+    durations: BTreeMap<(String, String), Histogram>,
+    /// Keyed by extractor.
+    bytes: BTreeMap<String, u64>,
+}
+
+/// Per-extractor counters of finished downloads, for `GET /metrics`. Labels only take values
+/// from small fixed sets: yt-dlp's extractor names and the final statuses. The URL, download
+/// key and title of a download go to the log instead, as labels with one value per download
+/// would blow up the series count of a Prometheus server.
+#[derive(Clone, Default)]
+pub struct DownloadMetrics {
+    recorded: Arc<Mutex<Recorded>>,
+}
+
+impl DownloadMetrics {
+    /// Records a finished download. `extractor` is yt-dlp's extractor key, e.g. "Youtube";
+    /// `outcome` is its final status, e.g. "completed" or "failed".
+    pub fn record(&self, extractor: Option<&str>, outcome: &str, duration: Duration, bytes: u64) {
+        let extractor = extractor.map(label_value).unwrap_or_else(|| UNKNOWN_EXTRACTOR.to_string());
+        let mut recorded = self.recorded.lock().unwrap();
+        recorded.durations.entry((extractor.clone(), outcome.to_string())).or_default().observe(duration.as_secs_f64());
+        *recorded.bytes.entry(extractor).or_insert(0) += bytes;
+    }
+
+    /// Renders the counters in the Prometheus text format. With `extractor_label` off, the
+    /// series of all extractors are summed and the label is left out.
+    pub fn render(&self, extractor_label: bool) -> String {
+        let recorded = self.recorded.lock().unwrap();
+        let mut durations: BTreeMap<(Option<&str>, &str), Histogram> = BTreeMap::new();
+        for ((extractor, outcome), histogram) in &recorded.durations {
+            let key = (extractor_label.then_some(extractor.as_str()), outcome.as_str());
+            let merged = durations.entry(key).or_default();
+            for (total, count) in merged.buckets.iter_mut().zip(histogram.buckets) {
+                *total += count;
+            }
+            merged.count += histogram.count;
+            merged.sum += histogram.sum;
+        }
+        let mut bytes: BTreeMap<Option<&str>, u64> = BTreeMap::new();
+        for (extractor, value) in &recorded.bytes {
+            *bytes.entry(extractor_label.then_some(extractor.as_str())).or_insert(0) += value;
+        }
+
+        let mut body = String::new();
+        let name = "yt_agent_downloads_duration_seconds";
+        let _ = writeln!(body, "# HELP {name} How long finished downloads ran, by extractor and outcome.");
+        let _ = writeln!(body, "# TYPE {name} histogram");
+        for ((extractor, outcome), histogram) in &durations {
+            let labels = labels(*extractor, Some(outcome));
+            let mut cumulative = 0;
+            for (bound, count) in DURATION_BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(body, "{name}_bucket{{{labels},le=\"{bound}\"}} {cumulative}");
+            }
+            let _ = writeln!(body, "{name}_bucket{{{labels},le=\"+Inf\"}} {}", histogram.count);
+            let _ = writeln!(body, "{name}_sum{{{labels}}} {}", histogram.sum);
+            let _ = writeln!(body, "{name}_count{{{labels}}} {}", histogram.count);
+        }
+        let name = "yt_agent_bytes_downloaded_total";
+        let _ = writeln!(body, "# HELP {name} Bytes transferred by finished downloads, by extractor.");
+        let _ = writeln!(body, "# TYPE {name} counter");
+        for (extractor, value) in &bytes {
+            match extractor {
+                Some(_) => {
+                    let _ = writeln!(body, "{name}{{{}}} {value}", labels(*extractor, None));
+                }
+                None => {
+                    let _ = writeln!(body, "{name} {value}");
+                }
+            }
+        }
+        let name = "yt_agent_poisoned_locks_total";
+        let _ = writeln!(body, "# HELP {name} Panics that left the download statuses locked and were recovered from.");
+        let _ = writeln!(body, "# TYPE {name} counter");
+        let _ = writeln!(body, "{name} {}", POISONED_LOCKS.load(Ordering::Relaxed));
+        body
+    }
+}
+
+fn labels(extractor: Option<&str>, outcome: Option<&str>) -> String {
+    let mut labels = Vec::new();
+    if let Some(extractor) = extractor {
+        labels.push(format!("extractor=\"{}\"", extractor));
+    }
+    if let Some(outcome) = outcome {
+        labels.push(format!("outcome=\"{}\"", outcome));
+    }
+    labels.join(",")
+}
+
+/// Keeps an extractor name to the characters yt-dlp uses in them (letters, digits, `:` and
+/// `_`), so a label value never needs escaping.
+fn label_value(extractor: &str) -> String {
+    let value: String = extractor.chars().filter(|c| c.is_ascii_alphanumeric() || *c == ':' || *c == '_').take(64).collect();
+    if value.is_empty() { UNKNOWN_EXTRACTOR.to_string() } else { value }
+}
@@ -1,33 +1,147 @@
+use crate::media_index::MediaInfo;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Per-extractor arguments, e.g. `{"youtube": {"player_client": "android,web"}}`.
+/// Rendered as `--extractor-args "youtube:player_client=android,web"`.
+pub type ExtractorArgs = HashMap<String, HashMap<String, String>>;
 
 // === API Request/Response Models ===
 
-/// The query parameters for a `GET /formats` request.
+/// The query parameters for a `GET /formats` request, or the JSON body for `POST /formats`.
 #[derive(Deserialize, Debug)]
 pub struct FormatRequest {
     pub url: String,
+    /// Return every format, including storyboards and formats with no streams.
+    #[serde(default)]
+    pub include_all: bool,
+    /// Extra headers for the probe, merged over `http_headers` from the config.
+    /// Only available on `POST /formats`.
+    #[serde(default)]
+    pub http_headers: HashMap<String, String>,
+    /// Overrides `user_agent` from the config.
+    pub user_agent: Option<String>,
+    /// Passed to yt-dlp as for a download, so the formats listed are the ones it would get.
+    /// Only available on `POST /formats`.
+    #[serde(default)]
+    pub extractor_args: ExtractorArgs,
+    /// Probe with this session's cookie jar (see `POST /session`).
+    pub session_id: Option<String>,
+    /// Look the video up in the download history and report `already_downloaded`.
+    #[serde(default = "default_true")]
+    pub include_history: bool,
+}
+
+/// The JSON body for a `POST /formats/batch` request.
+#[derive(Deserialize, Debug)]
+pub struct BatchFormatRequest {
+    pub urls: Vec<String>,
+    #[serde(default)]
+    pub extractor_args: ExtractorArgs,
+    /// Return every format, including storyboards and formats with no streams.
+    #[serde(default)]
+    pub include_all: bool,
+    /// Probe with this session's cookie jar (see `POST /session`).
+    pub session_id: Option<String>,
+}
+
+/// The outcome of probing a single URL in a batch.
+#[derive(Serialize, Debug)]
+pub struct BatchFormatResult {
+    pub url: String,
+    pub info: Option<FormatsResponse>,
+    pub error: Option<String>,
 }
 
 /// Represents the top-level JSON output from `yt-dlp --dump-json`.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct VideoInfo {
+    #[serde(default)]
+    pub id: Option<String>,
     pub title: String,
     pub formats: Vec<Format>,
     pub thumbnail: Option<String>,
+    /// The minimum viewer age the site requires, e.g. 18 for age-restricted videos.
+    #[serde(default)]
+    pub age_limit: Option<u32>,
+    /// Whether the download history has this video's ID. `false` when the video has no ID or
+    /// the history wasn't consulted (`include_history=false`) or couldn't be read.
+    #[serde(default)]
+    pub already_downloaded: bool,
+    /// The latest download of this video, when `already_downloaded` is true.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_download: Option<PreviousDownload>,
+}
+
+/// The latest history entry of a video that was downloaded before, as `/formats` reports it.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct PreviousDownload {
+    pub download_key: String,
+    /// RFC 3339 timestamp of when the item finished downloading.
+    pub downloaded_at: String,
+    /// The format selector it was downloaded with, if recorded.
+    pub format: Option<String>,
+    pub files: Vec<String>,
+}
+
+/// One line of `yt-dlp --dump-json` output: the video plus its playlist context, if any.
+#[derive(Deserialize, Debug)]
+pub struct DumpJsonEntry {
+    #[serde(flatten)]
+    pub info: VideoInfo,
+    pub webpage_url: Option<String>,
+    pub playlist_title: Option<String>,
+    pub playlist_count: Option<u64>,
+    pub playlist_index: Option<u64>,
+}
+
+/// The `/formats` response: a single video, or the first page of a playlist.
+#[derive(Clone, Serialize, Debug)]
+#[serde(untagged)]
+pub enum FormatsResponse {
+    Video(VideoInfo),
+    Playlist(PlaylistFormats),
+}
+
+/// The `/formats` response for a playlist URL. Only the first entries are probed.
+#[derive(Clone, Serialize, Debug)]
+pub struct PlaylistFormats {
+    pub playlist_title: Option<String>,
+    /// Total number of entries in the playlist, if yt-dlp reported it.
+    pub total_entries: Option<u64>,
+    pub entries: Vec<PlaylistEntryFormats>,
+    /// True if the playlist has more entries than were probed.
+    pub truncated: bool,
+}
+
+/// A single probed playlist entry with its own format list.
+#[derive(Clone, Serialize, Debug)]
+pub struct PlaylistEntryFormats {
+    pub url: Option<String>,
+    pub playlist_index: Option<u64>,
+    #[serde(flatten)]
+    pub info: VideoInfo,
 }
 
 /// Represents a single format available for download.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct Format {
     pub format_id: String,
     pub ext: String,
     pub resolution: String,
     #[serde(default)]
+    pub height: Option<u32>,
+    #[serde(default)]
+    pub format_note: Option<String>,
+    #[serde(default)]
     pub vcodec: String,
     #[serde(default)]
     pub acodec: String,
     #[serde(default)]
     pub filesize: Option<u64>,
+    /// yt-dlp's estimate when the exact `filesize` isn't known, e.g. from the bitrate.
+    #[serde(default)]
+    pub filesize_approx: Option<u64>,
     #[serde(default)]
     pub tbr: Option<f64>, // Total Bitrate in KBit/s
 }
@@ -35,20 +149,46 @@ pub struct Format {
 // === Download & Status Models ===
 
 /// The JSON body for a `POST /download` request with extended functionality.
-#[derive(Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
 pub struct DownloadRequest {
     // === Core Fields ===
     pub url: String,
+    /// Required unless `formats` or `outputs` is given.
+    #[serde(default)]
     pub format_id: String,
+    /// Download several formats of the URL as one job group: a parent job with a child per
+    /// format, sharing a single metadata extraction. Replaces `format_id` when non-empty.
+    #[serde(default)]
+    pub formats: Vec<FormatSpec>,
+    /// Like `formats`, but an audio extraction and a plain download of the same `format_id`
+    /// share one download, and the parent's status tracks each output's files.
+    #[serde(default)]
+    pub outputs: Vec<OutputSpec>,
+    /// Preferred audio language, e.g. "de" or "pt-BR". Narrows each `bestaudio`/`ba` in the
+    /// format selection to that language, falling back to the unrestricted selection.
+    pub audio_lang: Option<String>,
+    /// A format selector to retry with once if yt-dlp reports that `format_id` isn't available,
+    /// e.g. "bv*+ba/b". Ignored for job groups (`formats`).
+    pub fallback_format: Option<String>,
+
+    /// RFC 3339 start time, e.g., "2024-05-01T02:00:00Z". The download waits in the
+    /// "scheduled" state until then.
+    pub scheduled_at: Option<String>,
 
     // === Filesystem & Metadata Fields ===
     /// Output template for the filename, e.g., "downloads/%(uploader)s/%(title)s.%(ext)s"
     /// Replaces the old `output_path`.
     pub output_template: Option<String>,
+    /// Alternate download root, must be one of the configured `allowed_download_roots`.
+    /// Relative output templates are resolved against it.
+    pub download_root: Option<String>,
     #[serde(default)]
     pub write_info_json: bool,
     #[serde(default)]
     pub write_thumbnail: bool,
+    /// Writes the video description to a `.description` file.
+    #[serde(default)]
+    pub write_description: bool,
     #[serde(default)]
     pub restrict_filenames: bool,
 
@@ -57,13 +197,56 @@ pub struct DownloadRequest {
     pub playlist_items: Option<String>,
     /// e.g., "duration > 600 & like_count > 1000"
     pub match_filter: Option<String>,
+    /// Helpers compiled into the match filter, and combined with `match_filter` if both are given.
+    pub min_duration_secs: Option<u64>,
+    pub max_duration_secs: Option<u64>,
+    pub min_views: Option<u64>,
+    /// Only items whose title contains this text, ignoring case.
+    pub title_contains: Option<String>,
     /// e.g., "50M" or "1G"
     pub max_filesize: Option<String>,
 
+    // === Extraction Fields ===
+    /// e.g., {"youtube": {"player_client": "android"}}
+    #[serde(default)]
+    pub extractor_args: ExtractorArgs,
+
+    // === Network Fields ===
+    /// Local IP address to bind to, e.g. to pin the download to one WAN link.
+    /// Defaults to `default_source_address` from the config.
+    pub source_address: Option<String>,
+    /// Force IPv4 or IPv6. Defaults to `default_force_ip` from the config.
+    pub force_ip: Option<ForceIp>,
+    /// Hash each finished file with this algorithm and record the digests in the status and
+    /// history. Defaults to `default_checksum` from the config.
+    pub compute_checksum: Option<ChecksumAlgorithm>,
+    /// Extra headers, merged over `http_headers` from the config.
+    #[serde(default)]
+    pub http_headers: HashMap<String, String>,
+    /// Overrides `user_agent` from the config.
+    pub user_agent: Option<String>,
+    /// Download with this session's cookie jar (see `POST /session`).
+    pub session_id: Option<String>,
+    /// Start right away in one of the `max_express_downloads` slots instead of waiting in the
+    /// queue. Needs an API key with `allow_express`.
+    #[serde(default)]
+    pub bypass_queue: bool,
+    /// Confirms a download estimated above the server's `large_download_threshold`.
+    #[serde(default)]
+    pub confirm_large: bool,
+    /// Downloads a playlist or channel with more items than the server's `max_playlist_items`.
+    #[serde(default)]
+    pub override_playlist_limit: bool,
+    /// Why the queue was bypassed, recorded in the log.
+    pub bypass_reason: Option<String>,
+
     // === Post-Processing Fields ===
     /// If true, triggers audio extraction.
     #[serde(default)]
     pub extract_audio: bool,
+    /// Keeps the downloaded video next to the extracted audio (`--keep-video`).
+    #[serde(default)]
+    pub keep_video: bool,
     /// e.g., "mp3", "flac", "wav"
     pub audio_format: Option<String>,
     /// e.g., "0" (best VBR) or "128K"
@@ -71,12 +254,181 @@ pub struct DownloadRequest {
     /// e.g., "mkv", "mp4"
     pub remux_video: Option<String>,
     pub embed_thumbnail: Option<bool>,
+    /// Writes title/artist/etc. tags into the output file.
+    #[serde(default)]
+    pub embed_metadata: bool,
+    /// Writes the video's `webpage_url` into the file's comment tag. Requires ffmpeg.
+    #[serde(default)]
+    pub embed_source_url: bool,
+
+    // === Subtitle Fields ===
+    #[serde(default)]
+    pub write_subs: bool,
+    #[serde(default)]
+    pub write_auto_subs: bool,
+    /// e.g., "en,de" or "all"
+    pub sub_langs: Option<String>,
+    /// Probes the subtitle languages the video has and downloads the first available one of
+    /// `preferred_sub_langs`, instead of passing `sub_langs` as is.
+    #[serde(default)]
+    pub auto_sub_langs: bool,
+    /// Languages in order of preference for `auto_sub_langs`, e.g. ["de", "en"].
+    #[serde(default)]
+    pub preferred_sub_langs: Vec<String>,
+    /// Preferred subtitle format, e.g., "srt" or "srt/vtt/best"
+    pub sub_format: Option<String>,
+    /// Convert subtitles after download: "srt", "vtt", "ass" or "lrc"
+    pub convert_subs: Option<String>,
+    /// Downloads the chat replay of a past live stream as the `live_chat` subtitle.
+    #[serde(default)]
+    pub write_live_chat: bool,
+    /// Also writes the chat replay as a `.live_chat.txt` with one line per message.
+    #[serde(default)]
+    pub convert_live_chat: bool,
 
     // === SponsorBlock Fields ===
     /// e.g., "sponsor,selfpromo" or "all"
     pub sponsorblock_remove: Option<String>,
     /// e.g., "all,-outro"
     pub sponsorblock_mark: Option<String>,
+    /// Title template for the chapters created by `sponsorblock_mark`,
+    /// e.g., "[SponsorBlock]: %(category_names)l"
+    pub sponsorblock_chapter_title: Option<String>,
+
+    /// Set internally for job group children: read the metadata extracted by the parent
+    /// instead of extracting it again.
+    #[serde(skip)]
+    pub load_info_json: Option<String>,
+    /// Set internally for `POST /fetch`: write only the side files, not the media.
+    #[serde(skip)]
+    pub skip_download: bool,
+    /// Set by the server to the client that sent the request; a value in the body is replaced.
+    /// Kept when a scheduled download is saved.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<DownloadOrigin>,
+}
+
+/// One format of a job group, with its own post-processing options.
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct FormatSpec {
+    pub format_id: String,
+    #[serde(default)]
+    pub extract_audio: bool,
+    #[serde(default)]
+    pub keep_video: bool,
+    pub audio_format: Option<String>,
+    pub audio_quality: Option<String>,
+    pub remux_video: Option<String>,
+    /// Inserted before the extension of the output template, e.g. ".audio", so the
+    /// group's files don't overwrite each other.
+    pub template_suffix: Option<String>,
+}
+
+/// One output of a download with `outputs`: the same options as a job group format.
+pub type OutputSpec = FormatSpec;
+
+/// The IP version yt-dlp is forced to use (`-4` / `-6`).
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ForceIp {
+    Ipv4,
+    Ipv6,
+}
+
+/// A digest algorithm for file checksums.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumAlgorithm {
+    Sha256,
+    Md5,
+}
+
+/// The checksum of a finished file.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct FileChecksum {
+    pub path: String,
+    pub algorithm: ChecksumAlgorithm,
+    /// Lowercase hex.
+    pub digest: String,
+}
+
+/// The JSON body for a `POST /session` request.
+#[derive(Deserialize, Debug, Default)]
+pub struct SessionRequest {
+    /// Cookies to start the jar with, in Netscape format (as exported by browser extensions).
+    pub cookies: Option<String>,
+}
+
+/// The JSON body for a `POST /uploads` request.
+#[derive(Deserialize, Debug)]
+pub struct UploadRequest {
+    /// The length of the whole file, in bytes.
+    pub size: u64,
+}
+
+/// The JSON body for a `POST /cookies` request: the cookies inline or as a finished upload.
+#[derive(Deserialize, Debug)]
+pub struct CookiesRequest {
+    /// Cookies in Netscape format.
+    pub cookies: Option<String>,
+    pub upload_id: Option<String>,
+}
+
+/// The JSON body for a `POST /download/import` request: the URL list inline or as a finished
+/// upload, and the options every download gets.
+#[derive(Deserialize, Debug)]
+pub struct ImportRequest {
+    /// URLs, one per line. Blank lines and lines starting with `#` are skipped.
+    pub urls: Option<String>,
+    pub upload_id: Option<String>,
+    /// Options for each download as for `POST /download`, without `url`.
+    #[serde(default)]
+    pub request: serde_json::Map<String, serde_json::Value>,
+}
+
+/// The JSON body for a `POST /ytdlp/install` request.
+#[derive(Deserialize, Debug)]
+pub struct YtDlpInstallRequest {
+    /// A release, e.g. "2024.08.06", or "latest".
+    pub version: String,
+}
+
+/// The JSON body for `POST /setup`: the few settings a new install needs. Everything else
+/// starts at its default.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SetupRequest {
+    /// Defaults to the OS's Downloads folder.
+    pub download_directory: Option<String>,
+    pub max_concurrent_downloads: Option<usize>,
+    pub max_concurrent_probes: Option<usize>,
+}
+
+/// The JSON body for a `POST /rip` request. Kept minimal on purpose so it stays stable.
+#[derive(Deserialize, Debug)]
+pub struct RipRequest {
+    pub url: String,
+    /// One of "mp3", "opus", "flac".
+    #[serde(default = "default_rip_format")]
+    pub format: String,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_rip_format() -> String {
+    "mp3".to_string()
+}
+
+/// The JSON body for a `POST /fetch` request: side files for a video, without the video.
+#[derive(Deserialize, Debug)]
+pub struct FetchRequest {
+    pub url: String,
+    /// Any of "subtitles", "thumbnail", "info_json", "description".
+    pub kinds: Vec<String>,
+    /// A directory relative to the download directory, e.g. where the video already is.
+    pub target_dir: Option<String>,
 }
 
 /// The response sent after successfully starting a download.
@@ -84,6 +436,14 @@ pub struct DownloadRequest {
 pub struct DownloadResponse {
     pub message: String,
     pub download_key: String,
+    /// Non-fatal problems found while validating the request.
+    pub warnings: Vec<String>,
+    /// The download's place among those waiting for `max_concurrent_downloads`; `null` when
+    /// it starts right away.
+    pub queue_position: Option<usize>,
+    /// When a queued download should start, from how long recent downloads took. `null`
+    /// until a download has finished.
+    pub estimated_start_at: Option<String>,
 }
 
 /// Represents the real-time status of a single download.
@@ -94,5 +454,434 @@ pub struct DownloadStatus {
     pub progress: f64,
     pub eta: String,    // Estimated Time of Arrival
     pub speed: String,
+    /// When the file currently being downloaded should be done (RFC 3339), from yt-dlp's ETA
+    /// or, without one, from the remaining bytes and the current speed. `None` while
+    /// post-processing and once the download has finished.
+    pub estimated_completion_at: Option<String>,
+    /// When yt-dlp last reported progress (RFC 3339).
+    pub last_progress_at: Option<String>,
+    #[serde(skip)]
+    pub speed_bytes_per_sec: Option<f64>,
+    /// Bytes downloaded so far of the file currently being written.
+    pub downloaded_bytes: Option<u64>,
+    /// Total (or estimated) size of the file currently being written.
+    pub total_bytes: Option<u64>,
+    /// The file currently being written, from yt-dlp's `Destination:` line.
+    pub current_file: Option<String>,
+    /// The size of `current_file` (or its `.part` file) on disk, checked every few seconds.
+    pub bytes_on_disk: Option<u64>,
+    /// `bytes_on_disk` and `downloaded_bytes` disagree by more than a tenth, which usually
+    /// means the progress parser follows a different stream than the one being written.
+    pub progress_mismatch: bool,
     pub error: Option<String>,
+    /// The root directory this download writes into.
+    pub download_root: Option<String>,
+    /// Files written by the download (media, subtitles, ...), as reported by yt-dlp.
+    pub files: Vec<String>,
+    /// The files `--extract-audio` wrote. A download shared by an audio and a video output
+    /// gives them to the audio output only.
+    #[serde(skip)]
+    pub converted_audio: Vec<String>,
+    /// Downloaded files `--extract-audio` left as they were, being in the wanted audio format
+    /// already. A shared download gives them to both outputs.
+    #[serde(skip)]
+    pub unconverted_audio: Vec<String>,
+    /// Any adjustment made to the requested format during validation, e.g. merging in audio.
+    pub format_decision: Option<String>,
+    /// The `fallback_format` the download switched to because the requested format wasn't available.
+    pub fallback_format: Option<String>,
+    /// Non-fatal problems found while validating the request.
+    pub warnings: Vec<String>,
+    /// When a scheduled download will start (RFC 3339).
+    pub scheduled_at: Option<String>,
+    /// Whether the source URL was embedded into the file's metadata.
+    pub source_url_embedded: bool,
+    /// Why a due scheduled download is still waiting, e.g. "storage unavailable".
+    pub queue_reason: Option<String>,
+    /// While waiting for a download slot: its place in line, 1 being next.
+    pub queue_position: Option<usize>,
+    /// While waiting for a download slot: when it should start, if that can be estimated.
+    pub estimated_start_at: Option<String>,
+    /// The download bypassed the queue (`bypass_queue`).
+    pub express: bool,
+    /// The local address the download is bound to, if any.
+    pub source_address: Option<String>,
+    pub force_ip: Option<ForceIp>,
+    /// The yt-dlp command line, once the process has been started.
+    pub command: Option<String>,
+    /// The files the download is expected to produce, from a `--skip-download` pass at start.
+    pub expected_files: Vec<ExpectedFile>,
+    /// For a job group parent: the keys of its per-format child jobs.
+    pub children: Vec<String>,
+    /// For a job group child: the key of its parent job.
+    pub parent: Option<String>,
+    /// For a download with `outputs`: each output's progress, in request order.
+    pub outputs: Vec<OutputStatus>,
+    /// Bytes transferred per second over the whole run, once the download has completed.
+    pub average_speed_bytes_per_sec: Option<f64>,
+    /// The recognized cause of a failure, e.g. "age_restricted".
+    pub error_code: Option<ErrorCode>,
+    /// What the user can do about `error_code`.
+    pub error_hint: Option<String>,
+    /// For a playlist: how many items were selected, as announced by yt-dlp.
+    pub items_total: Option<u64>,
+    /// For a playlist: how many items finished downloading.
+    pub items_completed: Option<u64>,
+    /// For a playlist that failed midway: the item that failed plus those yt-dlp didn't reach.
+    pub items_failed: Option<u64>,
+    /// The playlist item that stopped the download.
+    pub failed_item: Option<FailedItem>,
+    /// The `playlist_items` that `POST /download/:key/retry?only_failed=true` downloads.
+    pub retry_playlist_items: Option<String>,
+    /// Explains an unusual outcome, such as `completed_no_output`.
+    pub note: Option<String>,
+    /// Set when `large_download_threshold` applies but the size couldn't be estimated.
+    pub size_unknown: bool,
+    /// The size check a download over `large_download_threshold` was confirmed against.
+    pub size_confirmation: Option<SizeConfirmation>,
+    /// Which client started the download.
+    pub origin: Option<DownloadOrigin>,
+    /// `files` that lie under a download root, with their links. Set when the download ends.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub file_refs: Vec<FileRef>,
+    /// The checksums of `files`, with `compute_checksum`. Empty otherwise.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub checksums: Vec<FileChecksum>,
+    /// When the download was added (RFC 3339).
+    pub created_at: Option<String>,
+    /// The request that started this job, for retries.
+    #[serde(skip)]
+    pub request: Option<DownloadRequest>,
+}
+
+/// The API client that started a download, recorded on its status and history.
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct DownloadOrigin {
+    /// The route that took the request, e.g. "POST /download", or "download command" for the CLI.
+    pub endpoint: String,
+    pub user_agent: Option<String>,
+    /// The client's address; not recorded with `record_client_ip = false`.
+    pub ip: Option<String>,
+    /// The name of the API key the request came with, from `api_keys`.
+    #[serde(default)]
+    pub api_key: Option<String>,
+}
+
+/// Records that a client confirmed a download larger than `large_download_threshold`.
+#[derive(Clone, Serialize, Debug)]
+pub struct SizeConfirmation {
+    pub estimated_bytes: u64,
+    pub threshold_bytes: u64,
+    /// RFC 3339 timestamp of the confirmed request.
+    pub confirmed_at: String,
+}
+
+/// A playlist item whose download failed.
+#[derive(Clone, Serialize, Debug)]
+pub struct FailedItem {
+    /// The item's index in the playlist, if it could be worked out from `playlist_items`.
+    pub playlist_index: Option<u64>,
+    pub error: String,
+}
+
+/// A machine-readable cause of a failure, recognized from yt-dlp's error output.
+#[derive(Clone, Copy, Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The site requires a signed-in, age-verified account.
+    AgeRestricted,
+    /// yt-dlp reported no progress for `stall_timeout_secs` and was killed.
+    Stalled,
+    /// The site refused the request as one too many (HTTP 429).
+    RateLimited,
+    /// The site answered with a server error (HTTP 5xx).
+    ServerError,
+    /// The site couldn't be reached, e.g. the connection was refused or timed out.
+    NetworkError,
+}
+
+impl ErrorCode {
+    pub fn hint(self) -> &'static str {
+        match self {
+            ErrorCode::AgeRestricted => {
+                "Configure cookies (cookies_file or cookies_from_browser in the config) to download age-restricted content."
+            }
+            ErrorCode::Stalled => "yt-dlp reported no progress for stall_timeout_secs and was stopped. Retry the download with POST /download/:key/retry.",
+            ErrorCode::RateLimited => "The site is limiting how often it may be asked. Wait before retrying, or lower max_concurrent_downloads.",
+            ErrorCode::ServerError => "The site had an internal error. Retry the download later.",
+            ErrorCode::NetworkError => "The site couldn't be reached. Check the network connection and retry the download.",
+        }
+    }
+
+    /// Whether the failure says the host itself is in trouble, rather than the one video, and so
+    /// counts towards its circuit breaker (see `circuit_breaker_threshold`).
+    pub fn is_host_failure(self) -> bool {
+        matches!(self, ErrorCode::RateLimited | ErrorCode::ServerError | ErrorCode::NetworkError)
+    }
+}
+
+/// The progress of one of a download's `outputs`.
+#[derive(Clone, Serialize, Debug)]
+pub struct OutputStatus {
+    pub format_id: String,
+    pub extract_audio: bool,
+    /// The child job producing the output. Outputs that share a download share a child.
+    pub download_key: String,
+    pub status: String,
+    pub files: Vec<String>,
+}
+
+/// A file a running download is expected to produce.
+#[derive(Clone, Serialize, Debug)]
+pub struct ExpectedFile {
+    pub path: String,
+    pub video_id: Option<String>,
+    pub title: Option<String>,
+    /// Set once yt-dlp reports writing the file.
+    pub completed: bool,
+}
+
+/// A status keyed by its download, as `GET /status` lists it.
+#[derive(Serialize, Debug)]
+pub struct KeyedStatus {
+    pub download_key: String,
+    #[serde(flatten)]
+    pub status: DownloadStatus,
+}
+
+/// How `GET /status` lays out the downloads.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StatusShape {
+    /// `{"downloads": [...]}`, newest first.
+    #[default]
+    List,
+    /// The statuses by download key, in key order.
+    Map,
+}
+
+/// The query parameters for `GET /status`.
+#[derive(Deserialize, Debug)]
+pub struct StatusQuery {
+    /// "json" (default) or "csv"; overrides the `Accept` header.
+    pub format: Option<String>,
+    #[serde(default)]
+    pub shape: StatusShape,
+    /// Only list downloads started with the API key of this name.
+    pub origin_key: Option<String>,
+}
+
+/// The query parameters shared by list endpoints (`GET /history`, `GET /history/export`).
+#[derive(Deserialize, Debug)]
+pub struct ListQuery {
+    /// "json" (default) or "csv"; overrides the `Accept` header.
+    pub format: Option<String>,
+}
+
+/// The query parameters for `POST /download/:key/retry`.
+#[derive(Deserialize, Debug)]
+pub struct RetryQuery {
+    /// Only download the playlist items that failed or weren't reached.
+    #[serde(default)]
+    pub only_failed: bool,
+}
+
+/// The query parameters for `DELETE /schedule`.
+#[derive(Deserialize, Debug)]
+pub struct ScheduleQuery {
+    pub key: String,
+}
+
+/// The query parameters for `GET /channel/new` and `POST /channel/sync`.
+#[derive(Deserialize, Debug)]
+pub struct ChannelQuery {
+    /// A channel or playlist URL, e.g. "https://www.youtube.com/@example/videos".
+    pub url: String,
+    /// The format `POST /channel/sync` downloads each item in. Defaults to the best available.
+    pub format_id: Option<String>,
+}
+
+// === History Models ===
+
+/// A completed download item, recorded so a file can always be traced back to its source.
+/// Captured for every download, whether or not `write_info_json` was requested.
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct HistoryEntry {
+    /// The key of the download job that produced this item.
+    pub download_key: String,
+    /// The item's own page URL (for playlists this differs from the job URL).
+    pub url: String,
+    pub video_id: Option<String>,
+    pub title: Option<String>,
+    pub uploader: Option<String>,
+    /// The playlist the item was downloaded from, if any.
+    #[serde(default)]
+    pub playlist: Option<String>,
+    /// The item's index in that playlist.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub playlist_index: Option<u64>,
+    /// The yt-dlp extractor that handled the item, e.g. "Youtube".
+    #[serde(default)]
+    pub extractor: Option<String>,
+    /// As reported by yt-dlp, e.g., "20240131"
+    pub upload_date: Option<String>,
+    /// Duration in seconds.
+    pub duration: Option<f64>,
+    /// RFC 3339 timestamp of when the download job started.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<String>,
+    /// RFC 3339 timestamp of when the item finished downloading.
+    pub downloaded_at: String,
+    /// The final path of the downloaded file.
+    pub filepath: Option<String>,
+    /// The format selector the item was downloaded with, e.g. "137+140". For a job group,
+    /// the first child's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    /// Which client started the download.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<DownloadOrigin>,
+    /// The size in bytes of the item's files when it finished.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filesize: Option<u64>,
+    /// The checksums of the item's files, when the download set `compute_checksum`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub checksums: Vec<FileChecksum>,
+    /// The item's files that still exist, with their links. Filled in by `GET /history` and
+    /// `GET /history/search` when they respond; not stored.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub file_refs: Vec<FileRef>,
+    /// Set by `GET /history` and `GET /history/search` when none of the item's files exist any
+    /// more and the library index couldn't find where they went; not stored.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub missing: bool,
+    /// For a job group: every file produced for this item (`filepath` is the first).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<String>,
+    /// Set when the download finished without producing a file: "completed_no_output".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<String>,
+}
+
+/// The query parameters for `GET /history/search`.
+#[derive(Deserialize, Debug)]
+pub struct HistorySearchQuery {
+    pub q: String,
+}
+
+// === File Models ===
+
+/// The query parameters for `GET /files` and `GET /files/*path`.
+#[derive(Deserialize, Debug)]
+pub struct FilesQuery {
+    /// Browse one of the `allowed_download_roots` instead of the primary directory.
+    pub root: Option<String>,
+    /// For `GET /files`: return objects joined with their download history instead of plain paths.
+    #[serde(default)]
+    pub metadata: bool,
+    /// For `GET /files`: return objects with each media file's duration, resolution and codecs.
+    #[serde(default)]
+    pub media_info: bool,
+    /// For `GET /files`: "json" (default) or "csv"; overrides the `Accept` header.
+    pub format: Option<String>,
+    /// For `GET /files/*path/frame`: the timestamp of the frame, e.g. "00:01:30" or "90.5".
+    pub at: Option<String>,
+    /// For `GET /files/*path/frame`: "jpeg" (default) or "png".
+    pub image: Option<String>,
+    /// For `GET /files/*path/checksum`: the algorithm; defaults to `default_checksum` from the
+    /// config, or SHA-256.
+    pub algorithm: Option<ChecksumAlgorithm>,
+}
+
+/// The query parameters for `POST /files/*path/storyboard`.
+#[derive(Deserialize, Debug)]
+pub struct StoryboardQuery {
+    /// A file under one of the `allowed_download_roots` instead of the primary directory.
+    pub root: Option<String>,
+    /// Seconds between frames; 10 by default.
+    pub interval: Option<u64>,
+}
+
+/// The sprite sheet and WebVTT track written by `POST /files/*path/storyboard`.
+#[derive(Serialize, Debug)]
+pub struct Storyboard {
+    pub sprite: FileRef,
+    pub vtt: FileRef,
+    pub interval: u64,
+    pub tiles: u64,
+    pub columns: u64,
+    pub tile_width: u32,
+    pub tile_height: u32,
+}
+
+/// A downloaded file as responses refer to it: its path under the download root (as
+/// `GET /files` lists it), its size, and the link that serves it.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct FileRef {
+    pub path: String,
+    pub size: Option<u64>,
+    /// `GET /files/*path` for the file, percent-encoded, under `public_base_url` if set.
+    pub url: String,
+}
+
+/// A `GET /files?metadata=true` or `?media_info=true` entry: the file plus where it came from,
+/// if known, and its media info.
+#[derive(Serialize, Debug)]
+pub struct FileEntry {
+    #[serde(flatten)]
+    pub file: FileRef,
+    pub source: Option<HistoryEntry>,
+    /// Only with `?media_info=true`, and only for media files. All fields are `null` until the
+    /// file has been indexed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media_info: Option<MediaInfo>,
+}
+
+// === Library Models ===
+
+/// The query parameters for the `/library` endpoints.
+#[derive(Deserialize, Debug)]
+pub struct LibraryQuery {
+    /// Group the files of one of the `allowed_download_roots` instead of the primary directory.
+    pub root: Option<String>,
+}
+
+/// A file in a library collection, with the history entry it was downloaded as, if known.
+#[derive(Serialize, Debug, Clone)]
+pub struct LibraryItem {
+    #[serde(flatten)]
+    pub file: FileRef,
+    /// The same as `size`, kept for existing clients.
+    pub size_bytes: u64,
+    pub source: Option<HistoryEntry>,
+}
+
+/// The files sharing an uploader or playlist.
+#[derive(Serialize, Debug)]
+pub struct LibraryGroup {
+    /// The uploader or playlist, or "unknown" for files without one in their history.
+    pub name: String,
+    pub count: usize,
+    pub total_bytes: u64,
+    /// RFC 3339 timestamp of the group's most recent download.
+    pub latest_download: Option<String>,
+    /// Only when a single group is requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub items: Option<Vec<LibraryItem>>,
+}
+
+// === Stats Models ===
+
+/// The files in a download directory, as counted for `GET /stats`.
+#[derive(Clone, Serialize, Debug, Default)]
+pub struct LibraryScan {
+    pub files: usize,
+    pub total_bytes: u64,
+}
+
+/// How often an extractor (site) appears in the history.
+#[derive(Serialize, Debug)]
+pub struct ExtractorCount {
+    pub extractor: String,
+    pub count: usize,
 }
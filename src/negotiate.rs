@@ -0,0 +1,313 @@
+use crate::{
+    error::AppError,
+    media_index::MediaInfo,
+    models::{DownloadStatus, FileEntry, ForceIp, HistoryEntry},
+};
+use axum::{
+    http::{header, HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
+};
+
+/// The representations a list endpoint can produce.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListFormat {
+    Json,
+    Csv,
+}
+
+/// Picks the representation for a list endpoint. An explicit `?format=` wins over the
+/// `Accept` header; JSON is the default.
+pub fn list_format(headers: &HeaderMap, format: Option<&str>) -> Result<ListFormat, AppError> {
+    match format.map(str::to_ascii_lowercase).as_deref() {
+        Some("json") => return Ok(ListFormat::Json),
+        Some("csv") => return Ok(ListFormat::Csv),
+        Some(other) => {
+            return Err(AppError::BadRequest(format!("Unsupported format '{}'. Supported formats: json, csv", other)))
+        }
+        None => {}
+    }
+    Ok(if accepts(headers, "text/csv") { ListFormat::Csv } else { ListFormat::Json })
+}
+
+/// The media type of a newline-delimited JSON stream.
+pub const NDJSON: &str = "application/x-ndjson";
+
+/// Whether the `Accept` header lists `media_type`, ignoring parameters such as `q`.
+pub fn accepts(headers: &HeaderMap, media_type: &str) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|media| media.split(';').next().unwrap_or("").trim().eq_ignore_ascii_case(media_type))
+}
+
+/// A type that can be written as one CSV row with a fixed column order.
+/// Only scalar columns are included; lists are joined with ';'.
+pub trait CsvRecord {
+    const HEADERS: &'static [&'static str];
+    fn record(&self) -> Vec<String>;
+}
+
+/// Serializes rows as a `text/csv` response with a header line.
+pub fn csv_response<T: CsvRecord>(rows: &[T]) -> Result<Response, AppError> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(T::HEADERS).map_err(anyhow::Error::from)?;
+    for row in rows {
+        writer.write_record(row.record()).map_err(anyhow::Error::from)?;
+    }
+    let body = writer.into_inner().map_err(|e| anyhow::anyhow!("Failed to finish CSV output: {}", e))?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/csv; charset=utf-8"));
+    Ok((headers, body).into_response())
+}
+
+fn opt<T: ToString>(value: &Option<T>) -> String {
+    value.as_ref().map(ToString::to_string).unwrap_or_default()
+}
+
+/// A `GET /status` row: the download key and its status.
+impl CsvRecord for (String, DownloadStatus) {
+    const HEADERS: &'static [&'static str] = &[
+        "download_key", "status", "progress", "eta", "speed", "downloaded_bytes", "total_bytes", "error",
+        "download_root", "files", "format_decision", "warnings", "scheduled_at",
+        "source_url_embedded", "queue_reason", "source_address", "force_ip", "command",
+        "children", "parent", "note", "express", "estimated_completion_at", "fallback_format",
+        "current_file", "bytes_on_disk", "progress_mismatch", "origin_endpoint", "origin_user_agent", "origin_ip",
+        "created_at", "queue_position", "estimated_start_at", "last_progress_at",
+    ];
+
+    fn record(&self) -> Vec<String> {
+        let (key, s) = self;
+        vec![
+            key.clone(),
+            s.status.clone(),
+            s.progress.to_string(),
+            s.eta.clone(),
+            s.speed.clone(),
+            opt(&s.downloaded_bytes),
+            opt(&s.total_bytes),
+            opt(&s.error),
+            opt(&s.download_root),
+            s.files.join(";"),
+            opt(&s.format_decision),
+            s.warnings.join(";"),
+            opt(&s.scheduled_at),
+            s.source_url_embedded.to_string(),
+            opt(&s.queue_reason),
+            opt(&s.source_address),
+            s.force_ip.map(|ip| match ip { ForceIp::Ipv4 => "ipv4", ForceIp::Ipv6 => "ipv6" }).unwrap_or_default().to_string(),
+            opt(&s.command),
+            s.children.join(";"),
+            opt(&s.parent),
+            opt(&s.note),
+            s.express.to_string(),
+            opt(&s.estimated_completion_at),
+            opt(&s.fallback_format),
+            opt(&s.current_file),
+            opt(&s.bytes_on_disk),
+            s.progress_mismatch.to_string(),
+            opt(&s.origin.as_ref().map(|o| o.endpoint.clone())),
+            opt(&s.origin.as_ref().and_then(|o| o.user_agent.clone())),
+            opt(&s.origin.as_ref().and_then(|o| o.ip.clone())),
+            opt(&s.created_at),
+            opt(&s.queue_position),
+            opt(&s.estimated_start_at),
+            opt(&s.last_progress_at),
+        ]
+    }
+}
+
+impl CsvRecord for HistoryEntry {
+    const HEADERS: &'static [&'static str] = &[
+        "download_key", "url", "video_id", "title", "uploader", "upload_date", "duration", "downloaded_at",
+        "filepath", "outcome",
+    ];
+
+    fn record(&self) -> Vec<String> {
+        vec![
+            self.download_key.clone(),
+            self.url.clone(),
+            opt(&self.video_id),
+            opt(&self.title),
+            opt(&self.uploader),
+            opt(&self.upload_date),
+            opt(&self.duration),
+            self.downloaded_at.clone(),
+            opt(&self.filepath),
+            opt(&self.outcome),
+        ]
+    }
+}
+
+/// A `GET /history/export` row: the history entry as a report, with its outcome as `status`.
+pub struct HistoryExportRow(pub HistoryEntry);
+
+impl CsvRecord for HistoryExportRow {
+    const HEADERS: &'static [&'static str] = &[
+        "url", "title", "status", "size", "duration", "started_at", "finished_at", "download_key", "video_id",
+        "uploader", "extractor", "format", "filepath",
+    ];
+
+    fn record(&self) -> Vec<String> {
+        let entry = &self.0;
+        vec![
+            entry.url.clone(),
+            opt(&entry.title),
+            entry.outcome.clone().unwrap_or_else(|| "completed".to_string()),
+            opt(&entry.filesize),
+            opt(&entry.duration),
+            opt(&entry.started_at),
+            entry.downloaded_at.clone(),
+            entry.download_key.clone(),
+            opt(&entry.video_id),
+            opt(&entry.uploader),
+            opt(&entry.extractor),
+            opt(&entry.format),
+            opt(&entry.filepath),
+        ]
+    }
+}
+
+/// Writes one CSV line.
+pub fn csv_line(fields: &[impl AsRef<[u8]>]) -> anyhow::Result<Vec<u8>> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(fields)?;
+    writer.into_inner().map_err(|e| anyhow::anyhow!("Failed to finish CSV output: {}", e))
+}
+
+/// A plain `GET /files` row.
+impl CsvRecord for String {
+    const HEADERS: &'static [&'static str] = &["path"];
+
+    fn record(&self) -> Vec<String> {
+        vec![self.clone()]
+    }
+}
+
+/// A `GET /files?metadata=true` row: the path followed by its history and media info columns.
+impl CsvRecord for FileEntry {
+    const HEADERS: &'static [&'static str] = &[
+        "path", "url", "video_id", "title", "uploader", "upload_date", "duration", "downloaded_at",
+        "media_duration", "width", "height", "video_codec", "audio_codec", "size", "file_url",
+    ];
+
+    fn record(&self) -> Vec<String> {
+        let source = self.source.as_ref();
+        let field = |f: fn(&HistoryEntry) -> String| source.map(f).unwrap_or_default();
+        let media = |f: fn(&MediaInfo) -> String| self.media_info.as_ref().map(f).unwrap_or_default();
+        vec![
+            self.file.path.clone(),
+            field(|e| e.url.clone()),
+            field(|e| opt(&e.video_id)),
+            field(|e| opt(&e.title)),
+            field(|e| opt(&e.uploader)),
+            field(|e| opt(&e.upload_date)),
+            field(|e| opt(&e.duration)),
+            field(|e| e.downloaded_at.clone()),
+            media(|m| opt(&m.duration)),
+            media(|m| opt(&m.width)),
+            media(|m| opt(&m.height)),
+            media(|m| opt(&m.video_codec)),
+            media(|m| opt(&m.audio_codec)),
+            opt(&self.file.size),
+            self.file.url.clone(),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history;
+    use crate::test_support::{app, lock_files, send, send_with, state, FakeRunner};
+    use axum::http::{Method, Request};
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    /// Parses a CSV response into its header and its rows as column name to value.
+    fn parse(text: &str) -> (Vec<String>, Vec<HashMap<String, String>>) {
+        let mut reader = csv::Reader::from_reader(text.as_bytes());
+        let headers: Vec<String> = reader.headers().unwrap().iter().map(str::to_string).collect();
+        let rows = reader.records().map(|record| headers.iter().cloned().zip(record.unwrap().iter().map(str::to_string)).collect()).collect();
+        (headers, rows)
+    }
+
+    #[tokio::test]
+    async fn exports_status_history_and_files_with_their_columns() {
+        let _files = lock_files().await;
+        let state = state(FakeRunner::new());
+        let title = "Say \"hi\", then\nleave";
+        let file = Path::new(&state.config.read().unwrap().download_directory).join("hi, there.mp4");
+        std::fs::write(&file, b"fake").unwrap();
+        let key = "https://example.com/v?a=1,2";
+        history::append(&[HistoryEntry {
+            download_key: key.to_string(),
+            url: key.to_string(),
+            video_id: Some("csv-export".to_string()),
+            title: Some(title.to_string()),
+            duration: Some(12.5),
+            downloaded_at: "2024-01-01T00:00:00Z".to_string(),
+            filepath: Some(file.to_string_lossy().to_string()),
+            ..Default::default()
+        }])
+        .await
+        .unwrap();
+        state.downloads.lock().insert(
+            key.to_string(),
+            DownloadStatus {
+                status: "completed".to_string(),
+                progress: 100.0,
+                downloaded_bytes: Some(4),
+                files: vec![file.to_string_lossy().to_string(), "/elsewhere/b.srt".to_string()],
+                warnings: vec!["one".to_string(), "two, too".to_string()],
+                express: true,
+                ..Default::default()
+            },
+        );
+        let app = app(&state);
+        let csv = |uri: &str| Request::builder().uri(uri).header("accept", "text/csv");
+
+        let status = send_with(&app, csv("/status"), None).await;
+        assert_eq!(status.headers["content-type"], "text/csv; charset=utf-8");
+        let (headers, rows) = parse(status.body.as_str().unwrap());
+        assert_eq!(headers, <(String, DownloadStatus)>::HEADERS);
+        let row = rows.iter().find(|row| row["download_key"] == key).unwrap();
+        assert_eq!(row["status"], "completed");
+        assert_eq!(row["progress"], "100");
+        assert_eq!(row["downloaded_bytes"], "4");
+        assert_eq!(row["total_bytes"], "");
+        assert_eq!(row["files"], format!("{};/elsewhere/b.srt", file.display()));
+        assert_eq!(row["warnings"], "one;two, too");
+        assert_eq!(row["express"], "true");
+        assert_eq!(row["source_url_embedded"], "false");
+
+        let listed = send_with(&app, csv("/history"), None).await;
+        let (headers, rows) = parse(listed.body.as_str().unwrap());
+        assert_eq!(headers, HistoryEntry::HEADERS);
+        let row = rows.iter().find(|row| row["video_id"] == "csv-export").unwrap();
+        assert_eq!(row["url"], key);
+        assert_eq!(row["title"], title);
+        assert_eq!(row["duration"], "12.5");
+        assert_eq!(row["filepath"], file.to_string_lossy());
+        assert_eq!(row["outcome"], "");
+
+        let files = send(&app, Method::GET, "/files?metadata=true&format=csv", None).await;
+        let (headers, rows) = parse(files.body.as_str().unwrap());
+        assert_eq!(headers, FileEntry::HEADERS);
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["path"], "hi, there.mp4");
+        assert_eq!(rows[0]["title"], title);
+        assert_eq!(rows[0]["duration"], "12.5");
+        assert_eq!(rows[0]["size"], "4");
+        assert_eq!(rows[0]["width"], "");
+        assert!(rows[0]["file_url"].ends_with("/files/hi%2C%20there.mp4"), "{}", rows[0]["file_url"]);
+    }
+
+    #[test]
+    fn quotes_single_lines_like_whole_responses() {
+        let line = csv_line(&["plain", "a,b", "say \"hi\"", "two\nlines", ""]).unwrap();
+        assert_eq!(String::from_utf8(line).unwrap(), "plain,\"a,b\",\"say \"\"hi\"\"\",\"two\nlines\",\n");
+    }
+}
@@ -0,0 +1,139 @@
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::redirect::{Attempt, Policy};
+use reqwest::Url;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+/// Builds the client for a request the server makes on its own, e.g. a usage report.
+/// It follows at most `max_redirects` redirects and refuses any redirect that leads to a
+/// loopback, private or link-local address. The target's own host is trusted, so `target`
+/// may itself be on the local network.
+pub fn client(target: &str, max_redirects: usize) -> reqwest::Result<reqwest::Client> {
+    let trusted_host = Url::parse(target).ok().and_then(|url| url.host_str().map(str::to_string));
+    let policy_host = trusted_host.clone();
+    let policy = Policy::custom(move |attempt: Attempt| {
+        if attempt.previous().len() > max_redirects {
+            return attempt.error(format!("too many redirects (max_redirects is {})", max_redirects));
+        }
+        // Address literals skip DNS resolution, so they are checked here.
+        let host = attempt.url().host_str().unwrap_or_default();
+        let ip = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().ok();
+        match ip {
+            Some(ip) if is_internal(ip) && Some(host) != policy_host.as_deref() => {
+                let blocked = format!("blocked a redirect to the internal address {}", ip);
+                attempt.error(blocked)
+            }
+            _ => attempt.follow(),
+        }
+    });
+    reqwest::Client::builder()
+        .redirect(policy)
+        .dns_resolver(Arc::new(ExternalResolver { trusted_host }))
+        .build()
+}
+
+/// Resolves host names, refusing internal addresses for every host but the trusted one.
+/// Redirects to a host name go through here, so they can't reach internal services either.
+struct ExternalResolver {
+    trusted_host: Option<String>,
+}
+
+impl Resolve for ExternalResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let trusted = self.trusted_host.as_deref() == Some(name.as_str());
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            if trusted {
+                return Ok(Box::new(addrs.into_iter()) as Addrs);
+            }
+            let external: Vec<SocketAddr> = addrs.into_iter().filter(|addr| !is_internal(addr.ip())).collect();
+            if external.is_empty() {
+                return Err(format!("blocked a request to {}, which resolves to an internal address", name.as_str()).into());
+            }
+            Ok(Box::new(external.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Whether `ip` is loopback, private, link-local (including cloud metadata endpoints),
+/// carrier-grade NAT, unspecified or otherwise not publicly routable. An IPv6 address that
+/// embeds an IPv4 one (mapped, compatible or NAT64) is judged by the IPv4 address.
+pub fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || a == 0
+                || ip.is_broadcast()
+                || a >= 240
+                || (a == 100 && (64..128).contains(&b))
+                || (a, b, c) == (192, 0, 0)
+                || (a == 198 && (18..20).contains(&b))
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            let embedded = ip.to_ipv4_mapped().or_else(|| {
+                // IPv4-compatible (::a.b.c.d, but not :: or ::1) and the NAT64 prefixes.
+                let compatible = segments[..6] == [0; 6] && !ip.is_unspecified() && !ip.is_loopback();
+                let nat64 = segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] || segments[..3] == [0x64, 0xff9b, 1];
+                (compatible || nat64).then(|| std::net::Ipv4Addr::from(u128::from(ip) as u32))
+            });
+            if let Some(v4) = embedded {
+                return is_internal(IpAddr::V4(v4));
+            }
+            let first = segments[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    #[test]
+    fn tells_internal_addresses_from_public_ones() {
+        let cases = [
+            ("127.0.0.1", true),
+            ("10.1.2.3", true),
+            ("172.16.0.1", true),
+            ("192.168.1.1", true),
+            ("169.254.169.254", true),
+            ("100.64.0.1", true),
+            ("100.127.255.255", true),
+            ("0.0.0.0", true),
+            ("0.1.2.3", true),
+            ("192.0.0.170", true),
+            ("198.18.0.1", true),
+            ("240.0.0.1", true),
+            ("255.255.255.255", true),
+            ("100.128.0.1", false),
+            ("192.0.2.1", false),
+            ("192.0.1.1", false),
+            ("198.20.0.1", false),
+            ("8.8.8.8", false),
+            ("1.1.1.1", false),
+            ("::1", true),
+            ("::", true),
+            ("fd00::1", true),
+            ("fe80::1", true),
+            ("::ffff:127.0.0.1", true),
+            ("::ffff:169.254.169.254", true),
+            ("::ffff:0.0.0.0", true),
+            ("::10.0.0.1", true),
+            ("64:ff9b::a9fe:a9fe", true),
+            ("64:ff9b:1::c0a8:101", true),
+            ("::ffff:8.8.8.8", false),
+            ("64:ff9b::808:808", false),
+            ("2001:4860:4860::8888", false),
+        ];
+        for (ip, internal) in cases {
+            assert_eq!(super::is_internal(ip.parse::<IpAddr>().unwrap()), internal, "{}", ip);
+        }
+    }
+}
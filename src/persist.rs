@@ -0,0 +1,314 @@
+use anyhow::Result;
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// Tells apart the temporary files of writes to the same file that overlap.
+static WRITE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// One lock per file, held for a whole write or append so that two writers can't both move
+/// the current file to the backup, or interleave their lines.
+static FILE_LOCKS: Lazy<Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>> = Lazy::new(Default::default);
+
+/// A file locked by [`lock`]; the lock is released when this is dropped.
+pub struct Locked {
+    path: PathBuf,
+    _guard: tokio::sync::OwnedMutexGuard<()>,
+    /// The `<path>.lock` file holding the advisory lock that other processes see.
+    #[cfg(unix)]
+    _file: std::fs::File,
+}
+
+impl Locked {
+    /// Saves `content` like [`write`], under this lock.
+    pub async fn write(&self, content: &[u8]) -> Result<()> {
+        write_locked(&self.path, content).await
+    }
+}
+
+/// Locks `path` against other writes and appends through this module, in this process and in
+/// other ones: `download` on the command line writes the same quota and history files as a
+/// running server. Across processes this is an advisory `flock` on `<path>.lock`.
+pub async fn lock(path: &Path) -> Result<Locked> {
+    let lock = FILE_LOCKS.lock().unwrap().entry(path.to_path_buf()).or_default().clone();
+    let guard = lock.lock_owned().await;
+    #[cfg(unix)]
+    let file = {
+        let mut lock_path = path.as_os_str().to_os_string();
+        lock_path.push(".lock");
+        tokio::task::spawn_blocking(move || flock(Path::new(&lock_path))).await??
+    };
+    Ok(Locked {
+        path: path.to_path_buf(),
+        _guard: guard,
+        #[cfg(unix)]
+        _file: file,
+    })
+}
+
+/// Opens `path` and waits for an exclusive advisory lock on it.
+#[cfg(unix)]
+fn flock(path: &Path) -> std::io::Result<std::fs::File> {
+    use std::os::unix::io::AsRawFd;
+    let file = std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(path)?;
+    loop {
+        // SAFETY: the descriptor belongs to `file`, which outlives the call.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+            return Ok(file);
+        }
+        let error = std::io::Error::last_os_error();
+        if error.kind() != std::io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+/// Syncs the directory holding `path`, so a rename into it survives a crash.
+async fn sync_parent(path: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        tokio::fs::File::open(parent).await?.sync_all().await?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// The previous generation of `path`, kept by [`write`].
+fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".bak");
+    PathBuf::from(name)
+}
+
+/// Saves `content` at `path` so that a crash or a full disk never leaves a half-written file
+/// behind: it goes to a temporary file that is synced and then renamed over `path`. The file
+/// it replaces is kept as `<path>.bak`. Writes to the same path take turns.
+pub async fn write(path: &Path, content: &[u8]) -> Result<()> {
+    lock(path).await?.write(content).await
+}
+
+async fn write_locked(path: &Path, content: &[u8]) -> Result<()> {
+    let mut temp = path.as_os_str().to_os_string();
+    temp.push(format!(".{}.partial", WRITE_COUNTER.fetch_add(1, Ordering::Relaxed)));
+    let temp = PathBuf::from(temp);
+
+    let written = async {
+        let mut file = tokio::fs::File::create(&temp).await?;
+        file.write_all(content).await?;
+        file.sync_all().await
+    }
+    .await;
+    if let Err(e) = written {
+        let _ = tokio::fs::remove_file(&temp).await;
+        return Err(e.into());
+    }
+
+    if tokio::fs::try_exists(path).await? {
+        tokio::fs::rename(path, backup_path(path)).await?;
+    }
+    tokio::fs::rename(&temp, path).await?;
+    sync_parent(path).await?;
+    Ok(())
+}
+
+/// Appends `content` to `path`, creating it if needed, and syncs it. An append cut short by a
+/// crash leaves a partial last line; the new content then starts on a line of its own, so
+/// only that line is lost.
+pub async fn append(path: &Path, content: &[u8]) -> Result<()> {
+    let _lock = lock(path).await?;
+    let mut file = tokio::fs::OpenOptions::new().create(true).read(true).append(true).open(path).await?;
+    let length = file.metadata().await?.len();
+    let mut last = [b'\n'];
+    if length > 0 {
+        file.seek(SeekFrom::Start(length - 1)).await?;
+        file.read_exact(&mut last).await?;
+    }
+    if last[0] != b'\n' {
+        tracing::warn!("{} ends with a partial line, e.g. after a crash; starting a new line", path.display());
+        file.write_all(b"\n").await?;
+    }
+    file.write_all(content).await?;
+    file.sync_data().await?;
+    if length == 0 {
+        sync_parent(path).await?;
+    }
+    Ok(())
+}
+
+/// Loads the JSON [`write`] saved at `path`, or `None` if it was never saved. A file that
+/// doesn't parse is renamed to `<path>.corrupt-<timestamp>` for inspection and the backup is
+/// tried instead; when neither is usable the caller starts empty. Either way it is logged,
+/// since state was lost.
+pub async fn read_json<T: DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    let backup = backup_path(path);
+    let mut corrupt = false;
+    for candidate in [path, &backup] {
+        let content = match tokio::fs::read(candidate).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        match serde_json::from_slice(&content) {
+            Ok(value) => {
+                if candidate == backup {
+                    tracing::warn!("Restored {} from its backup {}", path.display(), backup.display());
+                }
+                return Ok(Some(value));
+            }
+            Err(e) => {
+                let mut kept = candidate.as_os_str().to_os_string();
+                kept.push(format!(".corrupt-{}", Utc::now().format("%Y%m%dT%H%M%S")));
+                let kept = PathBuf::from(kept);
+                tokio::fs::rename(candidate, &kept).await?;
+                corrupt = true;
+                tracing::error!("{} is corrupt ({}); moved it to {}", candidate.display(), e, kept.display());
+            }
+        }
+    }
+    if corrupt {
+        tracing::error!("No usable copy of {} is left; starting empty", path.display());
+    }
+    Ok(None)
+}
+
+/// Loads the JSON at `path` like [`read_json`] (the default if there is none), lets `update`
+/// change it and saves it like [`write`]. The file stays locked in between, so updates from
+/// this process and others don't undo each other.
+pub async fn update_json<T, R>(path: &Path, update: impl FnOnce(&mut T) -> R) -> Result<R>
+where
+    T: DeserializeOwned + Serialize + Default,
+{
+    let locked = lock(path).await?;
+    let mut value = read_json(path).await?.unwrap_or_default();
+    let result = update(&mut value);
+    locked.write(&serde_json::to_vec_pretty(&value)?).await?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn files_in(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().to_string()).collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn keeps_the_previous_generation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        write(&path, b"[1]").await.unwrap();
+        write(&path, b"[2]").await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"[2]");
+        assert_eq!(std::fs::read(backup_path(&path)).unwrap(), b"[1]");
+        assert_eq!(files_in(dir.path()), ["state.json", "state.json.bak", "state.json.lock"]);
+    }
+
+    #[tokio::test]
+    async fn a_truncated_file_falls_back_to_the_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        write(&path, br#"{"jobs": [1, 2]}"#).await.unwrap();
+        write(&path, br#"{"jobs": [1, 2, 3]}"#).await.unwrap();
+        // A crash mid-write without the temporary file, as with a plain write.
+        std::fs::write(&path, br#"{"jobs": [1, "#).unwrap();
+
+        let restored: Option<Value> = read_json(&path).await.unwrap();
+        assert_eq!(restored, Some(json!({ "jobs": [1, 2] })));
+        let names = files_in(dir.path());
+        assert_eq!(names[0], "state.json.bak");
+        assert!(names[1].starts_with("state.json.corrupt-"), "{:?}", names);
+        assert_eq!(std::fs::read(dir.path().join(&names[1])).unwrap(), br#"{"jobs": [1, "#);
+    }
+
+    #[tokio::test]
+    async fn corrupt_copies_are_kept_and_the_state_starts_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        std::fs::write(&path, b"\0\0\0\0").unwrap();
+        std::fs::write(backup_path(&path), b"").unwrap();
+
+        let restored: Option<Value> = read_json(&path).await.unwrap();
+        assert_eq!(restored, None);
+        let names = files_in(dir.path());
+        assert!(names.iter().all(|name| name.contains(".corrupt-")), "{:?}", names);
+        assert_eq!(names.len(), 2);
+        assert_eq!(read_json::<Value>(&path).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn overlapping_writes_take_turns() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = Arc::new(dir.path().join("state.json"));
+        let writers: Vec<_> = (0..32)
+            .map(|i| {
+                let path = path.clone();
+                tokio::spawn(async move { write(&path, json!({ "writer": i }).to_string().as_bytes()).await })
+            })
+            .collect();
+        for writer in writers {
+            writer.await.unwrap().unwrap();
+        }
+        let current: Value = serde_json::from_slice(&std::fs::read(&*path).unwrap()).unwrap();
+        let previous: Value = serde_json::from_slice(&std::fs::read(backup_path(&path)).unwrap()).unwrap();
+        assert_ne!(current, previous);
+        assert_eq!(files_in(dir.path()), ["state.json", "state.json.bak", "state.json.lock"]);
+    }
+
+    #[tokio::test]
+    async fn an_append_after_a_partial_line_starts_a_new_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.jsonl");
+        append(&path, b"{\"n\": 1}\n").await.unwrap();
+        // A crash partway through the second append.
+        tokio::fs::OpenOptions::new().append(true).open(&path).await.unwrap().write_all(b"{\"n\": 2, \"ti").await.unwrap();
+        append(&path, b"{\"n\": 3}\n").await.unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let parsed: Vec<Value> = content.lines().filter_map(|line| serde_json::from_str(line).ok()).collect();
+        assert_eq!(parsed, [json!({ "n": 1 }), json!({ "n": 3 })]);
+        assert_eq!(content.lines().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn overlapping_updates_all_count() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = Arc::new(dir.path().join("counter.json"));
+        let updaters: Vec<_> = (0..32)
+            .map(|_| {
+                let path = path.clone();
+                tokio::spawn(async move { update_json(&path, |count: &mut u64| *count += 1).await })
+            })
+            .collect();
+        for updater in updaters {
+            updater.await.unwrap().unwrap();
+        }
+        assert_eq!(read_json::<u64>(&path).await.unwrap(), Some(32));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn other_processes_see_the_lock() {
+        use std::os::unix::io::AsRawFd;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("quota.json");
+        let locked = lock(&path).await.unwrap();
+        // A separate open of the lock file stands in for another process.
+        let other = std::fs::File::open(dir.path().join("quota.json.lock")).unwrap();
+        let try_lock = || unsafe { libc::flock(other.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        assert_eq!(try_lock(), -1);
+        drop(locked);
+        assert_eq!(try_lock(), 0);
+    }
+}
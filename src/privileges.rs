@@ -0,0 +1,103 @@
+//! Running yt-dlp as an unprivileged user (`run_as_uid` / `run_as_gid`). Unix only.
+
+use tokio::process::Command;
+
+/// Everything that keeps the server from running yt-dlp as `uid` and `gid`: they must exist,
+/// and switching to them needs root unless the server already runs as them.
+#[cfg(unix)]
+pub fn problems(uid: Option<u32>, gid: Option<u32>) -> Vec<String> {
+    let mut problems = Vec::new();
+    if uid.is_none() && gid.is_none() {
+        return problems;
+    }
+    if let Some(uid) = uid {
+        if primary_gid(uid).is_none() {
+            problems.push(format!("run_as_uid {} is not a user on this system", uid));
+        }
+    }
+    if let Some(gid) = gid {
+        if !group_exists(gid) {
+            problems.push(format!("run_as_gid {} is not a group on this system", gid));
+        }
+    }
+    // SAFETY: geteuid and getegid only read the process's credentials.
+    let (euid, egid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    let switches = uid.is_some_and(|uid| uid != euid) || resolved_gid(uid, gid).is_some_and(|gid| gid != egid);
+    if euid != 0 && switches {
+        problems.push("run_as_uid and run_as_gid need the server to start as root".to_string());
+    }
+    problems
+}
+
+#[cfg(not(unix))]
+pub fn problems(uid: Option<u32>, gid: Option<u32>) -> Vec<String> {
+    if uid.is_none() && gid.is_none() {
+        return Vec::new();
+    }
+    vec!["run_as_uid and run_as_gid are only supported on Unix".to_string()]
+}
+
+/// The group yt-dlp runs as: `run_as_gid`, or else the primary group of `run_as_uid`, so a
+/// root server doesn't leave it in the root group.
+#[cfg(unix)]
+fn resolved_gid(uid: Option<u32>, gid: Option<u32>) -> Option<u32> {
+    gid.or_else(|| uid.and_then(primary_gid))
+}
+
+/// Makes `cmd` switch to `uid` and `gid` in the child before yt-dlp starts. The switch drops
+/// the supplementary groups too.
+pub fn apply(cmd: &mut Command, uid: Option<u32>, gid: Option<u32>) {
+    #[cfg(unix)]
+    {
+        if let Some(gid) = resolved_gid(uid, gid) {
+            cmd.gid(gid);
+        }
+        if let Some(uid) = uid {
+            cmd.uid(uid);
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (cmd, uid, gid);
+    }
+}
+
+/// Gives a file the server created for yt-dlp, such as a session's cookie jar, to `uid` and
+/// `gid`, so yt-dlp can still read and update it after the switch.
+pub fn hand_over(path: &std::path::Path, uid: Option<u32>, gid: Option<u32>) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        if uid.is_some() || gid.is_some() {
+            std::os::unix::fs::chown(path, uid, resolved_gid(uid, gid))?;
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (path, uid, gid);
+    }
+    Ok(())
+}
+
+/// The primary group of a user, or `None` if there is no such user.
+#[cfg(unix)]
+fn primary_gid(uid: u32) -> Option<u32> {
+    // SAFETY: `passwd` is plain data, and all-zero is a valid value for it.
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+    let mut result = std::ptr::null_mut();
+    // SAFETY: every pointer refers to a live local, and `buffer.len()` is its real size.
+    let status = unsafe { libc::getpwuid_r(uid, &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+    (status == 0 && !result.is_null()).then_some(passwd.pw_gid)
+}
+
+#[cfg(unix)]
+fn group_exists(gid: u32) -> bool {
+    // SAFETY: `group` is plain data, and all-zero is a valid value for it.
+    let mut group: libc::group = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 64 * 1024];
+    let mut result = std::ptr::null_mut();
+    // SAFETY: every pointer refers to a live local, and `buffer.len()` is its real size.
+    let status = unsafe { libc::getgrgid_r(gid, &mut group, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+    // A group with more members than fit in the buffer still exists.
+    status == libc::ERANGE || (status == 0 && !result.is_null())
+}
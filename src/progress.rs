@@ -0,0 +1,293 @@
+use crate::debug_bundle::tool_version;
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Deserialize;
+use tokio::process::Command;
+use tokio::sync::OnceCell;
+
+/// Marks the progress lines produced by our `--progress-template`.
+const TEMPLATE_PREFIX: &str = "[yt-agent-progress] ";
+
+/// A compact, machine-readable progress line: the prefix followed by a JSON object.
+const PROGRESS_TEMPLATE: &str =
+    "download:[yt-agent-progress] %(progress.{status,downloaded_bytes,total_bytes,total_bytes_estimate,speed,eta})j";
+
+/// The first yt-dlp release with `--progress-template`.
+const MIN_TEMPLATE_VERSION: (u32, u32, u32) = (2021, 10, 9);
+
+/// The human-readable progress line printed by yt-dlp without a template.
+static LEGACY_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"\[download\]\s+(?P<progress>[\d\.]+)%\s+of\s+~?\s*(?P<size>[\d\.\w/]+)(?:\s+at\s+(?P<speed>[\d\.\w/]+))?\s+ETA\s+(?P<eta>[\d:]+)").unwrap()
+});
+
+/// yt-dlp's announcement of the next playlist item, e.g. `[download] Downloading item 3 of 50`.
+static ITEM_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^\[download\] Downloading (?:item|video) (?P<position>\d+) of (?P<total>\d+)").unwrap());
+
+/// Lines printed by yt-dlp's post-processors, e.g. `[Merger] Merging formats into ...`.
+static POSTPROCESSOR_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^\[(?:Merger|ExtractAudio|VideoRemuxer|VideoConvertor|Fixup\w*|SponsorBlock|ModifyChapters|Embed\w*|Metadata|\w+Convertor)\] ").unwrap()
+});
+
+static SIZE_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"^(?P<value>[\d\.]+)(?P<unit>[KMGT]?i?B)").unwrap());
+
+/// Whether the installed yt-dlp supports `--progress-template`; detected once per process.
+static TEMPLATE_SUPPORTED: OnceCell<bool> = OnceCell::const_new();
+
+/// How progress is read from a yt-dlp process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressMode {
+    /// Our JSON `--progress-template` lines.
+    Template,
+    /// The human-readable `[download]  42.0% of ...` lines.
+    Legacy,
+}
+
+/// A single progress tick, independent of the output format it was parsed from.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProgressUpdate {
+    pub percent: f64,
+    pub downloaded_bytes: Option<u64>,
+    pub total_bytes: Option<u64>,
+    pub speed_bytes_per_sec: Option<f64>,
+    pub eta_secs: Option<u64>,
+    /// Display form of the speed, e.g. "1.50MiB/s".
+    pub speed: String,
+    /// Display form of the ETA, e.g. "01:23".
+    pub eta: String,
+}
+
+/// The JSON emitted by `PROGRESS_TEMPLATE`. yt-dlp leaves unknown values as null.
+#[derive(Deserialize)]
+struct TemplateProgress {
+    downloaded_bytes: Option<f64>,
+    total_bytes: Option<f64>,
+    total_bytes_estimate: Option<f64>,
+    speed: Option<f64>,
+    eta: Option<f64>,
+}
+
+/// Picks the progress mode for the installed yt-dlp, checking its version on first use.
+pub async fn detect_mode() -> ProgressMode {
+    let supported = *TEMPLATE_SUPPORTED
+        .get_or_init(|| async {
+            let version = tool_version("yt-dlp", "--version").await;
+            let supported = version.as_deref().and_then(parse_version).is_some_and(|v| v >= MIN_TEMPLATE_VERSION);
+            if !supported {
+                tracing::warn!("yt-dlp {:?} lacks --progress-template; using legacy progress parsing", version);
+            }
+            supported
+        })
+        .await;
+    if supported { ProgressMode::Template } else { ProgressMode::Legacy }
+}
+
+/// Adds the progress-related arguments for `mode` to a yt-dlp command.
+pub fn configure(cmd: &mut Command, mode: ProgressMode) {
+    cmd.arg("--newline");
+    if mode == ProgressMode::Template {
+        cmd.arg("--progress-template").arg(PROGRESS_TEMPLATE);
+    }
+}
+
+/// Parses a line of yt-dlp output into a progress update, if it is one.
+/// Template lines are always recognised; legacy lines are the fallback.
+pub fn parse_line(line: &str) -> Option<ProgressUpdate> {
+    if let Some(json) = line.strip_prefix(TEMPLATE_PREFIX) {
+        return parse_template(json);
+    }
+    parse_legacy(line)
+}
+
+/// Whether a line of yt-dlp output comes from a post-processor such as ffmpeg merging or
+/// audio extraction, which runs after the download itself.
+pub fn is_postprocessing(line: &str) -> bool {
+    POSTPROCESSOR_REGEX.is_match(line)
+}
+
+/// When the transfer described by `update` should finish: now plus yt-dlp's ETA, or, when it
+/// has none, the remaining bytes at the current speed.
+pub fn estimated_completion(update: &ProgressUpdate) -> Option<DateTime<Utc>> {
+    let secs = match update.eta_secs {
+        Some(eta) => eta as f64,
+        None => {
+            let remaining = update.total_bytes?.saturating_sub(update.downloaded_bytes?);
+            let speed = update.speed_bytes_per_sec.filter(|speed| *speed > 0.0)?;
+            remaining as f64 / speed
+        }
+    };
+    Some(Utc::now() + chrono::Duration::milliseconds((secs * 1000.0) as i64))
+}
+
+/// Parses a playlist item announcement into the item's 1-based position among the selected
+/// items and their count. A position of 0 isn't an item.
+pub fn parse_item(line: &str) -> Option<(u64, u64)> {
+    let caps = ITEM_REGEX.captures(line)?;
+    let position = caps["position"].parse().ok().filter(|&position| position > 0)?;
+    Some((position, caps["total"].parse().ok()?))
+}
+
+fn parse_template(json: &str) -> Option<ProgressUpdate> {
+    let progress: TemplateProgress = serde_json::from_str(json).ok()?;
+    let downloaded = progress.downloaded_bytes.map(|b| b as u64);
+    let total = progress.total_bytes.or(progress.total_bytes_estimate).map(|b| b as u64);
+    let percent = match (downloaded, total) {
+        (Some(done), Some(total)) if total > 0 => (done as f64 / total as f64 * 100.0).min(100.0),
+        _ => 0.0,
+    };
+    let eta_secs = progress.eta.map(|e| e as u64);
+    Some(ProgressUpdate {
+        percent,
+        downloaded_bytes: downloaded,
+        total_bytes: total,
+        speed_bytes_per_sec: progress.speed,
+        eta_secs,
+        speed: progress.speed.map(format_speed).unwrap_or_default(),
+        eta: eta_secs.map(format_eta).unwrap_or_default(),
+    })
+}
+
+fn parse_legacy(line: &str) -> Option<ProgressUpdate> {
+    let caps = LEGACY_REGEX.captures(line)?;
+    let percent: f64 = caps.name("progress").and_then(|m| m.as_str().parse().ok()).unwrap_or(0.0);
+    let total = caps.name("size").and_then(|m| parse_size(m.as_str()));
+    let speed = caps.name("speed").map_or_else(String::new, |m| m.as_str().to_string());
+    let eta = caps.name("eta").map_or_else(String::new, |m| m.as_str().to_string());
+    Some(ProgressUpdate {
+        percent,
+        downloaded_bytes: total.map(|t| (t as f64 * percent / 100.0) as u64),
+        total_bytes: total,
+        speed_bytes_per_sec: speed.strip_suffix("/s").and_then(parse_size).map(|b| b as f64),
+        eta_secs: parse_eta(&eta),
+        speed,
+        eta,
+    })
+}
+
+/// Parses a yt-dlp version such as "2024.08.06" (or "2024.08.06.232") into a comparable tuple.
+fn parse_version(version: &str) -> Option<(u32, u32, u32)> {
+    let mut parts = version.trim().split('.').map(|p| p.parse::<u32>().ok());
+    Some((parts.next()??, parts.next()??, parts.next()??))
+}
+
+/// Parses a size like "12.34MiB" or "800KiB" into bytes.
+fn parse_size(text: &str) -> Option<u64> {
+    let caps = SIZE_REGEX.captures(text)?;
+    let value: f64 = caps["value"].parse().ok()?;
+    let multiplier = match &caps["unit"] {
+        "B" => 1.0,
+        "KiB" => 1024.0,
+        "MiB" => 1024.0 * 1024.0,
+        "GiB" => 1024.0 * 1024.0 * 1024.0,
+        "TiB" => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+        "KB" => 1e3,
+        "MB" => 1e6,
+        "GB" => 1e9,
+        "TB" => 1e12,
+        _ => return None,
+    };
+    Some((value * multiplier) as u64)
+}
+
+/// Parses an ETA like "01:23" or "1:02:03" into seconds.
+fn parse_eta(eta: &str) -> Option<u64> {
+    if eta.is_empty() {
+        return None;
+    }
+    eta.split(':').try_fold(0u64, |acc, part| Some(acc * 60 + part.parse::<u64>().ok()?))
+}
+
+fn format_speed(bytes_per_sec: f64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes_per_sec;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2}{}/s", value, UNITS[unit])
+}
+
+fn format_eta(secs: u64) -> String {
+    let (hours, minutes, seconds) = (secs / 3600, (secs % 3600) / 60, secs % 60);
+    if hours > 0 {
+        format!("{:02}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{:02}:{:02}", minutes, seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What a download of one video with merged formats prints with `PROGRESS_TEMPLATE`.
+    const TEMPLATE_TRANSCRIPT: &str = r#"[youtube] Extracting URL: https://www.youtube.com/watch?v=abc
+[youtube] abc: Downloading webpage
+[info] abc: Downloading 1 format(s): 137+140
+[download] Destination: Video [abc].f137.mp4
+[yt-agent-progress] {"status": "downloading", "downloaded_bytes": 1024, "total_bytes": null, "total_bytes_estimate": 4096, "speed": null, "eta": null}
+[yt-agent-progress] {"status": "downloading", "downloaded_bytes": 2048, "total_bytes": 4096, "total_bytes_estimate": null, "speed": 1536.0, "eta": 2}
+[yt-agent-progress] {"status": "finished", "downloaded_bytes": 4096, "total_bytes": 4096, "total_bytes_estimate": null, "speed": null, "eta": null}
+[download] Destination: Video [abc].f140.m4a
+[yt-agent-progress] {"status": "downloading", "downloaded_bytes": 0, "total_bytes": 0, "total_bytes_estimate": null, "speed": null, "eta": null}
+[yt-agent-progress] {"status": "downloading", "downloaded_bytes": 512, "total_bytes": 1000, "total_bytes_estimate": null, "speed": 4000000.0, "eta": 3725}
+[Merger] Merging formats into "Video [abc].mp4"
+Deleting original file Video [abc].f137.mp4 (pass -k to keep)"#;
+
+    /// The same download from a yt-dlp too old for `--progress-template`.
+    const LEGACY_TRANSCRIPT: &str = r#"[youtube] abc: Downloading webpage
+[download] Downloading item 2 of 5
+[download] Destination: Video [abc].f137.mp4
+[download]   0.0% of   10.00MiB at  Unknown B/s ETA Unknown
+[download]  10.0% of   10.00MiB at    1.00MiB/s ETA 00:09
+[download]  50.0% of ~  10.00MiB at    2.00MiB/s ETA 00:02
+[download]  75.5% of 800.00KiB ETA 1:02:03
+[download] 100% of   10.00MiB in 00:00:05 at 2.00MiB/s
+[Merger] Merging formats into "Video [abc].mp4""#;
+
+    /// Percent, downloaded and total bytes, speed, ETA, and the speed and ETA as displayed.
+    type Replayed = (f64, Option<u64>, Option<u64>, Option<f64>, Option<u64>, String, String);
+
+    fn replay(transcript: &str) -> Vec<Replayed> {
+        transcript
+            .lines()
+            .filter_map(parse_line)
+            .map(|u| (u.percent, u.downloaded_bytes, u.total_bytes, u.speed_bytes_per_sec, u.eta_secs, u.speed, u.eta))
+            .collect()
+    }
+
+    #[test]
+    fn replays_a_template_transcript() {
+        assert_eq!(replay(TEMPLATE_TRANSCRIPT), [
+            (25.0, Some(1024), Some(4096), None, None, String::new(), String::new()),
+            (50.0, Some(2048), Some(4096), Some(1536.0), Some(2), "1.50KiB/s".to_string(), "00:02".to_string()),
+            (100.0, Some(4096), Some(4096), None, None, String::new(), String::new()),
+            (0.0, Some(0), Some(0), None, None, String::new(), String::new()),
+            (51.2, Some(512), Some(1000), Some(4000000.0), Some(3725), "3.81MiB/s".to_string(), "01:02:05".to_string()),
+        ]);
+        let postprocessing: Vec<&str> = TEMPLATE_TRANSCRIPT.lines().filter(|line| is_postprocessing(line)).collect();
+        assert_eq!(postprocessing, [r#"[Merger] Merging formats into "Video [abc].mp4""#]);
+    }
+
+    #[test]
+    fn replays_a_legacy_transcript() {
+        const MIB: u64 = 1024 * 1024;
+        assert_eq!(replay(LEGACY_TRANSCRIPT), [
+            (10.0, Some(MIB), Some(10 * MIB), Some(MIB as f64), Some(9), "1.00MiB/s".to_string(), "00:09".to_string()),
+            (50.0, Some(5 * MIB), Some(10 * MIB), Some(2.0 * MIB as f64), Some(2), "2.00MiB/s".to_string(), "00:02".to_string()),
+            (75.5, Some(618_496), Some(819_200), None, Some(3723), String::new(), "1:02:03".to_string()),
+        ]);
+        let items: Vec<_> = LEGACY_TRANSCRIPT.lines().filter_map(parse_item).collect();
+        assert_eq!(items, [(2, 5)]);
+        assert_eq!(parse_item("[download] Downloading item 0 of 5"), None);
+        assert_eq!(parse_item("[download] Downloading item 99999999999999999999 of 5"), None);
+    }
+
+    #[test]
+    fn a_template_line_that_is_not_json_is_no_progress() {
+        assert_eq!(parse_line("[yt-agent-progress] NA"), None);
+        assert_eq!(parse_line(r#"[yt-agent-progress] {"downloaded_bytes": "#), None);
+    }
+}
@@ -0,0 +1,328 @@
+use crate::{config, error::AppError, persist, AppState};
+use anyhow::Result;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often the usage counted while downloads run is written to disk, so a crash loses at
+/// most this much of it.
+const PERSIST_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Cumulative bytes downloaded since the last reset. Persisted across restarts.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct QuotaUsage {
+    pub used_bytes: u64,
+    /// When counting started (the last reset).
+    pub since: DateTime<Utc>,
+}
+
+impl Default for QuotaUsage {
+    fn default() -> Self {
+        QuotaUsage { used_bytes: 0, since: Utc::now() }
+    }
+}
+
+/// The quota as reported by `/health` and `POST /admin/quota/reset`.
+#[derive(Serialize, Debug)]
+pub struct QuotaReport {
+    pub limit_bytes: Option<u64>,
+    pub used_bytes: u64,
+    pub remaining_bytes: Option<u64>,
+    pub since: DateTime<Utc>,
+    pub exceeded: bool,
+}
+
+/// Tracks bytes downloaded against `total_bytes_quota`.
+#[derive(Clone, Default)]
+pub struct QuotaTracker {
+    usage: Arc<Mutex<QuotaUsage>>,
+    /// Bytes counted here but not yet added to the persisted usage.
+    unsaved: Arc<AtomicU64>,
+    /// When `persist_if_due` last wrote the usage.
+    persisted_at: Arc<Mutex<Option<Instant>>>,
+}
+
+impl QuotaTracker {
+    /// Restores the persisted usage. Called once at startup.
+    pub async fn load(&self) -> Result<()> {
+        if let Some(usage) = persist::read_json(&quota_path()?).await? {
+            *self.usage.lock().unwrap() = usage;
+        }
+        Ok(())
+    }
+
+    /// Adds the bytes counted since the last call to the persisted usage, which a CLI download
+    /// running alongside the server counts into as well, and takes up the sum.
+    pub async fn persist(&self) -> Result<()> {
+        let unsaved = self.unsaved.swap(0, Ordering::SeqCst);
+        let stored = persist::update_json(&quota_path()?, |stored: &mut QuotaUsage| {
+            stored.used_bytes = stored.used_bytes.saturating_add(unsaved);
+            stored.clone()
+        })
+        .await;
+        match stored {
+            Ok(stored) => {
+                let mut usage = self.usage.lock().unwrap();
+                *usage = QuotaUsage { used_bytes: stored.used_bytes.saturating_add(self.unsaved.load(Ordering::SeqCst)), ..stored };
+                Ok(())
+            }
+            Err(e) => {
+                self.unsaved.fetch_add(unsaved, Ordering::SeqCst);
+                Err(e)
+            }
+        }
+    }
+
+    /// Persists the usage counted so far, unless it was written within `PERSIST_INTERVAL`.
+    /// Called as a download counts its bytes.
+    pub async fn persist_if_due(&self) -> Result<()> {
+        {
+            let mut persisted_at = self.persisted_at.lock().unwrap();
+            if persisted_at.is_some_and(|at| at.elapsed() < PERSIST_INTERVAL) {
+                return Ok(());
+            }
+            *persisted_at = Some(Instant::now());
+        }
+        self.persist().await
+    }
+
+    pub fn add(&self, bytes: u64) {
+        let mut usage = self.usage.lock().unwrap();
+        usage.used_bytes = usage.used_bytes.saturating_add(bytes);
+        self.unsaved.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    pub fn usage(&self) -> QuotaUsage {
+        self.usage.lock().unwrap().clone()
+    }
+
+    /// Starts counting from zero again.
+    pub async fn reset(&self) -> Result<()> {
+        let file = persist::lock(&quota_path()?).await?;
+        let usage = QuotaUsage::default();
+        *self.usage.lock().unwrap() = usage.clone();
+        self.unsaved.store(0, Ordering::SeqCst);
+        file.write(&serde_json::to_vec_pretty(&usage)?).await
+    }
+}
+
+/// Reports the current usage against the configured quota.
+pub fn report(state: &AppState) -> QuotaReport {
+    let limit = state.config.read().unwrap().total_bytes_quota;
+    let usage = state.quota.usage();
+    QuotaReport {
+        limit_bytes: limit,
+        used_bytes: usage.used_bytes,
+        remaining_bytes: limit.map(|l| l.saturating_sub(usage.used_bytes)),
+        since: usage.since,
+        exceeded: limit.is_some_and(|l| usage.used_bytes >= l),
+    }
+}
+
+/// Returns the path of the persisted usage.
+fn quota_path() -> Result<PathBuf> {
+    Ok(config::data_dir()?.join("quota.json"))
+}
+
+/// What one API key used on one day.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct KeyUsage {
+    /// Downloads started, including those still running.
+    pub downloads: u64,
+    /// Bytes downloaded by finished downloads.
+    pub bytes: u64,
+}
+
+/// The usage of every API key on `day` (UTC), as persisted.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+struct DailyUsage {
+    day: NaiveDate,
+    keys: BTreeMap<String, KeyUsage>,
+}
+
+impl Default for DailyUsage {
+    fn default() -> Self {
+        DailyUsage { day: Utc::now().date_naive(), keys: BTreeMap::new() }
+    }
+}
+
+/// One API key's quota for today, as `GET /quota` and `GET /admin/quota` report it and a
+/// refused download carries it.
+#[derive(Clone, Serialize, Debug, PartialEq)]
+pub struct KeyQuota {
+    pub key: String,
+    pub downloads: u64,
+    pub bytes: u64,
+    pub max_downloads_per_day: Option<u64>,
+    pub max_bytes_per_day: Option<u64>,
+    pub remaining_downloads: Option<u64>,
+    pub remaining_bytes: Option<u64>,
+    /// When the counters start over: the next midnight, UTC.
+    pub resets_at: DateTime<Utc>,
+}
+
+impl KeyQuota {
+    pub fn exceeded(&self) -> bool {
+        self.remaining_downloads == Some(0) || self.remaining_bytes == Some(0)
+    }
+}
+
+/// Counts downloads and bytes per API key against `max_downloads_per_day` and
+/// `max_bytes_per_day`. The counters start over at midnight UTC and survive restarts.
+#[derive(Clone, Default)]
+pub struct KeyQuotas {
+    usage: Arc<Mutex<DailyUsage>>,
+}
+
+impl KeyQuotas {
+    /// Restores the persisted counters. Called once at startup.
+    pub async fn load(&self) -> Result<()> {
+        if let Some(usage) = persist::read_json(&key_quotas_path()?).await? {
+            *self.usage.lock().unwrap() = usage;
+        }
+        Ok(())
+    }
+
+    pub async fn persist(&self) -> Result<()> {
+        let snapshot = serde_json::to_vec_pretty(&*self.usage.lock().unwrap())?;
+        persist::write(&key_quotas_path()?, &snapshot).await
+    }
+
+    /// Counts a download for `key`, or refuses it when the key used up either limit today. A
+    /// download counted here always runs to the end, even if its bytes go over the limit.
+    pub fn reserve(&self, state: &AppState, key: &str) -> Result<(), AppError> {
+        let mut usage = self.today();
+        let quota = report_key(state, key, usage.keys.get(key).copied().unwrap_or_default());
+        if quota.exceeded() {
+            return Err(AppError::KeyQuotaExceeded(quota));
+        }
+        usage.keys.entry(key.to_string()).or_default().downloads += 1;
+        Ok(())
+    }
+
+    /// Takes back a download counted by `reserve` that didn't start after all.
+    pub fn release(&self, key: &str) {
+        if let Some(usage) = self.today().keys.get_mut(key) {
+            usage.downloads = usage.downloads.saturating_sub(1);
+        }
+    }
+
+    /// Counts the bytes of a finished download for `key`.
+    pub fn add_bytes(&self, key: &str, bytes: u64) {
+        let mut usage = self.today();
+        let entry = usage.keys.entry(key.to_string()).or_default();
+        entry.bytes = entry.bytes.saturating_add(bytes);
+    }
+
+    pub fn usage(&self, key: &str) -> KeyUsage {
+        self.today().keys.get(key).copied().unwrap_or_default()
+    }
+
+    /// Locks the counters, first starting them over if they are from an earlier day.
+    fn today(&self) -> std::sync::MutexGuard<'_, DailyUsage> {
+        let mut usage = self.usage.lock().unwrap();
+        if usage.day != Utc::now().date_naive() {
+            *usage = DailyUsage::default();
+        }
+        usage
+    }
+}
+
+/// Reports `key`'s quota for today.
+pub fn key_report(state: &AppState, key: &str) -> KeyQuota {
+    report_key(state, key, state.key_quotas.usage(key))
+}
+
+/// Reports the quota of every configured API key, by name.
+pub fn key_reports(state: &AppState) -> Vec<KeyQuota> {
+    let keys: Vec<String> = state.config.read().unwrap().api_keys.keys().cloned().collect();
+    keys.iter().map(|key| key_report(state, key)).collect()
+}
+
+fn report_key(state: &AppState, key: &str, usage: KeyUsage) -> KeyQuota {
+    let (max_downloads, max_bytes) = {
+        let config = state.config.read().unwrap();
+        (config.max_downloads_per_day, config.max_bytes_per_day)
+    };
+    let tomorrow = Utc::now().date_naive().succ_opt().unwrap_or(NaiveDate::MAX);
+    KeyQuota {
+        key: key.to_string(),
+        downloads: usage.downloads,
+        bytes: usage.bytes,
+        max_downloads_per_day: max_downloads,
+        max_bytes_per_day: max_bytes,
+        remaining_downloads: max_downloads.map(|max| max.saturating_sub(usage.downloads)),
+        remaining_bytes: max_bytes.map(|max| max.saturating_sub(usage.bytes)),
+        resets_at: tomorrow.and_time(chrono::NaiveTime::MIN).and_utc(),
+    }
+}
+
+/// Returns the path of the persisted per-key counters.
+fn key_quotas_path() -> Result<PathBuf> {
+    Ok(config::data_dir()?.join("key_quotas.json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{lock_files, state, FakeRunner};
+
+    #[tokio::test]
+    async fn persists_the_usage_while_it_is_counted() {
+        let _files = lock_files().await;
+        let state = state(FakeRunner::new());
+        state.quota.reset().await.unwrap();
+        let persisted = || async {
+            let usage: Option<QuotaUsage> = persist::read_json(&quota_path().unwrap()).await.unwrap();
+            usage.unwrap().used_bytes
+        };
+
+        state.quota.add(100);
+        state.quota.persist_if_due().await.unwrap();
+        assert_eq!(persisted().await, 100);
+        // Not again right away; the download persists the rest when it ends.
+        state.quota.add(50);
+        state.quota.persist_if_due().await.unwrap();
+        assert_eq!(persisted().await, 100);
+        *state.quota.persisted_at.lock().unwrap() = Instant::now().checked_sub(PERSIST_INTERVAL);
+        state.quota.persist_if_due().await.unwrap();
+        assert_eq!(persisted().await, 150);
+        state.quota.reset().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn adds_up_with_the_usage_other_processes_persist() {
+        let _files = lock_files().await;
+        let state = state(FakeRunner::new());
+        state.quota.reset().await.unwrap();
+
+        state.quota.add(100);
+        // A CLI download counting into the same file meanwhile.
+        persist::update_json(&quota_path().unwrap(), |usage: &mut QuotaUsage| usage.used_bytes += 1000).await.unwrap();
+        state.quota.persist().await.unwrap();
+        assert_eq!(state.quota.usage().used_bytes, 1100);
+        state.quota.add(1);
+        state.quota.persist().await.unwrap();
+        let usage: Option<QuotaUsage> = persist::read_json(&quota_path().unwrap()).await.unwrap();
+        assert_eq!(usage.unwrap().used_bytes, 1101);
+        state.quota.reset().await.unwrap();
+    }
+
+    #[test]
+    fn key_counters_start_over_on_a_new_day() {
+        let state = state(FakeRunner::new());
+        state.config.write().unwrap().max_downloads_per_day = Some(1);
+        state.key_quotas.reserve(&state, "alice").unwrap_or_else(|e| panic!("{}", e));
+        state.key_quotas.add_bytes("alice", 100);
+        assert!(state.key_quotas.reserve(&state, "alice").is_err());
+
+        state.key_quotas.usage.lock().unwrap().day = Utc::now().date_naive().pred_opt().unwrap();
+        assert_eq!(state.key_quotas.usage("alice"), KeyUsage::default());
+        assert!(state.key_quotas.reserve(&state, "alice").is_ok());
+        assert_eq!(key_report(&state, "alice").resets_at, Utc::now().date_naive().succ_opt().unwrap().and_time(chrono::NaiveTime::MIN).and_utc());
+    }
+}
@@ -0,0 +1,71 @@
+use serde_json::Value;
+
+/// The placeholder that replaces any redacted value.
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Key fragments that mark a value as sensitive.
+const SECRET_KEY_MARKERS: &[&str] = &[
+    "api_key",
+    "apikey",
+    "password",
+    "passwd",
+    "secret",
+    "token",
+    "cookie",
+    "authorization",
+];
+
+/// yt-dlp options that take a secret but aren't named like one.
+const SECRET_OPTIONS: &[&str] = &["-p", "-2", "--twofactor", "--netrc-cmd"];
+
+/// yt-dlp options whose value is a `Name: value` header.
+const HEADER_OPTIONS: &[&str] = &["--add-header", "--add-headers"];
+
+/// Returns true if a field or header name looks like it holds a secret.
+pub fn is_secret_key(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    SECRET_KEY_MARKERS.iter().any(|marker| key.contains(marker))
+}
+
+/// Recursively replaces the values of secret-looking keys with `REDACTED`.
+/// Null values are left alone so "not configured" stays distinguishable from "set".
+pub fn redact_json(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, inner) in map.iter_mut() {
+                if is_secret_key(key) && !inner.is_null() {
+                    *inner = Value::String(REDACTED.to_string());
+                } else {
+                    redact_json(inner);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_json),
+        _ => {}
+    }
+}
+
+/// Replaces the values of secret options in yt-dlp config file text, e.g. `--password` or
+/// `--add-header "Authorization: ..."`. Expects one option per line, as such files usually
+/// have; other lines are kept as they are.
+pub fn redact_ytdlp_options(text: &str) -> String {
+    let redact_line = |line: &str| {
+        let trimmed = line.trim_start();
+        let indent = &line[..line.len() - trimmed.len()];
+        let Some((option, value)) = trimmed.split_once(|c: char| c.is_whitespace() || c == '=') else { return line.to_string() };
+        if !option.starts_with('-') || value.trim().is_empty() {
+            return line.to_string();
+        }
+        if is_secret_key(option) || SECRET_OPTIONS.contains(&option) {
+            return format!("{}{} {}", indent, option, REDACTED);
+        }
+        let header = value.trim().trim_matches(|c| c == '"' || c == '\'');
+        match header.split_once(':') {
+            Some((name, _)) if HEADER_OPTIONS.contains(&option) && is_secret_key(name) => {
+                format!("{}{} \"{}:{}\"", indent, option, name.trim(), REDACTED)
+            }
+            _ => line.to_string(),
+        }
+    };
+    text.lines().map(redact_line).collect::<Vec<_>>().join("\n")
+}
@@ -0,0 +1,166 @@
+use crate::{debug_bundle, outbound, storage, AppState};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How often the report task re-reads the config while reporting is switched off.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Failed deliveries double the wait, up to this many times the reporting interval.
+const MAX_BACKOFF_FACTOR: u32 = 8;
+
+/// How long a single delivery may take.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The header carrying `sha256=<hex HMAC of the body>` when `report_secret` is set.
+pub const SIGNATURE_HEADER: &str = "X-Yt-Agent-Signature";
+
+/// The snapshot sent to `report_to`. Counters only: no URLs, titles or file names.
+#[derive(Serialize, Debug)]
+pub struct UsageReport {
+    pub instance: Option<String>,
+    pub version: &'static str,
+    pub generated_at: DateTime<Utc>,
+    /// The number of downloads in each state, e.g. `{"completed": 12, "downloading": 1}`.
+    pub downloads: BTreeMap<String, usize>,
+    /// Bytes downloaded since the previous report.
+    pub downloaded_bytes: u64,
+    /// Free space in the primary download directory.
+    pub disk_free_bytes: Option<u64>,
+    pub yt_dlp_version: Option<String>,
+}
+
+/// The outcome of the latest deliveries, as shown in `/health`.
+#[derive(Clone, Serialize, Debug, Default)]
+pub struct ReportStatus {
+    pub last_attempt: Option<DateTime<Utc>>,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub consecutive_failures: u32,
+}
+
+/// Shared state of the usage-report task.
+#[derive(Clone, Default)]
+pub struct Reporter {
+    status: Arc<Mutex<ReportStatus>>,
+}
+
+impl Reporter {
+    pub fn status(&self) -> ReportStatus {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+/// Sends a usage report to `report_to` every `report_interval_minutes`, backing off after
+/// failures. Does nothing while `report_to` is unset. Runs until the server exits.
+pub async fn run(state: AppState) {
+    let mut bytes_at_last_report = state.quota.usage().used_bytes;
+    loop {
+        let (target, interval) = {
+            let config = state.config.read().unwrap();
+            (config.report_to.clone(), Duration::from_secs(config.report_interval_minutes.max(1).saturating_mul(60)))
+        };
+        let Some(target) = target else {
+            tokio::time::sleep(IDLE_CHECK_INTERVAL).await;
+            continue;
+        };
+        tokio::time::sleep(backoff(interval, state.reporter.status().consecutive_failures)).await;
+
+        // The quota counter restarts at zero when reset; count from there.
+        let used_bytes = state.quota.usage().used_bytes;
+        let downloaded_bytes = used_bytes.checked_sub(bytes_at_last_report).unwrap_or(used_bytes);
+        let result = send(&state, &target, downloaded_bytes).await;
+
+        let mut status = state.reporter.status.lock().unwrap();
+        status.last_attempt = Some(Utc::now());
+        match result {
+            Ok(()) => {
+                bytes_at_last_report = used_bytes;
+                status.last_success = status.last_attempt;
+                status.last_error = None;
+                status.consecutive_failures = 0;
+            }
+            Err(e) => {
+                tracing::warn!("Failed to send the usage report to {}: {:#}", target, e);
+                status.last_error = Some(format!("{:#}", e));
+                status.consecutive_failures = status.consecutive_failures.saturating_add(1);
+            }
+        }
+    }
+}
+
+/// The wait before the next delivery: the interval, doubled per consecutive failure.
+fn backoff(interval: Duration, failures: u32) -> Duration {
+    let factor = 2u32.saturating_pow(failures).min(MAX_BACKOFF_FACTOR);
+    interval.saturating_mul(factor)
+}
+
+async fn send(state: &AppState, target: &str, downloaded_bytes: u64) -> Result<()> {
+    let report = build(state, downloaded_bytes).await;
+    let secret = state.config.read().unwrap().report_secret.clone();
+    deliver(state, target, serde_json::to_vec(&report)?, secret).await
+}
+
+/// POSTs a JSON `body` to `target`, signed with `secret` when there is one. Shared by usage
+/// reports and webhooks, which each have their own secret.
+pub(crate) async fn deliver(state: &AppState, target: &str, body: Vec<u8>, secret: Option<String>) -> Result<()> {
+    let max_redirects = state.config.read().unwrap().max_redirects;
+
+    let mut request = outbound::client(target, max_redirects)?
+        .post(target)
+        .timeout(DELIVERY_TIMEOUT)
+        .header(reqwest::header::CONTENT_TYPE, "application/json");
+    if let Some(secret) = secret {
+        request = request.header(SIGNATURE_HEADER, format!("sha256={}", sign(secret.as_bytes(), &body)));
+    }
+    request.body(body).send().await?.error_for_status()?;
+    Ok(())
+}
+
+async fn build(state: &AppState, downloaded_bytes: u64) -> UsageReport {
+    let (instance, download_directory) = {
+        let config = state.config.read().unwrap();
+        (config.instance_name.clone(), config.download_directory.clone())
+    };
+    let mut downloads = BTreeMap::new();
+    for status in state.downloads.lock().values() {
+        *downloads.entry(status.status.clone()).or_insert(0) += 1;
+    }
+    UsageReport {
+        instance,
+        version: env!("CARGO_PKG_VERSION"),
+        generated_at: Utc::now(),
+        downloads,
+        downloaded_bytes,
+        disk_free_bytes: storage::probe(state, Path::new(&download_directory)).await.available_bytes,
+        yt_dlp_version: debug_bundle::tool_version("yt-dlp", "--version").await,
+    }
+}
+
+/// Returns the hex-encoded HMAC-SHA256 of `body`.
+fn sign(secret: &[u8], body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    #[test]
+    fn backs_off_without_overflowing() {
+        let interval = Duration::from_secs(15 * 60);
+        assert_eq!(super::backoff(interval, 0), interval);
+        assert_eq!(super::backoff(interval, 2), interval * 4);
+        assert_eq!(super::backoff(interval, 40), interval * super::MAX_BACKOFF_FACTOR);
+        assert_eq!(super::backoff(Duration::MAX, 3), Duration::MAX);
+        assert_eq!(super::backoff(Duration::from_secs(u64::MAX / 2), 1), Duration::from_secs(u64::MAX / 2) * 2);
+    }
+}
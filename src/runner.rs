@@ -0,0 +1,27 @@
+use tokio::process::Command;
+
+/// Creates the commands used to run external tools such as yt-dlp.
+/// Handlers go through this instead of `Command::new` so a fake binary can be swapped in.
+pub trait ProcessRunner: Send + Sync {
+    /// Returns a command for `program`, ready for arguments.
+    fn command(&self, program: &str) -> Command;
+}
+
+/// Runs the real programs found on PATH, or the managed yt-dlp release when one is active.
+/// yt-dlp loads `ytdlp_config_inline` first, when it is set, and runs as `run_as_uid`.
+pub struct SystemRunner;
+
+impl ProcessRunner for SystemRunner {
+    fn command(&self, program: &str) -> Command {
+        if program == "yt-dlp" {
+            let mut cmd = Command::new(crate::ytdlp::program());
+            let (uid, gid) = crate::ytdlp::run_as();
+            crate::privileges::apply(&mut cmd, uid, gid);
+            if let Some(path) = crate::ytdlp::inline_config() {
+                cmd.arg("--config-locations").arg(path);
+            }
+            return cmd;
+        }
+        Command::new(program)
+    }
+}
@@ -0,0 +1,336 @@
+use crate::{config, error::AppError, handlers, models::DownloadRequest, persist, storage, AppState};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// How long the scheduler waits before re-probing unavailable storage.
+/// Longer than the default probe cache TTL so each retry sees a fresh probe.
+const STORAGE_RETRY_INTERVAL: Duration = Duration::from_secs(15);
+
+/// The `queue_reason` given to a due download held back by a failed storage probe.
+const STORAGE_UNAVAILABLE: &str = "storage unavailable";
+
+/// A download held back until `scheduled_at`.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ScheduledDownload {
+    pub download_key: String,
+    pub scheduled_at: DateTime<Utc>,
+    pub request: DownloadRequest,
+}
+
+/// A scheduled download and where the scheduler task is with it.
+#[derive(Clone, Debug)]
+struct Pending {
+    job: ScheduledDownload,
+    /// Set while the task probes the job's storage before starting it. The job stays listed,
+    /// and can still be cancelled, until it is handed to `enqueue_download`.
+    taken: bool,
+    /// Set while the job's storage is unavailable: it is probed again after this, and the
+    /// jobs behind it are started meanwhile.
+    held_until: Option<Instant>,
+}
+
+impl Pending {
+    fn new(job: ScheduledDownload) -> Self {
+        Pending { job, taken: false, held_until: None }
+    }
+}
+
+/// Holds scheduled downloads sorted by start time and wakes the scheduler task on changes.
+#[derive(Clone, Default)]
+pub struct Scheduler {
+    jobs: Arc<Mutex<Vec<Pending>>>,
+    notify: Arc<Notify>,
+    /// Set while due downloads are held back because their storage is unavailable.
+    paused: Arc<AtomicBool>,
+}
+
+impl Scheduler {
+    /// Adds (or replaces) a scheduled download and persists the schedule.
+    pub async fn add(&self, job: ScheduledDownload) -> Result<()> {
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            jobs.retain(|p| p.job.download_key != job.download_key);
+            let position = jobs.partition_point(|p| p.job.scheduled_at <= job.scheduled_at);
+            jobs.insert(position, Pending::new(job));
+        }
+        self.notify.notify_one();
+        self.persist().await
+    }
+
+    /// Removes a scheduled download before it starts, including one whose storage is being
+    /// probed. Returns it if it was still pending.
+    pub async fn cancel(&self, download_key: &str) -> Result<Option<ScheduledDownload>> {
+        let removed = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let position = jobs.iter().position(|p| p.job.download_key == download_key);
+            position.map(|p| jobs.remove(p).job)
+        };
+        if removed.is_some() {
+            self.notify.notify_one();
+            self.persist().await?;
+        }
+        Ok(removed)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    pub fn contains(&self, download_key: &str) -> bool {
+        self.jobs.lock().unwrap().iter().any(|p| p.job.download_key == download_key)
+    }
+
+    /// Returns the pending downloads, soonest first.
+    pub fn list(&self) -> Vec<ScheduledDownload> {
+        self.jobs.lock().unwrap().iter().map(|p| p.job.clone()).collect()
+    }
+
+    /// Wakes the scheduler task, e.g. so it notices that shutdown began.
+    pub fn wake(&self) {
+        self.notify.notify_one();
+    }
+
+    /// Restores the persisted schedule. Called once at startup.
+    pub async fn load(&self) -> Result<()> {
+        let Some(mut jobs) = persist::read_json::<Vec<ScheduledDownload>>(&schedule_path()?).await? else {
+            return Ok(());
+        };
+        jobs.sort_by_key(|j| j.scheduled_at);
+        *self.jobs.lock().unwrap() = jobs.into_iter().map(Pending::new).collect();
+        Ok(())
+    }
+
+    async fn persist(&self) -> Result<()> {
+        let snapshot = serde_json::to_vec_pretty(&self.list())?;
+        persist::write(&schedule_path()?, &snapshot).await
+    }
+
+    /// Marks the first due job that isn't held back as taken and returns it. Otherwise returns
+    /// how long until a job is due or a held job is probed again, if there is one.
+    fn take_due(&self) -> Result<ScheduledDownload, Option<Duration>> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let (now, instant) = (Utc::now(), Instant::now());
+        let mut next: Option<Duration> = None;
+        for pending in jobs.iter_mut().filter(|p| !p.taken) {
+            let wait = match pending.held_until {
+                Some(until) if until > instant => until - instant,
+                _ => match (pending.job.scheduled_at - now).to_std() {
+                    Ok(wait) if !wait.is_zero() => wait,
+                    _ => {
+                        pending.taken = true;
+                        return Ok(pending.job.clone());
+                    }
+                },
+            };
+            next = Some(next.map_or(wait, |next| next.min(wait)));
+        }
+        Err(next)
+    }
+
+    /// Removes a taken job to start it. `None` if it was cancelled or replaced meanwhile.
+    fn start_taken(&self, download_key: &str) -> Option<ScheduledDownload> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let position = jobs.iter().position(|p| p.taken && p.job.download_key == download_key)?;
+        Some(jobs.remove(position).job)
+    }
+
+    /// Puts a taken job back, not to be tried again for `delay`. Returns whether it was still
+    /// there to hold.
+    fn hold_taken(&self, download_key: &str, delay: Duration) -> bool {
+        let mut jobs = self.jobs.lock().unwrap();
+        let Some(pending) = jobs.iter_mut().find(|p| p.taken && p.job.download_key == download_key) else {
+            return false;
+        };
+        pending.taken = false;
+        pending.held_until = Some(Instant::now() + delay);
+        true
+    }
+
+    fn any_held(&self) -> bool {
+        let instant = Instant::now();
+        self.jobs.lock().unwrap().iter().any(|p| p.held_until.is_some_and(|until| until > instant))
+    }
+}
+
+/// Probes the storage a scheduled job would write into.
+/// A root that is no longer allowed is left for `enqueue_download` to reject.
+async fn storage_error(state: &AppState, job: &ScheduledDownload) -> Option<AppError> {
+    let root = handlers::resolve_download_root(state, job.request.download_root.as_deref()).ok()?;
+    storage::probe(state, &root).await.to_error()
+}
+
+/// Returns the path of the persisted schedule.
+fn schedule_path() -> Result<PathBuf> {
+    Ok(config::data_dir()?.join("scheduled.json"))
+}
+
+/// The background task that starts scheduled downloads when their time arrives.
+pub async fn run(state: AppState) {
+    run_with_retry(state, STORAGE_RETRY_INTERVAL).await
+}
+
+/// Runs the scheduler, probing the storage of held jobs again every `storage_retry`.
+async fn run_with_retry(state: AppState, storage_retry: Duration) {
+    let scheduler = state.scheduler.clone();
+    loop {
+        // Leave pending jobs persisted for the next start once shutdown begins.
+        if state.draining.load(Ordering::SeqCst) {
+            return;
+        }
+        match scheduler.take_due() {
+            Ok(job) => {
+                // Hold a job back while its storage is unavailable, retrying until a probe
+                // succeeds. Jobs writing elsewhere start meanwhile.
+                if let Some(e) = storage_error(&state, &job).await {
+                    if !scheduler.hold_taken(&job.download_key, storage_retry) {
+                        continue;
+                    }
+                    if !scheduler.paused.swap(true, Ordering::SeqCst) {
+                        tracing::warn!("Pausing scheduled downloads: {}", e);
+                    }
+                    if let Some(status) = state.downloads.lock().get_mut(&job.download_key) {
+                        status.queue_reason = Some(STORAGE_UNAVAILABLE.to_string());
+                    }
+                    continue;
+                }
+                let Some(job) = scheduler.start_taken(&job.download_key) else {
+                    tracing::info!("Scheduled download {} was cancelled before it started", job.download_key);
+                    continue;
+                };
+                if !scheduler.any_held() && scheduler.paused.swap(false, Ordering::SeqCst) {
+                    tracing::info!("Storage is available again; resuming scheduled downloads");
+                }
+                if let Err(e) = scheduler.persist().await {
+                    tracing::error!("Failed to persist schedule: {:?}", e);
+                }
+                tracing::info!("Starting scheduled download {}", job.download_key);
+                if let Err(e) = handlers::download::enqueue_download(&state, job.request).await {
+                    handlers::download::update_status_to_failed(
+                        &state.downloads,
+                        &job.download_key,
+                        format!("Scheduled download could not start: {}", e),
+                    );
+                }
+            }
+            // Changes to the schedule and the start of shutdown wake the task early.
+            Err(Some(wait)) => {
+                tokio::select! {
+                    _ = tokio::time::sleep(wait) => {}
+                    _ = scheduler.notify.notified() => {}
+                }
+            }
+            Err(None) => scheduler.notify.notified().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run_with_retry, ScheduledDownload, STORAGE_UNAVAILABLE};
+    use crate::cache::CacheControl;
+    use crate::models::{DownloadRequest, DownloadStatus};
+    use crate::test_support::{fake_ytdlp, finished, lock_files, state, FakeRunner};
+    use chrono::Utc;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    fn job(key: &str, download_root: Option<&str>, seconds_ago: i64) -> ScheduledDownload {
+        ScheduledDownload {
+            download_key: key.to_string(),
+            scheduled_at: Utc::now() - chrono::Duration::seconds(seconds_ago),
+            request: DownloadRequest {
+                url: key.to_string(),
+                format_id: "best".to_string(),
+                download_root: download_root.map(str::to_string),
+                ..Default::default()
+            },
+        }
+    }
+
+    async fn eventually(what: &str, condition: impl Fn() -> bool) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !condition() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("timed out waiting until {}", what));
+    }
+
+    #[tokio::test]
+    async fn a_job_held_by_its_storage_doesnt_hold_up_the_others() {
+        let _files = lock_files().await;
+        let state = state(fake_ytdlp());
+        // A root under a regular file can't be created.
+        let blocker = tempfile::NamedTempFile::new().unwrap();
+        let broken_root = blocker.path().join("videos").to_string_lossy().to_string();
+        state.config.write().unwrap().allowed_download_roots = vec![broken_root.clone()];
+        let (held, behind) = ("https://example.com/held", "https://example.com/behind");
+        state.downloads.lock().insert(held.to_string(), DownloadStatus { status: "scheduled".to_string(), ..Default::default() });
+        state.scheduler.add(job(held, Some(&broken_root), 2)).await.unwrap();
+        state.scheduler.add(job(behind, None, 1)).await.unwrap();
+        let task = tokio::spawn(run_with_retry(state.clone(), Duration::from_millis(20)));
+
+        assert_eq!(finished(&state, behind).await.status, "completed");
+        assert!(state.scheduler.is_paused());
+        assert_eq!(state.downloads.lock()[held].queue_reason.as_deref(), Some(STORAGE_UNAVAILABLE));
+        let listed: Vec<String> = state.scheduler.list().into_iter().map(|job| job.download_key).collect();
+        assert_eq!(listed, [held]);
+
+        // Once the storage is back, the held job starts too.
+        let root = state.config.read().unwrap().download_directory.clone();
+        state.config.write().unwrap().allowed_download_roots = vec![root.clone()];
+        state.scheduler.jobs.lock().unwrap()[0].job.request.download_root = Some(root);
+        state.storage_probes.clear();
+        assert_eq!(finished(&state, held).await.status, "completed");
+        eventually("the scheduler resumes", || !state.scheduler.is_paused()).await;
+        assert!(state.scheduler.list().is_empty());
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn a_taken_job_can_still_be_cancelled() {
+        let _files = lock_files().await;
+        let state = state(FakeRunner::new());
+        let key = "https://example.com/taken";
+        state.scheduler.add(job(key, None, 1)).await.unwrap();
+        let taken = state.scheduler.take_due().unwrap();
+        assert_eq!(taken.download_key, key);
+        // Listed and cancellable while its storage is probed, but not taken twice.
+        assert!(state.scheduler.contains(key));
+        assert_eq!(state.scheduler.take_due().unwrap_err(), None);
+        assert!(state.scheduler.cancel(key).await.unwrap().is_some());
+        assert!(state.scheduler.start_taken(key).is_none());
+        assert!(!state.scheduler.hold_taken(key, Duration::from_secs(1)));
+
+        state.scheduler.add(job(key, None, 1)).await.unwrap();
+        state.scheduler.take_due().unwrap();
+        assert_eq!(state.scheduler.start_taken(key).unwrap().download_key, key);
+        assert!(state.scheduler.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn stops_waiting_for_held_storage_when_shutdown_begins() {
+        let _files = lock_files().await;
+        let state = state(FakeRunner::new());
+        let blocker = tempfile::NamedTempFile::new().unwrap();
+        let broken_root = blocker.path().join("videos").to_string_lossy().to_string();
+        state.config.write().unwrap().allowed_download_roots = vec![broken_root.clone()];
+        state.scheduler.add(job("https://example.com/waiting", Some(&broken_root), 1)).await.unwrap();
+        let task = tokio::spawn(run_with_retry(state.clone(), Duration::from_secs(3600)));
+        eventually("the job is held", || state.scheduler.is_paused()).await;
+
+        state.draining.store(true, Ordering::SeqCst);
+        state.scheduler.wake();
+        tokio::time::timeout(Duration::from_secs(5), task).await.expect("the scheduler kept sleeping").unwrap();
+        // Left for the next start.
+        assert_eq!(state.scheduler.list().len(), 1);
+        state.scheduler.cancel("https://example.com/waiting").await.unwrap();
+    }
+}
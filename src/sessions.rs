@@ -0,0 +1,216 @@
+use crate::{config, AppState};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How often expired sessions are cleaned up.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The first line yt-dlp expects in a cookies file.
+pub(crate) const NETSCAPE_HEADER: &str = "# Netscape HTTP Cookie File";
+
+/// A cookie jar shared by the downloads and probes that name it. yt-dlp reads it with
+/// `--cookies` and writes updated cookies back, so a login carries over between operations.
+#[derive(Clone, Serialize, Debug)]
+pub struct Session {
+    pub session_id: String,
+    pub created_at: DateTime<Utc>,
+    /// Extended by `session_ttl_secs` every time the session is used.
+    pub expires_at: DateTime<Utc>,
+    #[serde(skip)]
+    pub cookies_path: PathBuf,
+}
+
+/// The live sessions, in memory only: they don't survive a restart.
+#[derive(Clone, Default)]
+pub struct Sessions {
+    entries: Arc<Mutex<HashMap<String, Session>>>,
+}
+
+/// Returns the directory holding the sessions' cookie files.
+fn sessions_dir() -> Result<PathBuf> {
+    Ok(config::data_dir()?.join("sessions"))
+}
+
+/// When a session used at `now` expires. A `session_ttl_secs` too large to add keeps it for
+/// as long as a date can express.
+fn expiry(state: &AppState, now: DateTime<Utc>) -> DateTime<Utc> {
+    let secs = state.config.read().unwrap().session_ttl_secs;
+    i64::try_from(secs)
+        .ok()
+        .and_then(chrono::Duration::try_seconds)
+        .and_then(|ttl| now.checked_add_signed(ttl))
+        .unwrap_or(DateTime::<Utc>::MAX_UTC)
+}
+
+impl Sessions {
+    /// Creates a session whose jar starts with `cookies` (Netscape format), or empty.
+    pub async fn create(&self, state: &AppState, cookies: Option<&str>) -> Result<Session> {
+        let dir = sessions_dir()?;
+        tokio::fs::create_dir_all(&dir).await?;
+        let session_id = uuid::Uuid::new_v4().to_string();
+        let cookies_path = dir.join(format!("{}.txt", session_id));
+        let cookies = cookies.unwrap_or_default();
+        let content = if cookies.trim_start().starts_with('#') {
+            cookies.to_string()
+        } else {
+            format!("{}\n{}", NETSCAPE_HEADER, cookies)
+        };
+        write_private(&cookies_path, content.as_bytes()).await?;
+        // yt-dlp reads the jar and writes cookies back to it as `run_as_uid`.
+        let (uid, gid) = {
+            let config = state.config.read().unwrap();
+            (config.run_as_uid, config.run_as_gid)
+        };
+        crate::privileges::hand_over(&cookies_path, uid, gid)?;
+
+        let now = Utc::now();
+        let session = Session { session_id: session_id.clone(), created_at: now, expires_at: expiry(state, now), cookies_path };
+        self.entries.lock().unwrap().insert(session_id, session.clone());
+        Ok(session)
+    }
+
+    /// Returns a live session and extends its lifetime, or `None` if it's unknown or expired.
+    pub fn touch(&self, state: &AppState, session_id: &str) -> Option<Session> {
+        let mut entries = self.entries.lock().unwrap();
+        let session = entries.get_mut(session_id).filter(|s| s.expires_at > Utc::now())?;
+        session.expires_at = expiry(state, Utc::now());
+        Some(session.clone())
+    }
+
+    /// Ends a session and deletes its jar. Returns whether it existed.
+    pub async fn remove(&self, session_id: &str) -> Result<bool> {
+        let Some(session) = self.entries.lock().unwrap().remove(session_id) else {
+            return Ok(false);
+        };
+        remove_jar(&session).await;
+        Ok(true)
+    }
+
+    /// Ends every expired session, except those a queued or running download still uses: their
+    /// jars are deleted by a later sweep once the download is over. Returns how many ended.
+    async fn expire(&self, state: &AppState) -> usize {
+        let now = Utc::now();
+        let in_use: HashSet<String> = state
+            .downloads
+            .lock()
+            .values()
+            .filter(|status| crate::handlers::download::is_active(&status.status))
+            .filter_map(|status| status.request.as_ref()?.session_id.clone())
+            .collect();
+        let expired: Vec<Session> = {
+            let mut entries = self.entries.lock().unwrap();
+            let ids: Vec<String> = entries
+                .values()
+                .filter(|s| s.expires_at <= now && !in_use.contains(&s.session_id))
+                .map(|s| s.session_id.clone())
+                .collect();
+            ids.iter().filter_map(|id| entries.remove(id)).collect()
+        };
+        for session in &expired {
+            remove_jar(session).await;
+        }
+        expired.len()
+    }
+}
+
+async fn remove_jar(session: &Session) {
+    if let Err(e) = tokio::fs::remove_file(&session.cookies_path).await {
+        tracing::warn!("Failed to delete the cookie jar of session {}: {}", session.session_id, e);
+    }
+}
+
+/// Writes a file only the server's user can read, as jars hold login cookies.
+pub(crate) async fn write_private(path: &std::path::Path, content: &[u8]) -> Result<()> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path).await?;
+    tokio::io::AsyncWriteExt::write_all(&mut file, content).await?;
+    Ok(())
+}
+
+/// Deletes the jars left by a previous run, whose sessions are gone. Called once at startup.
+pub async fn clear_stale_jars() -> Result<()> {
+    let dir = sessions_dir()?;
+    if dir.exists() {
+        tokio::fs::remove_dir_all(&dir).await?;
+    }
+    Ok(())
+}
+
+/// Ends expired sessions every minute. Runs until the server exits.
+pub async fn run(state: AppState) {
+    loop {
+        tokio::time::sleep(CLEANUP_INTERVAL).await;
+        let count = state.sessions.expire(&state).await;
+        if count > 0 {
+            tracing::info!("Expired {} sessions", count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::{DownloadRequest, DownloadStatus};
+    use crate::test_support::{state, FakeRunner};
+    use chrono::{DateTime, Utc};
+
+    #[tokio::test]
+    async fn keeps_the_jars_of_downloads_that_still_use_them() {
+        let state = state(FakeRunner::new());
+        let busy = state.sessions.create(&state, None).await.unwrap();
+        let idle = state.sessions.create(&state, None).await.unwrap();
+        let request = DownloadRequest { session_id: Some(busy.session_id.clone()), ..Default::default() };
+        let status = DownloadStatus { status: "downloading".to_string(), request: Some(request), ..Default::default() };
+        state.downloads.lock().insert("https://example.com/busy".to_string(), status);
+        for session in state.sessions.entries.lock().unwrap().values_mut() {
+            session.expires_at = Utc::now();
+        }
+
+        assert_eq!(state.sessions.expire(&state).await, 1);
+        assert!(busy.cookies_path.exists());
+        assert!(!idle.cookies_path.exists());
+
+        state.downloads.lock().get_mut("https://example.com/busy").unwrap().status = "completed".to_string();
+        assert_eq!(state.sessions.expire(&state).await, 1);
+        assert!(!busy.cookies_path.exists());
+    }
+
+    #[tokio::test]
+    async fn a_huge_ttl_keeps_the_session_instead_of_panicking() {
+        let state = state(FakeRunner::new());
+        state.config.write().unwrap().session_ttl_secs = u64::MAX;
+        let session = state.sessions.create(&state, None).await.unwrap();
+        assert_eq!(session.expires_at, DateTime::<Utc>::MAX_UTC);
+        assert!(state.sessions.touch(&state, &session.session_id).is_some());
+        state.config.write().unwrap().session_ttl_secs = i64::MAX as u64 / 1000;
+        assert_eq!(state.sessions.touch(&state, &session.session_id).unwrap().expires_at, DateTime::<Utc>::MAX_UTC);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn gives_the_jar_to_the_user_yt_dlp_runs_as() {
+        use std::os::unix::fs::MetadataExt;
+        let state = state(FakeRunner::new());
+        // As root, hand it to "nobody"; otherwise only the server's own user is allowed.
+        // SAFETY: geteuid and getegid only read the process's credentials.
+        let (uid, gid) = match unsafe { (libc::geteuid(), libc::getegid()) } {
+            (0, _) => (65534, 65534),
+            own => own,
+        };
+        {
+            let mut config = state.config.write().unwrap();
+            config.run_as_uid = Some(uid);
+            config.run_as_gid = Some(gid);
+        }
+        let session = state.sessions.create(&state, None).await.unwrap();
+        let meta = std::fs::metadata(&session.cookies_path).unwrap();
+        assert_eq!((meta.uid(), meta.gid(), meta.mode() & 0o777), (uid, gid, 0o600));
+    }
+}
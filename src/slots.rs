@@ -0,0 +1,388 @@
+use crate::ConfigState;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// How many of the latest downloads' slot times the wait estimate averages.
+const RECENT_DURATIONS: usize = 20;
+
+/// Limits how many downloads run at once to `max_concurrent_downloads`, starting waiting
+/// downloads in arrival order. The limit is read from the config on every check: raising it
+/// starts waiting downloads right away, lowering it lets running downloads finish but holds
+/// back new ones until fewer than the new limit are running.
+///
+/// Downloads that bypass the queue use a separate, small pool of `max_express_downloads`
+/// slots, so they start without waiting for the queue but can't run unbounded.
+///
+/// Downloads with CPU-heavy post-processing also need one of `max_concurrent_postprocess`
+/// slots. yt-dlp runs ffmpeg inside the download's process, so the slot covers the whole
+/// download rather than only the ffmpeg step.
+#[derive(Clone, Default)]
+pub struct DownloadSlots {
+    queue: Arc<Mutex<SlotQueue>>,
+    notify: Arc<Notify>,
+}
+
+#[derive(Default)]
+struct SlotQueue {
+    running: usize,
+    express_running: usize,
+    postprocess_running: usize,
+    waiting: VecDeque<u64>,
+    next_ticket: u64,
+    /// How long the latest downloads held their slot, newest last.
+    recent_durations: VecDeque<Duration>,
+}
+
+/// Where a waiting download stands in the queue.
+#[derive(Clone, Copy, Debug)]
+pub struct QueueEstimate {
+    /// 1 for the next download to start.
+    pub position: usize,
+    /// When it should start, from how long recent downloads held their slot. `None` until a
+    /// download has finished, or while downloads are unlimited.
+    pub estimated_start_at: Option<DateTime<Utc>>,
+}
+
+/// The slot usage as reported by `/health`.
+#[derive(Serialize, Debug)]
+pub struct SlotReport {
+    /// `None` when downloads are unlimited.
+    pub limit: Option<usize>,
+    pub running: usize,
+    pub waiting: usize,
+    /// More downloads are running than the limit allows, after the limit was lowered.
+    /// Nothing new starts until enough of them finish.
+    pub draining: bool,
+    /// Downloads running in the express slots, outside the limit.
+    pub express_running: usize,
+    /// `None` when post-processing is unlimited.
+    pub postprocess_limit: Option<usize>,
+    pub postprocess_running: usize,
+}
+
+#[derive(Clone, Copy)]
+enum SlotKind {
+    Download,
+    Express,
+    Postprocess,
+}
+
+/// Held by a running download; frees the slot when dropped.
+pub struct SlotGuard {
+    slots: DownloadSlots,
+    kind: SlotKind,
+    acquired_at: Instant,
+}
+
+/// Removes a download's place in line if it stops waiting without getting a slot.
+struct Ticket<'a> {
+    slots: &'a DownloadSlots,
+    number: u64,
+    started: bool,
+}
+
+impl DownloadSlots {
+    /// Waits until the download may start. Downloads start in the order they called this.
+    /// `on_wait` gets the download's place in line whenever it changes while it waits.
+    pub async fn acquire(&self, config: &ConfigState, on_wait: impl Fn(QueueEstimate)) -> SlotGuard {
+        let mut ticket = {
+            let mut queue = self.queue.lock().unwrap();
+            let number = queue.next_ticket;
+            queue.next_ticket += 1;
+            queue.waiting.push_back(number);
+            Ticket { slots: self, number, started: false }
+        };
+        loop {
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            let limit = config.read().unwrap().max_concurrent_downloads;
+            if self.try_start(ticket.number, limit) {
+                ticket.started = true;
+                // The next in line may fit too, e.g. after the limit was raised.
+                self.notify.notify_waiters();
+                return SlotGuard { slots: self.clone(), kind: SlotKind::Download, acquired_at: Instant::now() };
+            }
+            let estimate = {
+                let queue = self.queue.lock().unwrap();
+                queue.waiting.iter().position(|t| *t == ticket.number).map(|index| queue.estimate(index + 1, limit))
+            };
+            if let Some(estimate) = estimate {
+                on_wait(estimate);
+            }
+            notified.await;
+        }
+    }
+
+    /// Waits for one of the `max_express_downloads` slots, skipping the queue.
+    pub async fn acquire_express(&self, config: &ConfigState) -> SlotGuard {
+        self.acquire_counted(SlotKind::Express, || Some(config.read().unwrap().max_express_downloads)).await
+    }
+
+    /// Waits for one of the `max_concurrent_postprocess` slots.
+    pub async fn acquire_postprocess(&self, config: &ConfigState) -> SlotGuard {
+        self.acquire_counted(SlotKind::Postprocess, || config.read().unwrap().max_concurrent_postprocess).await
+    }
+
+    /// Whether a download needing post-processing would have to wait for a slot.
+    pub fn postprocess_full(&self, config: &ConfigState) -> bool {
+        let limit = config.read().unwrap().max_concurrent_postprocess;
+        limit.is_some_and(|limit| self.queue.lock().unwrap().postprocess_running >= limit)
+    }
+
+    /// Waits until fewer than `limit()` slots of `kind` are taken. Waiters aren't ordered.
+    async fn acquire_counted(&self, kind: SlotKind, limit: impl Fn() -> Option<usize>) -> SlotGuard {
+        loop {
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            let limit = limit();
+            {
+                let mut queue = self.queue.lock().unwrap();
+                let running = queue.running_mut(kind);
+                if limit.is_none_or(|limit| *running < limit) {
+                    *running += 1;
+                    return SlotGuard { slots: self.clone(), kind, acquired_at: Instant::now() };
+                }
+            }
+            notified.await;
+        }
+    }
+
+    fn try_start(&self, ticket: u64, limit: Option<usize>) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        let has_room = limit.is_none_or(|limit| queue.running < limit);
+        if queue.waiting.front() != Some(&ticket) || !has_room {
+            return false;
+        }
+        queue.waiting.pop_front();
+        queue.running += 1;
+        true
+    }
+
+    /// Where a download would stand if it joined the queue now, or `None` if it would start
+    /// right away. For the response to a new download; never waits.
+    pub fn preview(&self, config: &ConfigState) -> Option<QueueEstimate> {
+        let limit = config.read().unwrap().max_concurrent_downloads;
+        let queue = self.queue.lock().unwrap();
+        let full = limit.is_some_and(|limit| queue.running >= limit);
+        (full || !queue.waiting.is_empty()).then(|| queue.estimate(queue.waiting.len() + 1, limit))
+    }
+
+    /// Re-checks the waiting downloads against a new limit. Called after a config update.
+    pub fn reconfigure(&self) {
+        self.notify.notify_waiters();
+    }
+
+    pub fn report(&self, config: &ConfigState) -> SlotReport {
+        let (limit, postprocess_limit) = {
+            let config = config.read().unwrap();
+            (config.max_concurrent_downloads, config.max_concurrent_postprocess)
+        };
+        let queue = self.queue.lock().unwrap();
+        SlotReport {
+            limit,
+            running: queue.running,
+            waiting: queue.waiting.len(),
+            draining: limit.is_some_and(|limit| queue.running > limit),
+            express_running: queue.express_running,
+            postprocess_limit,
+            postprocess_running: queue.postprocess_running,
+        }
+    }
+}
+
+impl SlotQueue {
+    /// Estimates when the download at `position` starts: with `limit` slots each freeing up
+    /// after the recent average, one starts every average / limit.
+    fn estimate(&self, position: usize, limit: Option<usize>) -> QueueEstimate {
+        let average = (!self.recent_durations.is_empty())
+            .then(|| self.recent_durations.iter().sum::<Duration>() / self.recent_durations.len() as u32);
+        let estimated_start_at = average.zip(limit.filter(|limit| *limit > 0)).and_then(|(average, limit)| {
+            let wait = average.mul_f64(position as f64 / limit as f64);
+            Some(Utc::now() + chrono::Duration::from_std(wait).ok()?)
+        });
+        QueueEstimate { position, estimated_start_at }
+    }
+
+    fn running_mut(&mut self, kind: SlotKind) -> &mut usize {
+        match kind {
+            SlotKind::Download => &mut self.running,
+            SlotKind::Express => &mut self.express_running,
+            SlotKind::Postprocess => &mut self.postprocess_running,
+        }
+    }
+}
+
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        {
+            let mut queue = self.slots.queue.lock().unwrap();
+            *queue.running_mut(self.kind) -= 1;
+            if matches!(self.kind, SlotKind::Download) {
+                if queue.recent_durations.len() == RECENT_DURATIONS {
+                    queue.recent_durations.pop_front();
+                }
+                queue.recent_durations.push_back(self.acquired_at.elapsed());
+            }
+        }
+        self.slots.notify.notify_waiters();
+    }
+}
+
+impl Drop for Ticket<'_> {
+    fn drop(&mut self) {
+        if !self.started {
+            self.slots.queue.lock().unwrap().waiting.retain(|t| *t != self.number);
+            self.slots.notify.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::collections::BTreeMap;
+    use std::sync::RwLock;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+    /// Each download's places in line, and whether they came with a start time.
+    type Places = Arc<Mutex<BTreeMap<&'static str, Vec<(usize, bool)>>>>;
+
+    fn config(limit: usize) -> ConfigState {
+        Arc::new(RwLock::new(Config { max_concurrent_downloads: Some(limit), ..Default::default() }))
+    }
+
+    fn set_limit(slots: &DownloadSlots, config: &ConfigState, limit: usize) {
+        config.write().unwrap().max_concurrent_downloads = Some(limit);
+        slots.reconfigure();
+    }
+
+    /// Joins the queue as `name` and sends the name and slot once it starts. Returns once the
+    /// download waits in line, so downloads joined one after another queue in that order.
+    async fn join(slots: &DownloadSlots, config: &ConfigState, name: &'static str, started: &UnboundedSender<(&'static str, SlotGuard)>) {
+        join_watching(slots, config, name, started, |_| {}).await;
+    }
+
+    /// Like `join`, passing every place in line the download is told of to `on_wait`.
+    async fn join_watching(
+        slots: &DownloadSlots,
+        config: &ConfigState,
+        name: &'static str,
+        started: &UnboundedSender<(&'static str, SlotGuard)>,
+        on_wait: impl Fn(QueueEstimate) + Send + 'static,
+    ) {
+        let waiting = slots.report(config).waiting;
+        let (slots_, config_, started) = (slots.clone(), config.clone(), started.clone());
+        tokio::spawn(async move {
+            let guard = slots_.acquire(&config_, on_wait).await;
+            started.send((name, guard)).unwrap();
+        });
+        while slots.report(config).waiting == waiting {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// The downloads that start within a short while, in the order they started.
+    async fn started(receiver: &mut UnboundedReceiver<(&'static str, SlotGuard)>) -> Vec<(&'static str, SlotGuard)> {
+        let mut started = Vec::new();
+        while let Ok(Some(next)) = tokio::time::timeout(Duration::from_millis(200), receiver.recv()).await {
+            started.push(next);
+        }
+        started
+    }
+
+    fn names(started: &[(&'static str, SlotGuard)]) -> Vec<&'static str> {
+        started.iter().map(|(name, _)| *name).collect()
+    }
+
+    #[tokio::test]
+    async fn starts_downloads_in_the_order_they_queued() {
+        let (slots, config) = (DownloadSlots::default(), config(1));
+        let (sender, mut receiver) = unbounded_channel();
+        let first = slots.acquire(&config, |_| {}).await;
+        for name in ["b", "c", "d", "e"] {
+            join(&slots, &config, name, &sender).await;
+        }
+        assert!(started(&mut receiver).await.is_empty());
+
+        drop(first);
+        let mut order = Vec::new();
+        for _ in 0..4 {
+            let next = started(&mut receiver).await;
+            assert_eq!(next.len(), 1, "only one download may run at a time");
+            order.extend(names(&next));
+            // `next` drops here, freeing the slot for the next one in line.
+        }
+        assert_eq!(order, ["b", "c", "d", "e"]);
+        assert_eq!(slots.report(&config).running, 0);
+    }
+
+    #[tokio::test]
+    async fn applies_a_limit_changed_while_downloads_wait() {
+        let (slots, config) = (DownloadSlots::default(), config(1));
+        let (sender, mut receiver) = unbounded_channel();
+        let first = slots.acquire(&config, |_| {}).await;
+        for name in ["b", "c", "d"] {
+            join(&slots, &config, name, &sender).await;
+        }
+
+        // Raising the limit starts as many as now fit, still in queue order.
+        set_limit(&slots, &config, 3);
+        let mut running = started(&mut receiver).await;
+        assert_eq!(names(&running), ["b", "c"]);
+        let report = slots.report(&config);
+        assert_eq!((report.running, report.waiting, report.draining), (3, 1, false));
+
+        // Lowering it starts nothing new until the running downloads fall under it.
+        set_limit(&slots, &config, 1);
+        assert!(slots.report(&config).draining);
+        drop(first);
+        running.pop();
+        assert!(started(&mut receiver).await.is_empty());
+        assert!(!slots.report(&config).draining);
+        running.pop();
+        let last = started(&mut receiver).await;
+        assert_eq!(names(&last), ["d"]);
+        let report = slots.report(&config);
+        assert_eq!((report.running, report.waiting, report.draining), (1, 0, false));
+    }
+
+    #[tokio::test]
+    async fn positions_count_down_as_the_queue_drains() {
+        let (slots, config) = (DownloadSlots::default(), config(1));
+        let (sender, mut receiver) = unbounded_channel();
+        let first = slots.acquire(&config, |_| {}).await;
+        let seen = Places::default();
+        let names = ["b", "c", "d", "e"];
+        for name in names {
+            let seen = seen.clone();
+            join_watching(&slots, &config, name, &sender, move |estimate| {
+                seen.lock().unwrap().entry(name).or_default().push((estimate.position, estimate.estimated_start_at.is_some()));
+            })
+            .await;
+        }
+        // Nothing has finished yet, so there's nothing to estimate a start from.
+        assert!(seen.lock().unwrap().values().flatten().all(|(_, estimated)| !estimated));
+
+        drop(first);
+        for _ in names {
+            // `started` drops the slot again, letting the next one in line start.
+            assert_eq!(started(&mut receiver).await.len(), 1);
+        }
+        let seen = seen.lock().unwrap();
+        for (index, name) in names.iter().enumerate() {
+            let positions: Vec<usize> = seen[name].iter().map(|(position, _)| *position).collect();
+            let mut deduplicated = positions.clone();
+            deduplicated.dedup();
+            assert_eq!(deduplicated, (1..=index + 1).rev().collect::<Vec<_>>(), "{} was told {:?}", name, positions);
+            // Once a download has finished, waiting ones get a start time too.
+            assert!(seen[name].iter().filter(|(position, _)| *position < index + 1).all(|(_, estimated)| *estimated), "{}: {:?}", name, seen[name]);
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use crate::{cache::TtlCache, error::AppError, AppState};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use sysinfo::Disks;
+
+/// Probe results are reused for `caches.storage_probes.ttl_secs`, so a struggling
+/// filesystem isn't hit on every request.
+pub type StorageProbes = TtlCache<StorageProbe>;
+
+/// Why a download directory can't take new downloads.
+#[derive(Clone, Copy, Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageCondition {
+    NotWritable,
+    InsufficientSpace,
+}
+
+/// The result of probing a download directory.
+#[derive(Clone, Serialize, Debug)]
+pub struct StorageProbe {
+    pub directory: String,
+    pub writable: bool,
+    /// Free space on the filesystem holding the directory, if it could be determined.
+    pub available_bytes: Option<u64>,
+    pub condition: Option<StorageCondition>,
+    /// The underlying error or shortfall, for humans.
+    pub detail: Option<String>,
+}
+
+impl StorageProbe {
+    /// The error to fail a download with, or `None` if the directory is usable.
+    pub fn to_error(&self) -> Option<AppError> {
+        let detail = self.detail.as_deref().unwrap_or("");
+        match self.condition? {
+            StorageCondition::NotWritable => Some(AppError::ServiceUnavailable(format!(
+                "Download directory '{}' is not writable: {}",
+                self.directory, detail
+            ))),
+            StorageCondition::InsufficientSpace => Some(AppError::InsufficientStorage(format!(
+                "Download directory '{}' is low on space: {}",
+                self.directory, detail
+            ))),
+        }
+    }
+}
+
+/// Probes `dir` (or returns a recent cached probe): creates it if needed, writes and removes
+/// a probe file, and checks the free space against `min_free_space_mb` from the config.
+pub async fn probe(state: &AppState, dir: &Path) -> StorageProbe {
+    let key = dir.to_string_lossy().to_string();
+    if let Some(cached) = state.storage_probes.get(&key) {
+        return cached;
+    }
+    let min_free_bytes = state.config.read().unwrap().min_free_space_mb.saturating_mul(1024 * 1024);
+    let result = run_probe(dir, min_free_bytes).await;
+    if let Some(condition) = result.condition {
+        tracing::warn!("Storage probe failed for {}: {:?} ({})", key, condition, result.detail.as_deref().unwrap_or(""));
+    }
+    state.storage_probes.insert(key, result.clone());
+    result
+}
+
+/// Fails fast with 503 (not writable) or 507 (out of space) if `dir` can't take a download.
+pub async fn check(state: &AppState, dir: &Path) -> Result<(), AppError> {
+    match probe(state, dir).await.to_error() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Checks, without creating anything, that `dir` could hold downloads: it must be a writable
+/// directory, or missing with a writable directory as its closest existing ancestor.
+pub async fn check_writable_without_creating(dir: &Path) -> Result<(), String> {
+    let mut existing = dir;
+    while !tokio::fs::try_exists(existing).await.unwrap_or(false) {
+        match existing.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => existing = parent,
+            _ => return Ok(()), // A relative path under the working directory.
+        }
+    }
+    if !existing.is_dir() {
+        return Err(format!("'{}' is not a directory", existing.display()));
+    }
+    let probe_file = existing.join(format!(".yt-agent-probe-{}", uuid::Uuid::new_v4()));
+    tokio::fs::write(&probe_file, b"probe")
+        .await
+        .map_err(|e| format!("'{}' is not writable: {}", existing.display(), e))?;
+    let _ = tokio::fs::remove_file(&probe_file).await;
+    Ok(())
+}
+
+async fn run_probe(dir: &Path, min_free_bytes: u64) -> StorageProbe {
+    let mut result = StorageProbe {
+        directory: dir.to_string_lossy().to_string(),
+        writable: false,
+        available_bytes: None,
+        condition: None,
+        detail: None,
+    };
+
+    let probe_file = dir.join(format!(".yt-agent-probe-{}", uuid::Uuid::new_v4()));
+    let write = async {
+        tokio::fs::create_dir_all(dir).await?;
+        tokio::fs::write(&probe_file, b"probe").await?;
+        tokio::fs::remove_file(&probe_file).await
+    };
+    if let Err(e) = write.await {
+        result.condition = Some(StorageCondition::NotWritable);
+        result.detail = Some(e.to_string());
+        return result;
+    }
+    result.writable = true;
+
+    let dir = dir.to_path_buf();
+    result.available_bytes = tokio::task::spawn_blocking(move || available_space(&dir)).await.ok().flatten();
+    if let Some(available) = result.available_bytes {
+        if available < min_free_bytes {
+            result.condition = Some(StorageCondition::InsufficientSpace);
+            result.detail = Some(format!(
+                "{} MiB free, at least {} MiB required",
+                available / (1024 * 1024),
+                min_free_bytes / (1024 * 1024)
+            ));
+        }
+    }
+    result
+}
+
+/// Returns the free space of the disk whose mount point is the closest ancestor of `dir`.
+fn available_space(dir: &Path) -> Option<u64> {
+    let dir: PathBuf = std::fs::canonicalize(dir).ok()?;
+    let disks = Disks::new_with_refreshed_list();
+    disks
+        .list()
+        .iter()
+        .filter(|disk| dir.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().as_os_str().len())
+        .map(|disk| disk.available_space())
+}
@@ -0,0 +1,198 @@
+//! Helpers shared by the tests: a fake `ProcessRunner`, a state whose config and data files live
+//! in a temporary home, and a way to send requests through the full router.
+
+use crate::{models::DownloadStatus, runner::ProcessRunner, AppState};
+use axum::{
+    body::{to_bytes, Body},
+    http::{HeaderMap, Method, Request, StatusCode},
+    Router,
+};
+use futures::StreamExt;
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Once};
+use tokio::process::Command;
+use tower::ServiceExt;
+
+/// The largest response body the tests read.
+const MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+static HOME: Lazy<PathBuf> = Lazy::new(|| tempfile::tempdir().unwrap().keep());
+static ISOLATE: Once = Once::new();
+
+/// Serializes tests that read or write the shared config and data files.
+static FILES: Lazy<tokio::sync::Mutex<()>> = Lazy::new(Default::default);
+
+/// A `--dump-json` line for one video with a video-only, an audio-only and a storyboard format.
+pub const VIDEO_JSON: &str = concat!(
+    r#"{"id": "abc", "title": "Video", "thumbnail": null, "webpage_url": "https://example.com/v", "formats": ["#,
+    r#"{"format_id": "137", "ext": "mp4", "resolution": "1920x1080", "height": 1080, "vcodec": "avc1", "acodec": "none", "filesize": 3000}, "#,
+    r#"{"format_id": "140", "ext": "m4a", "resolution": "audio only", "vcodec": "none", "acodec": "mp4a", "filesize": 1000}, "#,
+    r#"{"format_id": "sb0", "ext": "mhtml", "resolution": "48x27", "format_note": "storyboard", "vcodec": "none", "acodec": "none"}]}"#,
+);
+
+/// A yt-dlp that answers probes with `VIDEO_JSON` and "downloads" by writing a small file named
+/// after the `-o` template, printing the lines yt-dlp would and recording provenance.
+pub const FAKE_YTDLP: &str = r#"
+for arg; do
+    case "$prev" in -o) template=$arg ;; esac
+    case "$prev2" in --print-to-file) capture=$arg ;; esac
+    prev2=$prev; prev=$arg; url=$arg
+done
+case "$*" in
+    *--flat-playlist*) echo NA; exit 0 ;;
+    *--dump-json*) echo "$VIDEO_JSON"; exit 0 ;;
+    *"--print %(."*) exit 0 ;;
+esac
+file=$(printf '%s' "$template" | sed -e 's/%(artist,uploader)s/Artist/g' -e 's/%(title)\(s\|\.[0-9]*B\)/Video/g' -e 's/%(id)s/abc/g' -e 's/%(ext)s/mp4/g')
+mkdir -p "$(dirname "$file")"
+echo "[download] Destination: $file"
+echo "[download]  50.0% of 4.00KiB at 1.00KiB/s ETA 00:02"
+printf 'fake' > "$file"
+echo "[download] 100% of 4.00KiB in 00:00:01"
+if [ -n "$capture" ]; then
+    printf '{"id": "abc", "title": "Video", "webpage_url": "%s", "filepath": "%s"}
+' "$url" "$file" >> "$capture"
+fi
+"#;
+
+/// Stands in for yt-dlp, ffmpeg and friends: each program runs a shell script, which gets the
+/// program's arguments as `$1`, `$2`, ... Programs without a script fail with exit code 127.
+#[derive(Default)]
+pub struct FakeRunner {
+    scripts: HashMap<String, String>,
+    /// The programs run so far, in order.
+    calls: Arc<Mutex<Vec<String>>>,
+}
+
+impl FakeRunner {
+    pub fn new() -> Self {
+        FakeRunner::default()
+    }
+
+    /// Runs `script` whenever `program` is started.
+    pub fn script(mut self, program: &str, script: &str) -> Self {
+        self.scripts.insert(program.to_string(), script.to_string());
+        self
+    }
+
+    /// A handle to the programs run so far.
+    pub fn calls(&self) -> Arc<Mutex<Vec<String>>> {
+        self.calls.clone()
+    }
+}
+
+impl ProcessRunner for FakeRunner {
+    fn command(&self, program: &str) -> Command {
+        self.calls.lock().unwrap().push(program.to_string());
+        let script = self.scripts.get(program).map_or("exit 127", String::as_str);
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(script).arg(program).env("VIDEO_JSON", VIDEO_JSON);
+        cmd
+    }
+}
+
+/// Points `HOME` at a temporary directory, once per test process, so config and data files
+/// never touch the real ones.
+pub fn isolate_home() -> &'static Path {
+    ISOLATE.call_once(|| {
+        std::env::set_var("HOME", &*HOME);
+        for var in ["XDG_CONFIG_HOME", "XDG_DATA_HOME", "XDG_CACHE_HOME"] {
+            std::env::remove_var(var);
+        }
+    });
+    &HOME
+}
+
+/// A fresh state that runs `runner`, with its own empty download directory.
+pub fn state(runner: FakeRunner) -> AppState {
+    isolate_home();
+    let state = AppState::new_for_tests(Arc::new(runner));
+    let downloads = tempfile::tempdir_in(&*HOME).unwrap().keep();
+    state.config.write().unwrap().download_directory = downloads.to_string_lossy().to_string();
+    state
+}
+
+/// A runner whose yt-dlp is `FAKE_YTDLP`.
+pub fn fake_ytdlp() -> FakeRunner {
+    FakeRunner::new().script("yt-dlp", FAKE_YTDLP)
+}
+
+/// Waits up to ten seconds for the download to leave the queued and running states, and
+/// returns its status.
+pub async fn finished(state: &AppState, key: &str) -> DownloadStatus {
+    for _ in 0..1000 {
+        let status = state.downloads.lock().get(key).cloned();
+        if let Some(status) = status.filter(|s| !matches!(s.status.as_str(), "queued" | "starting" | "downloading" | "scheduled")) {
+            return status;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    panic!("{} didn't finish: {:?}", key, state.downloads.lock().get(key));
+}
+
+/// Held by tests that read or write the config file, the history or other shared data files.
+pub async fn lock_files() -> tokio::sync::MutexGuard<'static, ()> {
+    FILES.lock().await
+}
+
+/// Where the config file lives under the temporary home.
+pub fn config_file() -> PathBuf {
+    isolate_home();
+    let dirs = directories::ProjectDirs::from("com", "YourOrg", "YT-DLP-API").unwrap();
+    dirs.config_dir().join("config.toml")
+}
+
+/// Deletes the config file, if there is one, as on a fresh install.
+pub fn remove_config_file() {
+    match std::fs::remove_file(config_file()) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => panic!("failed to remove the config file: {}", e),
+    }
+}
+
+/// The router `server run` serves, for `state`.
+pub fn app(state: &AppState) -> Router {
+    crate::router(state)
+}
+
+/// A response as the tests look at it. Bodies that aren't JSON become a JSON string.
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Value,
+}
+
+/// Sends one request through the router. `body` is sent as JSON.
+pub async fn send(app: &Router, method: Method, uri: &str, body: Option<Value>) -> TestResponse {
+    send_with(app, Request::builder().method(method).uri(uri), body).await
+}
+
+/// Like `send`, with headers or other parts already set on `request`.
+pub async fn send_with(app: &Router, request: axum::http::request::Builder, body: Option<Value>) -> TestResponse {
+    let request = match body {
+        Some(body) => request.header("content-type", "application/json").body(Body::from(body.to_string())),
+        None => request.body(Body::empty()),
+    }
+    .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let headers = response.headers().clone();
+    let bytes = to_bytes(response.into_body(), MAX_BODY_BYTES).await.unwrap();
+    let body = serde_json::from_slice(&bytes).unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&bytes).to_string()));
+    TestResponse { status, headers, body }
+}
+
+/// Sends one request and returns the status, the headers and the first chunk of the body, for
+/// responses that stream without end.
+pub async fn first_chunk(app: &Router, request: axum::http::request::Builder) -> (StatusCode, HeaderMap, String) {
+    let response = app.clone().oneshot(request.body(Body::empty()).unwrap()).await.unwrap();
+    let (status, headers) = (response.status(), response.headers().clone());
+    let mut body = response.into_body().into_data_stream();
+    let chunk = tokio::time::timeout(std::time::Duration::from_secs(10), body.next()).await.expect("no chunk within 10s");
+    let chunk = chunk.map(|chunk| chunk.unwrap()).unwrap_or_default();
+    (status, headers, String::from_utf8_lossy(&chunk).to_string())
+}
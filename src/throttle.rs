@@ -0,0 +1,64 @@
+use axum::body::Bytes;
+use futures::{Stream, StreamExt};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+static RATE_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"^(?P<value>\d+(?:\.\d+)?)(?P<unit>[KMG]?)$").unwrap());
+
+/// Parses a rate in bytes per second the way yt-dlp's `--limit-rate` takes it, e.g. "500K",
+/// "4.2M" or "1048576". Suffixes are binary (K = 1024). Zero isn't a rate.
+pub fn parse_rate(text: &str) -> Option<u64> {
+    let caps = RATE_REGEX.captures(text.trim())?;
+    let value: f64 = caps["value"].parse().ok()?;
+    let multiplier = match &caps["unit"] {
+        "" => 1.0,
+        "K" => 1024.0,
+        "M" => 1024.0 * 1024.0,
+        "G" => 1024.0 * 1024.0 * 1024.0,
+        _ => return None,
+    };
+    let rate = (value * multiplier) as u64;
+    (rate > 0).then_some(rate)
+}
+
+/// Spaces out chunks so they leave at a set rate. Each chunk reserves the time it takes to
+/// send at that rate, and the next one may only go once the reservation ends. Cloned pacers
+/// share their reservations, so one pacer shared by every transfer caps their sum. Idle
+/// time isn't saved up for a later burst.
+#[derive(Clone, Default)]
+pub struct Pacer {
+    next: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Pacer {
+    /// Reserves the time `bytes` take at `rate` bytes per second and returns when they may go.
+    fn reserve(&self, bytes: usize, rate: u64) -> Instant {
+        let now = Instant::now();
+        let mut next = self.next.lock().unwrap();
+        let start = next.map_or(now, |next| next.max(now));
+        *next = Some(start + Duration::from_secs_f64(bytes as f64 / rate as f64));
+        start
+    }
+}
+
+/// Throttles a byte stream to `per_connection` bytes per second, and all the streams sharing
+/// the `global` pacer to its rate. Chunks wait for whichever limit is further behind.
+pub fn throttle<S, E>(stream: S, per_connection: Option<u64>, global: Option<(Pacer, u64)>) -> impl Stream<Item = Result<Bytes, E>>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    let connection = Pacer::default();
+    stream.then(move |chunk| {
+        let len = chunk.as_ref().map_or(0, Bytes::len);
+        let connection_at = per_connection.map(|rate| connection.reserve(len, rate));
+        let global_at = global.as_ref().map(|(pacer, rate)| pacer.reserve(len, *rate));
+        async move {
+            if let Some(at) = connection_at.into_iter().chain(global_at).max() {
+                tokio::time::sleep_until(at.into()).await;
+            }
+            chunk
+        }
+    })
+}
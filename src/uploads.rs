@@ -0,0 +1,260 @@
+use crate::{config, error::AppError, AppState};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+/// How often stale uploads are cleaned up.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A file sent in `Content-Range` chunks, for `POST /cookies` and `POST /download/import`
+/// over links too slow to send it in one go. Chunks may arrive in any order and be repeated.
+#[derive(Clone, Serialize, Debug)]
+pub struct Upload {
+    pub upload_id: String,
+    /// The length of the whole file, in bytes.
+    pub size: u64,
+    /// The byte ranges received so far, sorted and merged.
+    pub received: Vec<ByteRange>,
+    pub complete: bool,
+    pub created_at: DateTime<Utc>,
+    /// Extended by `upload_stale_after_secs` every time a chunk arrives.
+    pub expires_at: DateTime<Utc>,
+    #[serde(skip)]
+    pub path: PathBuf,
+}
+
+/// A range of bytes, both ends included, as in `Content-Range`.
+#[derive(Clone, Copy, Serialize, Debug, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+/// The uploads in progress, in memory only: they don't survive a restart.
+#[derive(Clone, Default)]
+pub struct Uploads {
+    entries: Arc<Mutex<HashMap<String, Upload>>>,
+}
+
+/// Returns the directory holding the uploads' temporary files.
+fn uploads_dir() -> anyhow::Result<PathBuf> {
+    Ok(config::data_dir()?.join("uploads"))
+}
+
+fn stale_after(state: &AppState) -> chrono::Duration {
+    chrono::Duration::seconds(state.config.read().unwrap().upload_stale_after_secs as i64)
+}
+
+impl Uploads {
+    /// Starts an upload of `size` bytes with an empty temporary file.
+    pub async fn create(&self, state: &AppState, size: u64) -> Result<Upload, AppError> {
+        let (allowed, max_bytes, max_uploads) = {
+            let config = state.config.read().unwrap();
+            (config.allow_uploads, config.max_upload_bytes, config.max_uploads)
+        };
+        if !allowed {
+            return Err(AppError::Forbidden("Uploads are disabled; set allow_uploads in the config to enable them.".to_string()));
+        }
+        if size == 0 {
+            return Err(AppError::BadRequest("size must be greater than 0".to_string()));
+        }
+        if size > max_bytes {
+            return Err(AppError::PayloadTooLarge(format!("The upload is larger than max_upload_bytes ({})", max_bytes)));
+        }
+        let dir = uploads_dir()?;
+        tokio::fs::create_dir_all(&dir).await?;
+        let upload_id = uuid::Uuid::new_v4().to_string();
+        let path = dir.join(format!("{}.part", upload_id));
+        crate::sessions::write_private(&path, b"").await?;
+
+        let now = Utc::now();
+        let upload = Upload {
+            upload_id: upload_id.clone(),
+            size,
+            received: Vec::new(),
+            complete: false,
+            created_at: now,
+            expires_at: now + stale_after(state),
+            path,
+        };
+        let in_progress = {
+            let mut entries = self.entries.lock().unwrap();
+            let in_progress = entries.len();
+            if in_progress < max_uploads {
+                entries.insert(upload_id, upload.clone());
+            }
+            in_progress
+        };
+        if in_progress >= max_uploads {
+            remove_file(&upload).await;
+            return Err(AppError::QuotaExceeded(format!(
+                "{} uploads are already in progress, the max_uploads limit; finish or wait for one to expire",
+                in_progress
+            )));
+        }
+        Ok(upload)
+    }
+
+    /// Returns an upload in progress, or `None` if it's unknown or was cleaned up.
+    pub fn get(&self, upload_id: &str) -> Option<Upload> {
+        self.entries.lock().unwrap().get(upload_id).cloned()
+    }
+
+    /// Writes one chunk, given its `Content-Range` header, and returns the upload's progress.
+    pub async fn write_chunk(&self, state: &AppState, upload_id: &str, content_range: &str, chunk: &[u8]) -> Result<Upload, AppError> {
+        let upload = self.get(upload_id).ok_or_else(|| unknown(upload_id))?;
+        let range = parse_content_range(content_range, upload.size)?;
+        if range.end - range.start + 1 != chunk.len() as u64 {
+            return Err(AppError::BadRequest(format!(
+                "The Content-Range covers {} bytes but the body has {}",
+                range.end - range.start + 1,
+                chunk.len()
+            )));
+        }
+        let mut file = tokio::fs::OpenOptions::new().write(true).open(&upload.path).await?;
+        file.seek(std::io::SeekFrom::Start(range.start)).await?;
+        file.write_all(chunk).await?;
+        file.sync_data().await?;
+
+        let mut entries = self.entries.lock().unwrap();
+        let upload = entries.get_mut(upload_id).ok_or_else(|| unknown(upload_id))?;
+        upload.received = merge_range(std::mem::take(&mut upload.received), range);
+        upload.complete = upload.received == [ByteRange { start: 0, end: upload.size - 1 }];
+        upload.expires_at = Utc::now() + stale_after(state);
+        Ok(upload.clone())
+    }
+
+    /// Ends a complete upload and returns its content. The temporary file is deleted.
+    pub async fn take(&self, upload_id: &str) -> Result<Vec<u8>, AppError> {
+        let upload = {
+            let mut entries = self.entries.lock().unwrap();
+            let upload = entries.get(upload_id).ok_or_else(|| unknown(upload_id))?;
+            if !upload.complete {
+                let received: u64 = upload.received.iter().map(|range| range.end - range.start + 1).sum();
+                return Err(AppError::Conflict(format!(
+                    "Upload '{}' is incomplete: {} of {} bytes received",
+                    upload_id, received, upload.size
+                )));
+            }
+            entries.remove(upload_id).unwrap()
+        };
+        let content = tokio::fs::read(&upload.path).await?;
+        remove_file(&upload).await;
+        Ok(content)
+    }
+
+    /// Deletes every upload that received nothing for `upload_stale_after_secs`. Returns how
+    /// many there were.
+    async fn expire(&self) -> usize {
+        let now = Utc::now();
+        let expired: Vec<Upload> = {
+            let mut entries = self.entries.lock().unwrap();
+            let ids: Vec<String> = entries.values().filter(|u| u.expires_at <= now).map(|u| u.upload_id.clone()).collect();
+            ids.iter().filter_map(|id| entries.remove(id)).collect()
+        };
+        for upload in &expired {
+            remove_file(upload).await;
+        }
+        expired.len()
+    }
+}
+
+fn unknown(upload_id: &str) -> AppError {
+    AppError::NotFound(format!("Unknown or expired upload '{}'", upload_id))
+}
+
+async fn remove_file(upload: &Upload) {
+    if let Err(e) = tokio::fs::remove_file(&upload.path).await {
+        tracing::warn!("Failed to delete the file of upload {}: {}", upload.upload_id, e);
+    }
+}
+
+/// Parses `bytes START-END/TOTAL`, where TOTAL must be the upload's size or `*`.
+fn parse_content_range(value: &str, size: u64) -> Result<ByteRange, AppError> {
+    let invalid = || AppError::BadRequest(format!("Invalid Content-Range '{}'; expected e.g. 'bytes 0-1023/{}'", value, size));
+    let (range, total) = value.trim().strip_prefix("bytes ").and_then(|rest| rest.split_once('/')).ok_or_else(invalid)?;
+    let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+    let (start, end): (u64, u64) = (start.parse().map_err(|_| invalid())?, end.parse().map_err(|_| invalid())?);
+    if total != "*" && total.parse::<u64>().ok() != Some(size) {
+        return Err(AppError::BadRequest(format!("The Content-Range total must be the upload's size, {}", size)));
+    }
+    if start > end || end >= size {
+        return Err(invalid());
+    }
+    Ok(ByteRange { start, end })
+}
+
+/// Adds `range` to sorted, merged `ranges`, joining ranges that overlap or touch.
+fn merge_range(mut ranges: Vec<ByteRange>, range: ByteRange) -> Vec<ByteRange> {
+    ranges.push(range);
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// Deletes the files of uploads left by a previous run, which can't be resumed. Called once at
+/// startup.
+pub async fn clear_stale_files() -> anyhow::Result<()> {
+    let dir = uploads_dir()?;
+    if dir.exists() {
+        tokio::fs::remove_dir_all(&dir).await?;
+    }
+    Ok(())
+}
+
+/// Deletes stale uploads every minute. Runs until the server exits.
+pub async fn run(state: AppState) {
+    loop {
+        tokio::time::sleep(CLEANUP_INTERVAL).await;
+        let count = state.uploads.expire().await;
+        if count > 0 {
+            tracing::info!("Deleted {} stale uploads", count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{state, FakeRunner};
+
+    #[tokio::test]
+    async fn deletes_uploads_that_went_stale() {
+        let state = state(FakeRunner::new());
+        state.config.write().unwrap().allow_uploads = true;
+        let stale = state.uploads.create(&state, 10).await.unwrap_or_else(|e| panic!("{}", e));
+        let fresh = state.uploads.create(&state, 10).await.unwrap_or_else(|e| panic!("{}", e));
+        state.uploads.entries.lock().unwrap().get_mut(&stale.upload_id).unwrap().expires_at = Utc::now();
+
+        assert_eq!(state.uploads.expire().await, 1);
+        assert!(state.uploads.get(&stale.upload_id).is_none() && !stale.path.exists());
+        assert!(state.uploads.get(&fresh.upload_id).is_some() && fresh.path.exists());
+    }
+
+    #[test]
+    fn merges_overlapping_and_adjacent_ranges() {
+        let ranges = [(10, 19), (0, 4), (5, 9), (30, 39), (15, 25)]
+            .into_iter()
+            .fold(Vec::new(), |ranges, (start, end)| merge_range(ranges, ByteRange { start, end }));
+        assert_eq!(ranges, [ByteRange { start: 0, end: 25 }, ByteRange { start: 30, end: 39 }]);
+    }
+
+    #[test]
+    fn parses_content_ranges() {
+        assert_eq!(parse_content_range("bytes 0-9/100", 100).ok(), Some(ByteRange { start: 0, end: 9 }));
+        assert_eq!(parse_content_range("bytes 90-99/*", 100).ok(), Some(ByteRange { start: 90, end: 99 }));
+        for invalid in ["bytes 0-9/50", "bytes 9-0/100", "bytes 90-100/100", "0-9/100", "bytes 0-/100"] {
+            assert!(parse_content_range(invalid, 100).is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use crate::{models::DownloadStatus, report, AppState};
+use std::path::Path;
+
+/// The placeholders `webhook_template` may use.
+const PLACEHOLDERS: [&str; 4] = ["event", "title", "status", "files"];
+
+/// POSTs the `started` event of a download that got past the queue.
+pub fn download_started(state: &AppState, download_key: &str) {
+    send(state, download_key, "started");
+}
+
+/// POSTs the `finished` event of a download that completed, failed or was cancelled.
+pub fn download_finished(state: &AppState, download_key: &str) {
+    send(state, download_key, "finished");
+}
+
+/// POSTs `event` for a download to `webhook_url`, when it's set: `webhook_template` rendered for
+/// the download, or else its status as JSON. Delivered in the background; a failure is logged.
+fn send(state: &AppState, download_key: &str, event: &str) {
+    let (target, secret, template) = {
+        let config = state.config.read().unwrap();
+        (config.webhook_url.clone(), config.webhook_secret.clone(), config.webhook_template.clone())
+    };
+    let Some(target) = target else { return };
+    let Some(status) = state.downloads.lock().get(download_key).cloned() else { return };
+    let body = match &template {
+        Some(template) => {
+            let (title, files) = (title(download_key, &status), file_names(&status));
+            render(template, &[("event", event), ("title", &title), ("status", &status.status), ("files", &files)]).into_bytes()
+        }
+        None => serde_json::to_vec(&status).unwrap_or_default(),
+    };
+    let (state, download_key, event) = (state.clone(), download_key.to_string(), event.to_string());
+    tokio::spawn(async move {
+        if let Err(e) = report::deliver(&state, &target, body, secret).await {
+            tracing::warn!("Failed to send the {} webhook for {} to {}: {:#}", event, download_key, target, e);
+        }
+    });
+}
+
+/// The download's title as yt-dlp reported it, else its first file's name, else its key.
+fn title(download_key: &str, status: &DownloadStatus) -> String {
+    status
+        .expected_files
+        .iter()
+        .find_map(|file| file.title.clone())
+        .or_else(|| status.files.first().and_then(|file| Path::new(file).file_stem()).map(|stem| stem.to_string_lossy().to_string()))
+        .unwrap_or_else(|| download_key.to_string())
+}
+
+fn file_names(status: &DownloadStatus) -> String {
+    let names: Vec<_> = status.files.iter().filter_map(|file| Path::new(file).file_name()).map(|name| name.to_string_lossy()).collect();
+    names.join(", ")
+}
+
+/// Replaces each `{{name}}` in `template` with its value in `values`, escaped to sit inside a
+/// JSON string. The template is read in one pass, so a value that itself contains a
+/// placeholder, e.g. a title with "{{status}}" in it, is left as it is. Unknown names are kept.
+pub fn render(template: &str, values: &[(&str, &str)]) -> String {
+    let escape = |value: &str| {
+        let quoted = serde_json::Value::from(value).to_string();
+        quoted[1..quoted.len() - 1].to_string()
+    };
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let value = after.find("}}").and_then(|end| values.iter().find(|(name, _)| *name == &after[..end]).map(|(_, value)| (end, value)));
+        match value {
+            Some((end, value)) => {
+                rendered.push_str(&escape(value));
+                rest = &after[end + 2..];
+            }
+            None => {
+                rendered.push_str("{{");
+                rest = after;
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Checks that `template` uses only known placeholders and renders to JSON, even for values
+/// with quotes and newlines.
+pub fn template_problems(template: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+    for (index, _) in template.match_indices("{{") {
+        let name = template[index + 2..].split("}}").next().unwrap_or_default();
+        if !PLACEHOLDERS.contains(&name) {
+            problems.push(format!("webhook_template has an unknown placeholder '{{{{{}}}}}'; use {{{{event}}}}, {{{{title}}}}, {{{{status}}}} or {{{{files}}}}", name));
+        }
+    }
+    let sample = render(
+        template,
+        &[("event", "finished"), ("title", "A \"quoted\"\ntitle"), ("status", "completed"), ("files", "a.mp4, b.m4a")],
+    );
+    if let Err(e) = serde_json::from_str::<serde_json::Value>(&sample) {
+        problems.push(format!("webhook_template doesn't render to JSON: {}", e));
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ExpectedFile;
+    use crate::test_support::{app, fake_ytdlp, finished, send, state, FakeRunner};
+    use axum::{body::Bytes, http::{HeaderMap, Method, StatusCode}, routing::post, Router};
+    use hmac::{Hmac, Mac};
+    use serde_json::{json, Value};
+    use sha2::Sha256;
+
+    /// A server that passes every body POSTed to `/hook` on to the returned channel.
+    async fn receiver() -> (String, tokio::sync::mpsc::UnboundedReceiver<Value>) {
+        let (url, mut signed) = signed_receiver().await;
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some((body, _)) = signed.recv().await {
+                let _ = sender.send(body);
+            }
+        });
+        (url, receiver)
+    }
+
+    /// Like `receiver`, but also passes on whether each body's signature header is the
+    /// HMAC-SHA256 of the body with `webhook-secret`.
+    async fn signed_receiver() -> (String, tokio::sync::mpsc::UnboundedReceiver<(Value, bool)>) {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let app = Router::new().route(
+            "/hook",
+            post(move |headers: HeaderMap, body: Bytes| async move {
+                let mut mac = Hmac::<Sha256>::new_from_slice(b"webhook-secret").unwrap();
+                mac.update(&body);
+                let expected = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+                let signed = headers.get(report::SIGNATURE_HEADER).is_some_and(|value| value.as_bytes() == expected.as_bytes());
+                sender.send((serde_json::from_slice(&body).unwrap(), signed)).unwrap();
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url, receiver)
+    }
+
+    fn finished_status() -> DownloadStatus {
+        DownloadStatus {
+            status: "completed".to_string(),
+            files: vec!["/downloads/Video.mp4".to_string(), "/downloads/Video.en.vtt".to_string()],
+            expected_files: vec![ExpectedFile {
+                path: "/downloads/Video.mp4".to_string(),
+                video_id: Some("abc".to_string()),
+                title: Some("Say \"hi\"".to_string()),
+                completed: true,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn posts_the_rendered_template() {
+        let state = state(FakeRunner::new());
+        let (url, mut bodies) = receiver().await;
+        {
+            let mut config = state.config.write().unwrap();
+            config.webhook_url = Some(url);
+            config.webhook_template = Some(r#"{"text": "{{title}}: {{status}}", "files": "{{files}}"}"#.to_string());
+        }
+        state.downloads.lock().insert("https://example.com/v".to_string(), finished_status());
+
+        download_finished(&state, "https://example.com/v");
+        let body = tokio::time::timeout(std::time::Duration::from_secs(10), bodies.recv()).await.unwrap().unwrap();
+        assert_eq!(body, json!({ "text": "Say \"hi\": completed", "files": "Video.mp4, Video.en.vtt" }));
+    }
+
+    #[tokio::test]
+    async fn posts_the_status_without_a_template() {
+        let state = state(FakeRunner::new());
+        let (url, mut bodies) = receiver().await;
+        state.config.write().unwrap().webhook_url = Some(url);
+        state.downloads.lock().insert("https://example.com/v".to_string(), finished_status());
+
+        download_finished(&state, "https://example.com/v");
+        let body = tokio::time::timeout(std::time::Duration::from_secs(10), bodies.recv()).await.unwrap().unwrap();
+        assert_eq!(body["status"], "completed");
+        assert_eq!(body["files"][0], "/downloads/Video.mp4");
+    }
+
+    #[tokio::test]
+    async fn sends_signed_started_and_finished_events() {
+        let state = state(fake_ytdlp());
+        let (url, mut bodies) = signed_receiver().await;
+        {
+            let mut config = state.config.write().unwrap();
+            config.webhook_url = Some(url);
+            config.webhook_secret = Some("webhook-secret".to_string());
+            config.report_secret = Some("report-secret".to_string());
+            config.webhook_template = Some(r#"{"event": "{{event}}", "status": "{{status}}"}"#.to_string());
+        }
+        let app = app(&state);
+        let key = "https://example.com/webhook-events";
+        let started = send(&app, Method::POST, "/download", Some(json!({ "url": key, "format_id": "best" }))).await;
+        assert_eq!(started.status, StatusCode::ACCEPTED, "{}", started.body);
+        finished(&state, key).await;
+
+        let mut events = Vec::new();
+        for _ in 0..2 {
+            let (body, signed) = tokio::time::timeout(std::time::Duration::from_secs(10), bodies.recv()).await.unwrap().unwrap();
+            assert!(signed, "{} isn't signed with webhook_secret", body);
+            events.push(body);
+        }
+        events.sort_by_key(|body| body["event"] != "started");
+        assert_eq!(events, [json!({ "event": "started", "status": "starting" }), json!({ "event": "finished", "status": "completed" })]);
+    }
+
+    #[test]
+    fn renders_placeholders_in_one_pass() {
+        let values = [("title", "{{status}} of \"{{files}}\""), ("status", "completed"), ("files", "a.mp4")];
+        assert_eq!(render(r#"{"text": "{{title}}: {{status}}"}"#, &values), r#"{"text": "{{status}} of \"{{files}}\": completed"}"#);
+        assert_eq!(render("{{unknown}} {{files", &values), "{{unknown}} {{files");
+    }
+
+    #[test]
+    fn validates_templates() {
+        assert!(template_problems(r#"{"text": "{{title}} is {{status}}: {{files}}"}"#).is_empty());
+        let unknown = template_problems(r#"{"text": "{{url}}"}"#);
+        assert_eq!(unknown.len(), 1);
+        assert!(unknown[0].contains("'{{url}}'"), "{}", unknown[0]);
+        // The placeholder must sit inside a string; bare, it renders to invalid JSON.
+        assert_eq!(template_problems(r#"{"title": {{title}}}"#).len(), 1);
+        assert_eq!(template_problems("not json").len(), 1);
+
+        let config = crate::config::Config { webhook_template: Some("{{url}}".to_string()), ..Default::default() };
+        assert!(config.validate().iter().any(|problem| problem.contains("webhook_template")));
+    }
+}
@@ -0,0 +1,557 @@
+use crate::cache::CacheControl;
+use crate::config::{self, YtDlpChannel};
+use crate::error::AppError;
+use crate::{outbound, AppState};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::{OwnedMutexGuard, OwnedRwLockReadGuard, OwnedRwLockWriteGuard};
+
+/// The `ytdlp_version` that installs the newest release.
+pub const LATEST: &str = "latest";
+
+/// The file in `ytdlp_dir()` naming the active managed version.
+const ACTIVE_FILE: &str = "active";
+
+/// The checksums file published with every yt-dlp release.
+const CHECKSUMS_ASSET: &str = "SHA2-256SUMS";
+
+/// The name of an installed binary inside its version's directory.
+const BINARY_NAME: &str = if cfg!(windows) { "yt-dlp.exe" } else { "yt-dlp" };
+
+/// The managed binary every yt-dlp invocation uses, or `None` for the one on PATH.
+/// Global rather than in `AppState` so helpers without the state, e.g. version checks, see it too.
+static ACTIVE: Lazy<RwLock<Option<InstalledVersion>>> = Lazy::new(|| RwLock::new(None));
+
+/// The file every yt-dlp invocation loads `ytdlp_config_inline` from, or `None` when it isn't
+/// set. Global for the same reason as `ACTIVE`.
+static INLINE_CONFIG: Lazy<RwLock<Option<PathBuf>>> = Lazy::new(|| RwLock::new(None));
+
+/// The `run_as_uid` and `run_as_gid` every yt-dlp invocation switches to. Global for the same
+/// reason as `ACTIVE`.
+static RUN_AS: Lazy<RwLock<(Option<u32>, Option<u32>)>> = Lazy::new(|| RwLock::new((None, None)));
+
+/// The file in the data directory holding `ytdlp_config_inline`.
+const INLINE_CONFIG_FILE: &str = "ytdlp-inline.conf";
+
+/// A release installed in the data directory.
+#[derive(Clone, Serialize, Debug)]
+pub struct InstalledVersion {
+    pub version: String,
+    pub path: PathBuf,
+    pub installed_at: Option<DateTime<Utc>>,
+}
+
+/// The outcome of `install_and_switch`.
+pub enum Switch {
+    /// The version is active now.
+    Active(InstalledVersion),
+    /// The version is installed and becomes active once running and queued downloads finish.
+    Pending(InstalledVersion),
+}
+
+/// Coordinates managed version switches with downloads. Every download holds the gate for
+/// reading from the moment it is queued until it finishes; a switch takes it for writing, so
+/// it waits for the queue to drain. Downloads added while a switch waits line up behind it
+/// and start with the new version.
+#[derive(Clone, Default)]
+pub struct YtDlpManager {
+    gate: Arc<tokio::sync::RwLock<()>>,
+    /// Held from the start of an install until its switch is done, so they don't overlap.
+    installing: Arc<tokio::sync::Mutex<()>>,
+    /// The installed version waiting for the queue to drain.
+    pending: Arc<Mutex<Option<String>>>,
+}
+
+impl YtDlpManager {
+    /// Held by a download, so the binary doesn't change between its steps.
+    pub async fn hold(&self) -> OwnedRwLockReadGuard<()> {
+        self.gate.clone().read_owned().await
+    }
+
+    /// The version a switch is waiting to activate.
+    pub fn pending(&self) -> Option<String> {
+        self.pending.lock().unwrap().clone()
+    }
+
+    pub fn is_installing(&self) -> bool {
+        self.installing.try_lock().is_err()
+    }
+
+    /// Takes the install lock as an install does, so tests can look at the server meanwhile.
+    #[cfg(test)]
+    pub async fn hold_install(&self) -> OwnedMutexGuard<()> {
+        self.installing.clone().lock_owned().await
+    }
+}
+
+/// Returns the program to run for yt-dlp: the active managed binary, or `yt-dlp` from PATH.
+pub fn program() -> PathBuf {
+    ACTIVE.read().unwrap().as_ref().map(|active| active.path.clone()).unwrap_or_else(|| PathBuf::from("yt-dlp"))
+}
+
+/// The file to pass as `--config-locations`, when `ytdlp_config_inline` is set.
+pub fn inline_config() -> Option<PathBuf> {
+    INLINE_CONFIG.read().unwrap().clone()
+}
+
+/// The user and group yt-dlp runs as, from `run_as_uid` and `run_as_gid`.
+pub fn run_as() -> (Option<u32>, Option<u32>) {
+    *RUN_AS.read().unwrap()
+}
+
+/// Options `ytdlp_config_inline` may not set, because they run programs, read or write files
+/// of the server's choosing, or load more options from elsewhere.
+const FORBIDDEN_INLINE_OPTIONS: &[&str] = &[
+    "--exec",
+    "--exec-before-download",
+    "--batch-file",
+    "--netrc-cmd",
+    "--netrc-location",
+    "--config-locations",
+    "--config-location",
+    "--downloader",
+    "--external-downloader",
+    "--downloader-args",
+    "--external-downloader-args",
+    "--postprocessor-args",
+    "--ppa",
+    "--use-postprocessor",
+    "--plugin-dirs",
+    "--ffmpeg-location",
+    "--cookies",
+    "--cache-dir",
+    "--download-archive",
+    "--load-info-json",
+    "--print-to-file",
+];
+
+/// Short forms of the forbidden options.
+const FORBIDDEN_SHORT_OPTIONS: &[&str] = &["-a"];
+
+/// Harmless options that look like abbreviations of forbidden ones.
+const ALLOWED_PREFIX_OPTIONS: &[&str] = &["--print", "--netrc"];
+
+/// Options taking an output template or path, which must stay relative and inside the download
+/// directory.
+const PATH_OPTIONS: &[&str] = &["-o", "--output", "-P", "--paths"];
+
+/// Splits yt-dlp config file content into words the way yt-dlp does: on whitespace, with `'`
+/// and `"` quoting, backslash escapes and `#` comments.
+fn config_words(content: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    for line in content.lines() {
+        let mut word: Option<String> = None;
+        let mut quote = None;
+        let mut chars = line.chars();
+        while let Some(c) = chars.next() {
+            match (quote, c) {
+                (Some(q), c) if c == q => quote = None,
+                (Some('"') | None, '\\') => word.get_or_insert_with(String::new).extend(chars.next()),
+                (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+                (None, '\'' | '"') => {
+                    quote = Some(c);
+                    word.get_or_insert_with(String::new);
+                }
+                (None, '#') if word.is_none() => break,
+                (None, c) if c.is_whitespace() => words.extend(word.take()),
+                (None, c) => word.get_or_insert_with(String::new).push(c),
+            }
+        }
+        words.extend(word);
+    }
+    words
+}
+
+/// Whether an output template or `--paths` value leaves the download directory: absolute, or
+/// climbing out with `..`. Either may start with a `TYPE:` prefix, e.g. `thumbnail:`.
+fn escapes_download_dir(value: &str) -> bool {
+    let escapes = |path: &str| {
+        let path = std::path::Path::new(path);
+        path.has_root() || path.components().any(|part| matches!(part, std::path::Component::ParentDir | std::path::Component::Prefix(_)))
+    };
+    escapes(value) || value.split_once(':').is_some_and(|(kind, rest)| kind.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') && escapes(rest))
+}
+
+/// The options in `ytdlp_config_inline` that it may not set. Abbreviated long options count as
+/// the option they abbreviate, since yt-dlp accepts those.
+pub fn inline_config_problems(content: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut words = config_words(content).into_iter();
+    while let Some(word) = words.next() {
+        if !word.starts_with('-') {
+            continue;
+        }
+        let (option, attached) = match word.split_once('=') {
+            Some((option, value)) if word.starts_with("--") => (option.to_string(), Some(value.to_string())),
+            _ if !word.starts_with("--") && word.len() > 2 => (word[..2].to_string(), Some(word[2..].to_string())),
+            _ => (word.clone(), None),
+        };
+        let forbidden = if option.starts_with("--") {
+            option.len() > 3
+                && !ALLOWED_PREFIX_OPTIONS.contains(&option.as_str())
+                && FORBIDDEN_INLINE_OPTIONS.iter().any(|forbidden| forbidden.starts_with(option.as_str()))
+        } else {
+            FORBIDDEN_SHORT_OPTIONS.contains(&option.as_str())
+        };
+        if forbidden {
+            problems.push(format!("{} is not allowed in ytdlp_config_inline", option));
+            continue;
+        }
+        let path_option = PATH_OPTIONS.iter().find(|path| **path == option || (option.len() > 3 && path.starts_with(option.as_str())));
+        if let Some(path_option) = path_option {
+            let value = attached.or_else(|| words.next()).unwrap_or_default();
+            if escapes_download_dir(&value) {
+                problems.push(format!("{} must stay inside the download directory, not '{}'", path_option, value));
+            }
+        }
+    }
+    problems
+}
+
+/// Checks `ytdlp_config_inline` before it is saved: yt-dlp loads it from a temporary file and
+/// only prints its version. Returns yt-dlp's complaint if it rejects the options.
+pub async fn check_inline_config(state: &AppState, content: &str) -> Result<(), String> {
+    let path = std::env::temp_dir().join(format!("yt-agent-{}.conf", uuid::Uuid::new_v4()));
+    tokio::fs::write(&path, content).await.map_err(|e| format!("it couldn't be written out for checking: {}", e))?;
+    let output = state.runner.command("yt-dlp").arg("--config-locations").arg(&path).arg("--version").output().await;
+    let _ = tokio::fs::remove_file(&path).await;
+    match output {
+        Ok(output) if output.status.success() => Ok(()),
+        Ok(output) => Err(String::from_utf8_lossy(&output.stderr).trim().to_string()),
+        Err(e) => Err(format!("yt-dlp couldn't be run to check it: {}", e)),
+    }
+}
+
+/// Writes `ytdlp_config_inline` to its file, replacing it atomically so a starting yt-dlp never
+/// reads half of it, or removes the file when the setting is unset. Returns the file in use.
+fn write_inline_config(content: Option<&str>) -> Result<Option<PathBuf>> {
+    let path = config::data_dir()?.join(INLINE_CONFIG_FILE);
+    let Some(content) = content.filter(|content| !content.trim().is_empty()) else {
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => return Ok(None),
+        }
+    };
+    let partial = path.with_extension("conf.partial");
+    std::fs::write(&partial, content)?;
+    std::fs::rename(&partial, &path)?;
+    Ok(Some(path))
+}
+
+/// Brings how yt-dlp is invoked in line with the config: the user it runs as and the inline
+/// config file. If the file can't be written, the previous one stays in use.
+fn apply_invocation_settings(state: &AppState) {
+    let (content, run_as) = {
+        let config = state.config.read().unwrap();
+        (config.ytdlp_config_inline.clone(), (config.run_as_uid, config.run_as_gid))
+    };
+    *RUN_AS.write().unwrap() = run_as;
+    // A config file edited by hand isn't checked before it is loaded; leave such options out.
+    let problems = content.as_deref().map(inline_config_problems).unwrap_or_default();
+    let content = if problems.is_empty() {
+        content
+    } else {
+        tracing::error!("Ignoring ytdlp_config_inline: {}", problems.join("; "));
+        None
+    };
+    match write_inline_config(content.as_deref()) {
+        Ok(path) => *INLINE_CONFIG.write().unwrap() = path,
+        Err(e) => tracing::error!("Failed to write ytdlp_config_inline: {:?}", e),
+    }
+}
+
+/// The active managed version, or `None` when yt-dlp comes from PATH.
+pub fn active() -> Option<InstalledVersion> {
+    ACTIVE.read().unwrap().clone()
+}
+
+/// Whether `version` can name a release: letters, digits, `.`, `-` and `_`, not starting with
+/// a dot. It becomes a directory name, so nothing else is allowed.
+pub fn is_valid_version(version: &str) -> bool {
+    !version.is_empty()
+        && version.len() <= 64
+        && !version.starts_with('.')
+        && version.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+}
+
+/// The release asset that runs on this platform without a Python install.
+pub(crate) fn asset_name() -> &'static str {
+    if cfg!(all(target_os = "linux", target_arch = "x86_64")) {
+        "yt-dlp_linux"
+    } else if cfg!(all(target_os = "linux", target_arch = "aarch64")) {
+        "yt-dlp_linux_aarch64"
+    } else if cfg!(target_os = "macos") {
+        "yt-dlp_macos"
+    } else if cfg!(windows) {
+        "yt-dlp.exe"
+    } else {
+        // The zipapp; needs python3 on PATH.
+        "yt-dlp"
+    }
+}
+
+/// Returns the directory holding one subdirectory per installed version.
+fn ytdlp_dir() -> Result<PathBuf> {
+    Ok(config::data_dir()?.join("ytdlp"))
+}
+
+/// Lists the installed versions, most recently installed first.
+pub async fn installed() -> Result<Vec<InstalledVersion>> {
+    let mut versions = Vec::new();
+    let mut entries = match tokio::fs::read_dir(ytdlp_dir()?).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(versions),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let version = entry.file_name().to_string_lossy().into_owned();
+        // Skips the active file and unfinished installs, whose directories start with a dot.
+        if !is_valid_version(&version) {
+            continue;
+        }
+        if let Some(installed) = find_installed(&version).await {
+            versions.push(installed);
+        }
+    }
+    versions.sort_by_key(|installed| std::cmp::Reverse(installed.installed_at));
+    Ok(versions)
+}
+
+async fn find_installed(version: &str) -> Option<InstalledVersion> {
+    let path = ytdlp_dir().ok()?.join(version).join(BINARY_NAME);
+    let metadata = tokio::fs::metadata(&path).await.ok().filter(|m| m.is_file())?;
+    let installed_at = metadata.modified().ok().map(DateTime::<Utc>::from);
+    Some(InstalledVersion { version: version.to_string(), path, installed_at })
+}
+
+/// Makes the binary match the config, in the background: the one on PATH for `system`, the
+/// configured version for `managed`. A managed version that isn't installed yet is downloaded
+/// first; until then the previous binary stays in use. With `ytdlp_version = "latest"` the
+/// release it resolved to last time is kept, so a restart doesn't upgrade yt-dlp by itself.
+/// The `ytdlp_config_inline` file and `run_as_uid` are applied before this returns.
+pub fn sync(state: &AppState) {
+    apply_invocation_settings(state);
+    let state = state.clone();
+    tokio::spawn(async move { sync_now(&state).await });
+}
+
+/// Like `sync`, but finishes the switch (or install) before returning.
+pub async fn sync_now(state: &AppState) {
+    apply_invocation_settings(state);
+    let (channel, wanted) = {
+        let config = state.config.read().unwrap();
+        (config.ytdlp_channel, config.ytdlp_version.clone())
+    };
+    if channel == YtDlpChannel::System {
+        if active().is_some() {
+            let _gate = state.ytdlp.gate.write().await;
+            *ACTIVE.write().unwrap() = None;
+            tracing::info!("Switched to yt-dlp from PATH");
+        }
+        return;
+    }
+    let mut requested = wanted;
+    if requested == LATEST {
+        if let Some(version) = read_active_file().await {
+            requested = version;
+        }
+    }
+    if active().is_some_and(|active| active.version == requested) {
+        return;
+    }
+    match install_and_switch(state, &requested).await {
+        Ok(_) => {}
+        Err(AppError::Internal(e)) => tracing::error!("Failed to install yt-dlp {}: {:?}", requested, e),
+        Err(e) => tracing::error!("Failed to install yt-dlp {}: {}", requested, e),
+    }
+}
+
+async fn read_active_file() -> Option<String> {
+    let version = tokio::fs::read_to_string(ytdlp_dir().ok()?.join(ACTIVE_FILE)).await.ok()?;
+    let version = version.trim();
+    (is_valid_version(version) && find_installed(version).await.is_some()).then(|| version.to_string())
+}
+
+/// Installs `requested` unless it's installed already, then makes it active: right away when
+/// no download is queued or running, otherwise once they have all finished. A failed download
+/// or verification leaves the active binary as it was.
+pub async fn install_and_switch(state: &AppState, requested: &str) -> Result<Switch, AppError> {
+    let Ok(installing) = state.ytdlp.installing.clone().try_lock_owned() else {
+        return Err(AppError::Conflict("Another yt-dlp install is in progress".to_string()));
+    };
+    let installed = install(state, requested).await?;
+    match state.ytdlp.gate.clone().try_write_owned() {
+        Ok(gate) => {
+            activate(state, &installed, gate, installing).await?;
+            Ok(Switch::Active(installed))
+        }
+        Err(_) => {
+            tracing::info!("yt-dlp {} is installed and becomes active once the download queue drains", installed.version);
+            *state.ytdlp.pending.lock().unwrap() = Some(installed.version.clone());
+            let (state, pending) = (state.clone(), installed.clone());
+            tokio::spawn(async move {
+                let gate = state.ytdlp.gate.clone().write_owned().await;
+                if let Err(e) = activate(&state, &pending, gate, installing).await {
+                    tracing::error!("Failed to switch to yt-dlp {}: {:?}", pending.version, e);
+                }
+            });
+            Ok(Switch::Pending(installed))
+        }
+    }
+}
+
+/// Makes `installed` the binary every invocation uses and remembers it across restarts.
+/// Cached formats are dropped, as they came from the previous version.
+async fn activate(
+    state: &AppState,
+    installed: &InstalledVersion,
+    _gate: OwnedRwLockWriteGuard<()>,
+    _installing: OwnedMutexGuard<()>,
+) -> Result<()> {
+    let result = tokio::fs::write(ytdlp_dir()?.join(ACTIVE_FILE), &installed.version).await;
+    *state.ytdlp.pending.lock().unwrap() = None;
+    result?;
+    *ACTIVE.write().unwrap() = Some(installed.clone());
+    state.formats_cache.clear();
+    tracing::info!("Switched to yt-dlp {} at {}", installed.version, installed.path.display());
+    Ok(())
+}
+
+/// Downloads the platform's binary of `requested` and checks it against the release's
+/// published SHA-256 sums. It is written to a scratch directory and only moved into place once
+/// verified, so a failure never leaves a half-written version behind.
+async fn install(state: &AppState, requested: &str) -> Result<InstalledVersion, AppError> {
+    let (releases, max_redirects) = {
+        let config = state.config.read().unwrap();
+        (config.ytdlp_release_url.trim_end_matches('/').to_string(), config.max_redirects)
+    };
+    let client = outbound::client(&releases, max_redirects)?;
+    let version = if requested == LATEST { resolve_latest(&client, &releases).await? } else { requested.to_string() };
+    if let Some(installed) = find_installed(&version).await {
+        return Ok(installed);
+    }
+
+    let asset = asset_name();
+    let release = format!("{}/download/{}", releases, version);
+    tracing::info!("Downloading yt-dlp {} ({}) from {}", version, asset, release);
+    let checksums = fetch(&client, &format!("{}/{}", release, CHECKSUMS_ASSET)).await?;
+    let checksums = String::from_utf8_lossy(&checksums);
+    let expected = checksums
+        .lines()
+        .filter_map(|line| line.split_once(char::is_whitespace))
+        .find(|(_, name)| name.trim().trim_start_matches('*') == asset)
+        .map(|(sum, _)| sum.to_ascii_lowercase())
+        .ok_or_else(|| AppError::ServiceUnavailable(format!("The {} of yt-dlp {} doesn't list {}", CHECKSUMS_ASSET, version, asset)))?;
+    let binary = fetch(&client, &format!("{}/{}", release, asset)).await?;
+    let actual = hex::encode(Sha256::digest(&binary));
+    if actual != expected {
+        return Err(AppError::ServiceUnavailable(format!(
+            "The downloaded yt-dlp {} failed verification: its SHA-256 is {} but the release lists {}",
+            version, actual, expected
+        )));
+    }
+
+    let installed = write_version(&version, &binary).await?;
+    tracing::info!("Installed yt-dlp {} at {}", version, installed.path.display());
+    Ok(installed)
+}
+
+/// Resolves "latest" to a version by following the releases page's redirect to its tag.
+async fn resolve_latest(client: &reqwest::Client, releases: &str) -> Result<String, AppError> {
+    let url = format!("{}/latest", releases);
+    let response = client
+        .get(&url)
+        .send()
+        .await
+        .map_err(|e| AppError::ServiceUnavailable(format!("Failed to look up the latest yt-dlp release at {}: {}", url, e)))?;
+    let version = response.url().path().rsplit_once("/tag/").map(|(_, tag)| tag.trim_end_matches('/').to_string());
+    version
+        .filter(|version| is_valid_version(version))
+        .ok_or_else(|| AppError::ServiceUnavailable(format!("{} didn't redirect to a release tag", url)))
+}
+
+async fn fetch(client: &reqwest::Client, url: &str) -> Result<Vec<u8>, AppError> {
+    let failed = |e: &dyn std::fmt::Display| AppError::ServiceUnavailable(format!("Failed to download {}: {}", url, e));
+    let response = client.get(url).send().await.map_err(|e| failed(&e))?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Err(AppError::NotFound(format!("{} doesn't exist; check the version", url)));
+    }
+    if !response.status().is_success() {
+        return Err(failed(&response.status()));
+    }
+    Ok(response.bytes().await.map_err(|e| failed(&e))?.to_vec())
+}
+
+async fn write_version(version: &str, binary: &[u8]) -> Result<InstalledVersion> {
+    let dir = ytdlp_dir()?;
+    let scratch = dir.join(format!(".{}.{}", version, uuid::Uuid::new_v4()));
+    tokio::fs::create_dir_all(&scratch).await?;
+    let result = async {
+        let path = scratch.join(BINARY_NAME);
+        tokio::fs::write(&path, binary).await?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            tokio::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).await?;
+        }
+        tokio::fs::rename(&scratch, dir.join(version)).await
+    }
+    .await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_dir_all(&scratch).await;
+    }
+    result?;
+    find_installed(version).await.ok_or_else(|| anyhow!("yt-dlp {} disappeared after installing it", version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_config_words_like_yt_dlp() {
+        let words = config_words("-o '%(title)s [%(id)s].%(ext)s'  # the name\n# --exec id\n--format \"best\\\"video\" --no-mtime");
+        assert_eq!(words, ["-o", "%(title)s [%(id)s].%(ext)s", "--format", "best\"video", "--no-mtime"]);
+    }
+
+    #[test]
+    fn finds_the_inline_options_that_are_not_allowed() {
+        for allowed in [
+            "--no-mtime",
+            "# --exec id",
+            "--print after_move:filepath",
+            "--netrc",
+            "--cookies-from-browser firefox",
+            "-o '%(uploader)s/%(title)s.%(ext)s'",
+            "--output-na-placeholder /none",
+            "-P temp:tmp",
+            "-x --audio-format mp3",
+        ] {
+            assert_eq!(inline_config_problems(allowed), Vec::<String>::new(), "{}", allowed);
+        }
+        for refused in [
+            "--exec id",
+            "--exec=id",
+            "--exe id",
+            "--external-downloader curl",
+            "--downloader=aria2c",
+            "--use-postprocessor Exec",
+            "--cookies /etc/passwd",
+            "--print-to-file title /etc/motd",
+            "-a /etc/passwd",
+            "-o /tmp/%(title)s.%(ext)s",
+            "-o/tmp/x",
+            "--output=subtitle:/tmp/x",
+            "-o '../%(title)s.%(ext)s'",
+            "--paths /srv",
+            "--pa home:/srv",
+        ] {
+            assert_eq!(inline_config_problems(refused).len(), 1, "{}", refused);
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! Sends SIGHUP to a running server process and checks that it picks up the edited config file.
+#![cfg(unix)]
+
+use serde_json::Value;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Stops the server when the test ends, even when it fails.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Polls `GET /config` until `done` accepts it.
+async fn wait_for_config(client: &reqwest::Client, base: &str, done: impl Fn(&Value) -> bool) -> Value {
+    let deadline = Instant::now() + Duration::from_secs(20);
+    loop {
+        if let Ok(response) = client.get(format!("{}/config", base)).send().await {
+            if let Ok(config) = response.json::<Value>().await {
+                if done(&config) {
+                    return config;
+                }
+            }
+        }
+        assert!(Instant::now() < deadline, "the server didn't report the expected config in time");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+fn download_directory(config: &Value) -> Option<&Path> {
+    config["download_directory"].as_str().map(Path::new)
+}
+
+#[tokio::test]
+async fn sighup_reloads_the_download_directory() {
+    let home = tempfile::tempdir().unwrap();
+    let (first, second) = (home.path().join("first"), home.path().join("second"));
+    std::fs::create_dir_all(&first).unwrap();
+    std::fs::create_dir_all(&second).unwrap();
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let base = format!("http://127.0.0.1:{}", port);
+
+    let child = Command::new(env!("CARGO_BIN_EXE_yt-agent"))
+        .args(["server", "run"])
+        .env("HOME", home.path())
+        .env("PORT", port.to_string())
+        .env_remove("XDG_CONFIG_HOME")
+        .env_remove("XDG_DATA_HOME")
+        .env_remove("XDG_CACHE_HOME")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let server = Server(child);
+    let client = reqwest::Client::new();
+
+    // Save a config that downloads to `first`, so there's a file to edit.
+    let mut config = wait_for_config(&client, &base, |_| true).await;
+    config["download_directory"] = Value::from(first.to_str().unwrap());
+    let saved = client.post(format!("{}/config", base)).json(&config).send().await.unwrap();
+    assert!(saved.status().is_success(), "saving the config failed: {}", saved.text().await.unwrap());
+    wait_for_config(&client, &base, |config| download_directory(config) == Some(&*first)).await;
+
+    // Edit the file by hand, as an admin would, and signal the server.
+    std::env::set_var("HOME", home.path());
+    std::env::remove_var("XDG_CONFIG_HOME");
+    let dirs = directories::ProjectDirs::from("com", "YourOrg", "YT-DLP-API").unwrap();
+    let file = dirs.config_dir().join("config.toml");
+    let text = std::fs::read_to_string(&file).unwrap();
+    assert!(text.contains(first.to_str().unwrap()), "the saved config doesn't name the first directory:\n{}", text);
+    std::fs::write(&file, text.replace(first.to_str().unwrap(), second.to_str().unwrap())).unwrap();
+    assert_eq!(unsafe { libc::kill(server.0.id() as libc::pid_t, libc::SIGHUP) }, 0);
+
+    wait_for_config(&client, &base, |config| download_directory(config) == Some(&*second)).await;
+    drop(server);
+}
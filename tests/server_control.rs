@@ -0,0 +1,147 @@
+//! Runs `server start`, `stop` and `restart` concurrently and checks that they never leave more
+//! than the one server the PID file names.
+#![cfg(target_os = "linux")]
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+/// The CLI with its own home and port, so it never touches a real server.
+struct Cli {
+    home: tempfile::TempDir,
+    port: u16,
+}
+
+impl Cli {
+    fn new() -> Self {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        Cli { home: tempfile::tempdir().unwrap(), port }
+    }
+
+    fn command(&self, action: &str) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_yt-agent"));
+        command
+            .args(["server", action])
+            .env("HOME", self.home.path())
+            .env("PORT", self.port.to_string())
+            .env_remove("XDG_CONFIG_HOME")
+            .env_remove("XDG_DATA_HOME")
+            .env_remove("XDG_CACHE_HOME")
+            .stdin(Stdio::null());
+        command
+    }
+
+    /// Runs all of `actions` at once and waits for them. Their output goes through files: a
+    /// started server inherits it and would hold a pipe open.
+    fn run_together(&self, actions: &[&str]) -> Vec<Output> {
+        let logs = tempfile::tempdir().unwrap();
+        let children: Vec<_> = actions
+            .iter()
+            .enumerate()
+            .map(|(index, action)| {
+                let (stdout, stderr) = (logs.path().join(format!("{}.out", index)), logs.path().join(format!("{}.err", index)));
+                let child = self
+                    .command(action)
+                    .stdout(std::fs::File::create(&stdout).unwrap())
+                    .stderr(std::fs::File::create(&stderr).unwrap())
+                    .spawn()
+                    .unwrap();
+                (child, stdout, stderr)
+            })
+            .collect();
+        children
+            .into_iter()
+            .map(|(mut child, stdout, stderr)| Output {
+                status: child.wait().unwrap(),
+                stdout: std::fs::read(stdout).unwrap(),
+                stderr: std::fs::read(stderr).unwrap(),
+            })
+            .collect()
+    }
+
+    fn pid_file(&self) -> PathBuf {
+        self.home.path().join(".local/share/yt-dlp-api/server.pid")
+    }
+
+    /// The PID the PID file names, if there is one.
+    fn recorded_pid(&self) -> Option<u32> {
+        std::fs::read_to_string(self.pid_file()).ok().map(|pid| pid.trim().parse().unwrap())
+    }
+
+    /// Every live `server run` process under this home.
+    fn servers(&self) -> Vec<u32> {
+        let home = format!("HOME={}", self.home.path().display());
+        let mut servers = Vec::new();
+        for entry in std::fs::read_dir("/proc").unwrap().flatten() {
+            let Some(pid) = entry.file_name().to_str().and_then(|name| name.parse::<u32>().ok()) else { continue };
+            let read = |file: &str| std::fs::read(entry.path().join(file)).unwrap_or_default();
+            let cmdline = read("cmdline");
+            let is_server = cmdline.split(|b| *b == 0).map(|arg| arg.to_vec()).collect::<Vec<_>>().windows(2).any(|args| args == [b"server".to_vec(), b"run".to_vec()]);
+            let in_home = read("environ").split(|b| *b == 0).any(|var| var == home.as_bytes());
+            if is_server && in_home && !is_zombie(&entry.path()) {
+                servers.push(pid);
+            }
+        }
+        servers
+    }
+
+    /// Checks that at most one server runs, and that it's the one the PID file names.
+    fn assert_consistent(&self, outputs: &[Output]) {
+        let servers = self.servers();
+        let expected: Vec<u32> = self.recorded_pid().into_iter().collect();
+        assert_eq!(servers, expected, "the running servers don't match the PID file; the commands printed:\n{}", describe(outputs));
+    }
+}
+
+impl Drop for Cli {
+    fn drop(&mut self) {
+        for pid in self.servers() {
+            unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) };
+        }
+    }
+}
+
+/// Whether the process has exited and only waits to be reaped.
+fn is_zombie(process: &Path) -> bool {
+    let stat = std::fs::read_to_string(process.join("stat")).unwrap_or_default();
+    // The state follows the command name, which is in parentheses and may contain spaces.
+    stat.rsplit_once(") ").is_none_or(|(_, rest)| rest.starts_with('Z') || rest.starts_with('X'))
+}
+
+fn describe(outputs: &[Output]) -> String {
+    outputs
+        .iter()
+        .map(|output| format!("{}{}{}", output.status, String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[test]
+fn concurrent_starts_and_stops_leave_at_most_one_server() {
+    let cli = Cli::new();
+    let rounds: [&[&str]; 4] = [
+        &["start", "start", "start", "start", "start"],
+        &["start", "stop", "start", "restart", "stop", "start"],
+        &["restart", "restart", "start", "stop"],
+        &["stop", "start", "start", "stop", "start"],
+    ];
+    for actions in rounds {
+        let outputs = cli.run_together(actions);
+        // Each command either did its job or saw the lock and refused to start.
+        for output in &outputs {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            assert!(output.status.success() || stderr.contains("is in progress"), "a command failed:\n{}", describe(&outputs));
+        }
+        cli.assert_consistent(&outputs);
+    }
+
+    // Once things settle, a start leaves exactly one server, and a stop none.
+    let outputs = cli.run_together(&["start"]);
+    assert!(outputs[0].status.success(), "{}", describe(&outputs));
+    cli.assert_consistent(&outputs);
+    assert_eq!(cli.servers().len(), 1);
+    let outputs = cli.run_together(&["stop"]);
+    assert!(outputs[0].status.success(), "{}", describe(&outputs));
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    cli.assert_consistent(&outputs);
+    assert!(cli.servers().is_empty());
+}